@@ -0,0 +1,175 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    str::FromStr,
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use bevy::math::Vec3;
+use crossbeam::channel::{bounded, unbounded, Receiver, Sender};
+
+use crate::constants::ADMIN_REPLY_TIMEOUT;
+
+/// A command of the admin endpoint, one per line: `ban <ip> [seconds]` bans the address for
+/// the duration or permanently, `unban <ip>` lifts its ban and `teleport <player_id> <x> <y> <z>`
+/// moves the player in its session.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdminCommand {
+    Ban {
+        ip: IpAddr,
+        duration: Option<Duration>,
+    },
+    Unban {
+        ip: IpAddr,
+    },
+    Teleport {
+        player_id: String,
+        position: Vec3,
+    },
+}
+
+impl FromStr for AdminCommand {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match args.as_slice() {
+            ["ban", ip] => Ok(AdminCommand::Ban {
+                ip: parse_arg(ip)?,
+                duration: None,
+            }),
+            ["ban", ip, secs] => Ok(AdminCommand::Ban {
+                ip: parse_arg(ip)?,
+                duration: Some(Duration::from_secs(parse_arg(secs)?)),
+            }),
+            ["unban", ip] => Ok(AdminCommand::Unban { ip: parse_arg(ip)? }),
+            ["teleport", player_id, x, y, z] => Ok(AdminCommand::Teleport {
+                player_id: player_id.to_string(),
+                position: parse_position(x, y, z)?,
+            }),
+            _ => Err(format!("unknown command {line:?}")),
+        }
+    }
+}
+
+fn parse_arg<T: FromStr>(arg: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    arg.parse()
+        .map_err(|e| format!("invalid argument {arg:?}: {e}"))
+}
+
+fn parse_position(x: &str, y: &str, z: &str) -> Result<Vec3, String> {
+    let position = Vec3::new(parse_arg(x)?, parse_arg(y)?, parse_arg(z)?);
+    if !position.is_finite() {
+        return Err(format!("non-finite position {x} {y} {z}"));
+    }
+    Ok(position)
+}
+
+/// An admin command waiting for the main loop, which sends back whether it was applied.
+#[derive(Debug)]
+pub struct AdminRequest {
+    pub command: AdminCommand,
+    pub applied: Sender<bool>,
+}
+
+/// Spawns a thread reading [`AdminCommand`]s from the connections on `addr`, one connection
+/// at a time. The commands are queued on the returned receiver and each line is answered with
+/// `ok`, `failed` or the parse error once the main loop handled it.
+/// The endpoint has no authentication, bind it to a local address.
+pub fn spawn_admin_endpoint(
+    addr: SocketAddr,
+) -> io::Result<(JoinHandle<()>, Receiver<AdminRequest>)> {
+    let listener = TcpListener::bind(addr)?;
+    tracing::info!("Admin endpoint listening on {addr}");
+
+    let (requests_tx, requests_rx) = unbounded();
+    let handle = std::thread::Builder::new()
+        .name("admin".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = serve(stream, &requests_tx) {
+                            tracing::debug!("Admin connection closed: {e}");
+                        }
+                    }
+                    Err(e) => tracing::error!("Failed to accept admin connection: {e}"),
+                }
+            }
+        })?;
+
+    Ok((handle, requests_rx))
+}
+
+fn serve(stream: TcpStream, requests: &Sender<AdminRequest>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match line.parse::<AdminCommand>() {
+            Ok(command) => {
+                tracing::info!(?command, "Admin command");
+                let (applied_tx, applied_rx) = bounded(1);
+                let request = AdminRequest {
+                    command,
+                    applied: applied_tx,
+                };
+                if requests.send(request).is_err() {
+                    return Ok(());
+                }
+                match applied_rx.recv_timeout(ADMIN_REPLY_TIMEOUT) {
+                    Ok(true) => "ok".to_string(),
+                    Ok(false) => "failed".to_string(),
+                    Err(_) => "timed out".to_string(),
+                }
+            }
+            Err(e) => format!("error: {e}"),
+        };
+        writeln!(writer, "{reply}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_parsed_from_lines() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(
+            "ban 10.0.0.1".parse(),
+            Ok(AdminCommand::Ban { ip, duration: None })
+        );
+        assert_eq!(
+            " ban  10.0.0.1 60 ".parse(),
+            Ok(AdminCommand::Ban {
+                ip,
+                duration: Some(Duration::from_secs(60))
+            })
+        );
+        assert_eq!("unban 10.0.0.1".parse(), Ok(AdminCommand::Unban { ip }));
+        assert_eq!(
+            "teleport player1 -20 5 30.5".parse(),
+            Ok(AdminCommand::Teleport {
+                player_id: "player1".to_string(),
+                position: Vec3::new(-20.0, 5.0, 30.5)
+            })
+        );
+
+        assert!("ban".parse::<AdminCommand>().is_err());
+        assert!("ban not-an-ip".parse::<AdminCommand>().is_err());
+        assert!("ban 10.0.0.1 soon".parse::<AdminCommand>().is_err());
+        assert!("teleport player1 0 0".parse::<AdminCommand>().is_err());
+        assert!("teleport player1 nan 0 0".parse::<AdminCommand>().is_err());
+        assert!("teleport player1 0 inf 0".parse::<AdminCommand>().is_err());
+        assert!("teleport player1 0 0 -inf".parse::<AdminCommand>().is_err());
+        assert!("kick player1".parse::<AdminCommand>().is_err());
+    }
+}
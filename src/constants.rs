@@ -1,18 +1,126 @@
 use std::time::Duration;
 
+use bevy::math::Vec3;
+
 pub const TRANSPORT_MAX_CLIENTS: usize = 1024;
 pub const TRANSPORT_MAX_PENDING_CLIENTS: usize = TRANSPORT_MAX_CLIENTS * 4;
 
+/// Protocol version sent by clients in the connection request, the server refuses any other.
+pub const TRANSPORT_CONNECTION_PREFIX: [u8; 3] = *b"MTA";
+
 pub const TRANSPORT_MAX_PACKET_BYTES: usize = 1400;
+/// Smallest datagram every IPv4 host must accept, the lower bound of a connection's packet size.
+pub const TRANSPORT_MIN_PACKET_BYTES: usize = 576;
+/// `u8 packet type | u64 client id` in front of the payload of a transport data packet.
+pub const TRANSPORT_DATA_HEADER_BYTES: usize = 9;
 /// The maximum number of bytes that a payload can have when generating a payload packet.
 pub const TRANSPORT_MAX_PAYLOAD_BYTES: usize = 1300;
 pub const MAX_MESSAGES_LENGTH: usize = 1200;
+/// Size limit of the messages split in chunks, like the full snapshot, so each one fits in
+/// the smallest packet of a connection.
+pub const CHUNKED_MESSAGE_MAX_BYTES: usize = 512;
+/// Longer messages of the day are truncated, so the motd message fits the smallest packet of a
+/// connection like the chunked messages.
+pub const MOTD_MAX_BYTES: usize = 500;
+/// Messages of a client handled in a single tick, the rest of that tick is dropped.
+pub const MAX_CLIENT_MESSAGES_PER_TICK: usize = 64;
+/// Server events kept for debugging after they were consumed.
+pub const SERVER_EVENT_HISTORY_CAPACITY: usize = 256;
+/// Clients that send no input for this long are disconnected, even if keepalives still arrive.
+pub const CLIENT_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+/// Player ids travel as fixed size, zero padded blobs. Longer ids are rejected instead of
+/// truncated, since truncation would make ids sharing a prefix indistinguishable.
+pub const PLAYER_ID_MAX_BYTES: usize = 16;
+/// Sent after the message type byte of every outgoing game message.
+/// Bump it whenever the serialized layout of a message changes.
+pub const MESSAGE_FORMAT_VERSION: u8 = 2;
+/// Compact transform messages quantize each position axis to a u16 within
+/// `[-WORLD_HALF_EXTENT, WORLD_HALF_EXTENT]`, a step of `2 * WORLD_HALF_EXTENT / 65535` (~1.6 cm).
+pub const WORLD_HALF_EXTENT: f32 = 512.0;
+/// Packets whose send would block are retried on the next sends, at most this many are kept.
+pub const TRANSPORT_SEND_QUEUE_MAX_PACKETS: usize = 1024;
+/// How many times a blocked send is retried before the packet is dropped.
+pub const TRANSPORT_SEND_MAX_RETRIES: u32 = 3;
+/// Port the server binds when none is configured.
+pub const DEFAULT_SERVER_PORT: u16 = 5000;
+/// While the transport is saturated, transforms are broadcast once every this many ticks.
+pub const SATURATED_BROADCAST_INTERVAL_TICKS: u32 = 3;
+/// A player's position is broadcast again once it moved further than this from the last sent one.
+pub const TRANSFORM_POSITION_EPSILON: f32 = 0.001;
+/// A player's rotation is broadcast again once it turned more than this many radians.
+pub const TRANSFORM_ROTATION_EPSILON: f32 = 0.001;
+/// Players further than this from a player are left out of the transforms sent to it, unless
+/// the player has its own [`InterestRadius`](crate::ecs::components::InterestRadius).
+pub const INTEREST_RADIUS: f32 = f32::INFINITY;
+/// How long a send of the transport may spend on the packets queued by the sessions.
+pub const TRANSPORT_SEND_BUDGET: Duration = Duration::from_millis(10);
+/// How long the address of a client that disconnected is kept to send the last packets of its
+/// session, when the transport flushes on disconnect.
+pub const TRANSPORT_DISCONNECT_FLUSH_WINDOW: Duration = Duration::from_millis(500);
 pub const TRANSPORT_SEND_RATE: Duration = Duration::from_millis(250);
+/// How long a client may go without sending a packet before the transport drops it.
+pub const TRANSPORT_CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+/// Size after which a packet recording file is rotated.
+pub const RECORDING_MAX_FILE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Level objects past this count are not spawned.
+pub const LEVEL_MAX_OBJECTS: usize = 100_000;
+/// Level colliders spawned per frame while the level loads.
+pub const LEVEL_SPAWN_BATCH_SIZE: usize = 500;
+
+/// How long the main loop may go without ticking before the health endpoint reports unhealthy.
+pub const HEALTH_MAX_TICK_AGE: Duration = Duration::from_millis(1000);
+/// How long the admin endpoint waits for the main loop to apply a command.
+pub const ADMIN_REPLY_TIMEOUT: Duration = Duration::from_secs(1);
 
 pub static VELOCITY_MUL: f32 = 0.3;
 pub static JUMP_SPEED: f32 = 5.5;
 pub static GRAVITY: f32 = 9.8;
+/// Damage of a single pistol hit.
+pub const HIT_DAMAGE: f32 = 20.0;
+pub const PISTOL_WEAPON_ID: u8 = 0;
+pub const ROCKET_WEAPON_ID: u8 = 1;
+pub const ROCKET_SPEED: f32 = 30.0;
+pub const ROCKET_DAMAGE: f32 = 50.0;
+pub const PISTOL_MAG_SIZE: u32 = 12;
+pub const PISTOL_MAX_RESERVE: u32 = 48;
+/// Hitscan shots miss targets further away than this, in meters.
+pub const PISTOL_RANGE: f32 = 100.0;
+pub const PISTOL_RELOAD_TIME: Duration = Duration::from_millis(1500);
+pub const ROCKET_MAG_SIZE: u32 = 1;
+pub const ROCKET_MAX_RESERVE: u32 = 4;
+pub const ROCKET_RELOAD_TIME: Duration = Duration::from_secs(2);
+/// Projectiles that hit nothing are removed after this long.
+pub const PROJECTILE_LIFETIME: Duration = Duration::from_secs(5);
+pub const PLAYER_MAX_HEALTH: f32 = 100.0;
+/// Radius of the player capsule, its half height depends on the [`Stance`](crate::ecs::components::Stance).
+pub const PLAYER_CAPSULE_RADIUS: f32 = 0.5;
+/// Damage multiplier of hits on the head hitbox.
+pub const HEADSHOT_DAMAGE_MULTIPLIER: f32 = 2.0;
+/// Damage multiplier of hits on the legs hitbox.
+pub const LEGS_DAMAGE_MULTIPLIER: f32 = 0.75;
+/// Where players spawn and respawn after a death.
+pub const PLAYER_SPAWN_POINT: Vec3 = Vec3::new(25.0, 20.0, -10.0);
+/// Players below this height are killed.
+pub const KILL_Y: f32 = -50.0;
+/// How often the full scoreboard is broadcast.
+pub const SCOREBOARD_SEND_INTERVAL: Duration = Duration::from_secs(2);
+/// How often the health of every player is rebroadcast, clients that missed a change catch up.
+pub const HEALTH_BROADCAST_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the latest input ack is resent, clients that lost one still reconcile.
+pub const INPUT_ACK_RESEND_INTERVAL: Duration = Duration::from_millis(250);
+pub const MATCH_WARMUP_DURATION: Duration = Duration::from_secs(30);
+/// Kills that end the match.
+pub const MATCH_SCORE_LIMIT: u32 = 20;
+pub const MATCH_TIME_LIMIT: Duration = Duration::from_secs(10 * 60);
 
 pub static TICK_DELTA: Duration = Duration::from_millis(16);
+/// Simulation ticks per second of a session, unless its creation asks for another rate.
+pub const SESSION_TICK_RATE: u32 = 120;
+
+/// Lines a rate limited log site writes per [`LOG_RATE_LIMIT_INTERVAL`], unless `LOG_RATE_LIMIT`
+/// sets another limit.
+pub const LOG_RATE_LIMIT: u32 = 10;
+pub const LOG_RATE_LIMIT_INTERVAL: Duration = Duration::from_secs(1);
 
 pub static DEBUG_CAMERA_SENSITIVITY: f32 = 0.01;
@@ -1,9 +1,35 @@
-use bevy::prelude::{Bundle, Component, Entity, Resource};
-use std::collections::HashMap;
+use bevy::{
+    app::ScheduleRunnerPlugin,
+    prelude::{Bundle, Component, Entity, Quat, Resource, Timer, TimerMode, Transform, Vec3},
+};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::constants::{
+    GRAVITY, HEADSHOT_DAMAGE_MULTIPLIER, HEALTH_BROADCAST_INTERVAL, HIT_DAMAGE,
+    INPUT_ACK_RESEND_INTERVAL, INTEREST_RADIUS, JUMP_SPEED, KILL_Y, LEGS_DAMAGE_MULTIPLIER,
+    MATCH_SCORE_LIMIT, MATCH_TIME_LIMIT, MATCH_WARMUP_DURATION, MOTD_MAX_BYTES, PISTOL_MAG_SIZE,
+    PISTOL_MAX_RESERVE, PISTOL_RANGE, PISTOL_RELOAD_TIME, PISTOL_WEAPON_ID, PLAYER_MAX_HEALTH,
+    PLAYER_SPAWN_POINT, PROJECTILE_LIFETIME, ROCKET_DAMAGE, ROCKET_MAG_SIZE, ROCKET_MAX_RESERVE,
+    ROCKET_RELOAD_TIME, ROCKET_SPEED, ROCKET_WEAPON_ID, SCOREBOARD_SEND_INTERVAL,
+    SESSION_TICK_RATE, TRANSFORM_POSITION_EPSILON, TRANSFORM_ROTATION_EPSILON, VELOCITY_MUL,
+    WORLD_HALF_EXTENT,
+};
+use crate::server::error::DisconnectReason;
 
 #[derive(Default, Component)]
 pub struct Player {
     pub id: String,
+    /// Compact id used instead of `id` in the hot network messages, assigned at spawn
+    pub network_id: u16,
 }
 #[derive(Default, Component)]
 pub struct Health(pub f32);
@@ -11,11 +37,343 @@ pub struct Health(pub f32);
 #[derive(Debug, Component)]
 pub struct VerticalVelocity(pub f32);
 
+/// Body posture of a player, it sets the height of the player capsule and so its hitbox.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Component)]
+pub enum Stance {
+    #[default]
+    Standing = 0,
+    Crouching = 1,
+    Prone = 2,
+}
+
+impl Stance {
+    /// Half height of the cylinder part of the player capsule.
+    pub fn half_height(self) -> f32 {
+        match self {
+            Stance::Standing => 0.5,
+            Stance::Crouching => 0.2,
+            Stance::Prone => 0.0,
+        }
+    }
+}
+
+/// Part of the body covered by a hitbox collider, the hitboxes are children of the player entity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Component)]
+pub enum HitRegion {
+    Head = 0,
+    Body = 1,
+    Legs = 2,
+}
+
+impl HitRegion {
+    pub const ALL: [HitRegion; 3] = [HitRegion::Head, HitRegion::Body, HitRegion::Legs];
+
+    pub fn damage_multiplier(self) -> f32 {
+        match self {
+            HitRegion::Head => HEADSHOT_DAMAGE_MULTIPLIER,
+            HitRegion::Body => 1.0,
+            HitRegion::Legs => LEGS_DAMAGE_MULTIPLIER,
+        }
+    }
+}
+
+impl TryFrom<u8> for Stance {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Stance::Standing),
+            1 => Ok(Stance::Crouching),
+            2 => Ok(Stance::Prone),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Component)]
+pub struct Score {
+    pub kills: u32,
+    pub deaths: u32,
+    pub assists: u32,
+}
+
+/// Network ids of the players that hit this player since its last death, they get an assist
+/// when someone else lands the kill.
+#[derive(Debug, Default, Component)]
+pub struct RecentAttackers(pub Vec<u16>);
+
+/// Velocity of the last character controller move in units per second,
+/// sent to the clients for dead reckoning.
+#[derive(Debug, Default, Component)]
+pub struct PlayerVelocity(pub Vec3);
+
 #[derive(Debug, Component)]
 pub struct MoveInput {
     pub x: f32,
     pub y: f32,
     pub z: f32,
+    /// Input sequence of the move, if the client sent one
+    pub sequence: Option<u32>,
+}
+
+/// The last input sequence applied to the movement of the player, acked to its client.
+#[derive(Debug, Default, Component)]
+pub struct LastProcessedInput(pub Option<u32>);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Component)]
+pub struct Team(pub u8);
+
+/// Players are spread over `team_count` teams at spawn, a single team is free for all.
+#[derive(Debug, Clone, Resource)]
+pub struct TeamConfig {
+    pub team_count: u8,
+    /// When off, shots pass through teammates
+    pub friendly_fire: bool,
+}
+
+impl Default for TeamConfig {
+    fn default() -> Self {
+        Self {
+            team_count: 1,
+            friendly_fire: true,
+        }
+    }
+}
+
+/// Who a hit may hurt, applied on top of the collision filtering of the shots.
+#[derive(Debug, Clone, Resource)]
+pub struct DamagePolicy {
+    /// When off, players can't hurt themselves
+    pub self_damage: bool,
+    /// Scales the damage between teammates, 0 makes friendly hits harmless.
+    /// Only reached when shots hit teammates, see [`TeamConfig::friendly_fire`]
+    pub friendly_fire_multiplier: f32,
+}
+
+impl Default for DamagePolicy {
+    fn default() -> Self {
+        Self {
+            self_damage: false,
+            friendly_fire_multiplier: 1.0,
+        }
+    }
+}
+
+impl DamagePolicy {
+    /// Multiplier of the damage of a hit, 0 when the hit must not hurt its target.
+    pub fn multiplier(&self, self_hit: bool, teammates: bool) -> f32 {
+        if self_hit {
+            if self.self_damage {
+                1.0
+            } else {
+                0.0
+            }
+        } else if teammates {
+            self.friendly_fire_multiplier
+        } else {
+            1.0
+        }
+    }
+}
+
+impl TeamConfig {
+    /// Picks one of the teams with the fewest players, ties are broken by the session RNG.
+    pub fn balanced_team<'a>(
+        &self,
+        teams: impl IntoIterator<Item = &'a Team>,
+        rng: &mut SessionRng,
+    ) -> Team {
+        let mut team_sizes = vec![0usize; self.team_count.max(1) as usize];
+        for team in teams {
+            if let Some(size) = team_sizes.get_mut(team.0 as usize) {
+                *size += 1;
+            }
+        }
+        let smallest = team_sizes.iter().min().copied().unwrap_or_default();
+        let candidates: Vec<u8> = (0..team_sizes.len() as u8)
+            .filter(|team| team_sizes[*team as usize] == smallest)
+            .collect();
+        Team(candidates.choose(rng.rng()).copied().unwrap_or_default())
+    }
+}
+
+/// Only source of randomness of a session. Seeded from the session creation parameters so
+/// a session with the same seed plays out the same spawns and team assignments.
+#[derive(Debug, Resource)]
+pub struct SessionRng {
+    seed: u64,
+    rng: StdRng,
+}
+
+impl SessionRng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn rng(&mut self) -> &mut StdRng {
+        &mut self.rng
+    }
+}
+
+/// Points players are randomly placed at when spawning and respawning.
+#[derive(Debug, Clone, Resource)]
+pub struct SpawnPoints(pub Vec<Vec3>);
+
+impl Default for SpawnPoints {
+    fn default() -> Self {
+        Self(vec![PLAYER_SPAWN_POINT])
+    }
+}
+
+impl SpawnPoints {
+    pub fn pick(&self, rng: &mut SessionRng) -> Vec3 {
+        self.0
+            .choose(rng.rng())
+            .copied()
+            .unwrap_or(PLAYER_SPAWN_POINT)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WeaponKind {
+    /// Hits instantly along a raycast
+    Hitscan,
+    /// Fires a [`Projectile`] travelling at `speed` units per second
+    Projectile { speed: f32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct WeaponDef {
+    pub name: &'static str,
+    pub kind: WeaponKind,
+    pub damage: f32,
+    pub mag_size: u32,
+    /// Rounds carried besides the magazine at spawn
+    pub max_reserve: u32,
+    pub reload_time: Duration,
+    /// Hitscan shots miss targets further than this, projectiles fly for about this far
+    pub range: f32,
+}
+
+impl WeaponDef {
+    pub fn full_ammo(&self) -> Ammo {
+        Ammo {
+            mag: self.mag_size,
+            reserve: self.max_reserve,
+        }
+    }
+}
+
+/// Weapon definitions by the weapon id clients send with a fire message.
+#[derive(Debug, Resource)]
+pub struct WeaponRegistry {
+    weapons: HashMap<u8, WeaponDef>,
+}
+
+impl Default for WeaponRegistry {
+    fn default() -> Self {
+        let mut registry = Self {
+            weapons: HashMap::new(),
+        };
+        registry.insert(
+            PISTOL_WEAPON_ID,
+            WeaponDef {
+                name: "pistol",
+                kind: WeaponKind::Hitscan,
+                damage: HIT_DAMAGE,
+                mag_size: PISTOL_MAG_SIZE,
+                max_reserve: PISTOL_MAX_RESERVE,
+                reload_time: PISTOL_RELOAD_TIME,
+                range: PISTOL_RANGE,
+            },
+        );
+        registry.insert(
+            ROCKET_WEAPON_ID,
+            WeaponDef {
+                name: "rocket",
+                kind: WeaponKind::Projectile {
+                    speed: ROCKET_SPEED,
+                },
+                damage: ROCKET_DAMAGE,
+                mag_size: ROCKET_MAG_SIZE,
+                max_reserve: ROCKET_MAX_RESERVE,
+                reload_time: ROCKET_RELOAD_TIME,
+                // Projectiles are removed at the end of their lifetime
+                range: ROCKET_SPEED * PROJECTILE_LIFETIME.as_secs_f32(),
+            },
+        );
+        registry
+    }
+}
+
+impl WeaponRegistry {
+    pub fn insert(&mut self, weapon_id: u8, weapon: WeaponDef) {
+        self.weapons.insert(weapon_id, weapon);
+    }
+
+    pub fn get(&self, weapon_id: u8) -> Option<&WeaponDef> {
+        self.weapons.get(&weapon_id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ammo {
+    pub mag: u32,
+    pub reserve: u32,
+}
+
+/// A reload in progress, the magazine is refilled from the reserve once `remaining` runs out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reload {
+    pub weapon_id: u8,
+    pub remaining: Duration,
+}
+
+/// Ammo of the player's weapons, a weapon starts with [`WeaponDef::full_ammo`].
+#[derive(Debug, Default, Component)]
+pub struct Loadout {
+    ammo: HashMap<u8, Ammo>,
+    pub reload: Option<Reload>,
+}
+
+impl Loadout {
+    pub fn ammo(&self, weapon_id: u8, weapon: &WeaponDef) -> Ammo {
+        self.ammo
+            .get(&weapon_id)
+            .copied()
+            .unwrap_or_else(|| weapon.full_ammo())
+    }
+
+    pub fn ammo_mut(&mut self, weapon_id: u8, weapon: &WeaponDef) -> &mut Ammo {
+        self.ammo
+            .entry(weapon_id)
+            .or_insert_with(|| weapon.full_ammo())
+    }
+
+    pub fn set_ammo(&mut self, weapon_id: u8, ammo: Ammo) {
+        self.ammo.insert(weapon_id, ammo);
+    }
+}
+
+/// A travelling shot, moved and checked for impacts every tick until it hits or expires.
+#[derive(Debug, Component)]
+pub struct Projectile {
+    pub owner: Entity,
+    pub owner_network_id: u16,
+    pub owner_team: Team,
+    pub weapon_id: u8,
+    pub damage: f32,
+    /// Units per second
+    pub velocity: Vec3,
+    /// Time left before the projectile expires
+    pub lifetime: Duration,
 }
 
 #[derive(Bundle)]
@@ -23,20 +381,352 @@ pub struct PlayerBundle {
     pub player: Player,
     pub health: Health,
     pub move_input: MoveInput,
+    pub last_processed_input: LastProcessedInput,
     pub v_velocity: VerticalVelocity,
+    pub velocity: PlayerVelocity,
+    pub stance: Stance,
+    pub score: Score,
+    pub recent_attackers: RecentAttackers,
+    pub team: Team,
+    pub loadout: Loadout,
 }
 
 impl Default for PlayerBundle {
     fn default() -> Self {
         PlayerBundle {
-            player: Player { id: String::new() },
-            health: Health(100.0),
+            player: Player::default(),
+            health: Health(PLAYER_MAX_HEALTH),
             move_input: MoveInput {
                 x: 0.0,
                 y: 0.0,
                 z: 0.0,
+                sequence: None,
             },
+            last_processed_input: LastProcessedInput::default(),
             v_velocity: VerticalVelocity(0.0),
+            velocity: PlayerVelocity::default(),
+            stance: Stance::default(),
+            score: Score::default(),
+            recent_attackers: RecentAttackers::default(),
+            team: Team::default(),
+            loadout: Loadout::default(),
+        }
+    }
+}
+
+/// Final state of a player that left the session.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisconnectedPlayer {
+    pub player_id: String,
+    pub reason: DisconnectReason,
+    /// `None` when the player entity was already gone
+    pub health: Option<f32>,
+}
+
+/// Called for every player that leaves the session, before its entity is despawned.
+/// It runs inside the session tick, slow work like persisting stats to a backend
+/// should be moved to its own thread.
+#[derive(Resource)]
+pub struct DisconnectHook(Box<dyn Fn(DisconnectedPlayer) + Send + Sync>);
+
+impl DisconnectHook {
+    pub fn new(hook: impl Fn(DisconnectedPlayer) + Send + Sync + 'static) -> Self {
+        Self(Box::new(hook))
+    }
+
+    pub fn call(&self, player: DisconnectedPlayer) {
+        (self.0)(player)
+    }
+}
+
+/// Movement tuning of a session, set from the session creation parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Resource)]
+pub struct MovementConfig {
+    pub gravity: f32,
+    pub jump_speed: f32,
+    pub velocity_mul: f32,
+}
+
+impl Default for MovementConfig {
+    fn default() -> Self {
+        Self {
+            gravity: GRAVITY,
+            jump_speed: JUMP_SPEED,
+            velocity_mul: VELOCITY_MUL,
+        }
+    }
+}
+
+impl MovementConfig {
+    /// Returns the vertical velocity after `delta_time`.
+    /// Grounded players jump with `jump_input * jump_speed`, airborne ones fall with gravity.
+    pub fn vertical_velocity(
+        &self,
+        velocity: f32,
+        jump_input: f32,
+        grounded: bool,
+        mass: f32,
+        delta_time: f32,
+    ) -> f32 {
+        if grounded {
+            jump_input * self.jump_speed
+        } else {
+            velocity - self.gravity * delta_time * mass
+        }
+    }
+}
+
+/// Simulation ticks per second of a session. It paces the schedule runner, the physics steps
+/// and the server updates, so the network sends follow the same cadence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Resource)]
+pub struct TickRate(pub u32);
+
+impl Default for TickRate {
+    fn default() -> Self {
+        Self(SESSION_TICK_RATE)
+    }
+}
+
+impl TickRate {
+    /// Duration of one tick.
+    pub fn delta(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.0.max(1) as f64)
+    }
+
+    /// Runs the session schedule once per tick.
+    pub fn runner(&self) -> ScheduleRunnerPlugin {
+        ScheduleRunnerPlugin::run_loop(self.delta())
+    }
+}
+
+/// Transform changes smaller than these are not broadcast, so physics jitter of a standing
+/// player costs no bandwidth.
+#[derive(Debug, Clone, Copy, PartialEq, Resource)]
+pub struct TransformSendThreshold {
+    /// Distance from the last sent position
+    pub position: f32,
+    /// Angle in radians from the last sent rotation
+    pub rotation: f32,
+}
+
+impl Default for TransformSendThreshold {
+    fn default() -> Self {
+        Self {
+            position: TRANSFORM_POSITION_EPSILON,
+            rotation: TRANSFORM_ROTATION_EPSILON,
+        }
+    }
+}
+
+impl TransformSendThreshold {
+    pub fn exceeded(&self, sent: &SentTransform, transform: &Transform) -> bool {
+        sent.translation.distance(transform.translation) > self.position
+            || sent.rotation.angle_between(transform.rotation) > self.rotation
+    }
+}
+
+/// Default interest radius of the players of a session, see [`InterestRadius`].
+#[derive(Debug, Clone, Copy, PartialEq, Resource)]
+pub struct InterestConfig {
+    pub radius: f32,
+}
+
+impl Default for InterestConfig {
+    fn default() -> Self {
+        Self {
+            radius: INTEREST_RADIUS,
+        }
+    }
+}
+
+/// Message of the day sent to each client once it is ready, an empty one is not sent.
+#[derive(Debug, Clone, Default, PartialEq, Resource)]
+pub struct Motd(pub String);
+
+impl Motd {
+    /// Truncates `text` to [`MOTD_MAX_BYTES`], on a char boundary.
+    pub fn new(mut text: String) -> Self {
+        if text.len() > MOTD_MAX_BYTES {
+            let mut len = MOTD_MAX_BYTES;
+            while !text.is_char_boundary(len) {
+                len -= 1;
+            }
+            tracing::warn!(
+                len = text.len(),
+                "Motd is longer than {MOTD_MAX_BYTES} bytes, truncating it"
+            );
+            text.truncate(len);
+        }
+        Self(text)
+    }
+}
+
+/// How far a player sees other players: the transforms of players further away are not sent
+/// to it. Players without one use the [`InterestConfig`] radius.
+#[derive(Debug, Clone, Copy, PartialEq, Component)]
+pub struct InterestRadius(pub f32);
+
+/// The transform of a player as last broadcast.
+#[derive(Debug, Clone, Copy, PartialEq, Component)]
+pub struct SentTransform {
+    pub translation: Vec3,
+    pub rotation: Quat,
+}
+
+impl From<&Transform> for SentTransform {
+    fn from(transform: &Transform) -> Self {
+        Self {
+            translation: transform.translation,
+            rotation: transform.rotation,
+        }
+    }
+}
+
+/// Players leaving this box die, `min.y` is the kill plane for players falling off the level.
+#[derive(Debug, Clone, Copy, PartialEq, Resource)]
+pub struct WorldBounds {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Default for WorldBounds {
+    fn default() -> Self {
+        Self {
+            min: Vec3::new(-WORLD_HALF_EXTENT, KILL_Y, -WORLD_HALF_EXTENT),
+            max: Vec3::splat(WORLD_HALF_EXTENT),
+        }
+    }
+}
+
+impl WorldBounds {
+    pub fn contains(&self, position: Vec3) -> bool {
+        position.cmpge(self.min).all() && position.cmple(self.max).all()
+    }
+}
+
+/// A periodic task of a session, paced by an [`IntervalTimer`].
+pub trait Interval: Send + Sync + 'static {
+    const DEFAULT: Duration;
+}
+
+/// Repeating timer pacing the periodic task `T`.
+#[derive(Resource)]
+pub struct IntervalTimer<T: Interval>(pub Timer, PhantomData<T>);
+
+impl<T: Interval> IntervalTimer<T> {
+    pub fn new(interval: Duration) -> Self {
+        Self(Timer::new(interval, TimerMode::Repeating), PhantomData)
+    }
+}
+
+impl<T: Interval> Default for IntervalTimer<T> {
+    fn default() -> Self {
+        Self::new(T::DEFAULT)
+    }
+}
+
+/// The scoreboard broadcast.
+pub struct ScoreboardInterval;
+
+impl Interval for ScoreboardInterval {
+    const DEFAULT: Duration = SCOREBOARD_SEND_INTERVAL;
+}
+
+pub type ScoreboardTimer = IntervalTimer<ScoreboardInterval>;
+
+/// The periodic health broadcast.
+pub struct HealthBroadcastInterval;
+
+impl Interval for HealthBroadcastInterval {
+    const DEFAULT: Duration = HEALTH_BROADCAST_INTERVAL;
+}
+
+pub type HealthBroadcastTimer = IntervalTimer<HealthBroadcastInterval>;
+
+/// The resend of the latest input acks.
+pub struct InputAckInterval;
+
+impl Interval for InputAckInterval {
+    const DEFAULT: Duration = INPUT_ACK_RESEND_INTERVAL;
+}
+
+pub type InputAckTimer = IntervalTimer<InputAckInterval>;
+
+/// Diagnostics published by a session every tick, shared with the transport so they can be
+/// scraped without the debug metrics UI.
+#[derive(Debug, Clone, Default, Resource)]
+pub struct SessionDiagnostics {
+    // Bits of the f64 tick time
+    tick_time_ms: Arc<AtomicU64>,
+    entity_count: Arc<AtomicUsize>,
+}
+
+impl SessionDiagnostics {
+    pub fn record(&self, tick_time_ms: f64, entity_count: usize) {
+        self.tick_time_ms
+            .store(tick_time_ms.to_bits(), Ordering::Relaxed);
+        self.entity_count.store(entity_count, Ordering::Relaxed);
+    }
+
+    /// Smoothed time between the last ticks, 0 until measured.
+    pub fn tick_time_ms(&self) -> f64 {
+        f64::from_bits(self.tick_time_ms.load(Ordering::Relaxed))
+    }
+
+    pub fn entity_count(&self) -> usize {
+        self.entity_count.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MatchPhase {
+    /// Players can move and shoot but take no damage
+    #[default]
+    Warmup = 0,
+    Active = 1,
+    /// The match is over, no more damage is dealt
+    Ended = 2,
+}
+
+#[derive(Debug, Default, Resource)]
+pub struct MatchState {
+    pub phase: MatchPhase,
+    /// Time spent in the current phase
+    pub elapsed: Duration,
+}
+
+impl MatchState {
+    pub fn damage_enabled(&self) -> bool {
+        self.phase == MatchPhase::Active
+    }
+
+    /// Time left in the current phase, zero when it has no time limit.
+    pub fn remaining(&self, match_config: &MatchConfig) -> Duration {
+        let limit = match self.phase {
+            MatchPhase::Warmup => Some(match_config.warmup),
+            MatchPhase::Active => match_config.time_limit,
+            MatchPhase::Ended => None,
+        };
+        limit.map_or(Duration::ZERO, |limit| limit.saturating_sub(self.elapsed))
+    }
+}
+
+/// The match ends when a player reaches `score_limit` kills or after `time_limit` of play,
+/// whichever comes first. `None` disables a condition.
+#[derive(Debug, Clone, Resource)]
+pub struct MatchConfig {
+    pub warmup: Duration,
+    pub score_limit: Option<u32>,
+    pub time_limit: Option<Duration>,
+}
+
+impl Default for MatchConfig {
+    fn default() -> Self {
+        Self {
+            warmup: MATCH_WARMUP_DURATION,
+            score_limit: Some(MATCH_SCORE_LIMIT),
+            time_limit: Some(MATCH_TIME_LIMIT),
         }
     }
 }
@@ -44,12 +734,116 @@ impl Default for PlayerBundle {
 #[derive(Resource)]
 pub struct PlayerLookup {
     pub map: HashMap<String, Entity>,
+    network_ids: HashMap<u16, String>,
+    next_network_id: u16,
 }
 
 impl PlayerLookup {
     pub fn new() -> PlayerLookup {
         PlayerLookup {
             map: HashMap::new(),
+            network_ids: HashMap::new(),
+            next_network_id: 1,
+        }
+    }
+
+    /// Assigns a free network id to the player. 0 is never assigned.
+    pub fn assign_network_id(&mut self, player_id: &str) -> u16 {
+        while self.next_network_id == 0 || self.network_ids.contains_key(&self.next_network_id) {
+            self.next_network_id = self.next_network_id.wrapping_add(1);
+        }
+        let network_id = self.next_network_id;
+        self.next_network_id = self.next_network_id.wrapping_add(1);
+        self.network_ids.insert(network_id, player_id.to_string());
+        network_id
+    }
+
+    /// Frees the network id of the player so it can be assigned again.
+    pub fn release_network_id(&mut self, player_id: &str) {
+        self.network_ids.retain(|_, id| id != player_id);
+    }
+
+    pub fn player_id_by_network_id(&self, network_id: u16) -> Option<&String> {
+        self.network_ids.get(&network_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::app::RunMode;
+
+    use super::*;
+
+    #[test]
+    fn network_id_mapping() {
+        let mut lookup = PlayerLookup::new();
+        let first = lookup.assign_network_id("player1");
+        let second = lookup.assign_network_id("a_player_id_longer_than_16_bytes");
+
+        assert_ne!(first, 0);
+        assert_ne!(first, second);
+        assert_eq!(lookup.player_id_by_network_id(first).unwrap(), "player1");
+        assert_eq!(
+            lookup.player_id_by_network_id(second).unwrap(),
+            "a_player_id_longer_than_16_bytes"
+        );
+
+        lookup.release_network_id("player1");
+        assert!(lookup.player_id_by_network_id(first).is_none());
+
+        // Wrapping around skips 0 and ids still in use
+        lookup.next_network_id = u16::MAX;
+        let third = lookup.assign_network_id("player3");
+        assert_eq!(third, u16::MAX);
+        let fourth = lookup.assign_network_id("player4");
+        assert_eq!(fourth, first);
+        let fifth = lookup.assign_network_id("player5");
+        assert_ne!(fifth, second);
+    }
+
+    #[test]
+    fn custom_tick_rate_sets_runner_interval() {
+        let runner = TickRate(30).runner();
+        assert!(matches!(
+            runner.run_mode,
+            RunMode::Loop { wait: Some(wait) } if wait == Duration::from_secs_f64(1.0 / 30.0)
+        ));
+        assert_eq!(
+            TickRate::default().delta(),
+            Duration::from_secs_f64(1.0 / 120.0)
+        );
+    }
+
+    #[test]
+    fn gravity_differs_per_session() {
+        let normal = MovementConfig::default();
+        let low = MovementConfig {
+            gravity: 1.6,
+            ..MovementConfig::default()
+        };
+
+        let mut normal_velocity = 0.0;
+        let mut low_velocity = 0.0;
+        for _ in 0..10 {
+            normal_velocity = normal.vertical_velocity(normal_velocity, 0.0, false, 1.0, 0.1);
+            low_velocity = low.vertical_velocity(low_velocity, 0.0, false, 1.0, 0.1);
         }
+
+        assert!((normal_velocity + GRAVITY).abs() < 1e-4);
+        assert!((low_velocity + 1.6).abs() < 1e-4);
+        assert_eq!(
+            normal.vertical_velocity(-3.0, 1.0, true, 1.0, 0.1),
+            JUMP_SPEED
+        );
+    }
+
+    #[test]
+    fn long_motd_is_truncated_on_a_char_boundary() {
+        assert_eq!(Motd::new("Welcome".to_string()).0, "Welcome");
+
+        // Two byte chars after a one byte one, the limit falls in the middle of one
+        let motd = Motd::new(format!("a{}", "é".repeat(MOTD_MAX_BYTES)));
+        assert_eq!(motd.0.len(), MOTD_MAX_BYTES - 1);
+        assert!(motd.0.ends_with('é'));
     }
 }
@@ -1,10 +1,16 @@
 use bevy::prelude::*;
 
+use crate::{
+    ecs::components::{HitRegion, Stance, Team},
+    server::{error::DisconnectReason, server::ClientId},
+};
+
 #[derive(Debug, Event)]
 pub struct MoveEvent {
     pub entity: Entity,
     pub x: f32,
     pub y: f32,
+    pub sequence: Option<u32>,
 }
 
 #[derive(Debug, Event)]
@@ -23,15 +29,55 @@ pub struct FireEvent {
     pub cam_origin: Vec3,
     pub direction: Vec3,
     pub barrel_origin: Vec3,
+    /// Id in the [`WeaponRegistry`](crate::ecs::components::WeaponRegistry)
+    pub weapon_id: u8,
+}
+
+#[derive(Event, Debug)]
+pub struct ReloadEvent {
+    pub entity: Entity,
+    pub weapon_id: u8,
+}
+
+#[derive(Event, Debug)]
+pub struct StanceEvent {
+    pub entity: Entity,
+    pub stance: Stance,
 }
 
 #[derive(Event, Debug)]
 pub struct HitEvent {
-    pub hitter_id: String,
+    pub hitter_network_id: u16,
+    pub hitter_team: Team,
     pub hitten: Entity,
     #[allow(dead_code)]
-    pub weapon: String,
+    pub weapon_id: u8,
+    /// Damage before the multiplier of the region
+    pub damage: f32,
     pub point: Vec3,
+    pub region: HitRegion,
+}
+
+/// The client confirmed its connection, the world snapshot can be sent to it.
+#[derive(Event, Debug)]
+pub struct ClientReadyEvent {
+    pub client_id: ClientId,
+}
+
+/// A message sent with [`DenariaServer::send_tracked_message`](crate::server::server::DenariaServer::send_tracked_message)
+/// was acked by the client.
+#[derive(Event, Debug)]
+pub struct MessageAckedEvent {
+    pub client_id: ClientId,
+    pub message_id: u64,
+}
+
+/// The client sent a control payload, see [`ServerEvent::ControlMessage`](crate::server::server::ServerEvent::ControlMessage).
+#[derive(Event, Debug)]
+pub struct ControlMessageEvent {
+    pub client_id: ClientId,
+    pub kind: u8,
+    pub payload: Vec<u8>,
 }
 
 #[derive(Event)]
@@ -39,7 +85,16 @@ pub struct SpawnEvent {
     pub player_id: String,
 }
 
+/// The player died, from a lethal hit or by leaving the [`WorldBounds`](crate::ecs::components::WorldBounds).
+#[derive(Event, Debug)]
+pub struct DeathEvent {
+    pub entity: Entity,
+    /// Network id of the player that landed the lethal hit
+    pub killer_network_id: Option<u16>,
+}
+
 #[derive(Event)]
 pub struct DisconnectEvent {
     pub player_id: String,
+    pub reason: DisconnectReason,
 }
@@ -0,0 +1,225 @@
+use bevy::prelude::*;
+
+use crate::{
+    ecs::{
+        components::{Loadout, Player, Reload, WeaponDef, WeaponRegistry},
+        events::ReloadEvent,
+    },
+    server::{channel::DefaultChannel, message_out::MessageOut, server::DenariaServer},
+};
+
+/// Takes a round from the magazine of the weapon. Returns false when the fire has to be rejected,
+/// because the magazine is empty or the weapon is being reloaded.
+pub fn consume_round(loadout: &mut Loadout, weapon_id: u8, weapon: &WeaponDef) -> bool {
+    if loadout
+        .reload
+        .is_some_and(|reload| reload.weapon_id == weapon_id)
+    {
+        return false;
+    }
+    let ammo = loadout.ammo_mut(weapon_id, weapon);
+    if ammo.mag == 0 {
+        return false;
+    }
+    ammo.mag -= 1;
+    true
+}
+
+// Sends the ammo of the weapon to the client of the player owning it
+pub fn send_ammo(
+    server: &mut DenariaServer,
+    player: &Player,
+    loadout: &Loadout,
+    weapon_id: u8,
+    weapon: &WeaponDef,
+) {
+    let Ok(client_id) = server.client_id_by_player_id(player.id.clone()) else {
+        return;
+    };
+    let ammo = loadout.ammo(weapon_id, weapon);
+    let reloading = loadout
+        .reload
+        .is_some_and(|reload| reload.weapon_id == weapon_id);
+    match MessageOut::ammo_message(weapon_id, ammo.mag, ammo.reserve, reloading) {
+        Ok(ammo_message) => server.send_message(
+            client_id,
+            DefaultChannel::ReliableOrdered,
+            ammo_message.data,
+        ),
+        Err(e) => tracing::error!(
+            player_id = player.id.as_str(),
+            "Failed to serialize ammo message: {e}"
+        ),
+    }
+}
+
+// Starts a timed reload, unless the magazine is full, the reserve is empty or a reload is running
+pub fn handle_reload_events(
+    mut reload_events: EventReader<ReloadEvent>,
+    mut query: Query<(&Player, &mut Loadout)>,
+    weapons: Res<WeaponRegistry>,
+    mut server: ResMut<DenariaServer>,
+) {
+    for event in reload_events.read() {
+        let Ok((player, mut loadout)) = query.get_mut(event.entity) else {
+            continue;
+        };
+        let Some(weapon) = weapons.get(event.weapon_id) else {
+            tracing::warn!(
+                player_id = player.id.as_str(),
+                "Reload of unknown weapon {}, skipping",
+                event.weapon_id
+            );
+            continue;
+        };
+        let ammo = loadout.ammo(event.weapon_id, weapon);
+        if loadout.reload.is_some() || ammo.mag >= weapon.mag_size || ammo.reserve == 0 {
+            tracing::debug!(player_id = player.id.as_str(), "Ignoring reload");
+            continue;
+        }
+
+        loadout.reload = Some(Reload {
+            weapon_id: event.weapon_id,
+            remaining: weapon.reload_time,
+        });
+        send_ammo(&mut server, player, &loadout, event.weapon_id, weapon);
+    }
+}
+
+// Refills the magazine from the reserve once the reload time is over
+pub fn update_reloads(
+    time: Res<Time>,
+    mut query: Query<(&Player, &mut Loadout)>,
+    weapons: Res<WeaponRegistry>,
+    mut server: ResMut<DenariaServer>,
+) {
+    for (player, mut loadout) in &mut query {
+        let Some(mut reload) = loadout.reload else {
+            continue;
+        };
+        reload.remaining = reload.remaining.saturating_sub(time.delta());
+        if !reload.remaining.is_zero() {
+            loadout.reload = Some(reload);
+            continue;
+        }
+
+        loadout.reload = None;
+        let Some(weapon) = weapons.get(reload.weapon_id) else {
+            continue;
+        };
+        let ammo = loadout.ammo_mut(reload.weapon_id, weapon);
+        let refill = weapon.mag_size.saturating_sub(ammo.mag).min(ammo.reserve);
+        ammo.mag += refill;
+        ammo.reserve -= refill;
+        send_ammo(&mut server, player, &loadout, reload.weapon_id, weapon);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bevy_rapier3d::prelude::RapierContext;
+
+    use super::*;
+    use crate::{
+        constants::{
+            PISTOL_MAG_SIZE, PISTOL_MAX_RESERVE, PISTOL_RELOAD_TIME, PISTOL_WEAPON_ID,
+            ROCKET_WEAPON_ID,
+        },
+        ecs::{
+            components::{Ammo, Projectile, TeamConfig},
+            events::{FireEvent, HitEvent},
+            systems::{
+                handle_events::handle_fire_events,
+                test_support::{player_bundle, server_app, test_server},
+            },
+        },
+    };
+
+    fn ammo_app() -> (App, Entity) {
+        let mut app = server_app(test_server(1));
+        app.init_resource::<WeaponRegistry>()
+            .init_resource::<Time>();
+        let player = app.world_mut().spawn(player_bundle(1)).id();
+        (app, player)
+    }
+
+    fn set_ammo(app: &mut App, player: Entity, weapon_id: u8, mag: u32, reserve: u32) {
+        app.world_mut()
+            .get_mut::<Loadout>(player)
+            .unwrap()
+            .set_ammo(weapon_id, Ammo { mag, reserve });
+    }
+
+    fn ammo(app: &App, player: Entity, weapon_id: u8) -> Ammo {
+        let weapons = app.world().resource::<WeaponRegistry>();
+        app.world()
+            .get::<Loadout>(player)
+            .unwrap()
+            .ammo(weapon_id, weapons.get(weapon_id).unwrap())
+    }
+
+    #[test]
+    fn fire_with_empty_magazine_is_rejected() {
+        let (mut app, player) = ammo_app();
+        app.add_event::<HitEvent>()
+            .init_resource::<TeamConfig>()
+            .init_resource::<RapierContext>()
+            .add_systems(Update, handle_fire_events);
+        let fire = |app: &mut App| {
+            app.world_mut().send_event(FireEvent {
+                entity: player,
+                cam_origin: Vec3::ZERO,
+                direction: Vec3::NEG_Z,
+                barrel_origin: Vec3::ZERO,
+                weapon_id: ROCKET_WEAPON_ID,
+            });
+            app.update();
+        };
+        let mut projectiles = app.world_mut().query::<&Projectile>();
+
+        set_ammo(&mut app, player, ROCKET_WEAPON_ID, 0, 4);
+        fire(&mut app);
+        assert_eq!(projectiles.iter(app.world()).count(), 0);
+
+        set_ammo(&mut app, player, ROCKET_WEAPON_ID, 1, 4);
+        fire(&mut app);
+        assert_eq!(projectiles.iter(app.world()).count(), 1);
+        assert_eq!(
+            ammo(&app, player, ROCKET_WEAPON_ID),
+            Ammo { mag: 0, reserve: 4 }
+        );
+    }
+
+    #[test]
+    fn reload_refills_after_reload_time() {
+        let (mut app, player) = ammo_app();
+        app.add_systems(Update, (update_reloads, handle_reload_events).chain());
+        let advance = |app: &mut App, delta: Duration| {
+            app.world_mut().resource_mut::<Time>().advance_by(delta);
+            app.update();
+        };
+
+        set_ammo(&mut app, player, PISTOL_WEAPON_ID, 0, PISTOL_MAX_RESERVE);
+        app.world_mut().send_event(ReloadEvent {
+            entity: player,
+            weapon_id: PISTOL_WEAPON_ID,
+        });
+        advance(&mut app, Duration::ZERO);
+
+        advance(&mut app, PISTOL_RELOAD_TIME - Duration::from_millis(100));
+        assert_eq!(ammo(&app, player, PISTOL_WEAPON_ID).mag, 0);
+        assert!(app.world().get::<Loadout>(player).unwrap().reload.is_some());
+
+        advance(&mut app, Duration::from_millis(100));
+        assert_eq!(
+            ammo(&app, player, PISTOL_WEAPON_ID),
+            Ammo {
+                mag: PISTOL_MAG_SIZE,
+                reserve: PISTOL_MAX_RESERVE - PISTOL_MAG_SIZE
+            }
+        );
+        assert!(app.world().get::<Loadout>(player).unwrap().reload.is_none());
+    }
+}
@@ -0,0 +1,216 @@
+use bevy::prelude::*;
+
+use crate::{
+    constants::PLAYER_MAX_HEALTH,
+    ecs::{
+        components::{
+            Health, Loadout, Player, PlayerVelocity, RecentAttackers, Score, SessionRng,
+            SpawnPoints, VerticalVelocity, WorldBounds,
+        },
+        events::DeathEvent,
+    },
+};
+
+// Kills the players that fell below the kill plane or left the world bounds
+pub fn enforce_world_bounds(
+    world_bounds: Res<WorldBounds>,
+    query: Query<(Entity, &Player, &Transform)>,
+    mut death_events: EventWriter<DeathEvent>,
+) {
+    for (entity, player, transform) in &query {
+        if !world_bounds.contains(transform.translation) {
+            tracing::info!(
+                player_id = player.id.as_str(),
+                "Player left the world bounds at {}",
+                transform.translation
+            );
+            death_events.send(DeathEvent {
+                entity,
+                killer_network_id: None,
+            });
+        }
+    }
+}
+
+// Updates the scores and respawns dead players at a random spawn point with full health
+#[allow(clippy::type_complexity)]
+pub fn handle_death_events(
+    mut death_events: EventReader<DeathEvent>,
+    mut query: Query<(
+        &Player,
+        &mut Health,
+        &mut Transform,
+        &mut VerticalVelocity,
+        &mut PlayerVelocity,
+        &mut Score,
+        &mut RecentAttackers,
+        &mut Loadout,
+    )>,
+    spawn_points: Res<SpawnPoints>,
+    mut rng: ResMut<SessionRng>,
+) {
+    for event in death_events.read() {
+        let Ok((
+            player,
+            mut health,
+            mut transform,
+            mut v_velocity,
+            mut velocity,
+            mut score,
+            mut recent_attackers,
+            mut loadout,
+        )) = query.get_mut(event.entity)
+        else {
+            continue;
+        };
+        tracing::info!(player_id = player.id.as_str(), "Player died, respawning");
+
+        let victim_network_id = player.network_id;
+        score.deaths += 1;
+        health.0 = PLAYER_MAX_HEALTH;
+        transform.translation = spawn_points.pick(&mut rng);
+        v_velocity.0 = 0.0;
+        velocity.0 = Vec3::ZERO;
+        // Respawned players start with full ammo
+        *loadout = Loadout::default();
+        let attackers = std::mem::take(&mut recent_attackers.0);
+
+        // A player killing itself gets neither a kill nor an assist
+        let killer = event
+            .killer_network_id
+            .filter(|killer| *killer != victim_network_id);
+        for (player, _, _, _, _, mut score, _, _) in &mut query {
+            if Some(player.network_id) == killer {
+                score.kills += 1;
+            } else if player.network_id != victim_network_id
+                && attackers.contains(&player.network_id)
+            {
+                score.assists += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::PLAYER_SPAWN_POINT,
+        ecs::{components::PlayerBundle, systems::test_support::player_bundle},
+    };
+
+    fn spawn_player(app: &mut App, network_id: u16, health: f32) -> Entity {
+        app.world_mut()
+            .spawn((
+                PlayerBundle {
+                    health: Health(health),
+                    ..player_bundle(network_id)
+                },
+                Transform::default(),
+            ))
+            .id()
+    }
+
+    #[test]
+    fn falling_below_kill_plane_respawns_player() {
+        let mut app = App::new();
+        app.add_event::<DeathEvent>()
+            .insert_resource(WorldBounds::default())
+            .insert_resource(SessionRng::new(0))
+            .init_resource::<SpawnPoints>()
+            .add_systems(Update, (enforce_world_bounds, handle_death_events).chain());
+
+        let falling = app
+            .world_mut()
+            .spawn((
+                PlayerBundle {
+                    health: Health(40.0),
+                    ..Default::default()
+                },
+                Transform::from_xyz(0.0, -60.0, 0.0),
+            ))
+            .id();
+        let standing = app
+            .world_mut()
+            .spawn((PlayerBundle::default(), Transform::from_xyz(3.0, 1.0, 3.0)))
+            .id();
+
+        app.update();
+
+        let deaths: Vec<Entity> = app
+            .world()
+            .resource::<Events<DeathEvent>>()
+            .iter_current_update_events()
+            .map(|event| event.entity)
+            .collect();
+        assert_eq!(deaths, vec![falling]);
+
+        let world = app.world();
+        assert_eq!(world.get::<Health>(falling).unwrap().0, PLAYER_MAX_HEALTH);
+        assert_eq!(
+            world.get::<Transform>(falling).unwrap().translation,
+            PLAYER_SPAWN_POINT
+        );
+        assert_eq!(
+            world.get::<Transform>(standing).unwrap().translation,
+            Vec3::new(3.0, 1.0, 3.0)
+        );
+    }
+
+    #[test]
+    fn kill_updates_killer_victim_and_assist_scores() {
+        let mut app = App::new();
+        app.add_event::<DeathEvent>()
+            .insert_resource(SessionRng::new(0))
+            .init_resource::<SpawnPoints>()
+            .add_systems(Update, handle_death_events);
+
+        let killer = spawn_player(&mut app, 1, PLAYER_MAX_HEALTH);
+        let assistant = spawn_player(&mut app, 2, PLAYER_MAX_HEALTH);
+        let victim = spawn_player(&mut app, 3, 0.0);
+        let bystander = spawn_player(&mut app, 4, PLAYER_MAX_HEALTH);
+        app.world_mut()
+            .get_mut::<RecentAttackers>(victim)
+            .unwrap()
+            .0 = vec![2, 1];
+
+        app.world_mut().send_event(DeathEvent {
+            entity: victim,
+            killer_network_id: Some(1),
+        });
+        app.update();
+
+        let score = |entity| *app.world().get::<Score>(entity).unwrap();
+        assert_eq!(
+            score(killer),
+            Score {
+                kills: 1,
+                deaths: 0,
+                assists: 0
+            }
+        );
+        assert_eq!(
+            score(assistant),
+            Score {
+                kills: 0,
+                deaths: 0,
+                assists: 1
+            }
+        );
+        assert_eq!(
+            score(victim),
+            Score {
+                kills: 0,
+                deaths: 1,
+                assists: 0
+            }
+        );
+        assert_eq!(score(bystander), Score::default());
+        assert!(app
+            .world()
+            .get::<RecentAttackers>(victim)
+            .unwrap()
+            .0
+            .is_empty());
+    }
+}
@@ -0,0 +1,50 @@
+use bevy::{
+    diagnostic::{DiagnosticsStore, EntityCountDiagnosticsPlugin, FrameTimeDiagnosticsPlugin},
+    prelude::*,
+};
+
+use crate::ecs::components::SessionDiagnostics;
+
+// Publishes the measures of the bevy diagnostics plugins, taken during Update
+pub fn export_diagnostics(store: Res<DiagnosticsStore>, diagnostics: Res<SessionDiagnostics>) {
+    let tick_time_ms = store
+        .get(&FrameTimeDiagnosticsPlugin::FRAME_TIME)
+        .and_then(|diagnostic| diagnostic.smoothed())
+        .unwrap_or(0.0);
+    let entity_count = store
+        .get(&EntityCountDiagnosticsPlugin::ENTITY_COUNT)
+        .and_then(|diagnostic| diagnostic.value())
+        .unwrap_or(0.0) as usize;
+    diagnostics.record(tick_time_ms, entity_count);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn diagnostics_are_readable_headless() {
+        let diagnostics = SessionDiagnostics::default();
+        let mut app = App::new();
+        app.add_plugins((
+            MinimalPlugins,
+            FrameTimeDiagnosticsPlugin,
+            EntityCountDiagnosticsPlugin,
+        ))
+        .insert_resource(diagnostics.clone())
+        .add_systems(PostUpdate, export_diagnostics);
+        for _ in 0..3 {
+            app.world_mut().spawn_empty();
+        }
+
+        for _ in 0..3 {
+            std::thread::sleep(Duration::from_millis(5));
+            app.update();
+        }
+
+        assert_eq!(diagnostics.entity_count(), 3);
+        assert!(diagnostics.tick_time_ms() > 0.0);
+    }
+}
@@ -4,36 +4,71 @@ use bevy::prelude::*;
 use bevy_rapier3d::prelude::*;
 
 use crate::{
-    constants::{GRAVITY, JUMP_SPEED, VELOCITY_MUL},
     ecs::{
-        components::{Health, MoveInput, Player, PlayerBundle, PlayerLookup, VerticalVelocity},
-        events::{DisconnectEvent, FireEvent, HitEvent, LookEvent, SpawnEvent},
+        components::{
+            DamagePolicy, DisconnectHook, DisconnectedPlayer, Health, HitRegion,
+            LastProcessedInput, Loadout, MatchState, MoveInput, MovementConfig, Player,
+            PlayerBundle, PlayerLookup, PlayerVelocity, RecentAttackers, SessionRng, SpawnPoints,
+            Stance, Team, TeamConfig, VerticalVelocity, WeaponKind, WeaponRegistry,
+        },
+        events::{DeathEvent, DisconnectEvent, FireEvent, HitEvent, LookEvent, SpawnEvent},
     },
-    server::{channel::DefaultChannel, message_out::MessageOut, server::DenariaServer},
+    server::{
+        channel::DefaultChannel, message_in::is_valid_direction, message_out::MessageOut,
+        server::DenariaServer,
+    },
+};
+
+use super::{
+    ammo::{consume_round, send_ammo},
+    projectile::spawn_projectile,
+    stance::{player_collider, HitboxBundle},
 };
 
+#[allow(clippy::type_complexity)]
 pub fn handle_character_movement(
     time: Res<Time>,
+    movement_config: Res<MovementConfig>,
     mut query: Query<(
         &mut KinematicCharacterController,
         &mut MoveInput,
+        &mut LastProcessedInput,
         &mut VerticalVelocity,
+        &mut PlayerVelocity,
         Option<&KinematicCharacterControllerOutput>,
     )>,
 ) {
     let delta_time = time.delta_seconds();
-    for (mut controller, mut move_input, mut v_velocity, output) in query.iter_mut() {
-        let mut movement = Vec3::new(move_input.x, 0.0, move_input.z) * VELOCITY_MUL;
-
-        if output.map(|o| o.grounded).unwrap_or(false) {
-            v_velocity.0 = move_input.y * JUMP_SPEED;
-        } else {
-            v_velocity.0 -= GRAVITY * delta_time * controller.custom_mass.unwrap_or(1.0);
+    for (
+        mut controller,
+        mut move_input,
+        mut last_processed,
+        mut v_velocity,
+        mut velocity,
+        output,
+    ) in query.iter_mut()
+    {
+        if let Some(output) = output.filter(|_| delta_time > 0.0) {
+            velocity.0 = output.effective_translation / delta_time;
         }
 
+        let mut movement =
+            Vec3::new(move_input.x, 0.0, move_input.z) * movement_config.velocity_mul;
+
+        v_velocity.0 = movement_config.vertical_velocity(
+            v_velocity.0,
+            move_input.y,
+            output.map(|o| o.grounded).unwrap_or(false),
+            controller.custom_mass.unwrap_or(1.0),
+            delta_time,
+        );
+
         move_input.x = 0.0;
         move_input.y = 0.0;
         move_input.z = 0.0;
+        if let Some(sequence) = move_input.sequence.take() {
+            last_processed.0 = Some(sequence);
+        }
 
         movement.y = v_velocity.0;
 
@@ -54,32 +89,108 @@ pub fn handle_look_events(
     }
 }
 
+/// Collision group of the movement capsules of the players, shots only see their hitboxes.
+pub const PLAYER_BODY_GROUP: Group = Group::GROUP_32;
+
+/// Collision group of the player hitboxes of a team. Level colliders keep the default groups
+/// so they interact with every team.
+pub fn team_group(team: Team) -> Group {
+    // The last group is taken by the player capsules
+    Group::from_bits_truncate(1 << (team.0 % 31))
+}
+
+// Raycasts of a shot skip the shooter, the player capsules, and the teammates when friendly fire
+// is off
+pub fn fire_filter(shooter: Entity, team: Team, team_config: &TeamConfig) -> QueryFilter<'static> {
+    let mut hit_groups = Group::ALL.difference(PLAYER_BODY_GROUP);
+    if !team_config.friendly_fire {
+        hit_groups = hit_groups.difference(team_group(team));
+    }
+    QueryFilter::default()
+        .exclude_collider(shooter)
+        .exclude_rigid_body(shooter)
+        .groups(CollisionGroups::new(Group::ALL, hit_groups))
+}
+
+/// Entity and region hit by a shot. A hitbox resolves to its player, any other collider is
+/// returned as is and counts as a body hit.
+pub fn hit_target(hitten: Entity, hitboxes: &Query<(&Parent, &HitRegion)>) -> (Entity, HitRegion) {
+    match hitboxes.get(hitten) {
+        Ok((parent, region)) => (parent.get(), *region),
+        Err(_) => (hitten, HitRegion::Body),
+    }
+}
+
 // TODO: Fire angle calculations needs to be fixed
+#[allow(clippy::too_many_arguments)]
 pub fn handle_fire_events(
+    mut commands: Commands,
     mut fire_events: EventReader<FireEvent>,
-    query: Query<&Player>,
+    mut query: Query<(&Player, &Team, &mut Loadout)>,
+    team_config: Res<TeamConfig>,
+    weapons: Res<WeaponRegistry>,
     rapier_context: Res<RapierContext>,
+    hitboxes: Query<(&Parent, &HitRegion)>,
     mut hit_event: EventWriter<HitEvent>,
     mut server: ResMut<DenariaServer>,
 ) {
-    let max_toi = f32::MAX;
     let solid = true;
 
     for event in fire_events.read() {
-        if let Ok(player) = query.get(event.entity) {
-            if let Some((initial_handle, initial_toi)) = rapier_context.cast_ray(
-                event.cam_origin,
-                event.direction,
-                max_toi,
-                solid,
-                QueryFilter::default().exclude_collider(event.entity),
-            ) {
-                let initial_hit_point = event.cam_origin * event.direction * initial_toi;
+        if let Ok((player, team, mut loadout)) = query.get_mut(event.entity) {
+            // Rejected when decoded, but a degenerate ray must never reach the physics query
+            if !is_valid_direction(event.direction) {
+                tracing::warn!(
+                    player_id = player.id.as_str(),
+                    "Fire with invalid direction {}, skipping",
+                    event.direction
+                );
+                continue;
+            }
+            let Some(weapon) = weapons.get(event.weapon_id) else {
+                tracing::warn!(
+                    player_id = player.id.as_str(),
+                    "Fire with unknown weapon {}, skipping",
+                    event.weapon_id
+                );
+                continue;
+            };
+            let fired = consume_round(&mut loadout, event.weapon_id, weapon);
+            // The client learns about the rejection from its unchanged ammo
+            send_ammo(&mut server, player, &loadout, event.weapon_id, weapon);
+            if !fired {
+                tracing::debug!(
+                    player_id = player.id.as_str(),
+                    "Rejected fire without ammo in weapon {}",
+                    event.weapon_id
+                );
+                continue;
+            }
+            if let WeaponKind::Projectile { speed } = weapon.kind {
+                spawn_projectile(
+                    &mut commands,
+                    &mut server,
+                    event,
+                    player.network_id,
+                    *team,
+                    weapon.damage,
+                    speed,
+                );
+                continue;
+            }
+
+            let filter = fire_filter(event.entity, *team, &team_config);
+            // Unit directions keep the time of impact in meters, so the range caps the distance
+            let direction = event.direction.normalize();
+            if let Some((initial_handle, initial_toi)) =
+                rapier_context.cast_ray(event.cam_origin, direction, weapon.range, solid, filter)
+            {
+                let initial_hit_point = event.cam_origin + direction * initial_toi;
 
                 // Second raycast from the barrel position to the initial hit point
                 let barrel_target_dir = initial_hit_point - event.barrel_origin;
 
-                let normalized_a = event.direction.normalize();
+                let normalized_a = direction;
                 let normalized_b = barrel_target_dir.normalize();
 
                 // Compute the dot product
@@ -96,7 +207,7 @@ pub fn handle_fire_events(
 
                 let angle_threshold = 70.0;
 
-                tracing::info!(
+                tracing::trace!(
                     "Angle: {:?}, Threshold: {:?}",
                     angle_in_degrees,
                     angle_threshold
@@ -105,74 +216,166 @@ pub fn handle_fire_events(
                 if angle_in_degrees <= angle_threshold {
                     if let Some((handle, toi)) = rapier_context.cast_ray(
                         event.barrel_origin,
-                        barrel_target_dir,
-                        max_toi,
+                        normalized_b,
+                        weapon.range,
                         solid,
-                        QueryFilter::default().exclude_collider(event.entity),
+                        filter,
                     ) {
-                        let hit_point = event.barrel_origin * barrel_target_dir * toi;
-                        tracing::info!("Main target or an obstacle hit");
+                        let hit_point = event.barrel_origin + normalized_b * toi;
+                        tracing::trace!("Main target or an obstacle hit");
 
+                        let (hitten, region) = hit_target(handle, &hitboxes);
                         hit_event.send(HitEvent {
-                            hitter_id: player.id.clone(),
-                            hitten: handle,
-                            weapon: String::from("pistol"),
+                            hitter_network_id: player.network_id,
+                            hitter_team: *team,
+                            hitten,
+                            weapon_id: event.weapon_id,
+                            damage: weapon.damage,
                             point: hit_point,
+                            region,
                         });
 
-                        let fire_message = MessageOut::fire_message(
-                            player.id.clone(),
+                        broadcast_fire(
+                            &mut server,
+                            player.network_id,
                             event.barrel_origin,
                             barrel_target_dir,
                         );
-                        server
-                            .broadcast_message(DefaultChannel::ReliableOrdered, fire_message.data);
                     }
                 } else {
                     // No obstacle between the barrel and the target, so use the initial hit point
 
-                    tracing::info!("Main target threshold misses");
+                    tracing::trace!("Main target threshold misses");
+                    let (hitten, region) = hit_target(initial_handle, &hitboxes);
                     hit_event.send(HitEvent {
-                        hitter_id: player.id.clone(),
-                        hitten: initial_handle,
-                        weapon: String::from("pistol"),
+                        hitter_network_id: player.network_id,
+                        hitter_team: *team,
+                        hitten,
+                        weapon_id: event.weapon_id,
+                        damage: weapon.damage,
                         point: initial_hit_point,
+                        region,
                     });
 
-                    let fire_message = MessageOut::fire_message(
-                        player.id.clone(),
+                    broadcast_fire(
+                        &mut server,
+                        player.network_id,
                         event.barrel_origin,
                         event.direction,
                     );
-                    server.broadcast_message(DefaultChannel::ReliableOrdered, fire_message.data);
                 }
             } else {
-                tracing::info!("No hit fire");
-                let fire_message = MessageOut::fire_message(
-                    player.id.clone(),
+                tracing::trace!("No hit fire");
+                broadcast_fire(
+                    &mut server,
+                    player.network_id,
                     event.barrel_origin,
                     event.direction,
                 );
-                server.broadcast_message(DefaultChannel::ReliableOrdered, fire_message.data);
             }
-            tracing::info!("Always come here");
+            tracing::trace!("Always come here");
         }
     }
 }
 
+// Tells the shooter its shot connected, for hitmarkers
+fn send_hit_confirm(
+    server: &mut DenariaServer,
+    player_lookup: &PlayerLookup,
+    hitter_network_id: u16,
+    target_network_id: u16,
+    damage: f32,
+) {
+    let Some(hitter_client_id) = player_lookup
+        .player_id_by_network_id(hitter_network_id)
+        .and_then(|player_id| server.client_id_by_player_id(player_id.clone()).ok())
+    else {
+        tracing::warn!(
+            hitter_network_id,
+            "Shooter has no connection, skipping hit confirm"
+        );
+        return;
+    };
+    match MessageOut::hit_confirm_message(target_network_id, damage) {
+        Ok(hit_confirm_message) => server.send_message(
+            hitter_client_id,
+            DefaultChannel::ReliableOrdered,
+            hit_confirm_message.data,
+        ),
+        Err(e) => tracing::error!("Failed to serialize hit confirm message: {e}"),
+    }
+}
+
+fn broadcast_fire(server: &mut DenariaServer, network_id: u16, origin: Vec3, direction: Vec3) {
+    match MessageOut::fire_message(network_id, origin, direction) {
+        Ok(fire_message) => {
+            server.broadcast_message(DefaultChannel::ReliableOrdered, fire_message.data)
+        }
+        Err(e) => tracing::error!("Failed to serialize fire message: {e}"),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn handle_hit_events(
     mut hit_events: EventReader<HitEvent>,
-    mut query: Query<(&Player, &mut Health)>,
+    mut query: Query<(&Player, &Team, &mut Health, &mut RecentAttackers)>,
+    mut death_events: EventWriter<DeathEvent>,
+    match_state: Res<MatchState>,
+    team_config: Res<TeamConfig>,
+    damage_policy: Res<DamagePolicy>,
+    player_lookup: Res<PlayerLookup>,
     mut server: ResMut<DenariaServer>,
 ) {
     for event in hit_events.read() {
-        tracing::info!("Hit event {:?}", event);
-        if let Ok((player, mut health)) = query.get_mut(event.hitten) {
-            tracing::info!("Hit Happened!!");
-            health.0 = (health.0 - 20.0).max(0.0);
-            let hit_message =
-                MessageOut::hit_message(event.hitter_id.clone(), player.id.clone(), event.point);
-            server.broadcast_message(DefaultChannel::ReliableOrdered, hit_message.data);
+        tracing::trace!("Hit event {:?}", event);
+        if let Ok((player, team, mut health, mut recent_attackers)) = query.get_mut(event.hitten) {
+            tracing::trace!("Hit Happened!!");
+            let self_hit = event.hitter_network_id == player.network_id;
+            // A single team is free for all, nobody is a teammate
+            let teammates = team_config.team_count > 1 && event.hitter_team == *team;
+            let multiplier = damage_policy.multiplier(self_hit, teammates);
+            // Hits are still broadcast during warmup or when the policy spares the target,
+            // they just don't hurt
+            let mut damage = 0.0;
+            if match_state.damage_enabled() && multiplier > 0.0 {
+                let was_alive = health.0 > 0.0;
+                damage = health
+                    .0
+                    .min(event.damage * event.region.damage_multiplier() * multiplier);
+                health.0 -= damage;
+                if !recent_attackers.0.contains(&event.hitter_network_id) {
+                    recent_attackers.0.push(event.hitter_network_id);
+                }
+                if was_alive && health.0 == 0.0 {
+                    death_events.send(DeathEvent {
+                        entity: event.hitten,
+                        killer_network_id: Some(event.hitter_network_id),
+                    });
+                }
+            }
+            match MessageOut::hit_message(
+                event.hitter_network_id,
+                player.network_id,
+                event.point,
+                event.region,
+            ) {
+                Ok(hit_message) => {
+                    server.broadcast_message(DefaultChannel::ReliableOrdered, hit_message.data)
+                }
+                Err(e) => tracing::error!("Failed to serialize hit message: {e}"),
+            }
+            send_hit_confirm(
+                &mut server,
+                &player_lookup,
+                event.hitter_network_id,
+                player.network_id,
+                damage,
+            );
+        } else {
+            tracing::warn!(
+                "Hit target {:?} is not a player anymore, skipping",
+                event.hitten
+            );
         }
     }
 }
@@ -181,28 +384,44 @@ pub fn handle_spawn_events(
     mut commands: Commands,
     mut spawn_events: EventReader<SpawnEvent>,
     mut player_lookup: ResMut<PlayerLookup>,
+    team_config: Res<TeamConfig>,
+    spawn_points: Res<SpawnPoints>,
+    mut rng: ResMut<SessionRng>,
+    teams: Query<&Team>,
 ) {
+    // Players spawned this frame are not in the query yet, but count for the balancing
+    let mut assigned_teams = vec![];
     for event in spawn_events.read() {
         if !player_lookup.map.contains_key(&event.player_id) {
-            let initial_translation = Vec3::new(25.0, 20.0, -10.0);
+            let network_id = player_lookup.assign_network_id(&event.player_id);
+            let team = team_config.balanced_team(teams.iter().chain(&assigned_teams), &mut rng);
+            assigned_teams.push(team);
             let entity = commands
                 .spawn(PlayerBundle {
                     player: Player {
                         id: event.player_id.clone(),
+                        network_id,
                     },
+                    team,
                     ..Default::default()
                 })
                 .insert(RigidBody::KinematicPositionBased)
                 .insert(LockedAxes::ROTATION_LOCKED_X | LockedAxes::ROTATION_LOCKED_Z)
-                .insert(Collider::capsule_y(0.5, 0.5))
+                .insert(player_collider(Stance::Standing))
+                .insert(CollisionGroups::new(PLAYER_BODY_GROUP, Group::ALL))
                 .insert(ActiveCollisionTypes::default() | ActiveCollisionTypes::KINEMATIC_STATIC)
                 .insert(TransformBundle::from(Transform::from_translation(
-                    initial_translation,
+                    spawn_points.pick(&mut rng),
                 )))
                 .insert(KinematicCharacterController {
                     offset: CharacterLength::Absolute(0.01),
                     ..KinematicCharacterController::default()
                 })
+                .with_children(|parent| {
+                    for region in HitRegion::ALL {
+                        parent.spawn(HitboxBundle::new(region, team));
+                    }
+                })
                 .id();
 
             player_lookup.map.insert(event.player_id.clone(), entity);
@@ -215,18 +434,439 @@ pub fn handle_disconnect_events(
     mut disconnect_events: EventReader<DisconnectEvent>,
     mut player_lookup: ResMut<PlayerLookup>,
     mut server: ResMut<DenariaServer>,
+    disconnect_hook: Option<Res<DisconnectHook>>,
+    health_query: Query<&Health>,
 ) {
     if disconnect_events.len() > 0 {
         let mut disconnect_player_ids: Vec<&String> = vec![];
         for event in disconnect_events.read() {
             if let Some(entity) = player_lookup.map.get(&event.player_id) {
-                commands.entity(*entity).despawn();
+                if let Some(disconnect_hook) = disconnect_hook.as_ref() {
+                    disconnect_hook.call(DisconnectedPlayer {
+                        player_id: event.player_id.clone(),
+                        reason: event.reason.clone(),
+                        health: health_query.get(*entity).ok().map(|health| health.0),
+                    });
+                }
+                // Rapier bodies and colliders of the entity and its hitboxes are removed together with it.
+                // A stale entity is only logged, the lookup entries are cleaned up regardless
+                match commands.get_entity(*entity) {
+                    Some(entity_commands) => entity_commands.despawn_recursive(),
+                    None => tracing::warn!(
+                        player_id = event.player_id,
+                        "Player entity was already despawned"
+                    ),
+                }
                 player_lookup.map.remove(&event.player_id);
+                player_lookup.release_network_id(&event.player_id);
                 disconnect_player_ids.push(&event.player_id);
             }
         }
-        let disconnect_event = MessageOut::disconnect_message(disconnect_player_ids).unwrap();
-        tracing::trace!("Disconnect event: {:?}", disconnect_event);
-        server.broadcast_message(DefaultChannel::ReliableOrdered, disconnect_event.data);
+        match MessageOut::disconnect_message(disconnect_player_ids) {
+            Ok(Some(disconnect_event)) => {
+                tracing::trace!("Disconnect event: {:?}", disconnect_event);
+                server.broadcast_message(DefaultChannel::ReliableOrdered, disconnect_event.data);
+            }
+            Ok(None) => {}
+            Err(e) => tracing::error!("Failed to serialize disconnect message: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{
+        constants::{HEADSHOT_DAMAGE_MULTIPLIER, HIT_DAMAGE, PISTOL_WEAPON_ID, PLAYER_MAX_HEALTH},
+        ecs::{
+            components::MatchPhase,
+            systems::{
+                stance::hitbox,
+                test_support::{
+                    physics_app, player_bundle, received_messages_of_type, register_player,
+                    server_app, spawn_player_with_hitboxes, spawn_team_player, test_server,
+                },
+            },
+        },
+        server::error::DisconnectReason,
+    };
+
+    #[test]
+    fn disconnect_of_despawned_player_does_not_panic() {
+        let mut app = server_app(test_server(0));
+        app.insert_resource(PlayerLookup::new())
+            .add_systems(Update, handle_disconnect_events);
+
+        let entity = app.world_mut().spawn(player_bundle(1)).id();
+        register_player(&mut app, entity);
+        app.world_mut()
+            .resource_mut::<PlayerLookup>()
+            .assign_network_id("player1");
+
+        // The entity goes away before the disconnect is handled
+        app.world_mut().despawn(entity);
+        app.world_mut().send_event(DisconnectEvent {
+            player_id: "player1".to_string(),
+            reason: DisconnectReason::Transport,
+        });
+        app.update();
+        app.update();
+
+        let player_lookup = app.world().resource::<PlayerLookup>();
+        assert!(player_lookup.map.is_empty());
+        assert!(player_lookup.player_id_by_network_id(1).is_none());
+    }
+
+    #[test]
+    fn disconnect_hook_receives_final_player_state() {
+        let disconnected = Arc::new(Mutex::new(Vec::new()));
+        let hook_disconnected = disconnected.clone();
+
+        let mut app = server_app(test_server(0));
+        app.insert_resource(PlayerLookup::new())
+            .insert_resource(DisconnectHook::new(move |player| {
+                hook_disconnected.lock().unwrap().push(player)
+            }))
+            .add_systems(Update, handle_disconnect_events);
+
+        let entity = app
+            .world_mut()
+            .spawn(PlayerBundle {
+                health: Health(35.0),
+                ..player_bundle(1)
+            })
+            .id();
+        register_player(&mut app, entity);
+        app.world_mut()
+            .resource_mut::<PlayerLookup>()
+            .assign_network_id("player1");
+
+        app.world_mut().send_event(DisconnectEvent {
+            player_id: "player1".to_string(),
+            reason: DisconnectReason::DisconnectedByClient,
+        });
+        app.update();
+
+        assert_eq!(
+            *disconnected.lock().unwrap(),
+            vec![DisconnectedPlayer {
+                player_id: "player1".to_string(),
+                reason: DisconnectReason::DisconnectedByClient,
+                health: Some(35.0),
+            }]
+        );
+    }
+
+    // An app handling hits during an active match
+    fn hit_app(server: DenariaServer) -> App {
+        let mut app = server_app(server);
+        app.add_event::<HitEvent>()
+            .add_event::<DeathEvent>()
+            .insert_resource(PlayerLookup::new())
+            .insert_resource(MatchState {
+                phase: MatchPhase::Active,
+                ..Default::default()
+            })
+            .add_systems(Update, handle_hit_events);
+        app
+    }
+
+    #[test]
+    fn hit_confirm_is_sent_only_to_shooter() {
+        let mut app = hit_app(test_server(3));
+        app.init_resource::<TeamConfig>()
+            .init_resource::<DamagePolicy>();
+        let mut entities = vec![];
+        for network_id in 1..=3 {
+            let entity = app.world_mut().spawn(player_bundle(network_id)).id();
+            register_player(&mut app, entity);
+            app.world_mut()
+                .resource_mut::<PlayerLookup>()
+                .assign_network_id(&format!("player{network_id}"));
+            entities.push(entity);
+        }
+
+        app.world_mut().send_event(HitEvent {
+            hitter_network_id: 1,
+            hitter_team: Team(0),
+            hitten: entities[1],
+            weapon_id: PISTOL_WEAPON_ID,
+            damage: HIT_DAMAGE,
+            point: Vec3::ZERO,
+            region: HitRegion::Body,
+        });
+        app.update();
+
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        let hit_confirms = received_messages_of_type(&mut server, 1, 16);
+        assert_eq!(hit_confirms.len(), 1);
+        // target network id and damage after the type and version
+        assert_eq!(hit_confirms[0][2..4], 2u16.to_le_bytes());
+        assert_eq!(hit_confirms[0][4..8], HIT_DAMAGE.to_le_bytes());
+        for client_id in 2..=3 {
+            assert!(received_messages_of_type(&mut server, client_id, 16).is_empty());
+        }
+        assert_eq!(
+            app.world().get::<Health>(entities[1]).unwrap().0,
+            PLAYER_MAX_HEALTH - HIT_DAMAGE
+        );
+    }
+
+    // Player 1 of team 0 hits player `target_network_id` of `target_team`, returns its health
+    fn health_after_hit(policy: DamagePolicy, target_network_id: u16, target_team: Team) -> f32 {
+        let mut app = hit_app(test_server(0));
+        app.insert_resource(TeamConfig {
+            team_count: 2,
+            friendly_fire: true,
+        })
+        .insert_resource(policy);
+        let target = app
+            .world_mut()
+            .spawn(PlayerBundle {
+                team: target_team,
+                ..player_bundle(target_network_id)
+            })
+            .id();
+
+        app.world_mut().send_event(HitEvent {
+            hitter_network_id: 1,
+            hitter_team: Team(0),
+            hitten: target,
+            weapon_id: PISTOL_WEAPON_ID,
+            damage: HIT_DAMAGE,
+            point: Vec3::ZERO,
+            region: HitRegion::Body,
+        });
+        app.update();
+        app.world().get::<Health>(target).unwrap().0
+    }
+
+    #[test]
+    fn self_hit_deals_no_damage() {
+        assert_eq!(
+            health_after_hit(DamagePolicy::default(), 1, Team(0)),
+            PLAYER_MAX_HEALTH
+        );
+        let self_damage = DamagePolicy {
+            self_damage: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            health_after_hit(self_damage, 1, Team(0)),
+            PLAYER_MAX_HEALTH - HIT_DAMAGE
+        );
+    }
+
+    #[test]
+    fn friendly_hit_deals_no_damage_when_policy_is_off() {
+        let policy = DamagePolicy {
+            friendly_fire_multiplier: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            health_after_hit(policy.clone(), 2, Team(0)),
+            PLAYER_MAX_HEALTH
+        );
+        assert_eq!(
+            health_after_hit(policy, 2, Team(1)),
+            PLAYER_MAX_HEALTH - HIT_DAMAGE
+        );
+    }
+
+    #[test]
+    fn friendly_hit_damage_is_reduced_by_multiplier() {
+        let policy = DamagePolicy {
+            friendly_fire_multiplier: 0.5,
+            ..Default::default()
+        };
+        assert_eq!(
+            health_after_hit(policy, 2, Team(0)),
+            PLAYER_MAX_HEALTH - HIT_DAMAGE * 0.5
+        );
+    }
+
+    // An app handling the shots of the given weapons
+    fn fire_app(friendly_fire: bool, weapons: WeaponRegistry) -> App {
+        let mut app = physics_app(test_server(0));
+        app.add_event::<HitEvent>()
+            .insert_resource(TeamConfig {
+                team_count: 2,
+                friendly_fire,
+            })
+            .insert_resource(weapons)
+            .add_systems(Update, handle_fire_events);
+        app
+    }
+
+    // Fires along -z through a teammate standing in front of an enemy, returns the hit entities
+    fn fire_through_teammate(friendly_fire: bool) -> (Vec<Entity>, Entity, Entity) {
+        let mut app = fire_app(friendly_fire, WeaponRegistry::default());
+
+        let shooter = spawn_team_player(&mut app, 1, Team(0), 0.0);
+        let teammate = spawn_team_player(&mut app, 2, Team(0), -3.0);
+        let enemy = spawn_team_player(&mut app, 3, Team(1), -6.0);
+        // Lets rapier create the colliders and update its query pipeline
+        app.update();
+        app.update();
+
+        // Camera and barrel at the origin keep the shot on the camera raycast
+        app.world_mut().send_event(FireEvent {
+            entity: shooter,
+            cam_origin: Vec3::ZERO,
+            direction: Vec3::NEG_Z,
+            barrel_origin: Vec3::ZERO,
+            weapon_id: PISTOL_WEAPON_ID,
+        });
+        app.update();
+
+        let hits = app
+            .world()
+            .resource::<Events<HitEvent>>()
+            .iter_current_update_events()
+            .map(|event| event.hitten)
+            .collect();
+        (hits, teammate, enemy)
+    }
+
+    // Fires along -z at an enemy whose front is `distance` meters away with a 10 meter pistol,
+    // returns the hit entities
+    fn fire_at_enemy(distance: f32) -> Vec<Entity> {
+        let mut weapons = WeaponRegistry::default();
+        let mut pistol = weapons.get(PISTOL_WEAPON_ID).unwrap().clone();
+        pistol.range = 10.0;
+        weapons.insert(PISTOL_WEAPON_ID, pistol);
+        let mut app = fire_app(false, weapons);
+
+        let shooter = spawn_team_player(&mut app, 1, Team(0), 0.0);
+        // The capsule has a radius of 0.5
+        spawn_team_player(&mut app, 2, Team(1), -(distance + 0.5));
+        app.update();
+        app.update();
+
+        app.world_mut().send_event(FireEvent {
+            entity: shooter,
+            cam_origin: Vec3::ZERO,
+            direction: Vec3::NEG_Z,
+            barrel_origin: Vec3::ZERO,
+            weapon_id: PISTOL_WEAPON_ID,
+        });
+        app.update();
+
+        app.world()
+            .resource::<Events<HitEvent>>()
+            .iter_current_update_events()
+            .map(|event| event.hitten)
+            .collect()
+    }
+
+    #[test]
+    fn shots_beyond_weapon_range_miss() {
+        assert_eq!(fire_at_enemy(9.9).len(), 1);
+        assert!(fire_at_enemy(10.1).is_empty());
+    }
+
+    #[test]
+    fn shot_passes_through_teammate_without_friendly_fire() {
+        let (hits, _, enemy) = fire_through_teammate(false);
+        assert_eq!(hits, vec![enemy]);
+
+        let (hits, teammate, _) = fire_through_teammate(true);
+        assert_eq!(hits, vec![teammate]);
+    }
+
+    #[test]
+    fn headshot_applies_damage_multiplier() {
+        let mut app = physics_app(test_server(0));
+        app.add_event::<HitEvent>()
+            .add_event::<DeathEvent>()
+            .insert_resource(PlayerLookup::new())
+            .insert_resource(TeamConfig {
+                team_count: 2,
+                friendly_fire: false,
+            })
+            .insert_resource(MatchState {
+                phase: MatchPhase::Active,
+                ..Default::default()
+            })
+            .init_resource::<DamagePolicy>()
+            .init_resource::<WeaponRegistry>()
+            .add_systems(Update, (handle_fire_events, handle_hit_events).chain());
+
+        let shooter = spawn_player_with_hitboxes(&mut app, 1, Team(0), 0.0);
+        let target = spawn_player_with_hitboxes(&mut app, 2, Team(1), -5.0);
+        app.update();
+        app.update();
+
+        // Straight at the center of the head, through the capsule around it
+        let (_, head_offset) = hitbox(Stance::Standing, HitRegion::Head);
+        app.world_mut().send_event(FireEvent {
+            entity: shooter,
+            cam_origin: head_offset,
+            direction: Vec3::NEG_Z,
+            barrel_origin: head_offset,
+            weapon_id: PISTOL_WEAPON_ID,
+        });
+        app.update();
+
+        let regions: Vec<HitRegion> = app
+            .world()
+            .resource::<Events<HitEvent>>()
+            .iter_current_update_events()
+            .map(|event| event.region)
+            .collect();
+        assert_eq!(regions, vec![HitRegion::Head]);
+        assert_eq!(
+            app.world().get::<Health>(target).unwrap().0,
+            PLAYER_MAX_HEALTH - HIT_DAMAGE * HEADSHOT_DAMAGE_MULTIPLIER
+        );
+    }
+
+    // Spawns six players into a fresh session and returns their team and spawn point
+    fn spawn_assignments(seed: u64) -> Vec<(String, u8, Vec3)> {
+        let mut app = App::new();
+        app.add_event::<SpawnEvent>()
+            .insert_resource(PlayerLookup::new())
+            .insert_resource(TeamConfig {
+                team_count: 3,
+                friendly_fire: true,
+            })
+            .insert_resource(SpawnPoints(
+                (0..8).map(|i| Vec3::new(i as f32, 1.0, 0.0)).collect(),
+            ))
+            .insert_resource(SessionRng::new(seed))
+            .add_systems(Update, handle_spawn_events);
+
+        for i in 1..=6 {
+            app.world_mut().send_event(SpawnEvent {
+                player_id: format!("player{i}"),
+            });
+            // Every other player spawns in a later frame
+            if i % 2 == 0 {
+                app.update();
+            }
+        }
+
+        let mut query = app.world_mut().query::<(&Player, &Team, &Transform)>();
+        let mut assignments: Vec<(String, u8, Vec3)> = query
+            .iter(app.world())
+            .map(|(player, team, transform)| (player.id.clone(), team.0, transform.translation))
+            .collect();
+        assignments.sort_by(|a, b| a.0.cmp(&b.0));
+        assignments
+    }
+
+    #[test]
+    fn same_seed_gives_same_spawns_and_teams() {
+        let assignments = spawn_assignments(42);
+        assert_eq!(assignments.len(), 6);
+        assert_eq!(assignments, spawn_assignments(42));
+
+        // Teams stay balanced whatever the seed
+        for team in 0..3 {
+            let players = assignments.iter().filter(|(_, t, _)| *t == team).count();
+            assert_eq!(players, 2);
+        }
     }
 }
@@ -1,43 +1,118 @@
-use bevy::prelude::{EventWriter, Query, Res, ResMut};
+use bevy::{
+    app::AppExit,
+    prelude::{EventWriter, Events, Local, Query, Res, ResMut},
+};
 
 use crate::{
-    constants::TICK_DELTA,
     ecs::{
-        components::{MoveInput, PlayerLookup},
-        events::{DisconnectEvent, FireEvent, LookEvent, SpawnEvent},
+        components::{MoveInput, PlayerLookup, TickRate},
+        events::{
+            ClientReadyEvent, ControlMessageEvent, DisconnectEvent, FireEvent, LookEvent,
+            MessageAckedEvent, ReloadEvent, SpawnEvent, StanceEvent,
+        },
     },
+    logging::LogLimiter,
     server::{
         channel::DefaultChannel,
         message_in::{MessageIn, MessageInType},
+        message_out::MessageOut,
         server::{DenariaServer, ServerEvent},
     },
 };
 
 pub fn handle_server_events(
     mut server: ResMut<DenariaServer>,
+    tick_rate: Res<TickRate>,
+    mut ready_event: EventWriter<ClientReadyEvent>,
     mut disconnect_event: EventWriter<DisconnectEvent>,
+    mut acked_event: EventWriter<MessageAckedEvent>,
+    mut control_event: EventWriter<ControlMessageEvent>,
 ) {
-    server.update(TICK_DELTA);
+    server.update(tick_rate.delta());
     server.process_server_transport_messages();
 
     // Check for client connections/disconnections
     while let Some(event) = server.get_event() {
         match event {
             ServerEvent::ClientConnected { client_id } => {
-                println!("Client {client_id} connected");
+                tracing::info!(
+                    client_id = client_id.raw(),
+                    session_id = server.session_id(),
+                    "Client connected"
+                );
+            }
+            ServerEvent::ClientReady { client_id } => {
+                tracing::info!(
+                    client_id = client_id.raw(),
+                    session_id = server.session_id(),
+                    "Client ready"
+                );
+                ready_event.send(ClientReadyEvent { client_id });
             }
             ServerEvent::ClientDisconnected {
                 client_id,
                 player_id,
                 reason,
             } => {
-                println!("Client {client_id} disconnected: {reason}");
-                disconnect_event.send(DisconnectEvent { player_id });
+                tracing::info!(
+                    client_id = client_id.raw(),
+                    player_id,
+                    session_id = server.session_id(),
+                    "Client disconnected: {reason}"
+                );
+                let history: Vec<_> = server.client_event_history(client_id).collect();
+                tracing::debug!(
+                    client_id = client_id.raw(),
+                    "Client event history {history:?}"
+                );
+                disconnect_event.send(DisconnectEvent { player_id, reason });
+            }
+            ServerEvent::MessageAcked {
+                client_id,
+                message_id,
+            } => {
+                tracing::debug!(client_id = client_id.raw(), message_id, "Message acked");
+                acked_event.send(MessageAckedEvent {
+                    client_id,
+                    message_id,
+                });
+            }
+            ServerEvent::ControlMessage {
+                client_id,
+                kind,
+                payload,
+            } => {
+                control_event.send(ControlMessageEvent {
+                    client_id,
+                    kind,
+                    payload,
+                });
             }
         }
     }
 }
 
+/// Consumes the control messages. The session handles no kind yet, they are drained so they
+/// don't pile up in the event buffer.
+pub fn handle_control_messages(mut control_events: ResMut<Events<ControlMessageEvent>>) {
+    for event in control_events.drain() {
+        tracing::debug!(
+            client_id = event.client_id.raw(),
+            kind = event.kind,
+            bytes = event.payload.len(),
+            "Unhandled control message"
+        );
+    }
+}
+
+// Lets `App::run` return once the transport asked the session to stop, so its thread can end
+pub fn exit_on_shutdown(server: Res<DenariaServer>, mut exit: EventWriter<AppExit>) {
+    if server.is_shutdown_requested() {
+        exit.send(AppExit::Success);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn handle_server_messages(
     mut server: ResMut<DenariaServer>,
     player_lookup: Res<PlayerLookup>,
@@ -45,63 +120,150 @@ pub fn handle_server_messages(
     mut move_query: Query<&mut MoveInput>,
     mut look_event: EventWriter<LookEvent>,
     mut fire_event: EventWriter<FireEvent>,
+    mut reload_event: EventWriter<ReloadEvent>,
+    mut stance_event: EventWriter<StanceEvent>,
+    // Shared by the per message rejections, a flooding client can't flood the logs
+    mut rejected_log: Local<LogLimiter>,
 ) {
     // Receive message from channel
+    let max_messages = server.max_messages_per_tick();
 
     server.clients_id().iter().for_each(|client_id| {
+        // The control messages handled this tick already took their share of the limit
+        let mut received = server.control_messages_this_tick(*client_id);
         while let Some((message, player_id)) =
             server.receive_message(*client_id, DefaultChannel::Unreliable)
         {
+            let player_id = player_id.clone();
+            if received >= max_messages {
+                // Inputs are superseded by the next ones, so the excess is dropped, not deferred
+                let mut dropped = 1;
+                while server
+                    .receive_message(*client_id, DefaultChannel::Unreliable)
+                    .is_some()
+                {
+                    dropped += 1;
+                }
+                server.record_dropped_messages(*client_id, dropped);
+                tracing::warn!(
+                    player_id,
+                    "Client went over {max_messages} messages per tick, dropped {dropped}"
+                );
+                break;
+            }
+            received += 1;
+
             let event_in = match MessageIn::new(message.to_vec(), player_id.clone()) {
                 Ok(event) => event,
                 Err(e) => {
-                    tracing::error!("Failed to create MessageIn: {}", e);
+                    if let Some(suppressed) = rejected_log.admit() {
+                        tracing::error!(suppressed, "Failed to create MessageIn: {}", e);
+                    }
                     continue;
                 }
             };
 
             match event_in.event_type {
+                MessageInType::Rotation
+                | MessageInType::Move
+                | MessageInType::Jump
+                | MessageInType::Fire
+                | MessageInType::Reload
+                | MessageInType::Stance
+                    if server.is_paused() =>
+                {
+                    tracing::trace!(player_id, "Dropping gameplay input while paused");
+                }
                 MessageInType::Rotation => {
-                    if let Some(player_entity) = player_lookup.map.get(player_id) {
+                    if let Some(player_entity) = player_lookup.map.get(&player_id) {
                         match event_in.to_look_event(*player_entity) {
                             Ok(event) => {
                                 look_event.send(event);
                             }
-                            Err(_) => {
-                                tracing::error!("Failed to create LookEvent");
+                            Err(e) => {
+                                if let Some(suppressed) = rejected_log.admit() {
+                                    tracing::warn!(
+                                        player_id = player_id.as_str(),
+                                        suppressed,
+                                        "Rejected look message: {e}"
+                                    );
+                                }
                             }
                         }
                     }
                 }
                 MessageInType::Move => {
-                    if let Some(player_entity) = player_lookup.map.get(player_id) {
+                    if let Some(player_entity) = player_lookup.map.get(&player_id) {
                         match event_in.to_move_event(*player_entity) {
                             Ok(event) => {
                                 if let Ok(mut move_entity) = move_query.get_mut(event.entity) {
                                     move_entity.x = event.x;
                                     move_entity.z = event.y;
+                                    if event.sequence.is_some() {
+                                        move_entity.sequence = event.sequence;
+                                    }
                                 }
                             }
                             Err(_) => {
-                                tracing::error!("Failed to create MoveEvent");
+                                if let Some(suppressed) = rejected_log.admit() {
+                                    tracing::error!(suppressed, "Failed to create MoveEvent");
+                                }
                             }
                         }
                     }
                 }
                 MessageInType::Fire => {
-                    if let Some(player_entity) = player_lookup.map.get(player_id) {
+                    if let Some(player_entity) = player_lookup.map.get(&player_id) {
                         match event_in.to_fire_event(*player_entity) {
                             Ok(event) => {
                                 fire_event.send(event);
                             }
+                            Err(e) => {
+                                if let Some(suppressed) = rejected_log.admit() {
+                                    tracing::warn!(
+                                        player_id = player_id.as_str(),
+                                        suppressed,
+                                        "Rejected fire message: {e}"
+                                    );
+                                }
+                            }
+                        }
+                    }
+                }
+                MessageInType::Reload => {
+                    if let Some(player_entity) = player_lookup.map.get(&player_id) {
+                        match event_in.to_reload_event(*player_entity) {
+                            Ok(event) => {
+                                reload_event.send(event);
+                            }
                             Err(_) => {
-                                tracing::error!("Failed to create FireEvent");
+                                if let Some(suppressed) = rejected_log.admit() {
+                                    tracing::error!(suppressed, "Failed to create ReloadEvent");
+                                }
+                            }
+                        }
+                    }
+                }
+                MessageInType::Stance => {
+                    if let Some(player_entity) = player_lookup.map.get(&player_id) {
+                        match event_in.to_stance_event(*player_entity) {
+                            Ok(event) => {
+                                stance_event.send(event);
+                            }
+                            Err(e) => {
+                                if let Some(suppressed) = rejected_log.admit() {
+                                    tracing::warn!(
+                                        player_id = player_id.as_str(),
+                                        suppressed,
+                                        "Rejected stance message: {e}"
+                                    );
+                                }
                             }
                         }
                     }
                 }
                 MessageInType::Jump => {
-                    if let Some(player_entity) = player_lookup.map.get(player_id) {
+                    if let Some(player_entity) = player_lookup.map.get(&player_id) {
                         match event_in.to_jump_event(*player_entity) {
                             Ok(event) => {
                                 if let Ok(mut move_entity) = move_query.get_mut(event.entity) {
@@ -112,6 +274,9 @@ pub fn handle_server_messages(
                         }
                     }
                 }
+                MessageInType::Spawn if server.is_spectator(*client_id) => {
+                    tracing::debug!(player_id, "Ignoring spawn of a spectator");
+                }
                 MessageInType::Spawn => match event_in.to_spawn_event() {
                     Ok(event) => {
                         tracing::info!("Sending spawn event to session");
@@ -120,8 +285,34 @@ pub fn handle_server_messages(
                     }
                     Err(_) => {}
                 },
+                MessageInType::Spectate => {
+                    if player_lookup.map.contains_key(&player_id) {
+                        tracing::warn!(player_id, "Spawned player can't become a spectator");
+                    } else {
+                        tracing::info!(player_id, "Client joined as spectator");
+                        server.set_spectator(*client_id);
+                    }
+                }
+                MessageInType::TimeSync => {
+                    let Ok(client_timestamp) = event_in.to_time_sync_request() else {
+                        tracing::error!(player_id, "Failed to read time sync request");
+                        continue;
+                    };
+                    // Requests are answered in the tick they arrive
+                    let now = server.current_time();
+                    match MessageOut::time_sync_message(client_timestamp, now, now) {
+                        Ok(message) => server.send_message(
+                            *client_id,
+                            DefaultChannel::Unreliable,
+                            message.data,
+                        ),
+                        Err(e) => tracing::error!("Failed to serialize time sync message: {e}"),
+                    }
+                }
                 MessageInType::Invalid => {
-                    tracing::error!("Invalid MessageInType");
+                    if let Some(suppressed) = rejected_log.admit() {
+                        tracing::error!(suppressed, "Invalid MessageInType");
+                    }
                 }
             }
         }
@@ -134,3 +325,245 @@ pub fn handle_outgoing_messages(mut server: ResMut<DenariaServer>) {
         server.send_packets_to_server_transport(client_id, packets);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bevy::{ecs::system::RunSystemOnce, prelude::*};
+
+    use super::*;
+    use crate::{
+        ecs::systems::test_support::{
+            player_bundle, received_messages, register_player, server_app, test_server,
+            test_server_with_transport, unreliable_packet,
+        },
+        server::{server::ClientId, transport::transport::ToDenariaServerMessage},
+    };
+
+    #[test]
+    fn shutdown_message_makes_run_return() {
+        let (server, to_server_tx, _from_server_rx) = test_server_with_transport(0);
+        let session_thread = std::thread::spawn(move || {
+            let mut app = server_app(server);
+            app.add_plugins(
+                MinimalPlugins.set(bevy::app::ScheduleRunnerPlugin::run_loop(
+                    Duration::from_millis(1),
+                )),
+            )
+            .insert_resource(TickRate::default())
+            .add_systems(PreUpdate, (handle_server_events, exit_on_shutdown).chain());
+            app.run()
+        });
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!session_thread.is_finished());
+        to_server_tx.send(ToDenariaServerMessage::Shutdown).unwrap();
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !session_thread.is_finished() && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(session_thread.is_finished());
+        assert_eq!(session_thread.join().unwrap(), AppExit::Success);
+    }
+
+    #[test]
+    fn control_flood_counts_toward_the_message_limit() {
+        let (mut server, to_server_tx, _from_server_rx) = test_server_with_transport(1);
+        let client = ClientId::from_raw(1);
+        server.set_max_messages_per_tick(4);
+        for _ in 0..10 {
+            to_server_tx
+                .send(ToDenariaServerMessage::Control {
+                    client_id: 1,
+                    kind: 9,
+                    payload: vec![1, 2, 3],
+                })
+                .unwrap();
+        }
+        // Queued in the same tick, after the control messages used up the limit
+        server
+            .process_packet_from(&unreliable_packet(vec![vec![0]]), client)
+            .unwrap();
+
+        let mut app = server_app(server);
+        app.insert_resource(PlayerLookup::new())
+            .insert_resource(TickRate::default())
+            .add_systems(
+                Update,
+                (handle_server_events, handle_server_messages).chain(),
+            );
+        app.update();
+
+        assert_eq!(
+            app.world().resource::<Events<ControlMessageEvent>>().len(),
+            4
+        );
+        assert_eq!(
+            app.world()
+                .resource::<DenariaServer>()
+                .dropped_messages(client),
+            7
+        );
+
+        app.world_mut().run_system_once(handle_control_messages);
+        assert!(app
+            .world()
+            .resource::<Events<ControlMessageEvent>>()
+            .is_empty());
+    }
+
+    #[test]
+    fn nan_look_rotation_leaves_rotation_unchanged() {
+        let client = ClientId::from_raw(1);
+        let mut app = server_app(test_server(1));
+        app.insert_resource(PlayerLookup::new()).add_systems(
+            Update,
+            (
+                handle_server_messages,
+                crate::ecs::systems::handle_events::handle_look_events,
+            )
+                .chain(),
+        );
+        let player = app
+            .world_mut()
+            .spawn((player_bundle(1), Transform::default()))
+            .id();
+        register_player(&mut app, player);
+        let look = |rotation: [f32; 4]| {
+            let mut message = vec![3];
+            for value in rotation {
+                message.extend_from_slice(&value.to_le_bytes());
+            }
+            unreliable_packet(vec![message])
+        };
+        app.world_mut()
+            .resource_mut::<DenariaServer>()
+            .process_packet_from(&look([f32::NAN, 0.0, 0.0, 1.0]), client)
+            .unwrap();
+        app.update();
+        let rotation = |app: &App| app.world().get::<Transform>(player).unwrap().rotation;
+        assert_eq!(rotation(&app), Quat::IDENTITY);
+
+        app.world_mut()
+            .resource_mut::<DenariaServer>()
+            .process_packet_from(&look([0.0, 2.0, 0.0, 2.0]), client)
+            .unwrap();
+        app.update();
+        assert!(rotation(&app).is_normalized());
+        assert!(
+            rotation(&app).abs_diff_eq(Quat::from_rotation_y(std::f32::consts::FRAC_PI_2), 1e-6)
+        );
+    }
+
+    #[test]
+    fn spectator_receives_broadcasts_without_spawning() {
+        let mut server = test_server(1);
+        let player = ClientId::from_raw(1);
+        let spectator = ClientId::from_raw(2);
+        server.add_connection(spectator, "caster".to_string());
+
+        server
+            .process_packet_from(&unreliable_packet(vec![vec![0]]), player)
+            .unwrap();
+        // A spectator trying to spawn afterwards is ignored
+        server
+            .process_packet_from(&unreliable_packet(vec![vec![6], vec![0]]), spectator)
+            .unwrap();
+
+        let mut app = server_app(server);
+        app.insert_resource(PlayerLookup::new())
+            .add_systems(Update, handle_server_messages);
+        app.update();
+
+        let spawn_events = app.world().resource::<Events<SpawnEvent>>();
+        let spawned: Vec<String> = spawn_events
+            .get_reader()
+            .read(spawn_events)
+            .map(|event| event.player_id.clone())
+            .collect();
+        assert_eq!(spawned, vec!["player1"]);
+
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        assert_eq!(server.spectators(), vec![spectator]);
+        assert!(!server.is_spectator(player));
+
+        let position = MessageOut::position_message(1, vec![(Vec3::ONE, 1)])
+            .unwrap()
+            .unwrap();
+        server.broadcast_message(DefaultChannel::Unreliable, position.data);
+        assert!(!received_messages(&mut server, 2).is_empty());
+
+        server.remove_connection(spectator);
+        assert!(server.spectators().is_empty());
+    }
+
+    #[test]
+    fn time_sync_reply_gives_offset_and_rtt() {
+        let mut server = test_server(1);
+        let client = ClientId::from_raw(1);
+        server.update(Duration::from_millis(100));
+
+        // The client clock is 5s ahead, each direction takes 25ms
+        let clock_offset: i64 = 5_000_000;
+        let one_way: i64 = 25_000;
+        let server_receive = server.current_time().as_micros() as i64;
+        let client_send = server_receive - one_way + clock_offset;
+
+        let mut request = vec![7];
+        request.extend_from_slice(&(client_send as u64).to_le_bytes());
+        server
+            .process_packet_from(&unreliable_packet(vec![request]), client)
+            .unwrap();
+
+        let mut app = server_app(server);
+        app.insert_resource(PlayerLookup::new())
+            .add_systems(Update, handle_server_messages);
+        app.update();
+
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        let messages = received_messages(&mut server, 1);
+        let reply = &messages[0];
+        assert_eq!(reply[0], 7);
+        let read = |at: usize| u64::from_le_bytes(reply[at..at + 8].try_into().unwrap()) as i64;
+        let (t0, t1, t2) = (read(2), read(10), read(18));
+        let t3 = t2 + one_way + clock_offset;
+
+        assert_eq!(t0, client_send);
+        assert_eq!(t1, server_receive);
+        let offset = ((t1 - t0) + (t2 - t3)) / 2;
+        let rtt = (t3 - t0) - (t2 - t1);
+        assert_eq!(offset, -clock_offset);
+        assert_eq!(rtt, 2 * one_way);
+    }
+
+    #[test]
+    fn flooding_client_is_capped_per_tick() {
+        let mut server = test_server(1);
+        server.set_max_messages_per_tick(50);
+        let client = ClientId::from_raw(1);
+
+        // 10k spawn messages arriving within one tick
+        for _ in 0..100 {
+            server
+                .process_packet_from(&unreliable_packet(vec![vec![0]; 100]), client)
+                .unwrap();
+        }
+
+        let mut app = server_app(server);
+        app.insert_resource(PlayerLookup::new())
+            .add_systems(Update, handle_server_messages);
+        app.update();
+
+        let spawn_events = app.world().resource::<Events<SpawnEvent>>();
+        assert_eq!(spawn_events.get_reader().read(spawn_events).count(), 50);
+        let server = app.world().resource::<DenariaServer>();
+        assert_eq!(server.dropped_messages(client), 10_000 - 50);
+
+        // The excess is dropped, not handled in the next tick
+        app.update();
+        let spawn_events = app.world().resource::<Events<SpawnEvent>>();
+        assert_eq!(spawn_events.iter_current_update_events().count(), 0);
+    }
+}
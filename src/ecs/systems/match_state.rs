@@ -0,0 +1,168 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+use crate::{
+    ecs::components::{MatchConfig, MatchPhase, MatchState, RecentAttackers, Score},
+    server::{channel::DefaultChannel, message_out::MessageOut, server::DenariaServer},
+};
+
+// Moves the match from warmup to active once the warmup is over, and from active to ended
+// once a player reaches the score limit or the time limit is up
+pub fn update_match_state(
+    time: Res<Time>,
+    match_config: Res<MatchConfig>,
+    mut match_state: ResMut<MatchState>,
+    mut scores: Query<(&mut Score, &mut RecentAttackers)>,
+    mut server: ResMut<DenariaServer>,
+) {
+    match_state.elapsed += time.delta();
+
+    let next_phase = match match_state.phase {
+        MatchPhase::Warmup if match_state.elapsed >= match_config.warmup => MatchPhase::Active,
+        MatchPhase::Active => {
+            let score_limit_reached = match_config.score_limit.is_some_and(|score_limit| {
+                scores.iter().any(|(score, _)| score.kills >= score_limit)
+            });
+            let time_is_up = match_config
+                .time_limit
+                .is_some_and(|time_limit| match_state.elapsed >= time_limit);
+            if score_limit_reached || time_is_up {
+                MatchPhase::Ended
+            } else {
+                return;
+            }
+        }
+        _ => return,
+    };
+
+    tracing::info!(
+        session_id = server.session_id(),
+        "Match phase {:?} -> {:?}",
+        match_state.phase,
+        next_phase
+    );
+    match_state.phase = next_phase;
+    match_state.elapsed = Duration::ZERO;
+
+    // Kills and deaths from the warmup don't count
+    if next_phase == MatchPhase::Active {
+        for (mut score, mut recent_attackers) in &mut scores {
+            *score = Score::default();
+            recent_attackers.0.clear();
+        }
+    }
+
+    let phase_duration = match next_phase {
+        MatchPhase::Active => match_config.time_limit.unwrap_or_default(),
+        _ => Duration::ZERO,
+    };
+    match MessageOut::match_state_message(next_phase as u8, phase_duration) {
+        Ok(match_state_message) => {
+            server.broadcast_message(DefaultChannel::ReliableOrdered, match_state_message.data)
+        }
+        Err(e) => tracing::error!("Failed to serialize match state message: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ecs::{
+        components::PlayerBundle,
+        systems::test_support::{received_messages_of_type, server_app, test_server},
+    };
+
+    fn match_app(match_config: MatchConfig) -> App {
+        let mut app = server_app(test_server(1));
+        app.insert_resource(match_config)
+            .init_resource::<MatchState>()
+            .init_resource::<Time>()
+            .add_systems(Update, update_match_state);
+        app
+    }
+
+    fn advance(app: &mut App, delta: Duration) {
+        app.world_mut().resource_mut::<Time>().advance_by(delta);
+        app.update();
+    }
+
+    // Returns the phases of the match state messages queued for the client
+    fn received_phases(app: &mut App) -> Vec<u8> {
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        received_messages_of_type(&mut server, 1, 15)
+            .iter()
+            .map(|message| message[2])
+            .collect()
+    }
+
+    #[test]
+    fn warmup_becomes_active_after_warmup_timer() {
+        let mut app = match_app(MatchConfig {
+            warmup: Duration::from_secs(5),
+            score_limit: None,
+            time_limit: None,
+        });
+        let player = app
+            .world_mut()
+            .spawn(PlayerBundle {
+                score: Score {
+                    kills: 2,
+                    deaths: 1,
+                    assists: 0,
+                },
+                ..Default::default()
+            })
+            .id();
+
+        advance(&mut app, Duration::from_secs(4));
+        assert_eq!(
+            app.world().resource::<MatchState>().phase,
+            MatchPhase::Warmup
+        );
+        assert!(!app.world().resource::<MatchState>().damage_enabled());
+        assert!(received_phases(&mut app).is_empty());
+
+        advance(&mut app, Duration::from_secs(1));
+        assert_eq!(
+            app.world().resource::<MatchState>().phase,
+            MatchPhase::Active
+        );
+        assert!(app.world().resource::<MatchState>().damage_enabled());
+        assert_eq!(received_phases(&mut app), vec![MatchPhase::Active as u8]);
+        assert_eq!(*app.world().get::<Score>(player).unwrap(), Score::default());
+    }
+
+    #[test]
+    fn active_ends_when_score_limit_is_reached() {
+        let mut app = match_app(MatchConfig {
+            warmup: Duration::ZERO,
+            score_limit: Some(3),
+            time_limit: None,
+        });
+        let player = app.world_mut().spawn(PlayerBundle::default()).id();
+
+        advance(&mut app, Duration::from_millis(10));
+        assert_eq!(
+            app.world().resource::<MatchState>().phase,
+            MatchPhase::Active
+        );
+        received_phases(&mut app);
+
+        app.world_mut().get_mut::<Score>(player).unwrap().kills = 2;
+        advance(&mut app, Duration::from_millis(10));
+        assert_eq!(
+            app.world().resource::<MatchState>().phase,
+            MatchPhase::Active
+        );
+
+        app.world_mut().get_mut::<Score>(player).unwrap().kills = 3;
+        advance(&mut app, Duration::from_millis(10));
+        assert_eq!(
+            app.world().resource::<MatchState>().phase,
+            MatchPhase::Ended
+        );
+        assert!(!app.world().resource::<MatchState>().damage_enabled());
+        assert_eq!(received_phases(&mut app), vec![MatchPhase::Ended as u8]);
+    }
+}
@@ -1,5 +1,16 @@
+pub(crate) mod ammo;
+pub(crate) mod death;
 pub(crate) mod debug;
+pub(crate) mod diagnostics;
 pub(crate) mod handle_events;
 pub(crate) mod handle_server;
+pub(crate) mod match_state;
 pub(crate) mod on_change;
+pub(crate) mod pause;
+pub(crate) mod projectile;
+pub(crate) mod scoreboard;
 pub(crate) mod setup;
+pub(crate) mod stance;
+pub(crate) mod teleport;
+#[cfg(test)]
+pub(crate) mod test_support;
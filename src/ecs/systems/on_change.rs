@@ -1,31 +1,237 @@
+use std::collections::{HashMap, HashSet};
+
 use bevy::{
     math::{Quat, Vec3},
-    prelude::{Added, Changed, Query, ResMut, Transform},
+    prelude::{
+        Added, Changed, Commands, DetectChanges, Entity, EventReader, Local, Query, Ref, Res,
+        ResMut, Transform,
+    },
+    time::Time,
 };
 
 use crate::{
-    ecs::components::{Health, Player},
-    server::{channel::DefaultChannel, message_out::MessageOut, server::DenariaServer},
+    constants::SATURATED_BROADCAST_INTERVAL_TICKS,
+    ecs::{
+        components::{
+            Health, HealthBroadcastTimer, InputAckTimer, InterestConfig, InterestRadius,
+            LastProcessedInput, MatchConfig, MatchState, Motd, Player, PlayerVelocity, Score,
+            SentTransform, Team, TransformSendThreshold,
+        },
+        events::ClientReadyEvent,
+    },
+    server::{
+        channel::DefaultChannel,
+        message_out::{MessageOut, PlayerSnapshot},
+        server::{ClientId, DenariaServer},
+    },
 };
 
+/// Run condition of the transform broadcasts: while the transport is saturated they only run
+/// once every [`SATURATED_BROADCAST_INTERVAL_TICKS`] ticks. Change detection spans the skipped
+/// ticks, only the intermediate transforms are not sent.
+pub fn transform_broadcast_due(server: Res<DenariaServer>, mut skipped: Local<u32>) -> bool {
+    if !server.is_send_saturated() || *skipped + 1 >= SATURATED_BROADCAST_INTERVAL_TICKS {
+        *skipped = 0;
+        return true;
+    }
+    *skipped += 1;
+    false
+}
+
 // Gets the Position component of all Entities whose Velocity has changed since the last run of the System
+#[allow(clippy::type_complexity)]
 pub fn on_transform_change(
-    query: Query<(&Player, &Transform), Changed<Transform>>,
+    mut commands: Commands,
+    mut query: Query<
+        (
+            Entity,
+            &Player,
+            &Transform,
+            Option<&PlayerVelocity>,
+            Option<&mut SentTransform>,
+        ),
+        Changed<Transform>,
+    >,
+    viewers: Query<(
+        &Player,
+        &Transform,
+        Option<&PlayerVelocity>,
+        Option<&InterestRadius>,
+    )>,
+    threshold: Res<TransformSendThreshold>,
+    interest: Res<InterestConfig>,
     mut server: ResMut<DenariaServer>,
+    // Network ids of the players each client had within its interest radius on the last run
+    mut visible: Local<HashMap<ClientId, HashSet<u16>>>,
 ) {
-    let mut positions: Vec<(Vec3, String)> = vec![];
-    let mut rotations: Vec<(Quat, String)> = vec![];
+    let mut positions: Vec<(Vec3, Vec3, u16)> = vec![];
+    let mut rotations: Vec<(Quat, u16)> = vec![];
 
-    for (player, transform) in &query {
-        positions.push((transform.translation, player.id.clone()));
-        rotations.push((transform.rotation, player.id.clone()));
+    // Only the players that moved past the threshold since their last broadcast
+    let mut changed = vec![];
+    for (entity, player, transform, velocity, sent) in &mut query {
+        match sent {
+            Some(mut sent) => {
+                if !threshold.exceeded(&sent, transform) {
+                    continue;
+                }
+                *sent = SentTransform::from(transform);
+            }
+            None => {
+                commands
+                    .entity(entity)
+                    .insert(SentTransform::from(transform));
+            }
+        }
+        changed.push((player, transform, velocity));
     }
-    if positions.len() > 0 {
-        if let Some(position_event) = MessageOut::position_message(positions) {
-            server.broadcast_message(DefaultChannel::Unreliable, position_event.data);
+
+    let culled =
+        interest.radius.is_finite() || viewers.iter().any(|(_, _, _, radius)| radius.is_some());
+    if culled {
+        let changed: HashSet<u16> = changed
+            .iter()
+            .map(|(player, _, _)| player.network_id)
+            .collect();
+        let players: Vec<(&Player, &Transform, Option<&PlayerVelocity>)> = viewers
+            .iter()
+            .map(|(player, transform, velocity, _)| (player, transform, velocity))
+            .collect();
+        let viewers: HashMap<ClientId, (&Player, Vec3, f32)> = viewers
+            .iter()
+            .filter_map(|(player, transform, _, radius)| {
+                let client_id = server.client_id_by_player_id(player.id.clone()).ok()?;
+                let radius = radius.map_or(interest.radius, |radius| radius.0);
+                Some((client_id, (player, transform.translation, radius)))
+            })
+            .collect();
+        let clients_id = server.clients_id();
+        visible.retain(|client_id, _| clients_id.contains(client_id));
+        for client_id in clients_id {
+            let viewer = viewers.get(&client_id).copied();
+            let visible = visible.entry(client_id).or_default();
+            let messages = culled_transform_messages(&server, viewer, &players, &changed, visible);
+            for data in messages {
+                server.send_message(client_id, DefaultChannel::Unreliable, data);
+            }
         }
-        if let Some(rotation_message) = MessageOut::rotation_message(rotations) {
-            server.broadcast_message(DefaultChannel::Unreliable, rotation_message.data);
+        return;
+    }
+
+    if server.skip_self_updates() {
+        for (player, transform, velocity) in changed {
+            broadcast_transform_except_self(&mut server, player, transform, velocity);
+        }
+        return;
+    }
+
+    for (player, transform, velocity) in changed {
+        let velocity = velocity.map(|v| v.0).unwrap_or(Vec3::ZERO);
+        positions.push((transform.translation, velocity, player.network_id));
+        rotations.push((transform.rotation, player.network_id));
+    }
+    for data in transform_messages(&server, positions, rotations) {
+        server.broadcast_message(DefaultChannel::Unreliable, data);
+    }
+}
+
+// Messages of the transforms a client sees that changed, or of the players that just came
+// within its interest radius, and `visible` becomes the players it now sees. Clients without a
+// spawned player, like spectators, see every player, the others only the ones within their
+// interest radius.
+fn culled_transform_messages(
+    server: &DenariaServer,
+    viewer: Option<(&Player, Vec3, f32)>,
+    players: &[(&Player, &Transform, Option<&PlayerVelocity>)],
+    changed: &HashSet<u16>,
+    visible: &mut HashSet<u16>,
+) -> Vec<Vec<u8>> {
+    let mut positions = vec![];
+    let mut rotations = vec![];
+    let mut now_visible = HashSet::new();
+    for (player, transform, velocity) in players {
+        if let Some((viewer, position, radius)) = viewer {
+            if viewer.id == player.id {
+                if server.skip_self_updates() {
+                    continue;
+                }
+            } else if position.distance(transform.translation) > radius {
+                continue;
+            }
+        }
+        now_visible.insert(player.network_id);
+        if !changed.contains(&player.network_id) && visible.contains(&player.network_id) {
+            continue;
+        }
+        let velocity = velocity.map(|v| v.0).unwrap_or(Vec3::ZERO);
+        positions.push((transform.translation, velocity, player.network_id));
+        rotations.push((transform.rotation, player.network_id));
+    }
+    *visible = now_visible;
+    transform_messages(server, positions, rotations)
+}
+
+// Serializes the position and rotation messages with the encoding configured on the server
+fn transform_messages(
+    server: &DenariaServer,
+    positions: Vec<(Vec3, Vec3, u16)>,
+    rotations: Vec<(Quat, u16)>,
+) -> Vec<Vec<u8>> {
+    let tick = server.tick();
+    let mut messages = vec![];
+    if server.compact_transforms() {
+        let positions = without_velocity(positions);
+        messages.extend(MessageOut::compact_position_message(tick, positions).map(|m| m.data));
+        messages.extend(MessageOut::compact_rotation_message(tick, rotations).map(|m| m.data));
+        return messages;
+    }
+
+    let position_message = if server.send_velocity() {
+        MessageOut::position_velocity_message(tick, positions)
+    } else {
+        MessageOut::position_message(tick, without_velocity(positions))
+    };
+
+    match position_message {
+        Ok(Some(position_event)) => messages.push(position_event.data),
+        Ok(None) => {}
+        Err(e) => tracing::error!("Failed to serialize position message: {e}"),
+    }
+    match MessageOut::rotation_message(tick, rotations) {
+        Ok(Some(rotation_message)) => messages.push(rotation_message.data),
+        Ok(None) => {}
+        Err(e) => tracing::error!("Failed to serialize rotation message: {e}"),
+    }
+    messages
+}
+
+fn without_velocity(positions: Vec<(Vec3, Vec3, u16)>) -> Vec<(Vec3, u16)> {
+    positions
+        .into_iter()
+        .map(|(position, _, network_id)| (position, network_id))
+        .collect()
+}
+
+// Sends the transform of a single player to everyone but the player itself
+fn broadcast_transform_except_self(
+    server: &mut DenariaServer,
+    player: &Player,
+    transform: &Transform,
+    velocity: Option<&PlayerVelocity>,
+) {
+    let client_id = server.client_id_by_player_id(player.id.clone()).ok();
+    let velocity = velocity.map(|v| v.0).unwrap_or(Vec3::ZERO);
+    let messages = transform_messages(
+        server,
+        vec![(transform.translation, velocity, player.network_id)],
+        vec![(transform.rotation, player.network_id)],
+    );
+    for data in messages {
+        match client_id {
+            Some(client_id) => {
+                server.broadcast_message_except(client_id, DefaultChannel::Unreliable, data)
+            }
+            None => server.broadcast_message(DefaultChannel::Unreliable, data),
         }
     }
 }
@@ -39,9 +245,159 @@ pub fn on_health_change(
         healths.push((player.id.clone(), health.0));
     }
     if healths.len() > 0 {
-        tracing::info!("Sending health messages: {:?}", healths);
-        let health_message = MessageOut::health_message(healths);
-        server.broadcast_message(DefaultChannel::ReliableOrdered, health_message.data);
+        tracing::trace!("Sending health messages: {:?}", healths);
+        broadcast_healths(&mut server, healths);
+    }
+}
+
+// Rebroadcasts the health of every player each time the health broadcast timer finishes,
+// changes are still sent right away by `on_health_change`
+pub fn broadcast_health(
+    time: Res<Time>,
+    mut timer: ResMut<HealthBroadcastTimer>,
+    query: Query<(&Player, &Health)>,
+    mut server: ResMut<DenariaServer>,
+) {
+    if !timer.0.tick(time.delta()).just_finished() {
+        return;
+    }
+    let healths: Vec<(String, f32)> = query
+        .iter()
+        .map(|(player, health)| (player.id.clone(), health.0))
+        .collect();
+    if !healths.is_empty() {
+        broadcast_healths(&mut server, healths);
+    }
+}
+
+// Sent unreliably, a lost health is caught up by the next `broadcast_health`
+fn broadcast_healths(server: &mut DenariaServer, healths: Vec<(String, f32)>) {
+    match MessageOut::health_message(healths) {
+        Ok(health_messages) => {
+            for health_message in health_messages {
+                server.broadcast_message(DefaultChannel::Unreliable, health_message.data);
+            }
+        }
+        Err(e) => tracing::error!("Failed to serialize health message: {e}"),
+    }
+}
+
+/// Sends the full state of the session to clients that just became ready: the match state,
+/// every player already in it and the pause message while the session is paused. Clients that
+/// never confirm their connection don't get it.
+pub fn send_world_snapshot(
+    mut ready_events: EventReader<ClientReadyEvent>,
+    query: Query<(&Player, &Transform, &Health, &Team, &Score)>,
+    match_state: Res<MatchState>,
+    match_config: Res<MatchConfig>,
+    mut server: ResMut<DenariaServer>,
+) {
+    if ready_events.is_empty() {
+        return;
+    }
+    let players = query
+        .iter()
+        .map(|(player, transform, health, team, score)| PlayerSnapshot {
+            network_id: player.network_id,
+            player_id: player.id.clone(),
+            position: transform.translation,
+            rotation: transform.rotation,
+            health: health.0,
+            team: team.0,
+            kills: score.kills,
+            deaths: score.deaths,
+            assists: score.assists,
+        })
+        .collect();
+    let messages = match MessageOut::full_snapshot(
+        server.tick(),
+        match_state.phase as u8,
+        match_state.remaining(&match_config).as_millis() as u64,
+        players,
+    ) {
+        Ok(messages) => messages,
+        Err(e) => {
+            tracing::error!("Failed to serialize full snapshot: {e}");
+            ready_events.clear();
+            return;
+        }
+    };
+    let pause_message = if server.is_paused() {
+        match MessageOut::pause_message(true) {
+            Ok(message) => Some(message),
+            Err(e) => {
+                tracing::error!("Failed to serialize pause message: {e}");
+                None
+            }
+        }
+    } else {
+        None
+    };
+    for event in ready_events.read() {
+        for message in messages.iter().chain(&pause_message) {
+            server.send_message(
+                event.client_id,
+                DefaultChannel::ReliableOrdered,
+                message.data.clone(),
+            );
+        }
+    }
+}
+
+/// Sends the [`Motd`] to clients that just became ready, after their world snapshot.
+pub fn send_motd(
+    mut ready_events: EventReader<ClientReadyEvent>,
+    motd: Res<Motd>,
+    mut server: ResMut<DenariaServer>,
+) {
+    if motd.0.is_empty() {
+        ready_events.clear();
+        return;
+    }
+    let data = match MessageOut::motd_message(&motd.0) {
+        Ok(message) => message.data,
+        Err(e) => {
+            tracing::error!("Failed to serialize motd message: {e}");
+            ready_events.clear();
+            return;
+        }
+    };
+    for event in ready_events.read() {
+        server.send_message(
+            event.client_id,
+            DefaultChannel::ReliableOrdered,
+            data.clone(),
+        );
+    }
+}
+
+/// Acks the last applied input sequence of each player to its own client, for reconciling
+/// its predicted movement. Sent when the sequence changed, and resent on every tick of the
+/// [`InputAckTimer`] as acks travel unreliably.
+pub fn send_input_acks(
+    time: Res<Time>,
+    mut timer: ResMut<InputAckTimer>,
+    query: Query<(&Player, Ref<LastProcessedInput>)>,
+    mut server: ResMut<DenariaServer>,
+) {
+    let resend = timer.0.tick(time.delta()).just_finished();
+    for (player, last_processed) in &query {
+        if !resend && !last_processed.is_changed() {
+            continue;
+        }
+        let Some(input_sequence) = last_processed.0 else {
+            continue;
+        };
+        let Ok(client_id) = server.client_id_by_player_id(player.id.clone()) else {
+            continue;
+        };
+        match MessageOut::reconcile_message(server.tick(), input_sequence) {
+            Ok(message) => server.send_message(client_id, DefaultChannel::Unreliable, message.data),
+            Err(e) => tracing::error!(
+                player_id = player.id.as_str(),
+                "Failed to serialize reconcile message: {e}"
+            ),
+        }
     }
 }
 
@@ -50,10 +406,536 @@ pub fn on_spawn_change(
     mut server: ResMut<DenariaServer>,
 ) {
     for (player, transform) in &query {
-        if let Some(spawn_message) =
-            MessageOut::spawn_message(player.id.clone(), transform.translation, transform.rotation)
+        match MessageOut::spawn_message(
+            player.network_id,
+            player.id.clone(),
+            transform.translation,
+            transform.rotation,
+        ) {
+            Ok(spawn_message) => {
+                server.broadcast_message(DefaultChannel::ReliableOrdered, spawn_message.data)
+            }
+            Err(e) => tracing::error!(
+                player_id = player.id.as_str(),
+                "Failed to serialize spawn message: {e}"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bevy::{prelude::*, time::TimeUpdateStrategy};
+    use bevy_rapier3d::prelude::KinematicCharacterController;
+    use bincode::Options;
+    use crossbeam::channel::Sender;
+
+    use super::*;
+    use crate::{
+        constants::MESSAGE_FORMAT_VERSION,
+        ecs::{
+            components::{MatchPhase, MovementConfig, PlayerBundle, TickRate},
+            systems::{
+                handle_events::handle_character_movement,
+                handle_server::{handle_server_events, handle_server_messages},
+                test_support::{
+                    move_message, player_bundle, received_messages, received_messages_of_type,
+                    register_player, server_app, test_server, test_server_with_transport,
+                    unreliable_packet,
+                },
+            },
+        },
+        server::{
+            message_out::{wire_options, FullSnapshot},
+            transport::transport::ToDenariaServerMessage,
+        },
+    };
+
+    // Returns the network ids of the position messages queued for the client
+    fn received_positions(server: &mut DenariaServer, client_id: u64) -> Vec<u16> {
+        let mut network_ids = vec![];
+        for message in received_messages_of_type(server, client_id, 1) {
+            // type, version, tick, count and then the entries
+            let count = u64::from_le_bytes(message[6..14].try_into().unwrap());
+            for i in 0..count as usize {
+                let offset = 14 + i * 14;
+                network_ids.push(u16::from_le_bytes(
+                    message[offset..offset + 2].try_into().unwrap(),
+                ));
+            }
+        }
+        network_ids.sort();
+        network_ids
+    }
+
+    // Returns the (network_id, position, velocity) entries of the velocity messages queued for the client
+    fn received_velocities(server: &mut DenariaServer, client_id: u64) -> Vec<(u16, Vec3, Vec3)> {
+        let read_vec3 = |data: &[u8]| {
+            Vec3::from_array(std::array::from_fn(|i| {
+                f32::from_le_bytes(data[i * 4..i * 4 + 4].try_into().unwrap())
+            }))
+        };
+        let mut entries = vec![];
+        for message in received_messages_of_type(server, client_id, 13) {
+            let count = u64::from_le_bytes(message[6..14].try_into().unwrap());
+            for i in 0..count as usize {
+                let offset = 14 + i * 26;
+                entries.push((
+                    u16::from_le_bytes(message[offset..offset + 2].try_into().unwrap()),
+                    read_vec3(&message[offset + 2..offset + 14]),
+                    read_vec3(&message[offset + 14..offset + 26]),
+                ));
+            }
+        }
+        entries
+    }
+
+    fn app_with_two_players(skip_self_updates: bool) -> App {
+        let mut server = test_server(2);
+        server.set_skip_self_updates(skip_self_updates);
+
+        let mut app = server_app(server);
+        app.insert_resource(TransformSendThreshold::default())
+            .insert_resource(InterestConfig::default())
+            .add_systems(Update, on_transform_change.run_if(transform_broadcast_due));
+        for network_id in 1..=2 {
+            app.world_mut().spawn((
+                player_bundle(network_id),
+                Transform::from_xyz(network_id as f32, 0.0, 0.0),
+            ));
+        }
+        app
+    }
+
+    #[test]
+    fn skip_self_updates_drops_own_position_echo() {
+        let mut app = app_with_two_players(false);
+        app.update();
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        assert_eq!(received_positions(&mut server, 1), vec![1, 2]);
+
+        let mut app = app_with_two_players(true);
+        app.update();
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        assert_eq!(received_positions(&mut server, 1), vec![2]);
+        assert_eq!(received_positions(&mut server, 2), vec![1]);
+    }
+
+    #[test]
+    fn larger_interest_radius_sees_further_players() {
+        let mut app = app_with_two_players(false);
+        app.insert_resource(InterestConfig { radius: 10.0 });
+        app.world_mut()
+            .resource_mut::<DenariaServer>()
+            .add_connection(ClientId::from_raw(3), "player3".to_string());
+        app.world_mut()
+            .spawn((player_bundle(3), Transform::from_xyz(50.0, 0.0, 0.0)));
+        // The sniper sees as far as player3
+        let sniper = app
+            .world_mut()
+            .query::<(Entity, &Player)>()
+            .iter(app.world())
+            .find(|(_, player)| player.network_id == 2)
+            .unwrap()
+            .0;
+        app.world_mut()
+            .entity_mut(sniper)
+            .insert(InterestRadius(100.0));
+        app.update();
+
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        assert_eq!(received_positions(&mut server, 1), vec![1, 2]);
+        assert_eq!(received_positions(&mut server, 2), vec![1, 2, 3]);
+        assert_eq!(received_positions(&mut server, 3), vec![3]);
+    }
+
+    fn move_player(app: &mut App, network_id: u16, x: f32) {
+        let mut players = app.world_mut().query::<(&Player, &mut Transform)>();
+        for (player, mut transform) in players.iter_mut(app.world_mut()) {
+            if player.network_id == network_id {
+                transform.translation.x = x;
+            }
+        }
+    }
+
+    #[test]
+    fn player_entering_interest_radius_is_sent_without_moving() {
+        let mut app = app_with_two_players(false);
+        app.insert_resource(InterestConfig { radius: 10.0 });
+        let received = |app: &mut App| {
+            let mut server = app.world_mut().resource_mut::<DenariaServer>();
+            received_positions(&mut server, 1)
+        };
+
+        app.update();
+        assert_eq!(received(&mut app), vec![1, 2]);
+
+        // player2 walks away and stops out of range
+        move_player(&mut app, 2, 50.0);
+        app.update();
+        app.update();
+        assert!(received(&mut app).is_empty());
+
+        // player1 walks up to the standing player2, which is sent although it didn't move
+        move_player(&mut app, 1, 45.0);
+        app.update();
+        assert_eq!(received(&mut app), vec![1, 2]);
+
+        // Once visible, a standing player is not sent again
+        app.update();
+        assert!(received(&mut app).is_empty());
+    }
+
+    // Moves both players for six ticks and returns the position entries sent to player1
+    fn positions_sent_over_six_ticks(app: &mut App) -> usize {
+        let mut transforms = app.world_mut().query::<&mut Transform>();
+        for _ in 0..6 {
+            for mut transform in transforms.iter_mut(app.world_mut()) {
+                transform.translation.y += 1.0;
+            }
+            app.update();
+        }
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        received_positions(&mut server, 1).len()
+    }
+
+    #[test]
+    fn saturated_transport_throttles_transform_broadcasts() {
+        let mut app = app_with_two_players(false);
+        assert_eq!(positions_sent_over_six_ticks(&mut app), 12);
+
+        app.world_mut()
+            .resource_mut::<DenariaServer>()
+            .set_send_saturated(true);
+        assert_eq!(positions_sent_over_six_ticks(&mut app), 4);
+
+        app.world_mut()
+            .resource_mut::<DenariaServer>()
+            .set_send_saturated(false);
+        assert_eq!(positions_sent_over_six_ticks(&mut app), 12);
+    }
+
+    #[test]
+    fn jitter_below_threshold_sends_no_positions() {
+        let mut app = app_with_two_players(false);
+        app.update();
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        assert_eq!(received_positions(&mut server, 1), vec![1, 2]);
+
+        let mut transforms = app.world_mut().query::<&mut Transform>();
+        for tick in 0..10 {
+            let jitter = if tick % 2 == 0 { 0.0004 } else { -0.0004 };
+            for mut transform in transforms.iter_mut(app.world_mut()) {
+                transform.translation.y += jitter;
+                transform.rotate_y(jitter);
+            }
+            app.update();
+        }
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        assert!(received_positions(&mut server, 1).is_empty());
+
+        // Small moves add up until they pass the threshold
+        for _ in 0..3 {
+            for mut transform in transforms.iter_mut(app.world_mut()) {
+                transform.translation.x += 0.0004;
+            }
+            app.update();
+        }
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        assert_eq!(received_positions(&mut server, 1), vec![1, 2]);
+    }
+
+    // Returns the types of the messages queued for the client
+    fn received_types(server: &mut DenariaServer, client_id: u64) -> Vec<u8> {
+        received_messages(server, client_id)
+            .iter()
+            .map(|message| message[0])
+            .collect()
+    }
+
+    #[test]
+    fn health_is_rebroadcast_without_changes() {
+        let mut app = server_app(test_server(1));
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_millis(
+                250,
+            )))
+            .insert_resource(HealthBroadcastTimer::new(Duration::from_secs(1)))
+            .add_systems(Update, (on_health_change, broadcast_health));
+        app.world_mut().spawn(player_bundle(1));
+        // The spawn is sent as a change
+        app.update();
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        assert_eq!(received_messages_of_type(&mut server, 1, 6).len(), 1);
+
+        let mut health_messages = 0;
+        for _ in 0..8 {
+            app.update();
+            let mut server = app.world_mut().resource_mut::<DenariaServer>();
+            health_messages += received_messages_of_type(&mut server, 1, 6).len();
+        }
+        // Two seconds without any health change
+        assert_eq!(health_messages, 2);
+    }
+
+    // A session with a connected but unconfirmed client 2, returns the transport sender
+    fn app_with_joining_client() -> (App, Sender<ToDenariaServerMessage>) {
+        let (server, to_server_tx, _) = test_server_with_transport(2);
+        let mut app = server_app(server);
+        app.insert_resource(MatchState::default())
+            .insert_resource(MatchConfig::default())
+            .insert_resource(TickRate::default())
+            .init_resource::<Motd>()
+            .add_systems(
+                Update,
+                (handle_server_events, send_world_snapshot, send_motd).chain(),
+            );
+        (app, to_server_tx)
+    }
+
+    // Returns the snapshots queued for the client
+    fn received_snapshots(server: &mut DenariaServer, client_id: u64) -> Vec<FullSnapshot> {
+        received_messages_of_type(server, client_id, 21)
+            .iter()
+            .map(|message| wire_options().deserialize(&message[2..]).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn last_processed_input_is_acked_to_its_client() {
+        let (server, to_server_tx, _) = test_server_with_transport(2);
+        let mut app = server_app(server);
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_millis(
+                250,
+            )))
+            .insert_resource(InputAckTimer::new(Duration::from_millis(500)))
+            .insert_resource(MovementConfig::default())
+            .insert_resource(TickRate::default())
+            .add_systems(
+                PreUpdate,
+                (handle_server_events, handle_server_messages).chain(),
+            )
+            .add_systems(Update, (handle_character_movement, send_input_acks).chain());
+        let player = app
+            .world_mut()
+            .spawn((
+                player_bundle(1),
+                KinematicCharacterController::default(),
+                Transform::default(),
+            ))
+            .id();
+        register_player(&mut app, player);
+
+        // Move input 42 of player 1
+        let mut message = move_message(1.0, 0.0);
+        message.extend_from_slice(&42u32.to_le_bytes());
+        to_server_tx
+            .send(ToDenariaServerMessage::Payload {
+                client_id: 1,
+                payload: unreliable_packet(vec![message]),
+            })
+            .unwrap();
+        app.update();
+
+        let received_acks = |server: &mut DenariaServer, client_id: u64| -> Vec<u32> {
+            received_messages_of_type(server, client_id, 25)
+                .iter()
+                // type, version, tick and then the sequence
+                .map(|message| u32::from_le_bytes(message[6..10].try_into().unwrap()))
+                .collect()
+        };
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        assert_eq!(received_acks(&mut server, 1), vec![42]);
+        assert!(received_acks(&mut server, 2).is_empty());
+
+        // Resent every 500ms without a new input, in case it was lost
+        let mut acks = vec![];
+        for _ in 0..4 {
+            app.update();
+            let mut server = app.world_mut().resource_mut::<DenariaServer>();
+            acks.extend(received_acks(&mut server, 1));
+        }
+        assert_eq!(acks, vec![42, 42]);
+    }
+
+    #[test]
+    fn world_snapshot_waits_for_client_confirmation() {
+        let (mut app, to_server_tx) = app_with_joining_client();
+        app.world_mut()
+            .spawn((player_bundle(1), Transform::from_xyz(1.0, 0.0, 0.0)));
+
+        // Connected, but not confirmed yet
+        app.update();
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        assert!(received_types(&mut server, 2).is_empty());
+
+        to_server_tx
+            .send(ToDenariaServerMessage::ClientConfirmed { client_id: 2 })
+            .unwrap();
+        app.update();
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        assert_eq!(received_types(&mut server, 2), vec![21]);
+        assert!(received_types(&mut server, 1).is_empty());
+    }
+
+    #[test]
+    fn motd_is_sent_once_to_a_ready_client() {
+        let (mut app, to_server_tx) = app_with_joining_client();
+        app.insert_resource(Motd("Welcome".to_string()));
+
+        to_server_tx
+            .send(ToDenariaServerMessage::ClientConfirmed { client_id: 2 })
+            .unwrap();
+        app.update();
+        app.update();
+
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        let motds: Vec<String> = received_messages_of_type(&mut server, 2, 24)
+            .iter()
+            .map(|message| wire_options().deserialize(&message[2..]).unwrap())
+            .collect();
+        assert_eq!(motds, vec!["Welcome".to_string()]);
+        assert!(received_types(&mut server, 1).is_empty());
+    }
+
+    #[test]
+    fn client_joining_a_paused_session_is_told_it_is_paused() {
+        let (mut app, to_server_tx) = app_with_joining_client();
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        server.set_paused(true);
+        // The pause was broadcast before client 2 became ready
+        received_messages(&mut server, 2);
+
+        to_server_tx
+            .send(ToDenariaServerMessage::ClientConfirmed { client_id: 2 })
+            .unwrap();
+        app.update();
+
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        let messages = received_messages(&mut server, 2);
+        let types: Vec<u8> = messages.iter().map(|message| message[0]).collect();
+        assert_eq!(types, vec![21, 20]);
+        assert_eq!(messages[1], vec![20, MESSAGE_FORMAT_VERSION, 1]);
+    }
+
+    #[test]
+    fn late_joiner_of_busy_session_receives_every_player() {
+        let (mut app, to_server_tx) = app_with_joining_client();
+        for network_id in 1..=30u16 {
+            app.world_mut().spawn((
+                PlayerBundle {
+                    player: Player {
+                        id: format!("player{network_id:0>10}"),
+                        network_id,
+                    },
+                    ..Default::default()
+                },
+                Transform::from_xyz(network_id as f32, 0.0, 0.0),
+            ));
+        }
+
+        to_server_tx
+            .send(ToDenariaServerMessage::ClientConfirmed { client_id: 2 })
+            .unwrap();
+        app.update();
+
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        let snapshots = received_snapshots(&mut server, 2);
+        assert!(snapshots.len() > 1);
+        for (chunk, snapshot) in snapshots.iter().enumerate() {
+            assert_eq!(snapshot.chunk as usize, chunk);
+            assert_eq!(snapshot.chunk_count as usize, snapshots.len());
+        }
+        let mut network_ids: Vec<u16> = snapshots
+            .iter()
+            .flat_map(|snapshot| snapshot.players.iter().map(|player| player.network_id))
+            .collect();
+        network_ids.sort();
+        assert_eq!(network_ids, (1..=30).collect::<Vec<u16>>());
+    }
+
+    #[test]
+    fn late_joiner_receives_current_state() {
+        let (mut app, to_server_tx) = app_with_joining_client();
         {
-            server.broadcast_message(DefaultChannel::ReliableOrdered, spawn_message.data);
+            let mut match_state = app.world_mut().resource_mut::<MatchState>();
+            match_state.phase = MatchPhase::Active;
+            match_state.elapsed = Duration::from_secs(60);
         }
+        app.world_mut().resource_mut::<MatchConfig>().time_limit = Some(Duration::from_secs(300));
+        app.world_mut().spawn((
+            PlayerBundle {
+                health: Health(35.0),
+                team: Team(1),
+                score: Score {
+                    kills: 3,
+                    deaths: 1,
+                    assists: 2,
+                },
+                ..player_bundle(1)
+            },
+            Transform::from_xyz(4.0, 1.0, -2.0).with_rotation(Quat::from_rotation_y(1.5)),
+        ));
+
+        to_server_tx
+            .send(ToDenariaServerMessage::ClientConfirmed { client_id: 2 })
+            .unwrap();
+        app.update();
+
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        let tick = server.tick();
+        assert_eq!(
+            received_snapshots(&mut server, 2),
+            vec![FullSnapshot {
+                tick,
+                phase: MatchPhase::Active as u8,
+                phase_remaining_ms: 240_000,
+                chunk: 0,
+                chunk_count: 1,
+                players: vec![PlayerSnapshot {
+                    network_id: 1,
+                    player_id: "player1".to_string(),
+                    position: Vec3::new(4.0, 1.0, -2.0),
+                    rotation: Quat::from_rotation_y(1.5),
+                    health: 35.0,
+                    team: 1,
+                    kills: 3,
+                    deaths: 1,
+                    assists: 2,
+                }],
+            }]
+        );
+    }
+
+    #[test]
+    fn position_carries_player_velocity() {
+        let mut app = app_with_two_players(false);
+        let velocity = Vec3::new(4.5, -9.8, 0.25);
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        server.set_send_velocity(true);
+        let mut query = app.world_mut().query::<(Entity, &Player)>();
+        let entity = query
+            .iter(app.world())
+            .find(|(_, player)| player.network_id == 2)
+            .unwrap()
+            .0;
+        app.world_mut()
+            .entity_mut(entity)
+            .insert(PlayerVelocity(velocity));
+        app.update();
+
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        let mut entries = received_velocities(&mut server, 1);
+        entries.sort_by_key(|(network_id, _, _)| *network_id);
+        assert_eq!(
+            entries,
+            vec![
+                (1, Vec3::new(1.0, 0.0, 0.0), Vec3::ZERO),
+                (2, Vec3::new(2.0, 0.0, 0.0), velocity),
+            ]
+        );
     }
 }
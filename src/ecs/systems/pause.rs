@@ -0,0 +1,138 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+use crate::server::server::DenariaServer;
+
+/// Run condition of the gameplay systems, they are skipped while the session is paused.
+pub fn session_running(server: Res<DenariaServer>) -> bool {
+    !server.is_paused()
+}
+
+// Stops the physics simulation while the session is paused
+pub fn pause_physics(server: Res<DenariaServer>, mut rapier_config: ResMut<RapierConfiguration>) {
+    let active = !server.is_paused();
+    if rapier_config.physics_pipeline_active != active {
+        rapier_config.physics_pipeline_active = active;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::MESSAGE_FORMAT_VERSION,
+        ecs::{
+            components::{MoveInput, MovementConfig, TickRate},
+            events::DisconnectEvent,
+            systems::{
+                handle_events::handle_character_movement,
+                handle_server::{handle_server_events, handle_server_messages},
+                test_support::{
+                    move_message, player_bundle, register_player, server_app,
+                    test_server_with_transport, unreliable_packet,
+                },
+            },
+        },
+        server::{
+            packet::Packet,
+            server::ClientId,
+            transport::transport::{FromDenariaServerMessage, ToDenariaServerMessage},
+        },
+    };
+
+    fn move_packet(x: f32, y: f32) -> Vec<u8> {
+        unreliable_packet(vec![move_message(x, y)])
+    }
+
+    #[test]
+    fn paused_session_ignores_movement_but_keeps_connections() {
+        let (server, to_server_tx, from_server_rx) = test_server_with_transport(1);
+        let client_id = ClientId::from_raw(1);
+
+        let mut app = server_app(server);
+        app.insert_resource(MovementConfig::default())
+            .insert_resource(TickRate::default())
+            .init_resource::<Time>()
+            .add_systems(
+                PreUpdate,
+                (handle_server_events, handle_server_messages).chain(),
+            )
+            .add_systems(Update, handle_character_movement.run_if(session_running));
+        let player = app
+            .world_mut()
+            .spawn((
+                player_bundle(1),
+                KinematicCharacterController::default(),
+                Transform::default(),
+            ))
+            .id();
+        register_player(&mut app, player);
+
+        to_server_tx
+            .send(ToDenariaServerMessage::SetPaused { paused: true })
+            .unwrap();
+        for _ in 0..3 {
+            to_server_tx
+                .send(ToDenariaServerMessage::Payload {
+                    client_id: 1,
+                    payload: move_packet(1.0, 0.0),
+                })
+                .unwrap();
+            app.update();
+        }
+
+        let world = app.world();
+        assert!(world.resource::<DenariaServer>().is_paused());
+        assert_eq!(world.get::<MoveInput>(player).unwrap().x, 0.0);
+        assert_eq!(
+            world
+                .get::<KinematicCharacterController>(player)
+                .unwrap()
+                .translation,
+            None
+        );
+        assert_eq!(
+            world.get::<Transform>(player).unwrap().translation,
+            Vec3::ZERO
+        );
+        assert_eq!(
+            world.resource::<DenariaServer>().clients_id(),
+            vec![client_id]
+        );
+        assert!(world.resource::<Events<DisconnectEvent>>().is_empty());
+
+        // The paused client is still sent its packets, starting with the pause message
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        let packets = server.get_packets_to_send(client_id).unwrap();
+        let paused = packets.iter().any(|payload| {
+            matches!(
+                Packet::from_bytes(payload),
+                Ok(Packet::SmallReliable { messages, .. })
+                    if messages.iter().any(|(_, message)| message[..] == [20, MESSAGE_FORMAT_VERSION, 1])
+            )
+        });
+        assert!(paused);
+        server.send_packets_to_server_transport(client_id, packets);
+        assert!(matches!(
+            from_server_rx.try_recv(),
+            Ok(FromDenariaServerMessage::SendPacket { client_id: 1, .. })
+        ));
+
+        to_server_tx
+            .send(ToDenariaServerMessage::SetPaused { paused: false })
+            .unwrap();
+        to_server_tx
+            .send(ToDenariaServerMessage::Payload {
+                client_id: 1,
+                payload: move_packet(1.0, 0.0),
+            })
+            .unwrap();
+        app.update();
+        assert!(app
+            .world()
+            .get::<KinematicCharacterController>(player)
+            .unwrap()
+            .translation
+            .is_some());
+    }
+}
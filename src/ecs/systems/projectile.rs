@@ -0,0 +1,196 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+use crate::{
+    constants::PROJECTILE_LIFETIME,
+    ecs::{
+        components::{HitRegion, Player, Projectile, Team, TeamConfig},
+        events::{FireEvent, HitEvent},
+    },
+    server::{channel::DefaultChannel, message_out::MessageOut, server::DenariaServer},
+};
+
+use super::handle_events::{fire_filter, hit_target};
+
+// Spawns a projectile at the barrel flying along the fire direction and tells the clients about it
+pub fn spawn_projectile(
+    commands: &mut Commands,
+    server: &mut DenariaServer,
+    event: &FireEvent,
+    owner_network_id: u16,
+    owner_team: Team,
+    damage: f32,
+    speed: f32,
+) {
+    let velocity = event.direction.normalize_or_zero() * speed;
+    let entity = commands
+        .spawn((
+            Projectile {
+                owner: event.entity,
+                owner_network_id,
+                owner_team,
+                weapon_id: event.weapon_id,
+                damage,
+                velocity,
+                lifetime: PROJECTILE_LIFETIME,
+            },
+            Transform::from_translation(event.barrel_origin),
+        ))
+        .id();
+
+    match MessageOut::projectile_spawn_message(
+        entity.index(),
+        owner_network_id,
+        event.weapon_id,
+        event.barrel_origin,
+        velocity,
+    ) {
+        Ok(projectile_spawn_message) => server.broadcast_message(
+            DefaultChannel::ReliableOrdered,
+            projectile_spawn_message.data,
+        ),
+        Err(e) => tracing::error!("Failed to serialize projectile spawn message: {e}"),
+    }
+}
+
+// Moves the projectiles along their velocity, casting a ray over each step so fast projectiles
+// can't tunnel through thin colliders
+#[allow(clippy::too_many_arguments)]
+pub fn advance_projectiles(
+    mut commands: Commands,
+    time: Res<Time>,
+    team_config: Res<TeamConfig>,
+    rapier_context: Res<RapierContext>,
+    mut projectiles: Query<(Entity, &mut Projectile, &mut Transform)>,
+    players: Query<(), With<Player>>,
+    hitboxes: Query<(&Parent, &HitRegion)>,
+    mut hit_events: EventWriter<HitEvent>,
+    mut server: ResMut<DenariaServer>,
+) {
+    let delta = time.delta();
+    for (entity, mut projectile, mut transform) in &mut projectiles {
+        let step = projectile.velocity * delta.as_secs_f32();
+        let distance = step.length();
+        if distance > 0.0 {
+            let direction = step / distance;
+            let filter = fire_filter(projectile.owner, projectile.owner_team, &team_config);
+            if let Some((handle, toi)) =
+                rapier_context.cast_ray(transform.translation, direction, distance, true, filter)
+            {
+                let point = transform.translation + direction * toi;
+                let (hitten, region) = hit_target(handle, &hitboxes);
+                // Level colliders just stop the projectile
+                if players.contains(hitten) {
+                    hit_events.send(HitEvent {
+                        hitter_network_id: projectile.owner_network_id,
+                        hitter_team: projectile.owner_team,
+                        hitten,
+                        weapon_id: projectile.weapon_id,
+                        damage: projectile.damage,
+                        point,
+                        region,
+                    });
+                }
+                despawn_projectile(&mut commands, &mut server, entity, point);
+                continue;
+            }
+        }
+
+        transform.translation += step;
+        projectile.lifetime = projectile.lifetime.saturating_sub(delta);
+        if projectile.lifetime.is_zero() {
+            despawn_projectile(&mut commands, &mut server, entity, transform.translation);
+        }
+    }
+}
+
+fn despawn_projectile(
+    commands: &mut Commands,
+    server: &mut DenariaServer,
+    entity: Entity,
+    point: Vec3,
+) {
+    commands.entity(entity).despawn();
+    match MessageOut::projectile_despawn_message(entity.index(), point) {
+        Ok(projectile_despawn_message) => server.broadcast_message(
+            DefaultChannel::ReliableOrdered,
+            projectile_despawn_message.data,
+        ),
+        Err(e) => tracing::error!("Failed to serialize projectile despawn message: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bevy::time::TimeUpdateStrategy;
+
+    use super::*;
+    use crate::{
+        constants::{ROCKET_DAMAGE, ROCKET_WEAPON_ID},
+        ecs::{
+            components::WeaponRegistry,
+            systems::{
+                handle_events::handle_fire_events,
+                test_support::{physics_app, spawn_team_player, test_server},
+            },
+        },
+    };
+
+    #[derive(Resource, Default)]
+    struct Hits(Vec<(Entity, f32)>);
+
+    fn record_hits(mut hit_events: EventReader<HitEvent>, mut hits: ResMut<Hits>) {
+        hits.0
+            .extend(hit_events.read().map(|event| (event.hitten, event.damage)));
+    }
+
+    #[test]
+    fn projectile_travels_and_hits_on_impact() {
+        let mut app = physics_app(test_server(0));
+        app.insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_millis(
+            100,
+        )))
+        .add_event::<HitEvent>()
+        .init_resource::<TeamConfig>()
+        .init_resource::<WeaponRegistry>()
+        .init_resource::<Hits>()
+        .add_systems(
+            Update,
+            (handle_fire_events, advance_projectiles, record_hits).chain(),
+        );
+
+        let shooter = spawn_team_player(&mut app, 1, Team(0), 0.0);
+        let target = spawn_team_player(&mut app, 2, Team(0), -10.0);
+        // Lets rapier create the colliders and update its query pipeline
+        app.update();
+        app.update();
+
+        app.world_mut().send_event(FireEvent {
+            entity: shooter,
+            cam_origin: Vec3::ZERO,
+            direction: Vec3::NEG_Z,
+            barrel_origin: Vec3::new(0.0, 0.0, -1.0),
+            weapon_id: ROCKET_WEAPON_ID,
+        });
+        app.update();
+
+        // Spawned at the barrel and moved one 100ms step, the target is not reached yet
+        let mut projectiles = app.world_mut().query::<(&Projectile, &Transform)>();
+        let (_, transform) = projectiles.single(app.world());
+        assert!(transform.translation.z < -1.0);
+        assert!(app.world().resource::<Hits>().0.is_empty());
+
+        for _ in 0..5 {
+            app.update();
+        }
+
+        assert_eq!(
+            app.world().resource::<Hits>().0,
+            vec![(target, ROCKET_DAMAGE)]
+        );
+        let mut projectiles = app.world_mut().query::<&Projectile>();
+        assert_eq!(projectiles.iter(app.world()).count(), 0);
+    }
+}
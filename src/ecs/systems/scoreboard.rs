@@ -0,0 +1,31 @@
+use bevy::prelude::*;
+
+use crate::{
+    ecs::components::{Player, Score, ScoreboardTimer},
+    server::{channel::DefaultChannel, message_out::MessageOut, server::DenariaServer},
+};
+
+// Broadcasts the scores of all players every time the scoreboard timer finishes
+pub fn broadcast_scoreboard(
+    time: Res<Time>,
+    mut timer: ResMut<ScoreboardTimer>,
+    query: Query<(&Player, &Score)>,
+    mut server: ResMut<DenariaServer>,
+) {
+    if !timer.0.tick(time.delta()).just_finished() {
+        return;
+    }
+    let scores: Vec<(u16, u32, u32, u32)> = query
+        .iter()
+        .map(|(player, score)| (player.network_id, score.kills, score.deaths, score.assists))
+        .collect();
+    if scores.is_empty() {
+        return;
+    }
+    match MessageOut::scoreboard_message(scores) {
+        Ok(scoreboard_message) => {
+            server.broadcast_message(DefaultChannel::ReliableOrdered, scoreboard_message.data)
+        }
+        Err(e) => tracing::error!("Failed to serialize scoreboard message: {e}"),
+    }
+}
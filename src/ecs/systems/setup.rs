@@ -1,6 +1,9 @@
 use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
     io::{BufReader, BufWriter, Error},
     path::Path,
+    sync::{Arc, Mutex, OnceLock, PoisonError},
     vec,
 };
 
@@ -16,52 +19,234 @@ use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use tokio::runtime::Runtime;
 
-use crate::ecs::{
-    components::PlayerLookup,
-    events::{DisconnectEvent, FireEvent, HitEvent, JumpEvent, LookEvent, MoveEvent, SpawnEvent},
+use crate::{
+    constants::{LEVEL_MAX_OBJECTS, LEVEL_SPAWN_BATCH_SIZE},
+    ecs::{
+        components::PlayerLookup,
+        events::{
+            ClientReadyEvent, ControlMessageEvent, DeathEvent, DisconnectEvent, FireEvent,
+            HitEvent, JumpEvent, LookEvent, MessageAckedEvent, MoveEvent, ReloadEvent, SpawnEvent,
+            StanceEvent,
+        },
+    },
 };
 
 pub fn setup(mut commands: Commands) {
     let objects: Vec<LevelObject> = vec![];
 
-    let level_objects = LevelObjects { objects };
+    let level_objects = LevelObjects {
+        objects,
+        spawned: 0,
+        skipped: 0,
+    };
 
     commands.insert_resource(PlayerLookup::new());
     commands.insert_resource(level_objects);
 
+    commands.insert_resource(Events::<ClientReadyEvent>::default());
+    commands.insert_resource(Events::<MessageAckedEvent>::default());
+    commands.insert_resource(Events::<ControlMessageEvent>::default());
     commands.insert_resource(Events::<SpawnEvent>::default());
     commands.insert_resource(Events::<DisconnectEvent>::default());
     commands.insert_resource(Events::<LookEvent>::default());
     commands.insert_resource(Events::<FireEvent>::default());
+    commands.insert_resource(Events::<ReloadEvent>::default());
+    commands.insert_resource(Events::<StanceEvent>::default());
     commands.insert_resource(Events::<HitEvent>::default());
+    commands.insert_resource(Events::<DeathEvent>::default());
     commands.insert_resource(Events::<MoveEvent>::default());
     commands.insert_resource(Events::<JumpEvent>::default());
 }
 
-pub fn setup_level(par_commands: ParallelCommands, mut level_objects: ResMut<LevelObjects>) {
-    let runtime = Runtime::new().unwrap();
-    runtime.block_on(async {
-        let level_objects_vec = get_level_objects().await;
-        level_objects.objects = level_objects_vec;
+/// Limits of the level loading. The colliders are spawned in batches by
+/// [`stream_level_objects`], so the physics keeps stepping while a large level loads.
+#[derive(Debug, Clone, Resource)]
+pub struct LevelLoadConfig {
+    /// Objects past this count are left out, with a warning. Default: [`LEVEL_MAX_OBJECTS`]
+    pub max_objects: usize,
+    /// Colliders spawned per frame. Default: [`LEVEL_SPAWN_BATCH_SIZE`]
+    pub batch_size: usize,
+}
+
+impl Default for LevelLoadConfig {
+    fn default() -> Self {
+        Self {
+            max_objects: LEVEL_MAX_OBJECTS,
+            batch_size: LEVEL_SPAWN_BATCH_SIZE,
+        }
+    }
+}
+
+/// Level objects parsed by earlier sessions of this process, by level version along with the
+/// hash of their content: versions with the same content share their objects. Clones share the
+/// same entries, [`LevelCache::global`] is the one every session uses.
+#[derive(Debug, Clone, Default, Resource)]
+pub struct LevelCache {
+    levels: Arc<Mutex<HashMap<String, LevelEntry>>>,
+}
+
+// Filled by the first session loading the level, locked meanwhile
+type LevelEntry = Arc<Mutex<Option<CachedLevel>>>;
+
+#[derive(Debug)]
+struct CachedLevel {
+    content_hash: u64,
+    objects: Arc<Vec<LevelObject>>,
+}
+
+impl LevelCache {
+    pub fn global() -> Self {
+        static CACHE: OnceLock<LevelCache> = OnceLock::new();
+        CACHE.get_or_init(LevelCache::default).clone()
+    }
+
+    /// Returns the objects of `version`, calling `load` if no session loaded them yet.
+    /// Sessions asking for the same version meanwhile wait for that load instead of
+    /// running their own. A load that panics or finds no objects isn't kept, the next
+    /// session loads the version again.
+    pub fn get_or_load<F>(&self, version: &str, load: F) -> Arc<Vec<LevelObject>>
+    where
+        F: FnOnce() -> Vec<LevelObject>,
+    {
+        // The map lock is only held to find the entry, not while loading
+        let entry = self
+            .levels
+            .lock()
+            .unwrap()
+            .entry(version.to_string())
+            .or_default()
+            .clone();
+        // A session that panicked while loading left the entry empty
+        let mut cached = entry.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(cached) = cached.as_ref() {
+            return cached.objects.clone();
+        }
+
+        let objects = load();
+        if objects.is_empty() {
+            warn!("Level objects v{version} are empty, not caching them");
+            return Arc::new(objects);
+        }
+        let content_hash = content_hash(&objects);
+        let objects = self
+            .find_content(content_hash, &entry)
+            .unwrap_or_else(|| Arc::new(objects));
+        trace!("Caching level objects v{version} with content hash {content_hash:x}");
+        *cached = Some(CachedLevel {
+            content_hash,
+            objects: objects.clone(),
+        });
+        objects
+    }
+
+    // Objects of another version with the same content, skipping the versions still loading
+    fn find_content(
+        &self,
+        content_hash: u64,
+        loading: &LevelEntry,
+    ) -> Option<Arc<Vec<LevelObject>>> {
+        let levels = self.levels.lock().unwrap();
+        levels
+            .values()
+            .filter(|entry| !Arc::ptr_eq(entry, loading))
+            .filter_map(|entry| entry.try_lock().ok())
+            .find_map(|entry| {
+                entry
+                    .as_ref()
+                    .filter(|cached| cached.content_hash == content_hash)
+                    .map(|cached| cached.objects.clone())
+            })
+    }
+}
+
+// Hash of the parsed objects, the floats by their bits
+fn content_hash(objects: &[LevelObject]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for object in objects {
+        object.id.hash(&mut hasher);
+        object.object_type.hash(&mut hasher);
+        object.collider.hash(&mut hasher);
+        for value in object
+            .translation
+            .to_array()
+            .into_iter()
+            .chain(object.rotation.to_array())
+            .chain(object.scale.to_array())
+        {
+            value.to_bits().hash(&mut hasher);
+        }
+    }
+    hasher.finish()
+}
+
+pub fn setup_level(
+    config: Res<LevelLoadConfig>,
+    cache: Res<LevelCache>,
+    level_objects: ResMut<LevelObjects>,
+) {
+    dotenvy::dotenv().ok();
+    let version = std::env::var("LEVEL_OBJECTS_VERSION").unwrap_or_default();
+    load_level(&version, &cache, &config, level_objects, || {
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(get_level_objects())
     });
+}
+
+fn load_level<F>(
+    version: &str,
+    cache: &LevelCache,
+    config: &LevelLoadConfig,
+    mut level_objects: ResMut<LevelObjects>,
+    fetch: F,
+) where
+    F: FnOnce() -> Vec<LevelObject>,
+{
+    let objects = cache.get_or_load(version, || {
+        trace!("Level objects v{version} not cached in this process, fetching");
+        fetch()
+    });
+    level_objects.set_objects(objects.as_ref().clone(), config.max_objects);
     trace!(
         "Spawning {:?} level object colliders",
         level_objects.objects.len()
     );
-    level_objects
-        .objects
+}
+
+/// Spawns the colliders of the next batch of level objects, until all are spawned.
+pub fn stream_level_objects(
+    par_commands: ParallelCommands,
+    config: Res<LevelLoadConfig>,
+    mut level_objects: ResMut<LevelObjects>,
+) {
+    if level_objects.is_loaded() {
+        return;
+    }
+    let start = level_objects.spawned;
+    let end = (start + config.batch_size.max(1)).min(level_objects.objects.len());
+    let batch = &level_objects.objects[start..end];
+    let skipped: usize = batch
         .par_splat_map(ComputeTaskPool::get(), None, |_, data| {
+            let mut skipped = 0;
             for item in data.iter() {
-                par_commands.command_scope(|mut commands| match item.object_type.as_str() {
-                    "MeshCollider" => item.new_mesh(&mut commands),
-                    "CapsuleCollider" => item.new_capsule(&mut commands),
-                    "SphereCollider" => item.new_sphere(&mut commands),
-                    "BoxCollider" => item.new_cuboid(&mut commands),
-                    _ => {}
-                });
+                let result =
+                    par_commands.command_scope(|mut commands| item.new_collider(&mut commands));
+                if let Err(e) = result {
+                    error!("Skipping level object {}: {e}", item.id);
+                    skipped += 1;
+                }
             }
-        });
-    trace!("Level Objects spawning completed!");
+            skipped
+        })
+        .into_iter()
+        .sum();
+    level_objects.spawned = end;
+    level_objects.skipped += skipped;
+    if level_objects.is_loaded() {
+        trace!(
+            "Level Objects spawning completed, {} skipped",
+            level_objects.skipped
+        );
+    }
 }
 
 pub async fn get_level_objects() -> Vec<LevelObject> {
@@ -162,6 +347,38 @@ pub async fn get_level_objects() -> Vec<LevelObject> {
 #[derive(Debug, Resource, Serialize)]
 pub struct LevelObjects {
     objects: Vec<LevelObject>,
+    // Objects before this index have their collider spawned
+    #[serde(skip)]
+    spawned: usize,
+    // Objects left out because their collider could not be built
+    #[serde(skip)]
+    skipped: usize,
+}
+
+impl LevelObjects {
+    /// Replaces the objects to spawn, keeping the first `max_objects`.
+    fn set_objects(&mut self, mut objects: Vec<LevelObject>, max_objects: usize) {
+        if objects.len() > max_objects {
+            warn!(
+                "Level has {} objects, only the first {max_objects} are spawned",
+                objects.len()
+            );
+            objects.truncate(max_objects);
+        }
+        self.objects = objects;
+        self.spawned = 0;
+        self.skipped = 0;
+    }
+
+    /// Whether the collider of every object is spawned.
+    pub fn is_loaded(&self) -> bool {
+        self.spawned >= self.objects.len()
+    }
+
+    /// Objects whose collider was not spawned, being of an unknown type or having invalid data.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
 }
 
 // Level Object size format uses the convention of Unity3D Game Engine's scale
@@ -259,9 +476,48 @@ impl MeshDataDeserialized {
     }
 }
 
+impl MeshData {
+    /// Triangle indices of the mesh, checked so building the trimesh can't panic.
+    fn triangle_indices(&self) -> Result<Vec<[u32; 3]>, String> {
+        if self.triangles.is_empty() {
+            return Err("no triangles".to_string());
+        }
+        let triangles = self.triangles.chunks_exact(3);
+        if !triangles.remainder().is_empty() {
+            return Err(format!(
+                "{} triangle indices, not a multiple of 3",
+                self.triangles.len()
+            ));
+        }
+        if let Some(index) = self
+            .triangles
+            .iter()
+            .find(|&&index| index < 0 || index as usize >= self.vertices.len())
+        {
+            return Err(format!(
+                "triangle index {index} out of range of {} vertices",
+                self.vertices.len()
+            ));
+        }
+        Ok(triangles
+            .map(|chunk| [chunk[0] as u32, chunk[1] as u32, chunk[2] as u32])
+            .collect())
+    }
+}
+
 impl LevelObject {
-    fn new_cuboid(&self, commands: &mut Commands) {
-        let coboid_data: CuboidData = serde_json::from_str(self.collider.as_str()).unwrap();
+    fn new_collider(&self, commands: &mut Commands) -> Result<(), String> {
+        match self.object_type.as_str() {
+            "MeshCollider" => self.new_mesh(commands),
+            "CapsuleCollider" => self.new_capsule(commands),
+            "SphereCollider" => self.new_sphere(commands),
+            "BoxCollider" => self.new_cuboid(commands),
+            object_type => Err(format!("unknown collider type {object_type:?}")),
+        }
+    }
+
+    fn new_cuboid(&self, commands: &mut Commands) -> Result<(), String> {
+        let coboid_data: CuboidData = parse_collider(&self.collider)?;
         commands
             .spawn(RigidBody::Fixed)
             .insert(Collider::cuboid(
@@ -274,10 +530,11 @@ impl LevelObject {
                     .with_rotation(self.rotation)
                     .with_scale(self.scale),
             ));
+        Ok(())
     }
 
-    fn new_capsule(&self, commands: &mut Commands) {
-        let capsule_data: CapsuleData = serde_json::from_str(self.collider.as_str()).unwrap();
+    fn new_capsule(&self, commands: &mut Commands) -> Result<(), String> {
+        let capsule_data: CapsuleData = parse_collider(&self.collider)?;
         match capsule_data.direction {
             0 => {
                 commands
@@ -320,15 +577,15 @@ impl LevelObject {
                             .with_scale(self.scale),
                     ));
             }
-            _ => {
-                tracing::error!("Invalid Capsule collider direction");
-                return;
+            direction => {
+                return Err(format!("invalid capsule collider direction {direction}"));
             }
         };
+        Ok(())
     }
 
-    fn new_sphere(&self, commands: &mut Commands) {
-        let ball_data: BallData = serde_json::from_str(self.collider.as_str()).unwrap();
+    fn new_sphere(&self, commands: &mut Commands) -> Result<(), String> {
+        let ball_data: BallData = parse_collider(&self.collider)?;
         commands
             .spawn(RigidBody::Fixed)
             .insert(Collider::ball(ball_data.radius))
@@ -337,11 +594,13 @@ impl LevelObject {
                     .with_rotation(self.rotation)
                     .with_scale(self.scale),
             ));
+        Ok(())
     }
 
-    fn new_mesh(&self, commands: &mut Commands) {
-        let data: MeshDataDeserialized = serde_json::from_str(self.collider.as_str()).unwrap();
+    fn new_mesh(&self, commands: &mut Commands) -> Result<(), String> {
+        let data: MeshDataDeserialized = parse_collider(&self.collider)?;
         let mesh_data = data.to_mesh_data();
+        let indices = mesh_data.triangle_indices()?;
 
         let vertices = mesh_data
             .vertices
@@ -349,12 +608,6 @@ impl LevelObject {
             .map(|vertice| Vec3::new(vertice.x, vertice.y, vertice.z))
             .collect();
 
-        let indices: Vec<[u32; 3]> = mesh_data
-            .triangles
-            .chunks(3)
-            .map(|chunk| [chunk[0] as u32, chunk[1] as u32, chunk[2] as u32])
-            .collect();
-
         commands
             .spawn(RigidBody::Fixed)
             .insert(Collider::trimesh(vertices, indices))
@@ -363,9 +616,14 @@ impl LevelObject {
                     .with_rotation(self.rotation)
                     .with_scale(self.scale),
             ));
+        Ok(())
     }
 }
 
+fn parse_collider<'a, T: Deserialize<'a>>(collider: &'a str) -> Result<T, String> {
+    serde_json::from_str(collider).map_err(|e| format!("invalid collider data: {e}"))
+}
+
 // Function to read data from a file
 fn read_from_file(file_path: &str) -> Result<Vec<LevelObject>, Error> {
     let path = Path::new(file_path);
@@ -389,3 +647,235 @@ fn write_to_file(file_path: &str, data: &Vec<LevelObject>) -> Result<(), Error>
     serde_json::to_writer(writer, data)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn box_object(id: i32) -> LevelObject {
+        LevelObject {
+            id,
+            object_type: "BoxCollider".to_string(),
+            translation: Vec3::new(id as f32, 0.0, 0.0),
+            rotation: Quat::IDENTITY,
+            scale: Vec3::ONE,
+            collider: r#"{"x":1.0,"y":1.0,"z":1.0}"#.to_string(),
+        }
+    }
+
+    #[test]
+    fn large_level_spawns_in_bounded_batches() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(LevelLoadConfig {
+                max_objects: 1_000,
+                batch_size: 128,
+            })
+            .insert_resource(LevelObjects {
+                objects: vec![],
+                spawned: 0,
+                skipped: 0,
+            })
+            .add_systems(Update, stream_level_objects);
+
+        let objects = (0..1_200).map(box_object).collect();
+        app.world_mut()
+            .resource_mut::<LevelObjects>()
+            .set_objects(objects, 1_000);
+
+        let mut colliders = 0;
+        let mut frames = 0;
+        while !app.world().resource::<LevelObjects>().is_loaded() {
+            app.update();
+            frames += 1;
+            let spawned = app
+                .world_mut()
+                .query::<&Collider>()
+                .iter(app.world())
+                .count();
+            assert!(spawned - colliders <= 128);
+            colliders = spawned;
+        }
+
+        // Capped to max_objects, spread over ceil(1000 / 128) frames
+        assert_eq!(colliders, 1_000);
+        assert_eq!(frames, 8);
+    }
+
+    fn mesh(triangles: Vec<i32>) -> MeshData {
+        MeshData {
+            vertices: vec![Vec3::ZERO, Vec3::X, Vec3::Y, Vec3::Z],
+            triangles,
+        }
+    }
+
+    #[test]
+    fn valid_mesh_builds_triangles() {
+        assert_eq!(
+            mesh(vec![0, 1, 2, 0, 2, 3]).triangle_indices(),
+            Ok(vec![[0, 1, 2], [0, 2, 3]])
+        );
+    }
+
+    #[test]
+    fn mesh_with_out_of_range_index_is_rejected() {
+        assert!(mesh(vec![0, 1, 4]).triangle_indices().is_err());
+        assert!(mesh(vec![0, -1, 2]).triangle_indices().is_err());
+
+        // The object is skipped without spawning a collider
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(LevelLoadConfig::default())
+            .insert_resource(LevelObjects {
+                objects: vec![LevelObject {
+                    object_type: "MeshCollider".to_string(),
+                    collider: r#"{"vertices":[{"x":0.0,"y":0.0,"z":0.0}],"triangles":[0,0,1]}"#
+                        .to_string(),
+                    ..box_object(0)
+                }],
+                spawned: 0,
+                skipped: 0,
+            })
+            .add_systems(Update, stream_level_objects);
+        app.update();
+        assert!(app.world().resource::<LevelObjects>().is_loaded());
+        assert_eq!(app.world().resource::<LevelObjects>().skipped(), 1);
+        assert_eq!(
+            app.world_mut()
+                .query::<&Collider>()
+                .iter(app.world())
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn invalid_level_objects_are_skipped_and_counted() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(LevelLoadConfig::default())
+            .insert_resource(LevelObjects {
+                objects: vec![],
+                spawned: 0,
+                skipped: 0,
+            })
+            .add_systems(Update, stream_level_objects);
+
+        let objects = vec![
+            box_object(0),
+            LevelObject {
+                object_type: "TerrainCollider".to_string(),
+                ..box_object(1)
+            },
+            LevelObject {
+                collider: r#"{"x":1.0,"y":"#.to_string(),
+                ..box_object(2)
+            },
+            LevelObject {
+                object_type: "CapsuleCollider".to_string(),
+                collider: r#"{"radius":0.5,"height":2.0,"direction":3}"#.to_string(),
+                ..box_object(3)
+            },
+        ];
+        app.world_mut()
+            .resource_mut::<LevelObjects>()
+            .set_objects(objects, LEVEL_MAX_OBJECTS);
+        app.update();
+
+        let level_objects = app.world().resource::<LevelObjects>();
+        assert!(level_objects.is_loaded());
+        assert_eq!(level_objects.skipped(), 3);
+        assert_eq!(
+            app.world_mut()
+                .query::<&Collider>()
+                .iter(app.world())
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn mesh_with_partial_triangle_is_rejected() {
+        assert!(mesh(vec![0, 1, 2, 3]).triangle_indices().is_err());
+        assert!(mesh(vec![]).triangle_indices().is_err());
+    }
+
+    #[test]
+    fn sessions_share_cached_level_objects() {
+        let cache = LevelCache::default();
+        let fetches = Arc::new(Mutex::new(0));
+
+        let sessions: Vec<App> = (0..2)
+            .map(|_| {
+                let mut app = App::new();
+                app.insert_resource(LevelLoadConfig::default())
+                    .insert_resource(cache.clone())
+                    .insert_resource(LevelObjects {
+                        objects: vec![],
+                        spawned: 0,
+                        skipped: 0,
+                    });
+                let fetches = fetches.clone();
+                app.add_systems(
+                    Startup,
+                    move |config: Res<LevelLoadConfig>,
+                          cache: Res<LevelCache>,
+                          level_objects: ResMut<LevelObjects>| {
+                        load_level("tps_0_1", &cache, &config, level_objects, || {
+                            *fetches.lock().unwrap() += 1;
+                            (0..3).map(box_object).collect()
+                        });
+                    },
+                );
+                app.update();
+                app
+            })
+            .collect();
+
+        assert_eq!(*fetches.lock().unwrap(), 1);
+        for app in &sessions {
+            let ids: Vec<i32> = app
+                .world()
+                .resource::<LevelObjects>()
+                .objects
+                .iter()
+                .map(|object| object.id)
+                .collect();
+            assert_eq!(ids, vec![0, 1, 2]);
+        }
+
+        // Another version is fetched on its own
+        cache.get_or_load("tps_0_2", || {
+            *fetches.lock().unwrap() += 1;
+            vec![]
+        });
+        assert_eq!(*fetches.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn empty_or_failed_level_loads_are_retried() {
+        let cache = LevelCache::default();
+
+        assert!(cache.get_or_load("tps_0_1", Vec::new).is_empty());
+        let failed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            cache.get_or_load("tps_0_1", || panic!("level server unreachable"))
+        }));
+        assert!(failed.is_err());
+
+        let objects = cache.get_or_load("tps_0_1", || (0..3).map(box_object).collect());
+        assert_eq!(objects.len(), 3);
+        let cached = cache.get_or_load("tps_0_1", || unreachable!("cached"));
+        assert!(Arc::ptr_eq(&objects, &cached));
+    }
+
+    #[test]
+    fn versions_with_the_same_content_share_objects() {
+        let cache = LevelCache::default();
+        let first = cache.get_or_load("tps_0_1", || (0..3).map(box_object).collect());
+        let same = cache.get_or_load("tps_0_2", || (0..3).map(box_object).collect());
+        let other = cache.get_or_load("tps_0_3", || (1..4).map(box_object).collect());
+
+        assert!(Arc::ptr_eq(&first, &same));
+        assert!(!Arc::ptr_eq(&first, &other));
+    }
+}
@@ -0,0 +1,226 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+use crate::{
+    constants::PLAYER_CAPSULE_RADIUS,
+    ecs::{
+        components::{HitRegion, Player, Stance, Team},
+        events::StanceEvent,
+    },
+    server::{channel::DefaultChannel, message_out::MessageOut, server::DenariaServer},
+};
+
+use super::handle_events::team_group;
+
+const HEAD_RADIUS: f32 = 0.2;
+const LIMB_HALF_WIDTH: f32 = 0.3;
+/// Share of the player height covered by the legs hitbox.
+const LEGS_HEIGHT_SHARE: f32 = 0.45;
+
+/// Capsule of a player in the given stance.
+pub fn player_collider(stance: Stance) -> Collider {
+    Collider::capsule_y(stance.half_height(), PLAYER_CAPSULE_RADIUS)
+}
+
+/// Collider and offset from the player center of a hitbox, stacked inside the capsule of the stance.
+pub fn hitbox(stance: Stance, region: HitRegion) -> (Collider, Vec3) {
+    let half_extent = stance.half_height() + PLAYER_CAPSULE_RADIUS;
+    let legs_top = -half_extent + 2.0 * half_extent * LEGS_HEIGHT_SHARE;
+    let head_bottom = half_extent - 2.0 * HEAD_RADIUS;
+    match region {
+        HitRegion::Head => (
+            Collider::ball(HEAD_RADIUS),
+            Vec3::new(0.0, half_extent - HEAD_RADIUS, 0.0),
+        ),
+        HitRegion::Body => (
+            Collider::cuboid(
+                LIMB_HALF_WIDTH,
+                (head_bottom - legs_top) / 2.0,
+                LIMB_HALF_WIDTH,
+            ),
+            Vec3::new(0.0, (head_bottom + legs_top) / 2.0, 0.0),
+        ),
+        HitRegion::Legs => (
+            Collider::cuboid(
+                LIMB_HALF_WIDTH,
+                (legs_top + half_extent) / 2.0,
+                LIMB_HALF_WIDTH,
+            ),
+            Vec3::new(0.0, (legs_top - half_extent) / 2.0, 0.0),
+        ),
+    }
+}
+
+/// Hitbox of a standing player, spawned as a child of its entity. Hitboxes are sensors, so
+/// only the raycasts of the shots see them, the capsule alone moves and collides.
+#[derive(Bundle)]
+pub struct HitboxBundle {
+    pub region: HitRegion,
+    pub collider: Collider,
+    pub sensor: Sensor,
+    pub collision_groups: CollisionGroups,
+    pub transform: TransformBundle,
+}
+
+impl HitboxBundle {
+    pub fn new(region: HitRegion, team: Team) -> Self {
+        let (collider, offset) = hitbox(Stance::Standing, region);
+        Self {
+            region,
+            collider,
+            sensor: Sensor,
+            collision_groups: CollisionGroups::new(team_group(team), Group::ALL),
+            transform: TransformBundle::from(Transform::from_translation(offset)),
+        }
+    }
+}
+
+// Resizes the capsule and hitboxes of players changing stance. Rapier picks up the new colliders
+// before the next physics step, so the raycasts of the following fires hit the current stance.
+#[allow(clippy::type_complexity)]
+pub fn handle_stance_events(
+    mut stance_events: EventReader<StanceEvent>,
+    mut query: Query<(
+        &Player,
+        &mut Stance,
+        &mut Collider,
+        &mut Transform,
+        Option<&Children>,
+    )>,
+    mut hitboxes: Query<(&HitRegion, &mut Collider, &mut Transform), Without<Player>>,
+    mut server: ResMut<DenariaServer>,
+) {
+    for event in stance_events.read() {
+        let Ok((player, mut stance, mut collider, mut transform, children)) =
+            query.get_mut(event.entity)
+        else {
+            continue;
+        };
+        if *stance == event.stance {
+            continue;
+        }
+        tracing::debug!(
+            player_id = player.id.as_str(),
+            "Stance {:?} -> {:?}",
+            *stance,
+            event.stance
+        );
+        // The capsule shrinks and grows around its center, moving it keeps the feet in place
+        transform.translation.y += event.stance.half_height() - stance.half_height();
+        *collider = player_collider(event.stance);
+        *stance = event.stance;
+        for &child in children.into_iter().flatten() {
+            if let Ok((region, mut collider, mut transform)) = hitboxes.get_mut(child) {
+                let (hitbox_collider, offset) = hitbox(event.stance, *region);
+                *collider = hitbox_collider;
+                transform.translation = offset;
+            }
+        }
+
+        match MessageOut::stance_message(server.tick(), player.network_id, event.stance) {
+            Ok(stance_message) => {
+                server.broadcast_message(DefaultChannel::ReliableOrdered, stance_message.data)
+            }
+            Err(e) => tracing::error!("Failed to serialize stance message: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::PISTOL_WEAPON_ID,
+        ecs::{
+            components::{Team, TeamConfig, WeaponRegistry},
+            events::{FireEvent, HitEvent},
+            systems::{
+                handle_events::handle_fire_events,
+                test_support::{
+                    physics_app, received_messages_of_type, spawn_player_with_hitboxes, test_server,
+                },
+            },
+        },
+    };
+
+    fn stance_app() -> App {
+        let mut app = physics_app(test_server(1));
+        app.add_event::<HitEvent>()
+            .insert_resource(TeamConfig {
+                team_count: 2,
+                friendly_fire: false,
+            })
+            .init_resource::<WeaponRegistry>()
+            .add_systems(Update, (handle_stance_events, handle_fire_events).chain());
+        app
+    }
+
+    // Fires along -z at chest height of a standing player, returns the hit entities
+    fn fire_at_chest_height(app: &mut App, shooter: Entity) -> Vec<Entity> {
+        let origin = Vec3::new(0.0, 0.7, 0.0);
+        app.world_mut().send_event(FireEvent {
+            entity: shooter,
+            cam_origin: origin,
+            direction: Vec3::NEG_Z,
+            barrel_origin: origin,
+            weapon_id: PISTOL_WEAPON_ID,
+        });
+        app.update();
+        app.world()
+            .resource::<Events<HitEvent>>()
+            .iter_current_update_events()
+            .map(|event| event.hitten)
+            .collect()
+    }
+
+    #[test]
+    fn crouching_reduces_collider_height() {
+        let mut app = stance_app();
+        let player = spawn_player_with_hitboxes(&mut app, 1, Team(0), 0.0);
+        app.update();
+
+        app.world_mut().send_event(StanceEvent {
+            entity: player,
+            stance: Stance::Crouching,
+        });
+        app.update();
+
+        let entity = app.world().entity(player);
+        assert_eq!(*entity.get::<Stance>().unwrap(), Stance::Crouching);
+        let capsule = entity.get::<Collider>().unwrap().as_capsule().unwrap();
+        assert!(capsule.half_height() < Stance::Standing.half_height());
+        // The feet stay on the ground
+        let feet = entity.get::<Transform>().unwrap().translation.y
+            - capsule.half_height()
+            - capsule.radius();
+        assert!((feet + Stance::Standing.half_height() + PLAYER_CAPSULE_RADIUS).abs() < 1e-6);
+
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        let stance_messages = received_messages_of_type(&mut server, 1, 23);
+        assert_eq!(stance_messages.len(), 1);
+        assert_eq!(
+            u16::from_le_bytes([stance_messages[0][6], stance_messages[0][7]]),
+            1
+        );
+        assert_eq!(stance_messages[0][8], Stance::Crouching as u8);
+    }
+
+    #[test]
+    fn shot_over_crouched_player_misses() {
+        let mut app = stance_app();
+        let shooter = spawn_player_with_hitboxes(&mut app, 1, Team(0), 0.0);
+        let target = spawn_player_with_hitboxes(&mut app, 2, Team(1), -5.0);
+        // Lets rapier create the colliders and update its query pipeline
+        app.update();
+        app.update();
+        assert_eq!(fire_at_chest_height(&mut app, shooter), vec![target]);
+
+        app.world_mut().send_event(StanceEvent {
+            entity: target,
+            stance: Stance::Crouching,
+        });
+        app.update();
+        app.update();
+        assert!(fire_at_chest_height(&mut app, shooter).is_empty());
+    }
+}
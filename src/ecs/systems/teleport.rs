@@ -0,0 +1,156 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+use crate::{
+    ecs::components::{Player, PlayerLookup, SentTransform, VerticalVelocity},
+    server::{channel::DefaultChannel, message_out::MessageOut, server::DenariaServer},
+};
+
+// Applies the teleports requested on the server. The kinematic body follows the new transform,
+// the pending move and fall of the player are dropped so they don't carry over.
+#[allow(clippy::type_complexity)]
+pub fn handle_teleports(
+    player_lookup: Res<PlayerLookup>,
+    mut query: Query<(
+        &Player,
+        &mut Transform,
+        &mut KinematicCharacterController,
+        &mut VerticalVelocity,
+        Option<&mut SentTransform>,
+    )>,
+    mut server: ResMut<DenariaServer>,
+) {
+    for (player_id, position) in server.take_teleports() {
+        let Some(&entity) = player_lookup.map.get(&player_id) else {
+            tracing::warn!(player_id, "Teleported player is not in the session");
+            continue;
+        };
+        let Ok((player, mut transform, mut controller, mut v_velocity, sent)) =
+            query.get_mut(entity)
+        else {
+            continue;
+        };
+        tracing::info!(
+            player_id,
+            "Teleporting player from {} to {position}",
+            transform.translation
+        );
+        transform.translation = position;
+        controller.translation = None;
+        v_velocity.0 = 0.0;
+        // The teleport message replaces the position update of this move
+        if let Some(mut sent) = sent {
+            sent.translation = position;
+        }
+
+        match MessageOut::teleport_message(server.tick(), player.network_id, position) {
+            Ok(teleport_message) => {
+                server.broadcast_message(DefaultChannel::ReliableOrdered, teleport_message.data)
+            }
+            Err(e) => tracing::error!("Failed to serialize teleport message: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bevy::time::TimeUpdateStrategy;
+
+    use super::*;
+    use crate::ecs::{
+        components::{InterestConfig, PlayerBundle, TransformSendThreshold},
+        systems::{
+            on_change::on_transform_change,
+            test_support::{
+                physics_app, player_bundle, received_messages, register_player, test_server,
+            },
+        },
+    };
+
+    // Returns the entity of the first collider on a downward ray above `position`
+    fn collider_below(app: &App, position: Vec3) -> Option<Entity> {
+        app.world()
+            .resource::<RapierContext>()
+            .cast_ray(
+                position + Vec3::Y * 5.0,
+                Vec3::NEG_Y,
+                10.0,
+                true,
+                QueryFilter::default(),
+            )
+            .map(|(entity, _)| entity)
+    }
+
+    #[test]
+    fn teleport_moves_player_and_sends_teleport_message() {
+        let mut app = physics_app(test_server(1));
+        app.insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_millis(
+            16,
+        )))
+        .insert_resource(TransformSendThreshold::default())
+        .insert_resource(InterestConfig::default())
+        .add_systems(Update, (handle_teleports, on_transform_change).chain());
+        let start = Vec3::new(1.0, 0.0, 0.0);
+        let entity = app
+            .world_mut()
+            .spawn((
+                PlayerBundle {
+                    v_velocity: VerticalVelocity(-12.0),
+                    ..player_bundle(1)
+                },
+                RigidBody::KinematicPositionBased,
+                Collider::capsule_y(0.5, 0.5),
+                TransformBundle::from(Transform::from_translation(start)),
+                KinematicCharacterController::default(),
+            ))
+            .id();
+        register_player(&mut app, entity);
+        // Lets rapier create the body, the spawn position is sent as a regular position update
+        app.update();
+        app.update();
+        received_messages(&mut app.world_mut().resource_mut::<DenariaServer>(), 1);
+        assert_eq!(collider_below(&app, start), Some(entity));
+
+        // A move is pending when the teleport is applied
+        app.world_mut()
+            .get_mut::<KinematicCharacterController>(entity)
+            .unwrap()
+            .translation = Some(Vec3::X);
+        let target = Vec3::new(-20.0, 5.0, 30.0);
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        server.teleport("player1".to_string(), target);
+        server.teleport("nobody".to_string(), Vec3::ZERO);
+        app.update();
+        // Rapier writes the body back to the transform and updates its queries
+        app.update();
+
+        // The body moved along, without the dropped move
+        assert_eq!(collider_below(&app, target), Some(entity));
+        assert_eq!(collider_below(&app, start), None);
+        let player = app.world().entity(entity);
+        assert_eq!(player.get::<Transform>().unwrap().translation, target);
+        assert_eq!(
+            player
+                .get::<KinematicCharacterController>()
+                .unwrap()
+                .translation,
+            None
+        );
+        assert_eq!(player.get::<VerticalVelocity>().unwrap().0, 0.0);
+
+        let mut server = app.world_mut().resource_mut::<DenariaServer>();
+        let messages = received_messages(&mut server, 1);
+        let teleports: Vec<&Vec<u8>> = messages.iter().filter(|m| m[0] == 22).collect();
+        // Only the teleport is sent, no position update to interpolate towards
+        assert!(!messages.iter().any(|m| m[0] == 1));
+        assert_eq!(teleports.len(), 1);
+        assert_eq!(u16::from_le_bytes([teleports[0][6], teleports[0][7]]), 1);
+        let position: Vec<f32> = teleports[0][8..20]
+            .chunks(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        assert_eq!(position, target.to_array());
+    }
+}
@@ -0,0 +1,187 @@
+//! Fixtures shared by the tests of the systems: a session server with connected clients,
+//! apps, players and the decoding of the messages queued for a client.
+
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+use crossbeam::channel::{Receiver, Sender};
+
+use super::{
+    handle_events::{team_group, PLAYER_BODY_GROUP},
+    stance::{player_collider, HitboxBundle},
+};
+use crate::{
+    ecs::{
+        components::{HitRegion, Player, PlayerBundle, PlayerLookup, Stance, Team},
+        events::{
+            ClientReadyEvent, ControlMessageEvent, DisconnectEvent, FireEvent, LookEvent,
+            MessageAckedEvent, ReloadEvent, SpawnEvent, StanceEvent,
+        },
+    },
+    server::{
+        packet::Packet,
+        server::{tests::server_with_channels, ClientId, DenariaServer},
+        transport::transport::{FromDenariaServerMessage, ToDenariaServerMessage},
+    },
+};
+
+/// A server with the clients `1..=clients` connected as `player{client_id}`, along with the
+/// transport ends of its channels.
+pub(crate) fn test_server_with_transport(
+    clients: u64,
+) -> (
+    DenariaServer,
+    Sender<ToDenariaServerMessage>,
+    Receiver<FromDenariaServerMessage>,
+) {
+    let (mut server, to_server_tx, from_server_rx) = server_with_channels();
+    for client_id in 1..=clients {
+        server.add_connection(ClientId::from_raw(client_id), format!("player{client_id}"));
+    }
+    (server, to_server_tx, from_server_rx)
+}
+
+/// A server with the clients `1..=clients` connected as `player{client_id}`.
+pub(crate) fn test_server(clients: u64) -> DenariaServer {
+    test_server_with_transport(clients).0
+}
+
+/// An app with the events written by `handle_server_events` and `handle_server_messages`.
+pub(crate) fn server_app(server: DenariaServer) -> App {
+    let mut app = App::new();
+    app.add_event::<SpawnEvent>()
+        .add_event::<LookEvent>()
+        .add_event::<FireEvent>()
+        .add_event::<ReloadEvent>()
+        .add_event::<StanceEvent>()
+        .add_event::<ClientReadyEvent>()
+        .add_event::<DisconnectEvent>()
+        .add_event::<MessageAckedEvent>()
+        .add_event::<ControlMessageEvent>()
+        .insert_resource(server);
+    app
+}
+
+/// Like [`server_app`], with rapier running for colliders and raycasts.
+pub(crate) fn physics_app(server: DenariaServer) -> App {
+    let mut app = server_app(server);
+    app.add_plugins((
+        MinimalPlugins,
+        TransformPlugin,
+        RapierPhysicsPlugin::<NoUserData>::default(),
+    ));
+    app
+}
+
+/// The player `player{network_id}`, the id its client connects with in [`test_server`].
+pub(crate) fn player_bundle(network_id: u16) -> PlayerBundle {
+    PlayerBundle {
+        player: Player {
+            id: format!("player{network_id}"),
+            network_id,
+        },
+        ..Default::default()
+    }
+}
+
+/// Adds the player to the [`PlayerLookup`] of the app, inserting it when missing.
+pub(crate) fn register_player(app: &mut App, entity: Entity) {
+    let player_id = app.world().get::<Player>(entity).unwrap().id.clone();
+    app.world_mut()
+        .get_resource_or_insert_with(PlayerLookup::new)
+        .map
+        .insert(player_id, entity);
+}
+
+/// Spawns a player with a plain capsule collider in the group of its team, at `z` on the z axis.
+pub(crate) fn spawn_team_player(app: &mut App, network_id: u16, team: Team, z: f32) -> Entity {
+    app.world_mut()
+        .spawn((
+            PlayerBundle {
+                team,
+                ..player_bundle(network_id)
+            },
+            Collider::capsule_y(0.5, 0.5),
+            CollisionGroups::new(team_group(team), Group::ALL),
+            TransformBundle::from(Transform::from_xyz(0.0, 0.0, z)),
+        ))
+        .id()
+}
+
+/// Spawns a standing player with its body collider and hitboxes, at `z` on the z axis.
+pub(crate) fn spawn_player_with_hitboxes(
+    app: &mut App,
+    network_id: u16,
+    team: Team,
+    z: f32,
+) -> Entity {
+    app.world_mut()
+        .spawn((
+            PlayerBundle {
+                team,
+                ..player_bundle(network_id)
+            },
+            RigidBody::KinematicPositionBased,
+            player_collider(Stance::Standing),
+            CollisionGroups::new(PLAYER_BODY_GROUP, Group::ALL),
+            TransformBundle::from(Transform::from_xyz(0.0, 0.0, z)),
+        ))
+        .with_children(|parent| {
+            for region in HitRegion::ALL {
+                parent.spawn(HitboxBundle::new(region, team));
+            }
+        })
+        .id()
+}
+
+/// A packet of unreliable messages, as a client sends it.
+pub(crate) fn unreliable_packet(messages: Vec<Vec<u8>>) -> Vec<u8> {
+    let packet = Packet::SmallUnreliable {
+        channel_id: 0,
+        messages: messages.into_iter().map(Into::into).collect(),
+    };
+    let mut buffer = [0u8; 1200];
+    let len = packet.to_bytes(&mut buffer).unwrap();
+    buffer[..len].to_vec()
+}
+
+/// The move input message of a client.
+pub(crate) fn move_message(x: f32, y: f32) -> Vec<u8> {
+    let mut message = vec![2];
+    message.extend_from_slice(&x.to_le_bytes());
+    message.extend_from_slice(&y.to_le_bytes());
+    message
+}
+
+/// Drains the packets queued for the client and returns their messages, reliable and
+/// unreliable ones alike, in packet order.
+pub(crate) fn received_messages(server: &mut DenariaServer, client_id: u64) -> Vec<Vec<u8>> {
+    let mut received = vec![];
+    for payload in server
+        .get_packets_to_send(ClientId::from_raw(client_id))
+        .unwrap()
+    {
+        match Packet::from_bytes(&payload) {
+            Ok(Packet::SmallReliable { messages, .. }) => {
+                received.extend(messages.into_iter().map(|(_, message)| message.to_vec()))
+            }
+            Ok(Packet::SmallUnreliable { messages, .. }) => {
+                received.extend(messages.into_iter().map(|message| message.to_vec()))
+            }
+            _ => {}
+        }
+    }
+    // Messages dropped by a full reliable channel are sent empty
+    received.retain(|message| !message.is_empty());
+    received
+}
+
+/// Like [`received_messages`], keeping the messages of the given type.
+pub(crate) fn received_messages_of_type(
+    server: &mut DenariaServer,
+    client_id: u64,
+    message_type: u8,
+) -> Vec<Vec<u8>> {
+    let mut messages = received_messages(server, client_id);
+    messages.retain(|message| message[0] == message_type);
+    messages
+}
@@ -0,0 +1,137 @@
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// Liveness state shared between the transport loop and the health endpoint.
+#[derive(Debug, Clone)]
+pub struct HealthState {
+    started_at: Instant,
+    // Milliseconds since `started_at`, 0 until the first tick
+    last_tick_millis: Arc<AtomicU64>,
+    active_sessions: Arc<AtomicUsize>,
+}
+
+impl HealthState {
+    pub fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            last_tick_millis: Arc::new(AtomicU64::new(0)),
+            active_sessions: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Marks the main update loop as alive.
+    /// Should be called every tick
+    pub fn record_tick(&self, active_sessions: usize) {
+        let elapsed = self.started_at.elapsed().as_millis().max(1) as u64;
+        self.last_tick_millis.store(elapsed, Ordering::Relaxed);
+        self.active_sessions
+            .store(active_sessions, Ordering::Relaxed);
+    }
+
+    /// Returns whether the main loop has ticked within `max_tick_age`.
+    pub fn is_healthy(&self, max_tick_age: Duration) -> bool {
+        let last_tick = self.last_tick_millis.load(Ordering::Relaxed);
+        if last_tick == 0 {
+            return false;
+        }
+        let now = self.started_at.elapsed().as_millis() as u64;
+        now.saturating_sub(last_tick) <= max_tick_age.as_millis() as u64
+    }
+
+    /// Returns the number of sessions reported by the last tick.
+    pub fn active_sessions(&self) -> usize {
+        self.active_sessions.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for HealthState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Spawns a thread answering every HTTP request on `addr` with 200 while the main loop
+/// is ticking and 503 otherwise. The body contains the number of active sessions.
+pub fn spawn_health_endpoint(
+    addr: SocketAddr,
+    state: HealthState,
+    max_tick_age: Duration,
+) -> io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(addr)?;
+    tracing::info!("Health endpoint listening on {addr}");
+
+    let handle = std::thread::Builder::new()
+        .name("health".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = respond(stream, &state, max_tick_age) {
+                            tracing::debug!("Failed to answer health request: {e}");
+                        }
+                    }
+                    Err(e) => tracing::error!("Failed to accept health connection: {e}"),
+                }
+            }
+        })?;
+
+    Ok(handle)
+}
+
+fn respond(mut stream: TcpStream, state: &HealthState, max_tick_age: Duration) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_millis(500)))?;
+    // The request itself is irrelevant, read what is there so the client sees a clean close
+    let mut request = [0u8; 1024];
+    let _ = stream.read(&mut request)?;
+
+    let healthy = state.is_healthy(max_tick_age);
+    let status = if healthy {
+        "200 OK"
+    } else {
+        "503 Service Unavailable"
+    };
+    let body = serde_json::json!({
+        "healthy": healthy,
+        "active_sessions": state.active_sessions(),
+    })
+    .to_string();
+
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unhealthy_when_ticks_stop() {
+        let state = HealthState::new();
+        let max_tick_age = Duration::from_millis(50);
+
+        // Never ticked
+        assert!(!state.is_healthy(max_tick_age));
+
+        state.record_tick(2);
+        assert!(state.is_healthy(max_tick_age));
+        assert_eq!(state.active_sessions(), 2);
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!state.is_healthy(max_tick_age));
+
+        state.record_tick(1);
+        assert!(state.is_healthy(max_tick_age));
+    }
+}
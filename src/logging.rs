@@ -0,0 +1,213 @@
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use tracing::Subscriber;
+use tracing_subscriber::{fmt::MakeWriter, EnvFilter};
+
+use crate::constants::{LOG_RATE_LIMIT, LOG_RATE_LIMIT_INTERVAL};
+
+// Lines per interval of the rate limited log sites, 0 for no limit
+static RATE_LIMIT: AtomicU32 = AtomicU32::new(LOG_RATE_LIMIT);
+
+/// Output format of the log lines, selected with the `LOG_FORMAT` env variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines, the default
+    Pretty,
+    /// One JSON object per line, for log aggregation
+    Json,
+}
+
+impl LogFormat {
+    pub fn from_env() -> Self {
+        match std::env::var("LOG_FORMAT") {
+            Ok(format) if format.to_lowercase() == "json" => LogFormat::Json,
+            _ => LogFormat::Pretty,
+        }
+    }
+}
+
+/// Sets the global tracing subscriber for the given format, and the limit of the rate limited
+/// log sites from the `LOG_RATE_LIMIT` env variable, see [`LogLimiter`].
+pub fn init(format: LogFormat) {
+    if let Some(rate_limit) = std::env::var("LOG_RATE_LIMIT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        RATE_LIMIT.store(rate_limit, Ordering::Relaxed);
+    }
+    tracing::subscriber::set_global_default(subscriber(format, std::io::stdout))
+        .expect("setting default subscriber failed");
+}
+
+fn subscriber<W>(format: LogFormat, make_writer: W) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt::Subscriber::builder()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_thread_ids(true)
+        .with_thread_names(true)
+        .with_writer(make_writer);
+
+    match format {
+        LogFormat::Pretty => Box::new(builder.finish()),
+        // Flatten so client_id/player_id/session_id are top level keys of each line
+        LogFormat::Json => Box::new(builder.json().flatten_event(true).finish()),
+    }
+}
+
+/// Rate limit of a log site hit for every packet, so a flood of packets doesn't flood the logs:
+/// `if let Some(suppressed) = limiter.admit() { tracing::warn!(suppressed, "...") }`.
+/// At most `max_lines` lines are let through per interval, the ones dropped in between are
+/// counted and reported by the next line let through. A `max_lines` of 0 lets every line through.
+#[derive(Debug)]
+pub struct LogLimiter {
+    max_lines: u32,
+    interval: Duration,
+    window_start: Option<Instant>,
+    lines: u32,
+    suppressed: u64,
+}
+
+impl LogLimiter {
+    pub fn new(max_lines: u32, interval: Duration) -> Self {
+        Self {
+            max_lines,
+            interval,
+            window_start: None,
+            lines: 0,
+            suppressed: 0,
+        }
+    }
+
+    /// Returns how many lines were suppressed since the last one let through, or `None` when
+    /// this line is suppressed.
+    pub fn admit(&mut self) -> Option<u64> {
+        self.admit_at(Instant::now())
+    }
+
+    pub fn admit_at(&mut self, now: Instant) -> Option<u64> {
+        if self.max_lines == 0 {
+            return Some(0);
+        }
+        match self.window_start {
+            Some(window_start) if now.duration_since(window_start) < self.interval => {}
+            _ => {
+                self.window_start = Some(now);
+                self.lines = 0;
+            }
+        }
+        if self.lines == self.max_lines {
+            self.suppressed += 1;
+            return None;
+        }
+        self.lines += 1;
+        Some(std::mem::take(&mut self.suppressed))
+    }
+}
+
+impl Default for LogLimiter {
+    /// The limit set by `LOG_RATE_LIMIT`, per [`LOG_RATE_LIMIT_INTERVAL`].
+    fn default() -> Self {
+        Self::new(RATE_LIMIT.load(Ordering::Relaxed), LOG_RATE_LIMIT_INTERVAL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::server::{
+        server::tests::server_with_channels, transport::transport::ToDenariaServerMessage,
+    };
+
+    #[derive(Clone, Default)]
+    struct CaptureWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for CaptureWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn session_log_site_has_structured_json_fields() {
+        std::env::set_var("LOG_FORMAT", "json");
+        let format = LogFormat::from_env();
+        std::env::remove_var("LOG_FORMAT");
+        assert_eq!(format, LogFormat::Json);
+
+        let writer = CaptureWriter::default();
+        let captured = writer.0.clone();
+        let subscriber = subscriber(format, move || writer.clone());
+
+        let (mut server, to_server_tx, _from_server_rx) = server_with_channels();
+        // From a client the session doesn't know, it logs that it failed to process the packet
+        to_server_tx
+            .send(ToDenariaServerMessage::Payload {
+                client_id: 7,
+                payload: vec![255, 255, 255],
+            })
+            .unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            server.process_server_transport_messages();
+        });
+
+        let output = String::from_utf8(captured.lock().unwrap().clone()).unwrap();
+        let line: serde_json::Value = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .find(|line: &serde_json::Value| {
+                line["message"]
+                    .as_str()
+                    .is_some_and(|message| message.starts_with("Failed to process packet"))
+            })
+            .expect("no line for the invalid packet");
+        assert_eq!(line["level"], "ERROR");
+        assert_eq!(line["client_id"], 7);
+        assert_eq!(line["session_id"], 0);
+        assert_eq!(line["suppressed"], 0);
+    }
+
+    #[test]
+    fn burst_of_events_writes_a_bounded_number_of_lines() {
+        let writer = CaptureWriter::default();
+        let captured = writer.0.clone();
+        let subscriber = subscriber(LogFormat::Json, move || writer.clone());
+        let mut limiter = LogLimiter::new(5, Duration::from_secs(1));
+        let start = Instant::now();
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..1000 {
+                if let Some(suppressed) = limiter.admit_at(start) {
+                    tracing::error!(suppressed, "Unexpected packet");
+                }
+            }
+            // The next interval reports what the previous one dropped
+            if let Some(suppressed) = limiter.admit_at(start + Duration::from_secs(1)) {
+                tracing::error!(suppressed, "Unexpected packet");
+            }
+        });
+
+        let output = String::from_utf8(captured.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0]["suppressed"], 0);
+        assert_eq!(lines[5]["suppressed"], 995);
+    }
+}
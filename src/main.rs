@@ -1,51 +1,144 @@
 use std::{
     io,
-    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
-    time::SystemTime,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::{Duration, SystemTime},
 };
+mod admin;
 mod constants;
 mod ecs;
+mod health;
+mod logging;
 mod server;
 mod sessions;
+mod settings;
 
-use constants::TICK_DELTA;
-use server::transport::{server::server::ServerConfig, transport::ServerTransport};
-use tracing_subscriber::EnvFilter;
+use constants::HEALTH_MAX_TICK_AGE;
+use crossbeam::channel::Receiver;
+use ecs::components::{MovementConfig, TickRate};
+use logging::LogFormat;
+use server::transport::{
+    load_test::{LoadTest, LoadTestConfig},
+    transport::ServerTransport,
+};
+use settings::ServerSettings;
 
 fn main() -> io::Result<()> {
-    let subscriber = tracing_subscriber::fmt::Subscriber::builder()
-        .with_env_filter(EnvFilter::from_default_env())
-        .with_thread_ids(true)
-        .with_thread_names(true)
-        .finish();
-
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+    logging::init(LogFormat::from_env());
 
     // Now the tracing macros can be used throughout your application
     tracing::info!("This will dynamically update on the terminal");
 
+    // `matta-server load-test` runs bots against a running server instead of serving,
+    // configured through the LOAD_TEST_* variables
+    if std::env::args().nth(1).as_deref() == Some("load-test") {
+        let report = LoadTest::new(LoadTestConfig::from_env())
+            .and_then(LoadTest::run)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        tracing::info!("Load test finished: {report}");
+        return Ok(());
+    }
+
+    let settings = ServerSettings::load(std::env::args().skip(1), |name| std::env::var(name).ok())?;
+    tracing::info!("Server settings {settings:?}");
+
     // Setup transport layer
-    const SERVER_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 5001);
-    let socket: UdpSocket = UdpSocket::bind(SERVER_ADDR)?;
-    let server_config = ServerConfig {
-        current_time: SystemTime::now()
+    let bind_config = settings
+        .bind_config()
+        .apply_args_and_env(std::env::args().skip(1), |name| std::env::var(name).ok())?;
+    let (socket, public_addresses) = bind_config.bind()?;
+    tracing::info!("Listening on {}", socket.local_addr()?);
+    let server_config = settings.server_config(
+        SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap(),
-        max_clients: 64,
-        public_addresses: vec![SERVER_ADDR],
-    };
+        public_addresses,
+    );
 
     let mut transport = ServerTransport::new(server_config, socket)?;
+    transport.set_connection_config(settings.connection_config()?);
+    transport.set_session_tick_rate(TickRate(settings.session_tick_rate));
+    if let Some(auth_config) = settings.auth.clone() {
+        transport.set_auth_config(auth_config);
+    }
+    let tick_delta = settings.tick_delta();
+    transport.set_tick_budget(tick_delta);
+    if let Some(send_budget) = std::env::var("TRANSPORT_SEND_BUDGET_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        transport.set_send_budget(Duration::from_millis(send_budget));
+    }
+    let flush_on_disconnect =
+        std::env::var("FLUSH_ON_DISCONNECT").is_ok_and(|v| v.to_lowercase() == "true");
+    transport.set_flush_on_disconnect(flush_on_disconnect);
+    // Comma separated addresses banned permanently, more can be banned while running
+    if let Ok(banned_ips) = std::env::var("BANNED_IPS") {
+        for ip in banned_ips
+            .split(',')
+            .map(str::trim)
+            .filter(|ip| !ip.is_empty())
+        {
+            match ip.parse::<IpAddr>() {
+                Ok(ip) => transport.ban_ip(ip, None),
+                Err(e) => tracing::warn!("Ignored banned address {ip:?}: {e}"),
+            }
+        }
+    }
+
+    // Optional liveness/readiness endpoint for container orchestration
+    if let Ok(health_port) = std::env::var("HEALTH_PORT") {
+        let health_port: u16 = health_port.parse().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("HEALTH_PORT must be a valid port number: {e}"),
+            )
+        })?;
+        let health_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), health_port);
+        health::spawn_health_endpoint(health_addr, transport.health(), HEALTH_MAX_TICK_AGE)?;
+    }
+
+    // Optional admin commands, only reachable from the host since they are not authenticated
+    let admin_requests = match std::env::var("ADMIN_PORT") {
+        Ok(admin_port) => {
+            let admin_port: u16 = admin_port.parse().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("ADMIN_PORT must be a valid port number: {e}"),
+                )
+            })?;
+            let admin_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), admin_port);
+            Some(admin::spawn_admin_endpoint(admin_addr)?.1)
+        }
+        Err(_) => None,
+    };
+
+    // SESSION_SEED replays the spawns and teams of an earlier run of the default session
+    let session_seed = std::env::var("SESSION_SEED")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(rand::random);
 
     // create default session with player_ids from player1 to player10
-    transport.create_session(0, (1..=10).map(|i| format!("player{}", i)).collect());
+    transport
+        .create_session(
+            0,
+            (1..=10).map(|i| format!("player{}", i)).collect(),
+            MovementConfig::default(),
+            session_seed,
+            TickRate(settings.session_tick_rate),
+        )
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
 
     loop {
-        transport.update(TICK_DELTA).unwrap();
+        for request in admin_requests.iter().flat_map(Receiver::try_iter) {
+            let applied = transport.apply_admin_command(request.command);
+            let _ = request.applied.send(applied);
+        }
+        transport.update(tick_delta).unwrap();
 
         transport.send_packets();
 
-        // make this loop run 60 times per second
-        std::thread::sleep(std::time::Duration::from_millis(16));
+        // make this loop run `tick_rate` times per second
+        std::thread::sleep(tick_delta);
     }
 }
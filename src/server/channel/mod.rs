@@ -3,6 +3,8 @@ pub(crate) mod unreliable;
 
 use std::time::Duration;
 
+use crate::constants::MAX_MESSAGES_LENGTH;
+
 /// Delivery garantee of a channel
 #[derive(Debug, Clone)]
 pub enum SendType {
@@ -14,6 +16,18 @@ pub enum SendType {
     },
 }
 
+/// What a reliable channel does with a new message when it is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// The message is rejected with an error, which disconnects the client.
+    #[default]
+    Error,
+    /// The oldest messages not sent yet are dropped to make room, for streams where
+    /// losing an update is better than losing the client. A dropped message is sent empty,
+    /// the receiver skips it and the ids of the other messages are kept.
+    DropOldest,
+}
+
 /// Configuration of a channel for a server or client
 /// Channels are unilateral and message based.
 #[derive(Debug, Clone)]
@@ -23,10 +37,24 @@ pub struct ChannelConfig {
     pub channel_id: u8,
     /// Maximum number of bytes that the channel may hold without acknowledgement of messages before becoming full.
     /// Unreliable channels will drop new messages when this value is reached.
-    /// Reliable channels will cause a disconnect when this value is reached.
+    /// Reliable channels will cause a disconnect when this value is reached,
+    /// unless [`ChannelConfig::overflow_policy`] drops their oldest messages.
     pub max_memory_usage_bytes: usize,
+    /// Messages larger than this are rejected when sent, on both channel types.
+    /// Default: [`MAX_MESSAGES_LENGTH`], so a message always fits in a packet.
+    pub max_message_size_bytes: usize,
     /// Delivery garantee of the channel.
     pub send_type: SendType,
+    /// Minimum time between two flushes of the channel, messages are accumulated in between.
+    /// Use it to send low priority data at a lower rate. `Duration::ZERO` flushes every tick.
+    pub send_interval: Duration,
+    /// Behaviour of a full reliable channel, ignored by unreliable channels.
+    pub overflow_policy: OverflowPolicy,
+    /// A reliable channel whose oldest message waited this long for its ack is lagging behind.
+    /// It then drops its unacked messages and sends a reset point, so the receiver moves past
+    /// them instead of waiting. For streams where only the latest state matters.
+    /// `None` never resets, ignored by unreliable channels.
+    pub reset_after: Option<Duration>,
 }
 
 /// Utility enumerator when using the default channels configuration.
@@ -46,19 +74,28 @@ impl From<DefaultChannel> for u8 {
 }
 
 impl DefaultChannel {
+    /// The reliable channel then the unreliable one, so the reliable channel is served first.
     pub fn config() -> Vec<ChannelConfig> {
         vec![
-            ChannelConfig {
-                channel_id: 0,
-                max_memory_usage_bytes: 5 * 1024 * 1024,
-                send_type: SendType::Unreliable,
-            },
             ChannelConfig {
                 channel_id: 1,
                 max_memory_usage_bytes: 5 * 1024 * 1024,
+                max_message_size_bytes: MAX_MESSAGES_LENGTH,
                 send_type: SendType::ReliableOrdered {
                     resend_time: Duration::from_millis(300),
                 },
+                send_interval: Duration::ZERO,
+                overflow_policy: OverflowPolicy::Error,
+                reset_after: None,
+            },
+            ChannelConfig {
+                channel_id: 0,
+                max_memory_usage_bytes: 5 * 1024 * 1024,
+                max_message_size_bytes: MAX_MESSAGES_LENGTH,
+                send_type: SendType::Unreliable,
+                send_interval: Duration::ZERO,
+                overflow_policy: OverflowPolicy::Error,
+                reset_after: None,
             },
         ]
     }
@@ -5,19 +5,31 @@ use std::{
 
 use bytes::Bytes;
 
-use crate::{
-    constants::MAX_MESSAGES_LENGTH,
-    server::{error::ChannelError, packet::Packet},
-};
+use super::OverflowPolicy;
+use crate::server::{error::ChannelError, packet::Packet};
+
+// Fixed fields of a SmallReliable packet, then `u64 message_id | u16 length` in front of each message
+const PACKET_HEADER_BYTES: usize = 15;
+const MESSAGE_HEADER_BYTES: usize = 10;
 
 #[derive(Debug)]
 enum UnackedMessage {
     Small {
         message: Bytes,
+        priority: u8,
+        first_sent: Option<Duration>,
         last_sent: Option<Duration>,
     },
 }
 
+impl UnackedMessage {
+    fn priority(&self) -> u8 {
+        match self {
+            UnackedMessage::Small { priority, .. } => *priority,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SendChannelReliable {
     channel_id: u8,
@@ -27,6 +39,16 @@ pub struct SendChannelReliable {
     resend_time: Duration,
     max_memory_usage_bytes: usize,
     memory_usage_bytes: usize,
+    max_message_size_bytes: usize,
+    // Bytes of a packet, a message that can't fit in one alone is refused
+    max_packet_bytes: usize,
+    overflow_policy: OverflowPolicy,
+    dropped_message_ids: Vec<u64>,
+    reset_after: Option<Duration>,
+    // Reset point not acked yet, and when it was last sent
+    reset_point: Option<u64>,
+    reset_last_sent: Option<Duration>,
+    retransmitted_messages: u64,
 }
 
 #[derive(Debug)]
@@ -41,10 +63,18 @@ pub struct ReceiveChannelReliable {
     reliable_order: ReliableOrder,
     memory_usage_bytes: usize,
     max_memory_usage_bytes: usize,
+    duplicate_messages: u64,
 }
 
 impl SendChannelReliable {
-    pub fn new(channel_id: u8, resend_time: Duration, max_memory_usage_bytes: usize) -> Self {
+    pub fn new(
+        channel_id: u8,
+        resend_time: Duration,
+        max_memory_usage_bytes: usize,
+        max_message_size_bytes: usize,
+        overflow_policy: OverflowPolicy,
+        reset_after: Option<Duration>,
+    ) -> Self {
         Self {
             channel_id,
             unacked_messages: BTreeMap::new(),
@@ -53,6 +83,14 @@ impl SendChannelReliable {
             resend_time,
             max_memory_usage_bytes,
             memory_usage_bytes: 0,
+            max_message_size_bytes,
+            max_packet_bytes: usize::MAX,
+            overflow_policy,
+            dropped_message_ids: Vec::new(),
+            reset_after,
+            reset_point: None,
+            reset_last_sent: None,
+            retransmitted_messages: 0,
         }
     }
 
@@ -60,65 +98,132 @@ impl SendChannelReliable {
         self.max_memory_usage_bytes - self.memory_usage_bytes
     }
 
+    /// Bytes held by the messages not acked yet
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage_bytes
+    }
+
+    pub fn max_memory_usage(&self) -> usize {
+        self.max_memory_usage_bytes
+    }
+
+    /// Messages sent again because their ack didn't arrive within the resend time.
+    pub fn retransmitted_messages(&self) -> u64 {
+        self.retransmitted_messages
+    }
+
+    /// Limits the messages to the ones that fit in a packet of `max_packet_bytes` on their own.
+    /// Messages queued before the limit was lowered are still sent whole.
+    pub fn set_max_packet_bytes(&mut self, max_packet_bytes: usize) {
+        self.max_packet_bytes = max_packet_bytes;
+    }
+
+    /// Largest message accepted: the configured max, capped by the packet size.
+    pub fn max_message_bytes(&self) -> usize {
+        self.max_message_size_bytes.min(
+            self.max_packet_bytes
+                .saturating_sub(PACKET_HEADER_BYTES + MESSAGE_HEADER_BYTES),
+        )
+    }
+
     pub fn can_send_message(&self, size_bytes: usize) -> bool {
-        size_bytes + self.memory_usage_bytes <= self.max_memory_usage_bytes
+        size_bytes <= self.max_message_bytes()
+            && size_bytes + self.memory_usage_bytes <= self.max_memory_usage_bytes
     }
 
+    /// Packs the messages due for a (re)send by priority, keeping the send order within a
+    /// priority, into packets of at most `max_packet_bytes`.
     pub fn get_packets_to_send(
         &mut self,
         available_bytes: &mut u64,
+        max_packet_bytes: usize,
         current_time: Duration,
     ) -> Vec<Packet> {
-        if self.unacked_messages.is_empty() {
-            return vec![];
+        let mut packets: Vec<Packet> = vec![];
+
+        if let Some(message_id) = self.reset_point {
+            let resend_due = self
+                .reset_last_sent
+                .is_none_or(|last_sent| current_time - last_sent >= self.resend_time);
+            if resend_due {
+                packets.push(Packet::ResetPoint {
+                    channel_id: self.channel_id,
+                    sequence_id: self.next_package_sequence_id,
+                    message_id,
+                });
+                self.next_package_sequence_id += 1;
+                self.reset_last_sent = Some(current_time);
+            }
         }
 
-        let mut packets: Vec<Packet> = vec![];
+        if self.unacked_messages.is_empty() {
+            return packets;
+        }
 
         let mut small_messages: Vec<(u64, Bytes)> = vec![];
-        let mut small_messages_bytes = 0;
-
-        for (&message_id, unacked_message) in self.unacked_messages.iter_mut() {
-            match unacked_message {
-                UnackedMessage::Small { message, last_sent } => {
-                    if *available_bytes < message.len() as u64 {
-                        // Skip message, no bytes available to send this message
-                        continue;
-                    }
+        let mut packet_bytes = PACKET_HEADER_BYTES;
+
+        // Highest priority first, one pass per priority in use so the usual case where every
+        // message has the default priority walks the messages once, in id order
+        let mut send_priority = self
+            .unacked_messages
+            .values()
+            .map(UnackedMessage::priority)
+            .max();
+        while let Some(current_priority) = send_priority {
+            for (&message_id, unacked_message) in self.unacked_messages.iter_mut() {
+                let UnackedMessage::Small {
+                    message,
+                    priority,
+                    first_sent,
+                    last_sent,
+                } = unacked_message;
+                if *priority != current_priority {
+                    continue;
+                }
 
-                    if let Some(last_sent) = last_sent {
-                        if current_time - *last_sent < self.resend_time {
-                            continue;
-                        }
-                    }
+                if *available_bytes < message.len() as u64 {
+                    // Skip message, no bytes available to send this message
+                    continue;
+                }
 
-                    *available_bytes -= message.len() as u64;
-
-                    // Generate packet with small messages if you cannot fit
-                    let serialized_size = message.len()
-                        + octets::varint_len(message.len() as u64)
-                        + octets::varint_len(message_id);
-                    if small_messages_bytes + serialized_size > MAX_MESSAGES_LENGTH {
-                        packets.push(Packet::SmallReliable {
-                            channel_id: self.channel_id,
-                            packet_type: 0,
-                            packet_process_time: 0,
-                            sequence_id: self.next_package_sequence_id,
-                            acked_seq_id: u16::MAX,
-                            acked_mask: 0,
-                            messages: std::mem::take(&mut small_messages),
-                        });
-                        small_messages_bytes = 0;
-                        self.next_package_sequence_id += 1;
+                if let Some(last_sent) = last_sent {
+                    if current_time - *last_sent < self.resend_time {
+                        continue;
                     }
+                }
 
-                    small_messages_bytes += serialized_size;
-                    small_messages.push((message_id, message.clone()));
-                    *last_sent = Some(current_time);
+                *available_bytes -= message.len() as u64;
+
+                // Generate packet with small messages if you cannot fit
+                let serialized_size = MESSAGE_HEADER_BYTES + message.len();
+                if packet_bytes + serialized_size > max_packet_bytes && !small_messages.is_empty() {
+                    packets.push(Packet::SmallReliable {
+                        channel_id: self.channel_id,
+                        packet_type: 0,
+                        packet_process_time: 0,
+                        sequence_id: self.next_package_sequence_id,
+                        acked_seq_id: u16::MAX,
+                        acked_mask: 0,
+                        messages: std::mem::take(&mut small_messages),
+                    });
+                    packet_bytes = PACKET_HEADER_BYTES;
+                    self.next_package_sequence_id += 1;
+                }
 
-                    continue;
+                packet_bytes += serialized_size;
+                small_messages.push((message_id, message.clone()));
+                first_sent.get_or_insert(current_time);
+                if last_sent.replace(current_time).is_some() {
+                    self.retransmitted_messages += 1;
                 }
             }
+            send_priority = self
+                .unacked_messages
+                .values()
+                .map(UnackedMessage::priority)
+                .filter(|&priority| priority < current_priority)
+                .max();
         }
 
         // Generate final packet for remaining small messages
@@ -137,25 +242,130 @@ impl SendChannelReliable {
         packets
     }
 
-    pub fn send_message(&mut self, message: Bytes) -> Result<(), ChannelError> {
+    /// Queues the message, returns the id its ack is reported with.
+    pub fn send_message(&mut self, message: Bytes) -> Result<u64, ChannelError> {
+        self.send_message_with_priority(message, 0)
+    }
+
+    /// Queues a message that is sent before the ones with a lower `priority` when the bytes of
+    /// a tick are scarce. The receiver still delivers the messages in send order.
+    pub fn send_message_with_priority(
+        &mut self,
+        message: Bytes,
+        priority: u8,
+    ) -> Result<u64, ChannelError> {
+        if message.len() > self.max_message_bytes() {
+            return Err(ChannelError::MessageTooLarge {
+                size: message.len(),
+                max: self.max_message_bytes(),
+            });
+        }
         if self.memory_usage_bytes + message.len() > self.max_memory_usage_bytes {
-            return Err(ChannelError::ReliableChannelMaxMemoryReached);
+            match self.overflow_policy {
+                OverflowPolicy::Error => return Err(ChannelError::ReliableChannelMaxMemoryReached),
+                OverflowPolicy::DropOldest => self.drop_oldest_unsent(message.len())?,
+            }
         }
 
         self.memory_usage_bytes += message.len();
         let unacked_message = UnackedMessage::Small {
             message,
+            priority,
+            first_sent: None,
             last_sent: None,
         };
 
-        self.unacked_messages
-            .insert(self.next_message_id, unacked_message);
+        let message_id = self.next_message_id;
+        self.unacked_messages.insert(message_id, unacked_message);
         self.next_message_id += 1;
 
+        Ok(message_id)
+    }
+
+    /// Makes room for `size_bytes` by dropping the oldest messages not sent yet, wherever
+    /// priorities put them among the sent ones. The receiver waits for every id in order, so a
+    /// dropped message is still sent empty and the ids of the other messages don't change.
+    fn drop_oldest_unsent(&mut self, size_bytes: usize) -> Result<(), ChannelError> {
+        // Oldest first
+        let unsent: Vec<(u64, usize)> = self
+            .unacked_messages
+            .iter()
+            .filter(
+                |&(
+                    _,
+                    UnackedMessage::Small {
+                        message, last_sent, ..
+                    },
+                )| { last_sent.is_none() && !message.is_empty() },
+            )
+            .map(|(&message_id, UnackedMessage::Small { message, .. })| (message_id, message.len()))
+            .collect();
+        let unsent_bytes: usize = unsent.iter().map(|&(_, size)| size).sum();
+        if unsent.is_empty()
+            || self.memory_usage_bytes - unsent_bytes + size_bytes > self.max_memory_usage_bytes
+        {
+            return Err(ChannelError::ReliableChannelMaxMemoryReached);
+        }
+
+        for (message_id, size) in unsent {
+            if self.memory_usage_bytes + size_bytes <= self.max_memory_usage_bytes {
+                break;
+            }
+            if let Some(UnackedMessage::Small { message, .. }) =
+                self.unacked_messages.get_mut(&message_id)
+            {
+                *message = Bytes::new();
+            }
+            self.memory_usage_bytes -= size;
+            self.dropped_message_ids.push(message_id);
+        }
         Ok(())
     }
 
-    pub fn process_message_ack(&mut self, message_id: u64) {
+    /// Ids of the messages dropped since the last call, in drop order, see
+    /// [`OverflowPolicy::DropOldest`].
+    pub fn take_dropped_messages(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.dropped_message_ids)
+    }
+
+    /// How long the oldest unacked message has been waiting for its ack since it was first sent.
+    pub fn lag(&self, current_time: Duration) -> Duration {
+        match self.unacked_messages.first_key_value() {
+            Some((
+                _,
+                UnackedMessage::Small {
+                    first_sent: Some(first_sent),
+                    ..
+                },
+            )) => current_time.saturating_sub(*first_sent),
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Whether the lag reached [`ChannelConfig::reset_after`](super::ChannelConfig::reset_after).
+    pub fn is_lagging(&self, current_time: Duration) -> bool {
+        self.reset_after
+            .is_some_and(|reset_after| self.lag(current_time) >= reset_after)
+    }
+
+    /// Drops every unacked message and sends a reset point until it is acked, so the receiver
+    /// stops waiting for them. Returns the reset point, the id of the next message sent.
+    pub fn reset(&mut self) -> u64 {
+        self.unacked_messages.clear();
+        self.memory_usage_bytes = 0;
+        self.reset_point = Some(self.next_message_id);
+        self.reset_last_sent = None;
+        self.next_message_id
+    }
+
+    pub fn process_reset_ack(&mut self, message_id: u64) {
+        if self.reset_point == Some(message_id) {
+            self.reset_point = None;
+        }
+    }
+
+    /// Returns whether the message was waiting for its ack, false for repeated acks.
+    pub fn process_message_ack(&mut self, message_id: u64) -> bool {
         if self.unacked_messages.contains_key(&message_id) {
             tracing::trace!("MESSAGE ID: {:?} IS ACKEDD!!!", message_id);
             let unacked_message = self.unacked_messages.remove(&message_id).unwrap();
@@ -164,7 +374,9 @@ impl SendChannelReliable {
             } = unacked_message;
 
             self.memory_usage_bytes -= payload.len();
+            return true;
         }
+        false
     }
 }
 
@@ -183,18 +395,25 @@ impl ReceiveChannelReliable {
             reliable_order,
             memory_usage_bytes: 0,
             max_memory_usage_bytes,
+            duplicate_messages: 0,
         }
     }
 
+    /// Messages discarded because they were already received.
+    pub fn duplicate_messages(&self) -> u64 {
+        self.duplicate_messages
+    }
+
     pub fn process_message(&mut self, message: Bytes, message_id: u64) -> Result<(), ChannelError> {
         if message_id < self.oldest_pending_message_id {
             // Discard old message already received
+            self.duplicate_messages += 1;
             return Ok(());
         }
 
         match &mut self.reliable_order {
-            ReliableOrder::Ordered => {
-                if let btree_map::Entry::Vacant(entry) = self.messages.entry(message_id) {
+            ReliableOrder::Ordered => match self.messages.entry(message_id) {
+                btree_map::Entry::Vacant(entry) => {
                     if self.memory_usage_bytes + message.len() > self.max_memory_usage_bytes {
                         return Err(ChannelError::ReliableChannelMaxMemoryReached);
                     }
@@ -202,23 +421,183 @@ impl ReceiveChannelReliable {
 
                     entry.insert(message);
                 }
-            }
+                btree_map::Entry::Occupied(_) => self.duplicate_messages += 1,
+            },
         }
 
         Ok(())
     }
 
+    /// Skips the messages below `message_id` that were not received yet, they will never be sent.
+    pub fn process_reset_point(&mut self, message_id: u64) {
+        if message_id <= self.oldest_pending_message_id {
+            return;
+        }
+        let kept = self.messages.split_off(&message_id);
+        for message in std::mem::replace(&mut self.messages, kept).into_values() {
+            self.memory_usage_bytes -= message.len();
+        }
+        self.oldest_pending_message_id = message_id;
+    }
+
+    /// Empty messages, the ones the sender dropped, are skipped.
     pub fn receive_message(&mut self) -> Option<Bytes> {
         match &mut self.reliable_order {
-            ReliableOrder::Ordered => {
-                let Some(message) = self.messages.remove(&self.oldest_pending_message_id) else {
-                    return None;
-                };
+            ReliableOrder::Ordered => loop {
+                let message = self.messages.remove(&self.oldest_pending_message_id)?;
 
                 self.oldest_pending_message_id += 1;
                 self.memory_usage_bytes -= message.len();
-                Some(message)
-            }
+                if !message.is_empty() {
+                    return Some(message);
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sent_messages(packets: Vec<Packet>) -> Vec<(u64, Bytes)> {
+        packets
+            .into_iter()
+            .flat_map(|packet| match packet {
+                Packet::SmallReliable { messages, .. } => messages,
+                _ => vec![],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn retransmits_and_duplicates_are_counted() {
+        let resend_time = Duration::from_millis(300);
+        let mut send_channel =
+            SendChannelReliable::new(1, resend_time, 1000, 100, OverflowPolicy::Error, None);
+        let mut receive_channel = ReceiveChannelReliable::new(1000, None);
+        let message_id = send_channel
+            .send_message(Bytes::from(vec![1u8; 10]))
+            .unwrap();
+
+        let sent = sent_messages(send_channel.get_packets_to_send(&mut 1000, 1000, Duration::ZERO));
+        assert_eq!(send_channel.retransmitted_messages(), 0);
+        // Not acked within the resend time
+        let resent = sent_messages(send_channel.get_packets_to_send(&mut 1000, 1000, resend_time));
+        assert_eq!(send_channel.retransmitted_messages(), 1);
+
+        // The first send only arrived with the resend
+        for (id, message) in sent.into_iter().chain(resent) {
+            receive_channel.process_message(message, id).unwrap();
+        }
+        assert_eq!(receive_channel.duplicate_messages(), 1);
+        assert!(receive_channel.receive_message().is_some());
+
+        // Once delivered, later copies are below the oldest pending id
+        receive_channel
+            .process_message(Bytes::from(vec![1u8; 10]), message_id)
+            .unwrap();
+        assert_eq!(receive_channel.duplicate_messages(), 2);
+        assert!(receive_channel.receive_message().is_none());
+    }
+
+    #[test]
+    fn drop_oldest_replaces_oldest_unsent_message_when_full() {
+        let mut channel = SendChannelReliable::new(
+            1,
+            Duration::from_millis(300),
+            100,
+            100,
+            OverflowPolicy::DropOldest,
+            None,
+        );
+        channel.send_message(Bytes::from(vec![1u8; 40])).unwrap();
+        // Messages in flight are kept until acked
+        let sent = sent_messages(channel.get_packets_to_send(&mut 1000, 1000, Duration::ZERO));
+        assert_eq!(sent.len(), 1);
+
+        channel.send_message(Bytes::from(vec![2u8; 30])).unwrap();
+        channel.send_message(Bytes::from(vec![3u8; 30])).unwrap();
+        let message_id = channel.send_message(Bytes::from(vec![4u8; 30])).unwrap();
+        assert_eq!(channel.take_dropped_messages(), vec![1]);
+        assert_eq!(message_id, 3);
+        assert_eq!(channel.memory_usage(), 100);
+
+        // The dropped message is sent empty, the others keep their ids
+        let sent = sent_messages(channel.get_packets_to_send(&mut 1000, 1000, Duration::ZERO));
+        let sent: Vec<(u64, Option<u8>)> = sent
+            .into_iter()
+            .map(|(message_id, message)| (message_id, message.first().copied()))
+            .collect();
+        assert_eq!(sent, vec![(1, None), (2, Some(3)), (3, Some(4))]);
+
+        // Nothing left to drop once every message is in flight
+        assert!(matches!(
+            channel.send_message(Bytes::from(vec![5u8; 30])),
+            Err(ChannelError::ReliableChannelMaxMemoryReached)
+        ));
+        assert!(channel.take_dropped_messages().is_empty());
+    }
+
+    #[test]
+    fn drop_oldest_finds_unsent_messages_before_a_sent_priority_one() {
+        let mut channel = SendChannelReliable::new(
+            1,
+            Duration::from_millis(300),
+            100,
+            100,
+            OverflowPolicy::DropOldest,
+            None,
+        );
+        channel.send_message(Bytes::from(vec![1u8; 30])).unwrap();
+        channel
+            .send_message_with_priority(Bytes::from(vec![2u8; 30]), 5)
+            .unwrap();
+        // Only the newer, prioritised message fits in the budget
+        let sent = sent_messages(channel.get_packets_to_send(&mut 30, 1000, Duration::ZERO));
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, 1);
+
+        channel.send_message(Bytes::from(vec![3u8; 30])).unwrap();
+        channel.send_message(Bytes::from(vec![4u8; 30])).unwrap();
+        assert_eq!(channel.take_dropped_messages(), vec![0]);
+        assert_eq!(channel.memory_usage(), 90);
+
+        let sent = sent_messages(channel.get_packets_to_send(&mut 1000, 1000, Duration::ZERO));
+        let sent: Vec<(u64, Option<u8>)> = sent
+            .into_iter()
+            .map(|(message_id, message)| (message_id, message.first().copied()))
+            .collect();
+        assert_eq!(sent, vec![(0, None), (2, Some(3)), (3, Some(4))]);
+    }
+
+    #[test]
+    fn high_priority_message_sent_before_older_ones_under_tight_budget() {
+        let mut channel = SendChannelReliable::new(
+            1,
+            Duration::from_millis(300),
+            1000,
+            100,
+            OverflowPolicy::Error,
+            None,
+        );
+        for i in 0..3u8 {
+            channel.send_message(Bytes::from(vec![i; 30])).unwrap();
         }
+        channel
+            .send_message_with_priority(Bytes::from(vec![9u8; 40]), 5)
+            .unwrap();
+
+        let sent = sent_messages(channel.get_packets_to_send(&mut 70, 1000, Duration::ZERO));
+        let sent: Vec<(u64, u8)> = sent
+            .into_iter()
+            .map(|(message_id, message)| (message_id, message[0]))
+            .collect();
+        assert_eq!(sent, vec![(3, 9), (0, 0)]);
+
+        // The remaining ones follow in send order
+        let sent = sent_messages(channel.get_packets_to_send(&mut 1000, 1000, Duration::ZERO));
+        let message_ids: Vec<u64> = sent.into_iter().map(|(message_id, _)| message_id).collect();
+        assert_eq!(message_ids, vec![1, 2]);
     }
 }
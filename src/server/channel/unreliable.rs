@@ -2,14 +2,20 @@ use std::collections::VecDeque;
 
 use bytes::Bytes;
 
-use crate::{constants::MAX_MESSAGES_LENGTH, server::packet::Packet};
+use crate::server::{error::ChannelError, packet::Packet};
+
+// `u8 channel_id | u16 message count`, then `u16 length` in front of each message
+const PACKET_HEADER_BYTES: usize = 3;
+const MESSAGE_HEADER_BYTES: usize = 2;
 
 #[derive(Debug)]
 pub struct SendChannelUnreliable {
     channel_id: u8,
-    unreliable_messages: VecDeque<Bytes>,
+    // Messages with their priority, higher priorities are packed first
+    unreliable_messages: VecDeque<(Bytes, u8)>,
     max_memory_usage_bytes: usize,
     memory_usage_bytes: usize,
+    max_message_size_bytes: usize,
 }
 
 #[derive(Debug)]
@@ -21,47 +27,81 @@ pub struct ReceiveChannelUnreliable {
 }
 
 impl SendChannelUnreliable {
-    pub fn new(channel_id: u8, max_memory_usage_bytes: usize) -> Self {
+    pub fn new(
+        channel_id: u8,
+        max_memory_usage_bytes: usize,
+        max_message_size_bytes: usize,
+    ) -> Self {
         Self {
             channel_id,
             unreliable_messages: VecDeque::new(),
             max_memory_usage_bytes,
             memory_usage_bytes: 0,
+            max_message_size_bytes,
         }
     }
 
     pub fn can_send_message(&self, size_bytes: usize) -> bool {
-        size_bytes + self.memory_usage_bytes <= self.max_memory_usage_bytes
+        size_bytes <= self.max_message_size_bytes
+            && size_bytes + self.memory_usage_bytes <= self.max_memory_usage_bytes
     }
 
     pub fn available_memory(&self) -> usize {
         self.max_memory_usage_bytes - self.memory_usage_bytes
     }
 
-    pub fn get_packets_to_send(&mut self, available_bytes: &mut u64) -> Vec<Packet> {
+    /// Bytes held by the queued messages
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage_bytes
+    }
+
+    pub fn max_memory_usage(&self) -> usize {
+        self.max_memory_usage_bytes
+    }
+
+    /// Packs the queued messages by priority, keeping the send order within a priority,
+    /// into packets of at most `max_packet_bytes`.
+    /// Messages that don't fit in `available_bytes` are dropped.
+    pub fn get_packets_to_send(
+        &mut self,
+        available_bytes: &mut u64,
+        max_packet_bytes: usize,
+    ) -> Vec<Packet> {
         let mut packets: Vec<Packet> = vec![];
         let mut small_messages: Vec<Bytes> = vec![];
-        let mut small_messages_bytes = 0;
+        let mut packet_bytes = PACKET_HEADER_BYTES;
+
+        self.unreliable_messages
+            .make_contiguous()
+            .sort_by(|(_, a), (_, b)| b.cmp(a));
 
-        while let Some(message) = self.unreliable_messages.pop_front() {
+        while let Some((message, _)) = self.unreliable_messages.pop_front() {
             self.memory_usage_bytes -= message.len();
             if *available_bytes < message.len() as u64 {
                 // Drop message, no available bytes to send
                 continue;
             }
 
+            let serialized_size = MESSAGE_HEADER_BYTES + message.len();
+            if PACKET_HEADER_BYTES + serialized_size > max_packet_bytes {
+                tracing::warn!(
+                    "dropped unreliable message of {} bytes, it doesn't fit in a packet of {max_packet_bytes} bytes",
+                    message.len()
+                );
+                continue;
+            }
+
             *available_bytes -= message.len() as u64;
 
-            let serialized_size = message.len() + octets::varint_len(message.len() as u64);
-            if small_messages_bytes + serialized_size > MAX_MESSAGES_LENGTH {
+            if packet_bytes + serialized_size > max_packet_bytes {
                 packets.push(Packet::SmallUnreliable {
                     channel_id: self.channel_id,
                     messages: std::mem::take(&mut small_messages),
                 });
-                small_messages_bytes = 0;
+                packet_bytes = PACKET_HEADER_BYTES;
             }
 
-            small_messages_bytes += serialized_size;
+            packet_bytes += serialized_size;
             small_messages.push(message);
         }
 
@@ -76,23 +116,36 @@ impl SendChannelUnreliable {
         packets
     }
 
-    pub fn send_message(&mut self, message: Bytes) {
+    pub fn send_message(&mut self, message: Bytes) -> Result<(), ChannelError> {
+        self.send_message_with_priority(message, 0)
+    }
+
+    /// Queues a message that is packed before the ones with a lower `priority`
+    /// when the bytes of a tick are scarce.
+    /// Oversized messages are rejected, a full channel drops the message without an error.
+    pub fn send_message_with_priority(
+        &mut self,
+        message: Bytes,
+        priority: u8,
+    ) -> Result<(), ChannelError> {
+        if message.len() > self.max_message_size_bytes {
+            return Err(ChannelError::MessageTooLarge {
+                size: message.len(),
+                max: self.max_message_size_bytes,
+            });
+        }
+
         if self.memory_usage_bytes + message.len() > self.max_memory_usage_bytes {
             tracing::warn!(
                 "dropped unreliable message sent because channel {} is memory limited",
                 self.channel_id
             );
-            return;
-        }
-
-        let message_size = message.len();
-        if message_size > MAX_MESSAGES_LENGTH {
-            tracing::error!(
-                "Sending a message that is longer than {MAX_MESSAGES_LENGTH} is prohibited. Attempted message size: {message_size} ");
+            return Ok(());
         }
 
         self.memory_usage_bytes += message.len();
-        self.unreliable_messages.push_back(message);
+        self.unreliable_messages.push_back((message, priority));
+        Ok(())
     }
 }
 
@@ -128,3 +181,42 @@ impl ReceiveChannelUnreliable {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sent_messages(packets: Vec<Packet>) -> Vec<Bytes> {
+        packets
+            .into_iter()
+            .flat_map(|packet| match packet {
+                Packet::SmallUnreliable { messages, .. } => messages,
+                _ => vec![],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn high_priority_sent_first_under_tight_budget() {
+        let mut channel = SendChannelUnreliable::new(0, 1024, 1024);
+        channel.send_message(Bytes::from(vec![1u8; 40])).unwrap();
+        channel
+            .send_message_with_priority(Bytes::from(vec![2u8; 40]), 10)
+            .unwrap();
+        channel.send_message(Bytes::from(vec![3u8; 40])).unwrap();
+        channel
+            .send_message_with_priority(Bytes::from(vec![4u8; 40]), 10)
+            .unwrap();
+
+        let mut available_bytes = 100;
+        let sent = sent_messages(channel.get_packets_to_send(&mut available_bytes, 1000));
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0][0], 2);
+        assert_eq!(sent[1][0], 4);
+        assert_eq!(available_bytes, 20);
+
+        // Low priority messages were dropped, not kept for the next tick
+        assert!(channel.get_packets_to_send(&mut 1000, 1000).is_empty());
+        assert_eq!(channel.available_memory(), 1024);
+    }
+}
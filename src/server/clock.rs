@@ -0,0 +1,48 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Source of the wall clock readings of the connections and the transport, the ones not driven
+/// by `update(duration)`, like the ack process time and the send budget.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// Reads [`Instant::now`], the clock used unless another one is injected.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when advanced, so tests get deterministic timings.
+/// Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+}
+
+impl ManualClock {
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
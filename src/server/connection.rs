@@ -1,30 +1,54 @@
 use bytes::Bytes;
 
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use crate::constants::{
+    TRANSPORT_DATA_HEADER_BYTES, TRANSPORT_MAX_PACKET_BYTES, TRANSPORT_MIN_PACKET_BYTES,
+};
+
 use super::channel::reliable::{ReceiveChannelReliable, SendChannelReliable};
 use super::channel::unreliable::{ReceiveChannelUnreliable, SendChannelUnreliable};
 use super::channel::{ChannelConfig, DefaultChannel, SendType};
+use super::clock::{Clock, SystemClock};
 use super::connection_stats::ConnectionStats;
-use super::error::DisconnectReason;
-use super::packet::{Packet, Payload};
+use super::error::{ChannelError, ChannelSide, ConnectionConfigError, DisconnectReason};
+use super::packet::{Packet, Payload, SerializationError};
 
 #[derive(Debug, Clone)]
 pub struct ConnectionConfig {
     /// The number of bytes that is available per update tick to send messages.
     /// Default: 60_000, at 60hz this is becomes 28.8 Mbps
     pub available_bytes_per_tick: u64,
-    /// The channels that the server sends to the client.
-    /// The order of the channels in this Vec determines which channel has priority when generating packets.
-    /// Each tick, the first channel can consume up to `available_bytes_per_tick`,
-    /// used bytes are removed from it and passed to the next channel
+    /// The channels that the server sends to the client, the unreliable and the reliable one in
+    /// either order, see [`ConnectionConfig::validate`].
+    /// The order of the channels determines which channel has priority when generating packets.
+    /// Each tick, the first channel can consume up to `available_bytes_per_tick`, the second
+    /// one gets the bytes it left. Unreliable messages that don't fit are dropped.
+    /// Default: the reliable channel first
     pub server_channels_config: Vec<ChannelConfig>,
-    /// The channels that the client sends to the server.
-    /// The order of the channels in this Vec determines which channel has priority when generating packets.
-    /// Each tick, the first channel can consume up to `available_bytes_per_tick`,
-    /// used bytes are removed from it and passed to the next channel
+    /// The channels that the client sends to the server, with the same layout and priority
+    /// as `server_channels_config`.
     pub client_channels_config: Vec<ChannelConfig>,
+    /// Number of undecodable packets tolerated within `decode_error_window`, they are treated
+    /// as dropped packets. One more disconnects the client.
+    /// Default: 3
+    pub max_decode_errors: usize,
+    /// Default: 1 second
+    pub decode_error_window: Duration,
+    /// Size limit of the datagrams sent to the client, transport header included.
+    /// Lower it for clients behind tunnels or VPNs with a smaller path MTU.
+    /// Clamped to [`TRANSPORT_MIN_PACKET_BYTES`]..=[`TRANSPORT_MAX_PACKET_BYTES`].
+    /// Default: [`TRANSPORT_MAX_PACKET_BYTES`]
+    pub max_packet_bytes: usize,
+    /// Round trip time assumed until the first ack is measured, later measurements are
+    /// blended into it. Zero takes the first measurement as is.
+    /// Default: 100 milliseconds
+    pub initial_rtt: Duration,
+    /// Wall clock of the ack process time, replace it to control the timings in tests.
+    /// Default: [`SystemClock`]
+    pub clock: Arc<dyn Clock>,
 }
 
 #[derive(Debug, Clone)]
@@ -41,6 +65,9 @@ enum PacketSentInfo {
         channel_id: u8,
         message_ids: Vec<u64>,
     },
+    ResetPoint {
+        message_id: u64,
+    },
 }
 
 #[derive(Debug)]
@@ -49,6 +76,34 @@ enum ChannelOrder {
     Unreliable(u8),
 }
 
+/// Tracks when a send channel was last flushed, see [`ChannelConfig::send_interval`].
+#[derive(Debug)]
+struct ChannelSendTimer {
+    interval: Duration,
+    last_flush: Option<Duration>,
+}
+
+impl ChannelSendTimer {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_flush: None,
+        }
+    }
+
+    /// Returns whether the channel should be flushed now, marking it as flushed if so.
+    fn try_flush(&mut self, current_time: Duration) -> bool {
+        let due = match self.last_flush {
+            Some(last_flush) => current_time >= last_flush + self.interval,
+            None => true,
+        };
+        if due {
+            self.last_flush = Some(current_time);
+        }
+        due
+    }
+}
+
 #[allow(dead_code)]
 /// Describes the stats of a connection.
 pub struct NetworkInfo {
@@ -57,6 +112,16 @@ pub struct NetworkInfo {
     pub packet_loss: f64,
     pub bytes_sent_per_second: f64,
     pub bytes_received_per_second: f64,
+    /// `(used, max)` bytes of the unreliable send channel
+    pub unreliable_memory: (usize, usize),
+    /// `(used, max)` bytes of the reliable send channel
+    pub reliable_memory: (usize, usize),
+    /// How long the oldest unacked reliable message has been waiting for its ack
+    pub reliable_lag: Duration,
+    /// Reliable messages sent again for a missing ack
+    pub reliable_retransmits: u64,
+    /// Reliable messages received again and discarded
+    pub reliable_duplicates: u64,
 }
 
 #[derive(Debug)]
@@ -70,19 +135,30 @@ pub enum ClientConnectionStatus {
 pub struct UnityClient {
     current_time: Duration,
     sent_packets: BTreeMap<u16, PacketSent>,
+    // Newest sequence sent, acks ahead of it are for packets that were never sent
+    last_sent_sequence: Option<u16>,
     pending_acks: VecDeque<u16>,
     new_ack_to_send: bool,
     ack_process_start_instant: Instant,
-    channel_send_order: Vec<ChannelOrder>,
+    clock: Arc<dyn Clock>,
+    channel_send_order: Vec<(ChannelOrder, ChannelSendTimer)>,
     send_unreliable_channel: SendChannelUnreliable,
     receive_unreliable_channel: ReceiveChannelUnreliable,
     send_reliable_channel: SendChannelReliable,
     receive_reliable_channel: ReceiveChannelReliable,
     stats: ConnectionStats,
     available_bytes_per_tick: u64,
+    // Times of the recent undecodable packets
+    decode_errors: VecDeque<Duration>,
+    max_decode_errors: usize,
+    decode_error_window: Duration,
+    max_packet_bytes: usize,
     connection_status: ClientConnectionStatus,
     rtt: f64,
     player_id: String,
+    // Reliable messages whose ack is reported, and the ones acked since the last report
+    tracked_messages: HashSet<u64>,
+    acked_tracked_messages: Vec<u64>,
 }
 
 impl Default for ConnectionConfig {
@@ -92,18 +168,134 @@ impl Default for ConnectionConfig {
             available_bytes_per_tick: 60_000,
             server_channels_config: DefaultChannel::config(),
             client_channels_config: DefaultChannel::config(),
+            max_decode_errors: 3,
+            decode_error_window: Duration::from_secs(1),
+            max_packet_bytes: TRANSPORT_MAX_PACKET_BYTES,
+            initial_rtt: Duration::from_millis(100),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl ConnectionConfig {
+    /// Starts from the default config, [`ConnectionConfigBuilder::build`] validates the channels.
+    pub fn builder() -> ConnectionConfigBuilder {
+        ConnectionConfigBuilder {
+            config: ConnectionConfig::default(),
         }
     }
+
+    /// Checks that both channel lists hold the unreliable channel and the reliable ordered one,
+    /// in either order, with the ids of [`DefaultChannel`], as [`UnityClient`] expects.
+    pub fn validate(&self) -> Result<(), ConnectionConfigError> {
+        validate_channels(ChannelSide::Server, &self.server_channels_config)?;
+        validate_channels(ChannelSide::Client, &self.client_channels_config)
+    }
+}
+
+fn validate_channels(
+    side: ChannelSide,
+    channels: &[ChannelConfig],
+) -> Result<(), ConnectionConfigError> {
+    if channels.len() < 2 {
+        return Err(ConnectionConfigError::MissingChannels {
+            side,
+            count: channels.len(),
+        });
+    }
+    let (unreliable, reliable) = split_channels(channels);
+    for (channel, expected) in [
+        (unreliable, DefaultChannel::Unreliable),
+        (reliable, DefaultChannel::ReliableOrdered),
+    ] {
+        let expected: u8 = expected.into();
+        if channel.channel_id != expected {
+            return Err(ConnectionConfigError::ChannelIdMismatch {
+                side,
+                expected,
+                found: channel.channel_id,
+            });
+        }
+    }
+    if !matches!(reliable.send_type, SendType::ReliableOrdered { .. }) {
+        return Err(ConnectionConfigError::ReliableChannelNotOrdered { side });
+    }
+    Ok(())
+}
+
+/// Returns the (unreliable, reliable) channels of a channel list, told apart by the id of the
+/// first one. Panics with less than 2 channels.
+fn split_channels(channels: &[ChannelConfig]) -> (&ChannelConfig, &ChannelConfig) {
+    let reliable_id: u8 = DefaultChannel::ReliableOrdered.into();
+    if channels[0].channel_id == reliable_id {
+        (&channels[1], &channels[0])
+    } else {
+        (&channels[0], &channels[1])
+    }
+}
+
+/// Builds a [`ConnectionConfig`], rejecting channel setups [`UnityClient`] can't work with.
+#[derive(Debug, Clone)]
+pub struct ConnectionConfigBuilder {
+    config: ConnectionConfig,
+}
+
+impl ConnectionConfigBuilder {
+    pub fn available_bytes_per_tick(mut self, available_bytes_per_tick: u64) -> Self {
+        self.config.available_bytes_per_tick = available_bytes_per_tick;
+        self
+    }
+
+    pub fn server_channels_config(mut self, server_channels_config: Vec<ChannelConfig>) -> Self {
+        self.config.server_channels_config = server_channels_config;
+        self
+    }
+
+    pub fn client_channels_config(mut self, client_channels_config: Vec<ChannelConfig>) -> Self {
+        self.config.client_channels_config = client_channels_config;
+        self
+    }
+
+    pub fn max_decode_errors(mut self, max_decode_errors: usize) -> Self {
+        self.config.max_decode_errors = max_decode_errors;
+        self
+    }
+
+    pub fn decode_error_window(mut self, decode_error_window: Duration) -> Self {
+        self.config.decode_error_window = decode_error_window;
+        self
+    }
+
+    pub fn max_packet_bytes(mut self, max_packet_bytes: usize) -> Self {
+        self.config.max_packet_bytes = max_packet_bytes;
+        self
+    }
+
+    pub fn initial_rtt(mut self, initial_rtt: Duration) -> Self {
+        self.config.initial_rtt = initial_rtt;
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.config.clock = clock;
+        self
+    }
+
+    pub fn build(self) -> Result<ConnectionConfig, ConnectionConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
 }
 
 impl UnityClient {
+    /// Panics when the channels of `config` don't pass [`ConnectionConfig::validate`],
+    /// build it with [`ConnectionConfig::builder`] to get the error instead.
     pub fn new(config: ConnectionConfig) -> Self {
+        // A client sends on the client_channels_config and receives on the server_channels_config
         Self::from_channels(
-            config.available_bytes_per_tick,
-            config.server_channels_config[0].clone(),
-            config.server_channels_config[1].clone(),
-            config.client_channels_config[0].clone(),
-            config.client_channels_config[1].clone(),
+            &config,
+            &config.client_channels_config,
+            &config.server_channels_config,
         )
     }
 
@@ -111,24 +303,25 @@ impl UnityClient {
     // and the client_channels_config is used as recv channels.
     pub(crate) fn new_from_server(config: ConnectionConfig) -> Self {
         Self::from_channels(
-            config.available_bytes_per_tick,
-            config.server_channels_config[0].clone(),
-            config.server_channels_config[1].clone(),
-            config.client_channels_config[0].clone(),
-            config.client_channels_config[1].clone(),
+            &config,
+            &config.server_channels_config,
+            &config.client_channels_config,
         )
     }
 
     fn from_channels(
-        available_bytes_per_tick: u64,
-        send_unreliable_channel_config: ChannelConfig,
-        send_reliable_channel_config: ChannelConfig,
-        receive_unreliable_channel_config: ChannelConfig,
-        receive_reliable_channel_config: ChannelConfig,
+        config: &ConnectionConfig,
+        send_channels_config: &[ChannelConfig],
+        receive_channels_config: &[ChannelConfig],
     ) -> Self {
+        let (send_unreliable_channel_config, send_reliable_channel_config) =
+            split_channels(send_channels_config);
+        let (receive_unreliable_channel_config, receive_reliable_channel_config) =
+            split_channels(receive_channels_config);
         let send_unreliable_channel = SendChannelUnreliable::new(
             send_unreliable_channel_config.channel_id,
             send_unreliable_channel_config.max_memory_usage_bytes,
+            send_unreliable_channel_config.max_message_size_bytes,
         );
 
         let send_reliable_resend_time;
@@ -141,20 +334,32 @@ impl UnityClient {
             }
         }
 
-        let send_reliable_channel = SendChannelReliable::new(
+        let max_packet_bytes = config
+            .max_packet_bytes
+            .clamp(TRANSPORT_MIN_PACKET_BYTES, TRANSPORT_MAX_PACKET_BYTES);
+        let mut send_reliable_channel = SendChannelReliable::new(
             send_reliable_channel_config.channel_id,
             send_reliable_resend_time,
             send_reliable_channel_config.max_memory_usage_bytes,
+            send_reliable_channel_config.max_message_size_bytes,
+            send_reliable_channel_config.overflow_policy,
+            send_reliable_channel_config.reset_after,
         );
+        send_reliable_channel.set_max_packet_bytes(max_packet_bytes - TRANSPORT_DATA_HEADER_BYTES);
 
-        let mut channel_send_order: Vec<ChannelOrder> = Vec::with_capacity(2);
-
-        channel_send_order.push(ChannelOrder::Reliable(
-            send_reliable_channel_config.channel_id,
-        ));
-        channel_send_order.push(ChannelOrder::Unreliable(
-            send_unreliable_channel_config.channel_id,
-        ));
+        // The channels are served in the order of the list, see
+        // `ConnectionConfig::server_channels_config`
+        let channel_send_order: Vec<(ChannelOrder, ChannelSendTimer)> = send_channels_config[..2]
+            .iter()
+            .map(|channel| {
+                let order = if channel.channel_id == send_reliable_channel_config.channel_id {
+                    ChannelOrder::Reliable(channel.channel_id)
+                } else {
+                    ChannelOrder::Unreliable(channel.channel_id)
+                };
+                (order, ChannelSendTimer::new(channel.send_interval))
+            })
+            .collect();
 
         let receive_unreliable_channel = ReceiveChannelUnreliable::new(
             receive_unreliable_channel_config.channel_id,
@@ -169,19 +374,27 @@ impl UnityClient {
         Self {
             current_time: Duration::ZERO,
             sent_packets: BTreeMap::new(),
+            last_sent_sequence: None,
             pending_acks: VecDeque::with_capacity(32),
             new_ack_to_send: false,
-            ack_process_start_instant: Instant::now(),
+            ack_process_start_instant: config.clock.now(),
+            clock: config.clock.clone(),
             channel_send_order,
             send_unreliable_channel,
             receive_unreliable_channel,
             send_reliable_channel,
             receive_reliable_channel,
             stats: ConnectionStats::new(),
-            rtt: 0.0,
-            available_bytes_per_tick,
+            rtt: config.initial_rtt.as_secs_f64(),
+            available_bytes_per_tick: config.available_bytes_per_tick,
+            decode_errors: VecDeque::new(),
+            max_decode_errors: config.max_decode_errors,
+            decode_error_window: config.decode_error_window,
+            max_packet_bytes,
             connection_status: ClientConnectionStatus::Connecting,
             player_id: String::new(),
+            tracked_messages: HashSet::new(),
+            acked_tracked_messages: Vec::new(),
         }
     }
 
@@ -212,6 +425,11 @@ impl UnityClient {
             packet_loss: self.stats.packet_loss(),
             bytes_sent_per_second: self.stats.bytes_sent_per_second(self.current_time),
             bytes_received_per_second: self.stats.bytes_received_per_second(self.current_time),
+            unreliable_memory: self.channel_memory_usage(DefaultChannel::Unreliable),
+            reliable_memory: self.channel_memory_usage(DefaultChannel::ReliableOrdered),
+            reliable_lag: self.send_reliable_channel.lag(self.current_time),
+            reliable_retransmits: self.send_reliable_channel.retransmitted_messages(),
+            reliable_duplicates: self.receive_reliable_channel.duplicate_messages(),
         }
     }
 
@@ -238,8 +456,8 @@ impl UnityClient {
 
     /// Returns the disconnect reason if the client is disconnected.
     pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
-        if let ClientConnectionStatus::Disconnected { reason } = self.connection_status {
-            Some(reason)
+        if let ClientConnectionStatus::Disconnected { reason } = &self.connection_status {
+            Some(reason.clone())
         } else {
             None
         }
@@ -259,6 +477,16 @@ impl UnityClient {
         }
     }
 
+    /// Sets the size limit of the datagrams sent to this client, e.g. from the path MTU
+    /// the client reported. See [`ConnectionConfig::max_packet_bytes`].
+    pub fn set_max_packet_bytes(&mut self, max_packet_bytes: usize) {
+        self.max_packet_bytes =
+            max_packet_bytes.clamp(TRANSPORT_MIN_PACKET_BYTES, TRANSPORT_MAX_PACKET_BYTES);
+        // Reliable messages can't be dropped later, one that can't fit is refused up front
+        self.send_reliable_channel
+            .set_max_packet_bytes(self.max_packet_bytes - TRANSPORT_DATA_HEADER_BYTES);
+    }
+
     pub fn player_id(&self) -> &String {
         &self.player_id
     }
@@ -303,6 +531,22 @@ impl UnityClient {
         }
     }
 
+    /// Returns the `(used, max)` memory in bytes of a send channel.
+    pub fn channel_memory_usage<I: Into<u8>>(&self, channel_id: I) -> (usize, usize) {
+        let channel_id = channel_id.into();
+        match channel_id {
+            0 => (
+                self.send_unreliable_channel.memory_usage(),
+                self.send_unreliable_channel.max_memory_usage(),
+            ),
+            1 => (
+                self.send_reliable_channel.memory_usage(),
+                self.send_reliable_channel.max_memory_usage(),
+            ),
+            _ => panic!("Called 'channel_memory_usage' with invalid channel {channel_id}"),
+        }
+    }
+
     /// Checks if the channel can send a message with the given size in bytes.
     pub fn can_send_message<I: Into<u8>>(&self, channel_id: I, size_bytes: usize) -> bool {
         let channel_id = channel_id.into();
@@ -320,21 +564,123 @@ impl UnityClient {
         }
 
         let channel_id = channel_id.into();
-        match channel_id {
-            0 => {
-                self.send_unreliable_channel.send_message(message.into());
-            }
+        let result = match channel_id {
+            0 => self.send_unreliable_channel.send_message(message.into()),
             1 => {
-                if let Err(error) = self.send_reliable_channel.send_message(message.into()) {
-                    self.disconnect_with_reason(DisconnectReason::SendChannelError {
-                        channel_id,
-                        error,
-                    });
-                }
+                let result = self.send_reliable_channel.send_message(message.into());
+                self.forget_dropped_messages();
+                result.map(|_| ())
             }
             _ => {
                 panic!("Called 'send_message' with invalid channel {channel_id}");
             }
+        };
+        self.handle_send_result(channel_id, result);
+    }
+
+    /// Send a message over the reliable channel, messages with a higher `priority` are sent
+    /// first when the available bytes of a tick run out. The client still receives them in
+    /// send order.
+    pub fn send_reliable_with_priority<B: Into<Bytes>>(&mut self, message: B, priority: u8) {
+        if self.is_disconnected() {
+            return;
+        }
+
+        let result = self
+            .send_reliable_channel
+            .send_message_with_priority(message.into(), priority);
+        self.forget_dropped_messages();
+        self.handle_send_result(DefaultChannel::ReliableOrdered.into(), result.map(|_| ()));
+    }
+
+    fn handle_send_result(&mut self, channel_id: u8, result: Result<(), ChannelError>) {
+        match result {
+            Ok(()) => {}
+            // Only the message is at fault, the connection is kept
+            Err(error @ ChannelError::MessageTooLarge { .. }) => {
+                tracing::error!(
+                    player_id = self.player_id.as_str(),
+                    "Dropped message sent on channel {channel_id}: {error}"
+                );
+            }
+            Err(error) => {
+                self.disconnect_with_reason(DisconnectReason::SendChannelError {
+                    channel_id,
+                    error,
+                });
+            }
+        }
+    }
+
+    /// Send a message over the reliable channel and track its delivery, the returned message id
+    /// is reported by [`UnityClient::take_acked_messages`] once the client acked it.
+    /// Returns `None` when the message could not be queued.
+    pub fn send_tracked_message<B: Into<Bytes>>(&mut self, message: B) -> Option<u64> {
+        if self.is_disconnected() {
+            return None;
+        }
+
+        match self.send_reliable_channel.send_message(message.into()) {
+            Ok(message_id) => {
+                self.forget_dropped_messages();
+                self.tracked_messages.insert(message_id);
+                Some(message_id)
+            }
+            Err(error @ ChannelError::MessageTooLarge { .. }) => {
+                tracing::error!(
+                    player_id = self.player_id.as_str(),
+                    "Dropped tracked message: {error}"
+                );
+                None
+            }
+            Err(error) => {
+                self.disconnect_with_reason(DisconnectReason::SendChannelError {
+                    channel_id: DefaultChannel::ReliableOrdered.into(),
+                    error,
+                });
+                None
+            }
+        }
+    }
+
+    // A message dropped by a full reliable channel is never delivered, its ack must not be
+    // reported, see `OverflowPolicy::DropOldest`
+    fn forget_dropped_messages(&mut self) {
+        for dropped_id in self.send_reliable_channel.take_dropped_messages() {
+            self.tracked_messages.remove(&dropped_id);
+        }
+    }
+
+    /// Drops the unacked messages of the reliable channel and moves the client past them,
+    /// for a client that fell too far behind to catch up. Returns the id of the next message.
+    /// Tracked messages among the dropped ones are never reported as acked.
+    pub fn reset_reliable_channel(&mut self) -> u64 {
+        let reset_point = self.send_reliable_channel.reset();
+        self.tracked_messages
+            .retain(|&message_id| message_id >= reset_point);
+        reset_point
+    }
+
+    /// Ids of the tracked messages acked by the client since the last call.
+    pub fn take_acked_messages(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.acked_tracked_messages)
+    }
+
+    /// Send a message over the unreliable channel, messages with a higher `priority` are
+    /// sent first when the available bytes of a tick run out.
+    pub fn send_unreliable_with_priority<B: Into<Bytes>>(&mut self, message: B, priority: u8) {
+        if self.is_disconnected() {
+            return;
+        }
+
+        if let Err(error) = self
+            .send_unreliable_channel
+            .send_message_with_priority(message.into(), priority)
+        {
+            tracing::error!(
+                player_id = self.player_id.as_str(),
+                "Dropped unreliable message: {error}"
+            );
         }
     }
 
@@ -376,6 +722,15 @@ impl UnityClient {
         for sequence in lost_packets.iter() {
             self.sent_packets.remove(sequence);
         }
+
+        if !self.is_disconnected() && self.send_reliable_channel.is_lagging(self.current_time) {
+            let lag = self.send_reliable_channel.lag(self.current_time);
+            let reset_point = self.reset_reliable_channel();
+            tracing::warn!(
+                player_id = self.player_id.as_str(),
+                "Reliable channel lagged {lag:?} behind, reset to message {reset_point}"
+            );
+        }
     }
 
     /// Process a packet received from the server.
@@ -390,7 +745,7 @@ impl UnityClient {
         self.stats.received_packet(packet.len() as u64);
         let packet = match Packet::from_bytes(&packet) {
             Err(err) => {
-                self.disconnect_with_reason(DisconnectReason::PacketDeserialization(err));
+                self.record_decode_error(err);
                 return;
             }
             Ok(packet) => packet,
@@ -422,6 +777,15 @@ impl UnityClient {
                     self.receive_unreliable_channel.process_message(message);
                 }
             }
+            Packet::ResetPoint {
+                sequence_id,
+                message_id,
+                ..
+            } => {
+                self.add_pending_ack(sequence_id);
+                self.receive_reliable_channel
+                    .process_reset_point(message_id);
+            }
 
             Packet::Ack {
                 acked_seq_id,
@@ -431,6 +795,10 @@ impl UnityClient {
                 // Create list with just new acks
                 // This prevents DoS from huge ack ranges
                 let new_acks = Self::get_acked_packet_ids(acked_seq_id, acked_mask);
+                if new_acks.iter().any(|&sequence| !self.was_sent(sequence)) {
+                    self.record_decode_error(SerializationError::InvalidAckRange);
+                    return;
+                }
 
                 for packet_sequence in new_acks {
                     if let Some(sent_packet) = self.sent_packets.remove(&packet_sequence) {
@@ -451,9 +819,16 @@ impl UnityClient {
                                 message_ids,
                             } => {
                                 for message_id in message_ids {
-                                    self.send_reliable_channel.process_message_ack(message_id);
+                                    if self.send_reliable_channel.process_message_ack(message_id)
+                                        && self.tracked_messages.remove(&message_id)
+                                    {
+                                        self.acked_tracked_messages.push(message_id);
+                                    }
                                 }
                             }
+                            PacketSentInfo::ResetPoint { message_id } => {
+                                self.send_reliable_channel.process_reset_ack(message_id);
+                            }
                             PacketSentInfo::None => {}
                         }
                     }
@@ -473,20 +848,25 @@ impl UnityClient {
         }
 
         let mut available_bytes = self.available_bytes_per_tick;
-        for order in self.channel_send_order.iter() {
+        let max_packet_bytes = self.max_packet_bytes - TRANSPORT_DATA_HEADER_BYTES;
+        for (order, send_timer) in self.channel_send_order.iter_mut() {
+            if !send_timer.try_flush(self.current_time) {
+                continue;
+            }
+
             match order {
                 ChannelOrder::Reliable(_channel_id) => {
-                    packets.append(
-                        &mut self
-                            .send_reliable_channel
-                            .get_packets_to_send(&mut available_bytes, self.current_time),
-                    );
+                    packets.append(&mut self.send_reliable_channel.get_packets_to_send(
+                        &mut available_bytes,
+                        max_packet_bytes,
+                        self.current_time,
+                    ));
                 }
                 ChannelOrder::Unreliable(_channel_id) => {
                     packets.append(
                         &mut self
                             .send_unreliable_channel
-                            .get_packets_to_send(&mut available_bytes),
+                            .get_packets_to_send(&mut available_bytes, max_packet_bytes),
                     );
                 }
             }
@@ -497,8 +877,11 @@ impl UnityClient {
                 let ack_packet = Packet::Ack {
                     channel_id: 1,
                     packet_type: 1,
-                    packet_process_time: self.ack_process_start_instant.elapsed().as_millis()
-                        as u16,
+                    packet_process_time: self
+                        .clock
+                        .now()
+                        .saturating_duration_since(self.ack_process_start_instant)
+                        .as_millis() as u16,
                     sequence_id: 0,
                     acked_seq_id: ack_seq_id,
                     acked_mask: ack_mask,
@@ -517,6 +900,7 @@ impl UnityClient {
                     messages,
                     ..
                 } => {
+                    self.last_sent_sequence = Some(*sequence_id);
                     self.sent_packets.insert(
                         *sequence_id,
                         PacketSent {
@@ -528,11 +912,27 @@ impl UnityClient {
                         },
                     );
                 }
+                Packet::ResetPoint {
+                    sequence_id,
+                    message_id,
+                    ..
+                } => {
+                    self.last_sent_sequence = Some(*sequence_id);
+                    self.sent_packets.insert(
+                        *sequence_id,
+                        PacketSent {
+                            sent_at,
+                            info: PacketSentInfo::ResetPoint {
+                                message_id: *message_id,
+                            },
+                        },
+                    );
+                }
                 _ => {}
             }
         }
 
-        let mut buffer = [0u8; 1400];
+        let mut buffer = [0u8; TRANSPORT_MAX_PACKET_BYTES];
         let mut serialized_packets = Vec::with_capacity(packets.len());
         let mut bytes_sent: u64 = 0;
         for packet in packets {
@@ -555,11 +955,17 @@ impl UnityClient {
     }
 
     fn add_pending_ack(&mut self, sequence_id: u16) {
-        if self.pending_acks[0] >= sequence_id || self.pending_acks.contains(&sequence_id) {
+        // Sequences older than the oldest tracked one were already acked
+        if self
+            .pending_acks
+            .front()
+            .is_some_and(|&oldest| oldest >= sequence_id)
+            || self.pending_acks.contains(&sequence_id)
+        {
             return;
         }
         self.new_ack_to_send = true;
-        self.ack_process_start_instant = Instant::now();
+        self.ack_process_start_instant = self.clock.now();
         if self.pending_acks.len() >= 32 {
             self.pending_acks.pop_front();
         }
@@ -579,13 +985,19 @@ impl UnityClient {
                 if self.pending_acks.contains(&seq_id) {
                     ack_mask |= 1 << i; // Write 1 to ack_mask if sequence ID exists
                 }
-                seq_id = seq_id - 1; // Move to the next sequence ID
+                seq_id = seq_id.wrapping_sub(1); // Move to the next sequence ID
             }
             return Some((*last_pending_ack, ack_mask));
         }
         None
     }
 
+    // Whether the sequence is not ahead of the newest one sent, sequences wrap around
+    fn was_sent(&self, sequence: u16) -> bool {
+        self.last_sent_sequence
+            .is_some_and(|last_sent| (sequence.wrapping_sub(last_sent) as i16) <= 0)
+    }
+
     pub fn get_acked_packet_ids(ack_seq: u16, ack_mask: u32) -> Vec<u16> {
         let mut acked_seqs = vec![];
         // Process the ack_mask for additional acknowledgments
@@ -598,9 +1010,529 @@ impl UnityClient {
         acked_seqs
     }
 
+    // An isolated corrupt packet is dropped, only a burst of them disconnects the client
+    fn record_decode_error(&mut self, err: SerializationError) {
+        while let Some(&first) = self.decode_errors.front() {
+            if self.current_time.saturating_sub(first) < self.decode_error_window {
+                break;
+            }
+            self.decode_errors.pop_front();
+        }
+
+        self.decode_errors.push_back(self.current_time);
+        if self.decode_errors.len() > self.max_decode_errors {
+            self.disconnect_with_reason(DisconnectReason::PacketDeserialization(err));
+        } else {
+            tracing::warn!(player_id = %self.player_id, "Dropped undecodable packet: {err}");
+        }
+    }
+
     pub(crate) fn disconnect_with_reason(&mut self, reason: DisconnectReason) {
         if !self.is_disconnected() {
             self.connection_status = ClientConnectionStatus::Disconnected { reason };
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{channel::OverflowPolicy, clock::ManualClock};
+
+    fn unreliable_packets_per_second(send_interval: Duration) -> usize {
+        let mut config = ConnectionConfig::default();
+        config.server_channels_config[1].send_interval = send_interval;
+        let mut connection = UnityClient::new_from_server(config);
+        connection.set_connected("player1".to_string());
+
+        let tick = Duration::from_nanos(16_666_667);
+        let mut packets = 0;
+        for _ in 0..60 {
+            connection.update(tick);
+            connection.send_message(DefaultChannel::Unreliable, vec![1u8; 20]);
+            packets += connection.get_packets_to_send().len();
+        }
+        packets
+    }
+
+    #[test]
+    fn channel_send_interval_lowers_packet_rate() {
+        let every_tick = unreliable_packets_per_second(Duration::ZERO);
+        let ten_hz = unreliable_packets_per_second(Duration::from_millis(100));
+
+        assert_eq!(every_tick, 60);
+        assert_eq!(ten_hz, 10);
+    }
+
+    // Sizes of the (reliable, unreliable) messages sent in one tick
+    fn sent_message_sizes(connection: &mut UnityClient) -> (Vec<usize>, Vec<usize>) {
+        let mut reliable = vec![];
+        let mut unreliable = vec![];
+        for packet in connection.get_packets_to_send() {
+            match Packet::from_bytes(&packet).unwrap() {
+                Packet::SmallReliable { messages, .. } => {
+                    reliable.extend(messages.iter().map(|(_, message)| message.len()))
+                }
+                Packet::SmallUnreliable { messages, .. } => {
+                    unreliable.extend(messages.iter().map(|message| message.len()))
+                }
+                _ => {}
+            }
+        }
+        (reliable, unreliable)
+    }
+
+    // A connection with a budget of 100 bytes per tick, sending on the channels in that order
+    fn connection_with_channels(server_channels: Vec<ChannelConfig>) -> UnityClient {
+        let config = ConnectionConfig::builder()
+            .available_bytes_per_tick(100)
+            .server_channels_config(server_channels)
+            .build()
+            .unwrap();
+        let mut connection = UnityClient::new_from_server(config);
+        connection.set_connected("player1".to_string());
+        connection
+    }
+
+    #[test]
+    fn first_listed_channel_is_served_first() {
+        // The default lists the reliable channel first
+        let mut connection = connection_with_channels(DefaultChannel::config());
+
+        // Both channels want more than the budget together, the unreliable one is queued first
+        for _ in 0..2 {
+            connection.send_message(DefaultChannel::Unreliable, vec![1u8; 30]);
+        }
+        for _ in 0..3 {
+            connection.send_message(DefaultChannel::ReliableOrdered, vec![2u8; 40]);
+        }
+        connection.update(Duration::from_millis(16));
+        assert_eq!(sent_message_sizes(&mut connection), (vec![40, 40], vec![]));
+
+        // The bytes the reliable channel leaves go to the unreliable one
+        for _ in 0..2 {
+            connection.send_message(DefaultChannel::Unreliable, vec![1u8; 30]);
+        }
+        connection.update(Duration::from_millis(16));
+        assert_eq!(
+            sent_message_sizes(&mut connection),
+            (vec![40], vec![30, 30])
+        );
+
+        // Listed first, the unreliable channel is served before the reliable one
+        let mut server_channels = DefaultChannel::config();
+        server_channels.reverse();
+        let mut connection = connection_with_channels(server_channels);
+        for _ in 0..2 {
+            connection.send_message(DefaultChannel::ReliableOrdered, vec![2u8; 40]);
+        }
+        for _ in 0..3 {
+            connection.send_message(DefaultChannel::Unreliable, vec![1u8; 30]);
+        }
+        connection.update(Duration::from_millis(16));
+        assert_eq!(
+            sent_message_sizes(&mut connection),
+            (vec![], vec![30, 30, 30])
+        );
+    }
+
+    #[test]
+    fn isolated_corrupt_packet_does_not_disconnect() {
+        let mut connection = UnityClient::new_from_server(ConnectionConfig::default());
+        connection.set_connected("player1".to_string());
+        // Unknown channel id
+        let corrupt = [7u8, 0, 0];
+
+        for _ in 0..5 {
+            connection.update(Duration::from_millis(500));
+            connection.process_packet(&corrupt);
+            assert!(connection.is_connected());
+        }
+
+        for _ in 0..3 {
+            connection.process_packet(&corrupt);
+        }
+        assert!(matches!(
+            connection.disconnect_reason(),
+            Some(DisconnectReason::PacketDeserialization(_))
+        ));
+    }
+
+    #[test]
+    fn acks_of_never_sent_packets_count_toward_disconnect() {
+        let ack = |acked_seq_id: u16| {
+            let mut buffer = [0u8; 64];
+            let len = Packet::Ack {
+                channel_id: 1,
+                packet_type: 1,
+                packet_process_time: 0,
+                sequence_id: 0,
+                acked_seq_id,
+                acked_mask: 1,
+                end_posfix: 0,
+            }
+            .to_bytes(&mut buffer)
+            .unwrap();
+            buffer[..len].to_vec()
+        };
+        let mut connection = UnityClient::new_from_server(ConnectionConfig::default());
+        connection.set_connected("player1".to_string());
+
+        // Nothing was sent yet
+        connection.process_packet(&ack(0));
+        assert!(connection.is_connected());
+
+        connection.send_message(DefaultChannel::ReliableOrdered, vec![1u8; 10]);
+        connection.update(Duration::from_millis(16));
+        connection.get_packets_to_send();
+        connection.process_packet(&ack(0));
+        assert_eq!(
+            connection
+                .channel_memory_usage(DefaultChannel::ReliableOrdered)
+                .0,
+            0
+        );
+        // Repeated acks of a packet that was sent are fine
+        for _ in 0..5 {
+            connection.process_packet(&ack(0));
+        }
+        assert!(connection.is_connected());
+
+        for sequence in [1, 500, 30_000] {
+            connection.process_packet(&ack(sequence));
+        }
+        assert_eq!(
+            connection.disconnect_reason(),
+            Some(DisconnectReason::PacketDeserialization(
+                SerializationError::InvalidAckRange
+            ))
+        );
+    }
+
+    #[test]
+    fn rtt_starts_at_initial_estimate_and_blends_measurements() {
+        let config = ConnectionConfig::builder()
+            .initial_rtt(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let mut connection = UnityClient::new_from_server(config);
+        connection.set_connected("player1".to_string());
+        assert_eq!(connection.rtt(), 0.2);
+
+        connection.send_message(DefaultChannel::ReliableOrdered, vec![1u8; 10]);
+        connection.update(Duration::from_millis(16));
+        connection.get_packets_to_send();
+        assert_eq!(connection.rtt(), 0.2);
+
+        // Acked 40ms after the send
+        connection.update(Duration::from_millis(40));
+        let mut buffer = [0u8; 64];
+        let len = Packet::Ack {
+            channel_id: 1,
+            packet_type: 1,
+            packet_process_time: 0,
+            sequence_id: 0,
+            acked_seq_id: 0,
+            acked_mask: 1,
+            end_posfix: 0,
+        }
+        .to_bytes(&mut buffer)
+        .unwrap();
+        connection.process_packet(&buffer[..len]);
+        assert!((connection.rtt() - (0.2 * 0.875 + 0.04 * 0.125)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn packet_process_time_follows_injected_clock() {
+        let clock = ManualClock::default();
+        let config = ConnectionConfig::builder()
+            .clock(Arc::new(clock.clone()))
+            .build()
+            .unwrap();
+        let mut connection = UnityClient::new_from_server(config);
+        connection.set_connected("player1".to_string());
+
+        let mut buffer = [0u8; 64];
+        let len = Packet::SmallReliable {
+            channel_id: 1,
+            packet_type: 0,
+            packet_process_time: 0,
+            sequence_id: 0,
+            acked_seq_id: u16::MAX,
+            acked_mask: 0,
+            messages: vec![(0, Bytes::from_static(&[1, 2, 3]))],
+        }
+        .to_bytes(&mut buffer)
+        .unwrap();
+        connection.process_packet(&buffer[..len]);
+        clock.advance(Duration::from_millis(7));
+
+        let packets = connection.get_packets_to_send();
+        assert!(matches!(
+            Packet::from_bytes(&packets[0]),
+            Ok(Packet::Ack {
+                packet_process_time: 7,
+                acked_seq_id: 0,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn packets_respect_connection_max_packet_bytes() {
+        let mut connection = UnityClient::new_from_server(ConnectionConfig {
+            max_packet_bytes: 900,
+            ..Default::default()
+        });
+        connection.set_connected("player1".to_string());
+        for i in 0..40u8 {
+            connection.send_message(DefaultChannel::Unreliable, vec![i; 100]);
+            connection.send_message(DefaultChannel::ReliableOrdered, vec![i; 150]);
+        }
+
+        connection.update(Duration::from_millis(16));
+        let packets = connection.get_packets_to_send();
+        assert!(packets.len() > 2);
+        for packet in packets {
+            assert!(packet.len() + TRANSPORT_DATA_HEADER_BYTES <= 900);
+        }
+    }
+
+    #[test]
+    fn reliable_message_larger_than_packet_is_refused() {
+        let mut connection = UnityClient::new_from_server(ConnectionConfig {
+            max_packet_bytes: 900,
+            ..Default::default()
+        });
+        connection.set_connected("player1".to_string());
+        connection.send_message(DefaultChannel::ReliableOrdered, vec![1u8; 1000]);
+        connection.send_message(DefaultChannel::ReliableOrdered, vec![2u8; 100]);
+
+        connection.update(Duration::from_millis(16));
+        let packets = connection.get_packets_to_send();
+        assert!(connection.is_connected());
+        assert_eq!(packets.len(), 1);
+        for packet in packets {
+            assert!(packet.len() + TRANSPORT_DATA_HEADER_BYTES <= 900);
+        }
+
+        // Also after the size limit was lowered at runtime
+        connection.set_max_packet_bytes(600);
+        connection.send_message(DefaultChannel::ReliableOrdered, vec![3u8; 580]);
+        connection.send_message(DefaultChannel::ReliableOrdered, vec![4u8; 500]);
+        connection.update(Duration::from_millis(16));
+        let packets = connection.get_packets_to_send();
+        assert!(!packets.is_empty());
+        for packet in packets {
+            assert!(packet.len() + TRANSPORT_DATA_HEADER_BYTES <= 600);
+        }
+    }
+
+    #[test]
+    fn builder_accepts_valid_config() {
+        let mut server_channels = DefaultChannel::config();
+        server_channels[0].send_interval = Duration::from_millis(50);
+        let config = ConnectionConfig::builder()
+            .server_channels_config(server_channels)
+            .max_packet_bytes(900)
+            .build()
+            .unwrap();
+        assert_eq!(
+            config.server_channels_config[0].send_interval,
+            Duration::from_millis(50)
+        );
+        assert_eq!(config.max_packet_bytes, 900);
+        assert!(ConnectionConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn builder_rejects_missing_channels() {
+        let result = ConnectionConfig::builder()
+            .server_channels_config(vec![])
+            .build();
+        assert_eq!(
+            result.unwrap_err(),
+            ConnectionConfigError::MissingChannels {
+                side: ChannelSide::Server,
+                count: 0
+            }
+        );
+
+        let result = ConnectionConfig::builder()
+            .client_channels_config(DefaultChannel::config()[..1].to_vec())
+            .build();
+        assert_eq!(
+            result.unwrap_err(),
+            ConnectionConfigError::MissingChannels {
+                side: ChannelSide::Client,
+                count: 1
+            }
+        );
+    }
+
+    #[test]
+    fn builder_rejects_mismatched_channel_ids() {
+        let mut client_channels = DefaultChannel::config();
+        client_channels[1].channel_id = 5;
+        let result = ConnectionConfig::builder()
+            .client_channels_config(client_channels)
+            .build();
+        assert_eq!(
+            result.unwrap_err(),
+            ConnectionConfigError::ChannelIdMismatch {
+                side: ChannelSide::Client,
+                expected: 0,
+                found: 5
+            }
+        );
+    }
+
+    #[test]
+    fn builder_rejects_unordered_reliable_channel() {
+        let mut server_channels = DefaultChannel::config();
+        server_channels[0].send_type = SendType::Unreliable;
+        let result = ConnectionConfig::builder()
+            .server_channels_config(server_channels)
+            .build();
+        assert_eq!(
+            result.unwrap_err(),
+            ConnectionConfigError::ReliableChannelNotOrdered {
+                side: ChannelSide::Server
+            }
+        );
+    }
+
+    #[test]
+    fn oversized_messages_are_rejected_on_both_channels() {
+        let mut config = ConnectionConfig::default();
+        for channel in config.server_channels_config.iter_mut() {
+            channel.max_message_size_bytes = 100;
+        }
+        let mut connection = UnityClient::new_from_server(config);
+        connection.set_connected("player1".to_string());
+
+        for channel_id in [DefaultChannel::Unreliable, DefaultChannel::ReliableOrdered] {
+            let channel_id: u8 = channel_id.into();
+            assert!(connection.can_send_message(channel_id, 100));
+            assert!(!connection.can_send_message(channel_id, 101));
+
+            connection.send_message(channel_id, vec![1u8; 101]);
+            connection.send_message(channel_id, vec![2u8; 100]);
+        }
+        connection.send_unreliable_with_priority(vec![3u8; 101], 10);
+        assert!(connection.is_connected());
+
+        connection.update(Duration::from_millis(16));
+        let mut sent = vec![];
+        for packet in connection.get_packets_to_send() {
+            match Packet::from_bytes(&packet).unwrap() {
+                Packet::SmallUnreliable { messages, .. } => sent.extend(messages),
+                Packet::SmallReliable { messages, .. } => {
+                    sent.extend(messages.into_iter().map(|(_, message)| message))
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().all(|message| message.len() == 100));
+    }
+
+    #[test]
+    fn tracked_message_keeps_its_id_when_an_older_one_is_dropped() {
+        let mut config = ConnectionConfig::default();
+        config.server_channels_config[0].max_memory_usage_bytes = 100;
+        config.server_channels_config[0].overflow_policy = OverflowPolicy::DropOldest;
+        let mut server = UnityClient::new_from_server(config);
+        server.set_connected("player1".to_string());
+        let mut client = UnityClient::new_from_server(ConnectionConfig::default());
+        client.set_connected("player1".to_string());
+        let tick = Duration::from_millis(16);
+
+        server.send_message(DefaultChannel::ReliableOrdered, vec![0u8; 40]);
+        server.update(tick);
+        for packet in server.get_packets_to_send() {
+            client.process_packet(&packet);
+        }
+        // Message 1 is dropped to make room for message 3
+        server.send_message(DefaultChannel::ReliableOrdered, vec![1u8; 30]);
+        let tracked_id = server.send_tracked_message(vec![2u8; 30]).unwrap();
+        server.send_message(DefaultChannel::ReliableOrdered, vec![3u8; 30]);
+        assert!(server.is_connected());
+        server.update(tick);
+        for packet in server.get_packets_to_send() {
+            client.process_packet(&packet);
+        }
+
+        let received: Vec<u8> =
+            std::iter::from_fn(|| client.receive_message(DefaultChannel::ReliableOrdered))
+                .map(|message| message[0])
+                .collect();
+        assert_eq!(received, vec![0, 2, 3]);
+
+        client.update(tick);
+        for packet in client.get_packets_to_send() {
+            server.process_packet(&packet);
+        }
+        assert_eq!(server.take_acked_messages(), vec![tracked_id]);
+    }
+
+    #[test]
+    fn reset_point_moves_lagging_receiver_past_gap() {
+        let mut config = ConnectionConfig::default();
+        config.server_channels_config[0].reset_after = Some(Duration::from_secs(1));
+        let mut server = UnityClient::new_from_server(config);
+        server.set_connected("player1".to_string());
+        let mut client = UnityClient::new_from_server(ConnectionConfig::default());
+        client.set_connected("player1".to_string());
+        let tick = Duration::from_millis(100);
+
+        // Message 0 is lost, the client holds 1 and 2 back until it arrives
+        server.send_message(DefaultChannel::ReliableOrdered, vec![0u8]);
+        server.update(tick);
+        server.get_packets_to_send();
+        server.send_message(DefaultChannel::ReliableOrdered, vec![1u8]);
+        server.send_message(DefaultChannel::ReliableOrdered, vec![2u8]);
+        server.update(tick);
+        for packet in server.get_packets_to_send() {
+            client.process_packet(&packet);
+        }
+        assert_eq!(
+            client.receive_message(DefaultChannel::ReliableOrdered),
+            None
+        );
+
+        // Nothing gets acked, the channel lags behind and resets
+        for _ in 0..10 {
+            server.update(tick);
+        }
+        assert_eq!(server.network_info().reliable_lag, Duration::ZERO);
+        server.send_message(DefaultChannel::ReliableOrdered, vec![3u8]);
+        let packets = server.get_packets_to_send();
+        assert!(matches!(
+            Packet::from_bytes(&packets[0]),
+            Ok(Packet::ResetPoint { message_id: 3, .. })
+        ));
+        for packet in packets {
+            client.process_packet(&packet);
+        }
+        assert_eq!(
+            client.receive_message(DefaultChannel::ReliableOrdered),
+            Some(Bytes::from(vec![3u8]))
+        );
+
+        // The reset point is resent until acked
+        client.update(tick);
+        for packet in client.get_packets_to_send() {
+            server.process_packet(&packet);
+        }
+        server.update(Duration::from_secs(1));
+        let resent: Vec<Packet> = server
+            .get_packets_to_send()
+            .iter()
+            .map(|packet| Packet::from_bytes(packet).unwrap())
+            .collect();
+        assert!(!resent
+            .iter()
+            .any(|packet| matches!(packet, Packet::ResetPoint { .. })));
+    }
+}
@@ -3,7 +3,7 @@ use std::fmt;
 use super::packet::SerializationError;
 
 /// Possible reasons for a disconnection.
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum DisconnectReason {
     /// Connection was terminated by the transport layer
     Transport,
@@ -22,6 +22,43 @@ pub enum DisconnectReason {
     SendChannelError { channel_id: u8, error: ChannelError },
     /// Error occurred in a receive channel
     ReceiveChannelError { channel_id: u8, error: ChannelError },
+    /// The session the client was routed to is no longer running
+    SessionClosed,
+    /// The client sent no input for longer than the idle timeout
+    Idle,
+    /// The client was kicked by an admin
+    Kicked { reason: String },
+    /// The client was banned by an admin, until the unix timestamp in seconds if any
+    Banned { until: Option<u64> },
+    /// The client speaks another protocol version than the server
+    IncompatibleVersion,
+    /// The authenticated player id is longer than the wire player id
+    InvalidPlayerId,
+}
+
+impl DisconnectReason {
+    /// The reason byte of the transport disconnect packet, clients only get the kind of
+    /// disconnection, not its details.
+    pub fn code(&self) -> u8 {
+        use DisconnectReason::*;
+
+        match self {
+            Transport => 0,
+            DisconnectedByClient => 1,
+            DisconnectedByServer => 2,
+            PacketSerialization(_) => 3,
+            PacketDeserialization(_) => 4,
+            ReceivedInvalidChannelId(_) => 5,
+            SendChannelError { .. } => 6,
+            ReceiveChannelError { .. } => 7,
+            SessionClosed => 8,
+            Idle => 9,
+            Kicked { .. } => 10,
+            Banned { .. } => 11,
+            IncompatibleVersion => 12,
+            InvalidPlayerId => 13,
+        }
+    }
 }
 
 /// Possibles errors that can occur in a channel.
@@ -32,6 +69,8 @@ pub enum ChannelError {
     /// Received an invalid slice message in the channel.
     #[allow(dead_code)]
     InvalidSliceMessage,
+    /// Sent message is larger than the max message size of the channel
+    MessageTooLarge { size: usize, max: usize },
 }
 
 impl fmt::Display for ChannelError {
@@ -43,6 +82,9 @@ impl fmt::Display for ChannelError {
                 write!(fmt, "reliable channel memory usage was exausted")
             }
             InvalidSliceMessage => write!(fmt, "received an invalid slice packet"),
+            MessageTooLarge { size, max } => {
+                write!(fmt, "message of {size} bytes is larger than {max} bytes")
+            }
         }
     }
 }
@@ -51,7 +93,7 @@ impl fmt::Display for DisconnectReason {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         use DisconnectReason::*;
 
-        match *self {
+        match self {
             Transport => write!(fmt, "connection terminated by the transport layer"),
             DisconnectedByClient => write!(fmt, "connection terminated by the client"),
             DisconnectedByServer => write!(fmt, "connection terminated by the server"),
@@ -66,6 +108,13 @@ impl fmt::Display for DisconnectReason {
             ReceiveChannelError { channel_id, error } => {
                 write!(fmt, "receive channel {channel_id} with error: {error}")
             }
+            SessionClosed => write!(fmt, "session of the client is no longer running"),
+            Idle => write!(fmt, "no input received from the client for too long"),
+            Kicked { reason } => write!(fmt, "kicked: {reason}"),
+            Banned { until: Some(until) } => write!(fmt, "banned until {until}"),
+            Banned { until: None } => write!(fmt, "banned permanently"),
+            IncompatibleVersion => write!(fmt, "incompatible protocol version"),
+            InvalidPlayerId => write!(fmt, "player id is too long"),
         }
     }
 }
@@ -82,3 +131,58 @@ impl fmt::Display for ClientNotFound {
         write!(fmt, "client with given id was not found")
     }
 }
+
+/// Side of the connection a channel list belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelSide {
+    /// [`ConnectionConfig::server_channels_config`](super::connection::ConnectionConfig::server_channels_config)
+    Server,
+    /// [`ConnectionConfig::client_channels_config`](super::connection::ConnectionConfig::client_channels_config)
+    Client,
+}
+
+impl fmt::Display for ChannelSide {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ChannelSide::Server => write!(fmt, "server"),
+            ChannelSide::Client => write!(fmt, "client"),
+        }
+    }
+}
+
+/// Misconfigurations rejected by [`ConnectionConfigBuilder::build`](super::connection::ConnectionConfigBuilder::build).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionConfigError {
+    /// The channel list lacks the unreliable or the reliable channel
+    MissingChannels { side: ChannelSide, count: usize },
+    /// A channel does not have the id of its [`DefaultChannel`](super::channel::DefaultChannel)
+    ChannelIdMismatch {
+        side: ChannelSide,
+        expected: u8,
+        found: u8,
+    },
+    /// The reliable channel does not use [`SendType::ReliableOrdered`](super::channel::SendType::ReliableOrdered)
+    ReliableChannelNotOrdered { side: ChannelSide },
+}
+
+impl std::error::Error for ConnectionConfigError {}
+
+impl fmt::Display for ConnectionConfigError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        use ConnectionConfigError::*;
+
+        match *self {
+            MissingChannels { side, count } => {
+                write!(fmt, "{side} channels need 2 entries, got {count}")
+            }
+            ChannelIdMismatch {
+                side,
+                expected,
+                found,
+            } => write!(fmt, "{side} channel {expected} has id {found}"),
+            ReliableChannelNotOrdered { side } => {
+                write!(fmt, "{side} reliable channel is not reliable ordered")
+            }
+        }
+    }
+}
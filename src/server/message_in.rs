@@ -1,4 +1,8 @@
-use crate::ecs::events::{FireEvent, JumpEvent, LookEvent, MoveEvent, SpawnEvent};
+use crate::constants::PISTOL_WEAPON_ID;
+use crate::ecs::components::Stance;
+use crate::ecs::events::{
+    FireEvent, JumpEvent, LookEvent, MoveEvent, ReloadEvent, SpawnEvent, StanceEvent,
+};
 use crate::server::packet::SerializationError;
 use bevy::math::{Vec3, Vec4};
 use bevy::prelude::Entity;
@@ -28,6 +32,8 @@ impl MessageIn {
         })
     }
 
+    /// Layout: `f32 x | f32 y`, optionally followed by a `u32 input_sequence` that the client
+    /// reconciles its predicted movement with.
     pub fn to_move_event(&self, player_entity: Entity) -> Result<MoveEvent, SerializationError> {
         // let data_slice: &[u8] = &self.data;
         if self.data.len() < 8 {
@@ -38,15 +44,24 @@ impl MessageIn {
 
         let x = reader.read_f32::<LittleEndian>()?;
         let y = reader.read_f32::<LittleEndian>()?;
+        let sequence = if self.data.len() >= 12 {
+            Some(reader.read_u32::<LittleEndian>()?)
+        } else {
+            None
+        };
 
         Ok(MoveEvent {
             entity: player_entity,
             x,
             y,
+            sequence,
         })
     }
+
+    /// Layout: `f32 x | f32 y | f32 z | f32 w`, the rotation quaternion.
+    /// It is normalized, a rotation without a finite, non-zero length is rejected.
     pub fn to_look_event(&self, player_entity: Entity) -> Result<LookEvent, SerializationError> {
-        if self.data.len() < 12 {
+        if self.data.len() < 16 {
             println!("Insufficent bytes: {:?}", self.data);
             return Err(SerializationError::BufferTooShort);
         }
@@ -57,9 +72,15 @@ impl MessageIn {
         let z = reader.read_f32::<LittleEndian>()?;
         let w = reader.read_f32::<LittleEndian>()?;
 
+        let rotation = Vec4::new(x, y, z, w);
+        let length = rotation.length();
+        if !length.is_finite() || length == 0.0 {
+            return Err(SerializationError::InvalidRotation);
+        }
+
         Ok(LookEvent {
             entity: player_entity,
-            direction: Vec4::new(x, y, z, w),
+            direction: rotation / length,
         })
     }
 
@@ -74,6 +95,50 @@ impl MessageIn {
             player_id: self.player_id.clone(),
         })
     }
+
+    /// Layout: `u8 weapon_id`.
+    pub fn to_reload_event(
+        &self,
+        player_entity: Entity,
+    ) -> Result<ReloadEvent, SerializationError> {
+        let mut reader = Cursor::new(&self.data);
+        let weapon_id = reader
+            .read_u8()
+            .map_err(|_| SerializationError::BufferTooShort)?;
+        Ok(ReloadEvent {
+            entity: player_entity,
+            weapon_id,
+        })
+    }
+
+    /// Layout: `u8 stance`, 0 standing, 1 crouching and 2 prone.
+    pub fn to_stance_event(
+        &self,
+        player_entity: Entity,
+    ) -> Result<StanceEvent, SerializationError> {
+        let mut reader = Cursor::new(&self.data);
+        let stance = reader
+            .read_u8()
+            .map_err(|_| SerializationError::BufferTooShort)?;
+        let stance = Stance::try_from(stance).map_err(|_| SerializationError::InvalidStance)?;
+        Ok(StanceEvent {
+            entity: player_entity,
+            stance,
+        })
+    }
+
+    /// Returns the client timestamp of a time sync request, in microseconds of the client clock.
+    pub fn to_time_sync_request(&self) -> Result<u64, SerializationError> {
+        if self.data.len() < 8 {
+            return Err(SerializationError::BufferTooShort);
+        }
+        let mut reader = Cursor::new(&self.data);
+        Ok(reader.read_u64::<LittleEndian>()?)
+    }
+
+    /// Layout: `3 * f32 cam_origin | 3 * f32 direction | 3 * f32 barrel_origin | u8 weapon_id`,
+    /// the weapon id is optional and defaults to the pistol.
+    /// A direction without a finite, non-zero length is rejected.
     pub fn to_fire_event(&self, player_entity: Entity) -> Result<FireEvent, SerializationError> {
         if self.data.len() < 8 {
             println!("Insufficent bytes: {:?}", self.data);
@@ -93,8 +158,13 @@ impl MessageIn {
         let barrel_origin_y = reader.read_f32::<LittleEndian>()?;
         let barrel_origin_z = reader.read_f32::<LittleEndian>()?;
 
+        let weapon_id = reader.read_u8().unwrap_or(PISTOL_WEAPON_ID);
+
         let cam_origin = Vec3::new(cam_origin_x, cam_origin_y, cam_origin_z);
         let direction = Vec3::new(direction_x, direction_y, direction_z);
+        if !is_valid_direction(direction) {
+            return Err(SerializationError::InvalidDirection);
+        }
         let barrel_origin = Vec3::new(barrel_origin_x, barrel_origin_y, barrel_origin_z);
 
         Ok(FireEvent {
@@ -102,10 +172,17 @@ impl MessageIn {
             cam_origin,
             direction,
             barrel_origin,
+            weapon_id,
         })
     }
 }
 
+/// Whether the direction can be normalized: finite components and a non-zero length.
+pub fn is_valid_direction(direction: Vec3) -> bool {
+    let length = direction.length();
+    length.is_finite() && length > 0.0
+}
+
 #[derive(Debug)]
 pub enum MessageInType {
     Spawn = 0,
@@ -113,6 +190,11 @@ pub enum MessageInType {
     Rotation = 3,
     Jump = 4,
     Fire = 5,
+    /// Sent instead of Spawn by clients that only observe the session
+    Spectate = 6,
+    TimeSync = 7,
+    Reload = 8,
+    Stance = 9,
     Invalid = 99,
     // SessionCreate = 100,
     // SessionJoin = 101,
@@ -128,8 +210,84 @@ impl TryFrom<u8> for MessageInType {
             3 => Ok(MessageInType::Rotation),
             4 => Ok(MessageInType::Jump),
             5 => Ok(MessageInType::Fire),
+            6 => Ok(MessageInType::Spectate),
+            7 => Ok(MessageInType::TimeSync),
+            8 => Ok(MessageInType::Reload),
+            9 => Ok(MessageInType::Stance),
             // 100 => Ok(MessageInType::SessionCreate),
             _ => Ok(MessageInType::Invalid),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::entity::Entity;
+    use byteorder::WriteBytesExt;
+
+    use super::*;
+
+    fn fire_message(direction: [f32; 3]) -> MessageIn {
+        let mut bytes = vec![MessageInType::Fire as u8];
+        for value in [0.0, 1.6, 0.0]
+            .into_iter()
+            .chain(direction)
+            .chain([0.3, 1.4, 0.2])
+        {
+            bytes.write_f32::<LittleEndian>(value).unwrap();
+        }
+        MessageIn::new(bytes, "player1".to_string()).unwrap()
+    }
+
+    fn look_message(rotation: [f32; 4]) -> MessageIn {
+        let mut bytes = vec![MessageInType::Rotation as u8];
+        for value in rotation {
+            bytes.write_f32::<LittleEndian>(value).unwrap();
+        }
+        MessageIn::new(bytes, "player1".to_string()).unwrap()
+    }
+
+    #[test]
+    fn look_rotation_is_normalized() {
+        let event = look_message([0.0, 2.0, 0.0, 2.0])
+            .to_look_event(Entity::from_raw(1))
+            .unwrap();
+        assert!((event.direction.length() - 1.0).abs() < 1e-6);
+        assert!((event.direction.y - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+        assert_eq!(event.direction.y, event.direction.w);
+    }
+
+    #[test]
+    fn look_with_degenerate_rotation_is_rejected() {
+        for rotation in [
+            [0.0, 0.0, 0.0, 0.0],
+            [f32::NAN, 0.0, 0.0, 1.0],
+            [0.0, 0.0, f32::INFINITY, 1.0],
+        ] {
+            assert!(matches!(
+                look_message(rotation).to_look_event(Entity::from_raw(1)),
+                Err(SerializationError::InvalidRotation)
+            ));
+        }
+    }
+
+    #[test]
+    fn fire_with_degenerate_direction_is_rejected() {
+        let entity = Entity::from_raw(1);
+
+        let event = fire_message([0.0, 0.0, 1.0]).to_fire_event(entity).unwrap();
+        assert_eq!(event.direction, Vec3::Z);
+        assert_eq!(event.weapon_id, PISTOL_WEAPON_ID);
+
+        for direction in [
+            [0.0, 0.0, 0.0],
+            [f32::NAN, 0.0, 1.0],
+            [0.0, f32::INFINITY, 0.0],
+        ] {
+            assert!(matches!(
+                fire_message(direction).to_fire_event(entity),
+                Err(SerializationError::InvalidDirection)
+            ));
+        }
+    }
+}
@@ -1,7 +1,52 @@
+use std::time::Duration;
+
 use bevy::math::{Quat, Vec3, Vec4};
-use bincode;
+use bincode::Options;
 use serde::{Deserialize, Serialize};
 
+use crate::constants::{
+    CHUNKED_MESSAGE_MAX_BYTES, MESSAGE_FORMAT_VERSION, PLAYER_ID_MAX_BYTES, WORLD_HALF_EXTENT,
+};
+use crate::ecs::components::{HitRegion, Stance};
+
+/// The bincode configuration of every outgoing message: little endian, fixed size integers.
+/// Pinned explicitly so the wire format doesn't depend on bincode defaults.
+pub fn wire_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_little_endian()
+        .with_fixint_encoding()
+}
+
+/// Serializes `payload` behind the `u8 message_type | u8 MESSAGE_FORMAT_VERSION` header.
+fn serialize_message<T: Serialize>(message_type: u8, payload: &T) -> bincode::Result<Vec<u8>> {
+    let mut data = vec![message_type, MESSAGE_FORMAT_VERSION];
+    wire_options().serialize_into(&mut data, payload)?;
+    Ok(data)
+}
+
+/// Splits `items` into chunks of at most `max_bytes` serialized bytes each, `header_bytes`
+/// included. An item larger than that gets a chunk of its own. There is always a chunk,
+/// possibly empty.
+fn chunk_by_size<T: Serialize>(
+    items: Vec<T>,
+    header_bytes: usize,
+    max_bytes: usize,
+) -> bincode::Result<Vec<Vec<T>>> {
+    let mut chunks: Vec<Vec<T>> = vec![vec![]];
+    let mut chunk_bytes = header_bytes;
+    for item in items {
+        let size = wire_options().serialized_size(&item)? as usize;
+        let chunk = chunks.last_mut().unwrap();
+        if chunk_bytes + size > max_bytes && !chunk.is_empty() {
+            chunks.push(vec![]);
+            chunk_bytes = header_bytes;
+        }
+        chunk_bytes += size;
+        chunks.last_mut().unwrap().push(item);
+    }
+    Ok(chunks)
+}
+
 #[derive(Debug)]
 pub struct MessageOut {
     // allow dead code because we have some unused message types
@@ -21,87 +66,211 @@ impl MessageOut {
         with_header
     }
 
-    pub fn position_message(positions: Vec<(Vec3, String)>) -> Option<MessageOut> {
+    /// Every message starts with `u8 type | u8 format version`, followed by the payload.
+    ///
+    /// Layout: `u8 type (1) | u8 version | u32 tick | u64 count | count * (u16 network_id, 3 * f32 position)`,
+    /// little endian. `tick` is the server tick the positions were sampled at, clients use it
+    /// to interpolate between snapshots and to detect missing ones.
+    pub fn position_message(
+        tick: u32,
+        positions: Vec<(Vec3, u16)>,
+    ) -> bincode::Result<Option<MessageOut>> {
         let position_details: Vec<PositionDetails> = positions
             .iter()
-            .map(|(position, player_id)| {
-                let player_id_bytes = normalize_player_id(player_id.as_str());
-                PositionDetails {
-                    player_id: player_id_bytes,
-                    position: *position,
-                }
+            .map(|(position, network_id)| PositionDetails {
+                network_id: *network_id,
+                position: *position,
             })
             .collect();
 
         if positions.len() > 0 {
             let position_event = PositionMessageOut {
+                tick,
                 positions: position_details,
             };
 
-            let mut serialized = bincode::serialize(&position_event).unwrap();
-            serialized.insert(0, 1); // Position Event Type 1
-            return Some(MessageOut {
+            let serialized = serialize_message(1, &position_event)?; // Position Event Type 1
+            return Ok(Some(MessageOut {
                 event_type: MessageOutType::Position,
                 data: serialized,
-            });
+            }));
         }
-        None
+        Ok(None)
     }
 
-    pub fn rotation_message(rotations: Vec<(Quat, String)>) -> Option<MessageOut> {
+    /// Position message with the velocity of each player, for client side dead reckoning.
+    /// Layout: `u8 type (13) | u8 version | u32 tick | u64 count |
+    /// count * (u16 network_id, 3 * f32 position, 3 * f32 velocity)`, little endian,
+    /// velocity in units per second.
+    pub fn position_velocity_message(
+        tick: u32,
+        positions: Vec<(Vec3, Vec3, u16)>,
+    ) -> bincode::Result<Option<MessageOut>> {
+        if positions.is_empty() {
+            return Ok(None);
+        }
+
+        let position_event = PositionVelocityMessageOut {
+            tick,
+            positions: positions
+                .into_iter()
+                .map(|(position, velocity, network_id)| PositionVelocityDetails {
+                    network_id,
+                    position,
+                    velocity,
+                })
+                .collect(),
+        };
+
+        let serialized = serialize_message(13, &position_event)?; // Position Velocity Type 13
+        Ok(Some(MessageOut {
+            event_type: MessageOutType::PositionVelocity,
+            data: serialized,
+        }))
+    }
+
+    /// Layout: `u8 type (2) | u8 version | u32 tick | u64 count | count * (u16 network_id, 4 * f32 rotation)`,
+    /// little endian. `tick` is the same server tick as in the position message of that update.
+    pub fn rotation_message(
+        tick: u32,
+        rotations: Vec<(Quat, u16)>,
+    ) -> bincode::Result<Option<MessageOut>> {
         let rotations: Vec<RotationDetails> = rotations
             .iter()
-            .map(|(rotation, player_id)| {
-                let player_id_bytes = normalize_player_id(player_id.as_str());
-                RotationDetails {
-                    player_id: player_id_bytes,
-                    rotation: Vec4::new(rotation.x, rotation.y, rotation.z, rotation.w),
-                }
+            .map(|(rotation, network_id)| RotationDetails {
+                network_id: *network_id,
+                rotation: Vec4::new(rotation.x, rotation.y, rotation.z, rotation.w),
             })
             .collect();
 
         if rotations.len() > 0 {
-            let rotation_event = RotationMessageOut { rotations };
+            let rotation_event = RotationMessageOut { tick, rotations };
 
-            let mut serialized = bincode::serialize(&rotation_event).unwrap();
-            serialized.insert(0, 2); // Rotation Event Type 1
-            return Some(MessageOut {
+            let serialized = serialize_message(2, &rotation_event)?; // Rotation Event Type 2
+            return Ok(Some(MessageOut {
                 event_type: MessageOutType::Rotation,
                 data: serialized,
-            });
+            }));
+        }
+        Ok(None)
+    }
+
+    /// Quantized alternative of [`MessageOut::position_message`], about half its size.
+    /// Layout: `u8 type (11) | u8 version | u32 tick | u16 count | count * (u16 network_id, 3 * u16 position)`,
+    /// little endian. See [`quantize_position`] for how clients recover the position.
+    pub fn compact_position_message(tick: u32, positions: Vec<(Vec3, u16)>) -> Option<MessageOut> {
+        if positions.is_empty() {
+            return None;
+        }
+
+        let mut data = compact_header(11, tick, positions.len()); // Compact Position Type 11
+        for (position, network_id) in positions {
+            data.extend_from_slice(&network_id.to_le_bytes());
+            for axis in quantize_position(position) {
+                data.extend_from_slice(&axis.to_le_bytes());
+            }
+        }
+        Some(MessageOut {
+            event_type: MessageOutType::CompactPosition,
+            data,
+        })
+    }
+
+    /// Quantized alternative of [`MessageOut::rotation_message`].
+    /// Layout: `u8 type (12) | u8 version | u32 tick | u16 count | count * (u16 network_id, 4 * i16 rotation)`,
+    /// little endian. See [`quantize_rotation`] for how clients recover the rotation.
+    pub fn compact_rotation_message(tick: u32, rotations: Vec<(Quat, u16)>) -> Option<MessageOut> {
+        if rotations.is_empty() {
+            return None;
+        }
+
+        let mut data = compact_header(12, tick, rotations.len()); // Compact Rotation Type 12
+        for (rotation, network_id) in rotations {
+            data.extend_from_slice(&network_id.to_le_bytes());
+            for component in quantize_rotation(rotation) {
+                data.extend_from_slice(&component.to_le_bytes());
+            }
         }
-        None
+        Some(MessageOut {
+            event_type: MessageOutType::CompactRotation,
+            data,
+        })
     }
 
-    pub fn disconnect_message(player_ids: Vec<&String>) -> Option<MessageOut> {
+    /// Layout: `u8 type (14) | u8 version | u64 count |
+    /// count * (u16 network_id, u32 kills, u32 deaths, u32 assists)`, little endian.
+    pub fn scoreboard_message(scores: Vec<(u16, u32, u32, u32)>) -> bincode::Result<MessageOut> {
+        let scoreboard: Vec<ScoreDetails> = scores
+            .into_iter()
+            .map(|(network_id, kills, deaths, assists)| ScoreDetails {
+                network_id,
+                kills,
+                deaths,
+                assists,
+            })
+            .collect();
+
+        let serialized = serialize_message(14, &scoreboard)?; // Scoreboard Message Type 14
+        Ok(MessageOut {
+            event_type: MessageOutType::Scoreboard,
+            data: serialized,
+        })
+    }
+
+    /// Layout: `u8 type (15) | u8 version | u8 phase | u64 phase_duration_ms`.
+    /// Phases are 0 warmup, 1 active and 2 ended, a duration of 0 means the phase has no time limit.
+    pub fn match_state_message(phase: u8, phase_duration: Duration) -> bincode::Result<MessageOut> {
+        let match_state = MatchStateDetails {
+            phase,
+            phase_duration_ms: phase_duration.as_millis() as u64,
+        };
+
+        let serialized = serialize_message(15, &match_state)?; // Match State Message Type 15
+        Ok(MessageOut {
+            event_type: MessageOutType::MatchState,
+            data: serialized,
+        })
+    }
+
+    /// Layout: `u8 type (10) | u8 version | u64 count | count * 16 bytes player_id`.
+    pub fn disconnect_message(player_ids: Vec<&String>) -> bincode::Result<Option<MessageOut>> {
         let player_num = player_ids.len() as u32;
         if player_num < 1 {
-            return None;
+            return Ok(None);
         }
         let mut disconnects: Vec<DisconnectDetails> = vec![];
 
         for player_id in player_ids {
-            let player_id_bytes = normalize_player_id(player_id.as_str());
-            disconnects.push(DisconnectDetails {
-                player_id: player_id_bytes,
-            });
+            if let Some(player_id_bytes) = normalize_player_id(player_id.as_str()) {
+                disconnects.push(DisconnectDetails {
+                    player_id: player_id_bytes,
+                });
+            }
         }
 
         let disconnect_event = DisconnectMessage { disconnects };
 
-        let mut serialized = bincode::serialize(&disconnect_event).unwrap();
-
-        serialized.insert(0, 10); // Disconnect Event Type 10
+        let serialized = serialize_message(10, &disconnect_event)?; // Disconnect Event Type 10
 
-        Some(MessageOut {
+        Ok(Some(MessageOut {
             event_type: MessageOutType::Disconnect,
             data: serialized,
-        })
+        }))
     }
 
-    pub fn spawn_message(player_id: String, position: Vec3, rotation: Quat) -> Option<MessageOut> {
+    /// Layout: `u8 type (0) | u8 version | u64 count | count * (u16 network_id, u64 len, len bytes player_id,
+    /// 3 * f32 position, 4 * f32 rotation)`, little endian.
+    /// This is the only message carrying the full player id, the others refer to the player
+    /// by `network_id`.
+    pub fn spawn_message(
+        network_id: u16,
+        player_id: String,
+        position: Vec3,
+        rotation: Quat,
+    ) -> bincode::Result<MessageOut> {
         let spawn_details = SpawnDetails {
-            player_id: normalize_player_id(player_id.as_str()),
+            network_id,
+            player_id,
             position,
             rotation: Vec4::new(rotation.x, rotation.y, rotation.z, rotation.w),
         };
@@ -110,77 +279,356 @@ impl MessageOut {
             spawns: vec![spawn_details],
         };
 
-        let mut serialized = bincode::serialize(&spawn_event).unwrap();
+        let serialized = serialize_message(0, &spawn_event)?; // Spawn Message Type 0
 
-        serialized.insert(0, 0); // Spawn Message Type 0
-
-        Some(MessageOut {
+        Ok(MessageOut {
             event_type: MessageOutType::Spawn,
             data: serialized,
         })
     }
 
-    pub fn fire_message(player_id: String, origin: Vec3, direction: Vec3) -> MessageOut {
+    /// Layout: `u8 type (3) | u8 version | u16 network_id | 3 * f32 origin | 3 * f32 direction`, little endian.
+    pub fn fire_message(
+        network_id: u16,
+        origin: Vec3,
+        direction: Vec3,
+    ) -> bincode::Result<MessageOut> {
         let fire_details: FireDetails = FireDetails {
-            player_id: normalize_player_id(player_id.as_str()),
+            network_id,
             origin,
             direction,
         };
 
-        tracing::info!("{:?}", fire_details);
+        tracing::trace!("{:?}", fire_details);
 
-        let mut serialized = bincode::serialize(&fire_details).unwrap();
-        serialized.insert(0, 3); // Fire Message Type 3
-        MessageOut {
+        let serialized = serialize_message(3, &fire_details)?; // Fire Message Type 3
+        Ok(MessageOut {
             event_type: MessageOutType::Fire,
             data: serialized,
-        }
+        })
     }
 
-    pub fn hit_message(player_id: String, target_id: String, point: Vec3) -> MessageOut {
+    /// Sent when the server moves a player, clients snap to the position instead of
+    /// interpolating towards it like they do for position messages.
+    /// Layout: `u8 type (22) | u8 version | u32 tick | u16 network_id | 3 * f32 position`,
+    /// little endian.
+    pub fn teleport_message(
+        tick: u32,
+        network_id: u16,
+        position: Vec3,
+    ) -> bincode::Result<MessageOut> {
+        let teleport = TeleportDetails {
+            tick,
+            network_id,
+            position,
+        };
+
+        let serialized = serialize_message(22, &teleport)?; // Teleport Message Type 22
+        Ok(MessageOut {
+            event_type: MessageOutType::Teleport,
+            data: serialized,
+        })
+    }
+
+    /// Sent when a player changes stance, clients switch the animation of the player.
+    /// Layout: `u8 type (23) | u8 version | u32 tick | u16 network_id | u8 stance`, little endian.
+    pub fn stance_message(
+        tick: u32,
+        network_id: u16,
+        stance: Stance,
+    ) -> bincode::Result<MessageOut> {
+        let details = StanceDetails {
+            tick,
+            network_id,
+            stance: stance as u8,
+        };
+
+        let serialized = serialize_message(23, &details)?; // Stance Message Type 23
+        Ok(MessageOut {
+            event_type: MessageOutType::Stance,
+            data: serialized,
+        })
+    }
+
+    /// Server message shown to a client when it joins.
+    /// Layout: `u8 type (24) | u8 version | u64 len | len bytes utf8 text`, little endian.
+    pub fn motd_message(text: &str) -> bincode::Result<MessageOut> {
+        let serialized = serialize_message(24, &text)?; // Motd Message Type 24
+        Ok(MessageOut {
+            event_type: MessageOutType::Motd,
+            data: serialized,
+        })
+    }
+
+    /// Layout: `u8 type (20) | u8 version | u8 paused`.
+    pub fn pause_message(paused: bool) -> bincode::Result<MessageOut> {
+        let serialized = serialize_message(20, &paused)?; // Pause Message Type 20
+        Ok(MessageOut {
+            event_type: MessageOutType::Pause,
+            data: serialized,
+        })
+    }
+
+    /// The whole state of the session, sent reliably to a client once it is ready so late
+    /// joiners don't wait for deltas. The players are split over `chunk_count` messages of at
+    /// most [`CHUNKED_MESSAGE_MAX_BYTES`], each repeating the match state.
+    /// Layout: `u8 type (21) | u8 version | u32 tick | u8 phase | u64 phase_remaining_ms |
+    /// u16 chunk | u16 chunk_count | u64 count | count * (u16 network_id, u64 len,
+    /// len bytes player_id, 3 * f32 position, 4 * f32 rotation, f32 health, u8 team, u32 kills,
+    /// u32 deaths, u32 assists)`, little endian.
+    /// Phases are those of the match state message, a remaining time of 0 means no time limit.
+    pub fn full_snapshot(
+        tick: u32,
+        phase: u8,
+        phase_remaining_ms: u64,
+        players: Vec<PlayerSnapshot>,
+    ) -> bincode::Result<Vec<MessageOut>> {
+        let mut snapshot = FullSnapshot {
+            tick,
+            phase,
+            phase_remaining_ms,
+            chunk: 0,
+            chunk_count: 0,
+            players: vec![],
+        };
+        let header_bytes = 2 + wire_options().serialized_size(&snapshot)? as usize;
+        let chunks = chunk_by_size(players, header_bytes, CHUNKED_MESSAGE_MAX_BYTES)?;
+        snapshot.chunk_count = chunks.len() as u16;
+
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(chunk, players)| {
+                snapshot.chunk = chunk as u16;
+                snapshot.players = players;
+                let serialized = serialize_message(21, &snapshot)?; // Full Snapshot Message Type 21
+                Ok(MessageOut {
+                    event_type: MessageOutType::FullSnapshot,
+                    data: serialized,
+                })
+            })
+            .collect()
+    }
+
+    /// Sent only to the player the input belongs to, it replays its inputs after `input_sequence`
+    /// on top of the server position.
+    /// Layout: `u8 type (25) | u8 version | u32 tick | u32 input_sequence`, little endian.
+    pub fn reconcile_message(tick: u32, input_sequence: u32) -> bincode::Result<MessageOut> {
+        let reconcile = ReconcileDetails {
+            tick,
+            input_sequence,
+        };
+
+        let serialized = serialize_message(25, &reconcile)?; // Reconcile Message Type 25
+        Ok(MessageOut {
+            event_type: MessageOutType::Reconcile,
+            data: serialized,
+        })
+    }
+
+    /// Sent only to the owner of the weapon.
+    /// Layout: `u8 type (19) | u8 version | u8 weapon_id | u32 mag | u32 reserve | u8 reloading`,
+    /// little endian.
+    pub fn ammo_message(
+        weapon_id: u8,
+        mag: u32,
+        reserve: u32,
+        reloading: bool,
+    ) -> bincode::Result<MessageOut> {
+        let ammo = AmmoDetails {
+            weapon_id,
+            mag,
+            reserve,
+            reloading,
+        };
+
+        let serialized = serialize_message(19, &ammo)?; // Ammo Message Type 19
+        Ok(MessageOut {
+            event_type: MessageOutType::Ammo,
+            data: serialized,
+        })
+    }
+
+    /// Layout: `u8 type (17) | u8 version | u32 projectile_id | u16 owner_network_id | u8 weapon_id |
+    /// 3 * f32 origin | 3 * f32 velocity`, little endian.
+    pub fn projectile_spawn_message(
+        projectile_id: u32,
+        owner_network_id: u16,
+        weapon_id: u8,
+        origin: Vec3,
+        velocity: Vec3,
+    ) -> bincode::Result<MessageOut> {
+        let projectile_spawn = ProjectileSpawnDetails {
+            projectile_id,
+            owner_network_id,
+            weapon_id,
+            origin,
+            velocity,
+        };
+
+        let serialized = serialize_message(17, &projectile_spawn)?; // Projectile Spawn Message Type 17
+        Ok(MessageOut {
+            event_type: MessageOutType::ProjectileSpawn,
+            data: serialized,
+        })
+    }
+
+    /// Sent when a projectile hits something or expires at `point`.
+    /// Layout: `u8 type (18) | u8 version | u32 projectile_id | 3 * f32 point`, little endian.
+    pub fn projectile_despawn_message(
+        projectile_id: u32,
+        point: Vec3,
+    ) -> bincode::Result<MessageOut> {
+        let projectile_despawn = ProjectileDespawnDetails {
+            projectile_id,
+            point,
+        };
+
+        let serialized = serialize_message(18, &projectile_despawn)?; // Projectile Despawn Message Type 18
+        Ok(MessageOut {
+            event_type: MessageOutType::ProjectileDespawn,
+            data: serialized,
+        })
+    }
+
+    /// Layout: `u8 type (4) | u8 version | u16 network_id | u16 target_network_id | 3 * f32 point |
+    /// u8 region`, little endian, the region is 0 head, 1 body and 2 legs.
+    pub fn hit_message(
+        network_id: u16,
+        target_network_id: u16,
+        point: Vec3,
+        region: HitRegion,
+    ) -> bincode::Result<MessageOut> {
         let hit_details: HitDetails = HitDetails {
-            player_id: normalize_player_id(player_id.as_str()),
-            target_id: normalize_player_id(target_id.as_str()),
+            network_id,
+            target_network_id,
             point,
+            region: region as u8,
         };
 
-        tracing::info!("{:?}", hit_details);
+        tracing::trace!("{:?}", hit_details);
 
-        let mut serialized = bincode::serialize(&hit_details).unwrap();
-        serialized.insert(0, 4); // Hit Message Type 4
-        MessageOut {
+        let serialized = serialize_message(4, &hit_details)?; // Hit Message Type 4
+        Ok(MessageOut {
             event_type: MessageOutType::Hit,
             data: serialized,
-        }
+        })
     }
 
-    pub fn health_message(healths: Vec<(String, f32)>) -> MessageOut {
+    /// Sent only to the shooter, `damage` is what the hit actually took off the target's health.
+    /// Layout: `u8 type (16) | u8 version | u16 target_network_id | f32 damage`, little endian.
+    pub fn hit_confirm_message(target_network_id: u16, damage: f32) -> bincode::Result<MessageOut> {
+        let hit_confirm = HitConfirmDetails {
+            target_network_id,
+            damage,
+        };
+
+        let serialized = serialize_message(16, &hit_confirm)?; // Hit Confirm Message Type 16
+        Ok(MessageOut {
+            event_type: MessageOutType::HitConfirm,
+            data: serialized,
+        })
+    }
+
+    /// Reply to a time sync request.
+    /// Layout: `u8 type (7) | u8 version | u64 client_timestamp | u64 server_receive_time |
+    /// u64 server_send_time`, little endian, server times in microseconds of the session clock.
+    ///
+    /// With `client_receive_time` the client computes
+    /// `offset = ((server_receive - client_timestamp) + (server_send - client_receive)) / 2` and
+    /// `rtt = (client_receive - client_timestamp) - (server_send - server_receive)`.
+    pub fn time_sync_message(
+        client_timestamp: u64,
+        server_receive_time: Duration,
+        server_send_time: Duration,
+    ) -> bincode::Result<MessageOut> {
+        let time_sync = TimeSyncDetails {
+            client_timestamp,
+            server_receive_time: server_receive_time.as_micros() as u64,
+            server_send_time: server_send_time.as_micros() as u64,
+        };
+
+        let serialized = serialize_message(7, &time_sync)?; // Time Sync Message Type 7
+        Ok(MessageOut {
+            event_type: MessageOutType::TimeSync,
+            data: serialized,
+        })
+    }
+
+    /// Split into messages of at most [`CHUNKED_MESSAGE_MAX_BYTES`], each one a complete list
+    /// for its players so it can be applied on its own.
+    /// Layout: `u8 type (6) | u8 version | u64 count | count * (16 bytes player_id, f32 health)`.
+    pub fn health_message(healths: Vec<(String, f32)>) -> bincode::Result<Vec<MessageOut>> {
         let health_details: Vec<HealthDetails> = healths
             .iter()
-            .map(|(player_id, health)| {
-                let player_id_bytes = normalize_player_id(player_id.as_str());
-                HealthDetails {
+            .filter_map(|(player_id, health)| {
+                let player_id_bytes = normalize_player_id(player_id.as_str())?;
+                Some(HealthDetails {
                     player_id: player_id_bytes,
                     health: *health,
-                }
+                })
             })
             .collect();
 
-        let mut serialized = bincode::serialize(&health_details).unwrap();
-        serialized.insert(0, 6); // Health Message Type 6
-        MessageOut {
-            event_type: MessageOutType::Health,
-            data: serialized,
-        }
+        // Type, version and the u64 count
+        let header_bytes = 2 + 8;
+        chunk_by_size(health_details, header_bytes, CHUNKED_MESSAGE_MAX_BYTES)?
+            .into_iter()
+            .map(|health_details| {
+                let serialized = serialize_message(6, &health_details)?; // Health Message Type 6
+                Ok(MessageOut {
+                    event_type: MessageOutType::Health,
+                    data: serialized,
+                })
+            })
+            .collect()
     }
 }
 
-fn normalize_player_id(player_id: &str) -> [u8; 16] {
-    let mut bytes = [0u8; 16];
+// Hand written header of the compact messages, the count is a u16 instead of bincode's u64
+fn compact_header(message_type: u8, tick: u32, count: usize) -> Vec<u8> {
+    let mut data = vec![message_type, MESSAGE_FORMAT_VERSION];
+    data.extend_from_slice(&tick.to_le_bytes());
+    data.extend_from_slice(&(count as u16).to_le_bytes());
+    data
+}
+
+/// Maps each axis from `[-WORLD_HALF_EXTENT, WORLD_HALF_EXTENT]` to `[0, 65535]`, positions
+/// outside the bounds are clamped. Clients dequantize with
+/// `q / 65535 * 2 * WORLD_HALF_EXTENT - WORLD_HALF_EXTENT`.
+pub fn quantize_position(position: Vec3) -> [u16; 3] {
+    position.to_array().map(|axis| {
+        let normalized = (axis.clamp(-WORLD_HALF_EXTENT, WORLD_HALF_EXTENT) + WORLD_HALF_EXTENT)
+            / (2.0 * WORLD_HALF_EXTENT);
+        (normalized * u16::MAX as f32).round() as u16
+    })
+}
+
+/// Maps each quaternion component (x, y, z, w) from `[-1, 1]` to `[-32767, 32767]`.
+/// Clients dequantize with `q / 32767` and renormalize.
+pub fn quantize_rotation(rotation: Quat) -> [i16; 4] {
+    rotation
+        .to_array()
+        .map(|component| (component.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16)
+}
+
+/// Returns the zero padded wire form of the player id.
+/// Returns None for ids longer than [`PLAYER_ID_MAX_BYTES`], truncating them could merge
+/// two different players into the same wire id. Such ids are already refused where they enter
+/// the server, when creating a session and when authenticating.
+fn normalize_player_id(player_id: &str) -> Option<[u8; PLAYER_ID_MAX_BYTES]> {
     let player_id_bytes = player_id.as_bytes();
-    let len = player_id_bytes.len().min(16);
-    bytes[..len].copy_from_slice(&player_id_bytes[..len]);
-    bytes
+    if player_id_bytes.len() > PLAYER_ID_MAX_BYTES {
+        tracing::error!(
+            player_id,
+            "Player id is longer than {PLAYER_ID_MAX_BYTES} bytes, it can't be sent"
+        );
+        return None;
+    }
+
+    let mut bytes = [0u8; PLAYER_ID_MAX_BYTES];
+    bytes[..player_id_bytes.len()].copy_from_slice(player_id_bytes);
+    Some(bytes)
 }
 
 #[derive(Debug)]
@@ -191,50 +639,176 @@ pub enum MessageOutType {
     Fire = 3,
     Hit = 4,
     Health = 6,
+    TimeSync = 7,
     Disconnect = 10,
+    CompactPosition = 11,
+    CompactRotation = 12,
+    PositionVelocity = 13,
+    Scoreboard = 14,
+    MatchState = 15,
+    HitConfirm = 16,
+    ProjectileSpawn = 17,
+    ProjectileDespawn = 18,
+    Ammo = 19,
+    Pause = 20,
+    FullSnapshot = 21,
+    Teleport = 22,
+    Stance = 23,
+    Motd = 24,
+    Reconcile = 25,
+}
+
+/// Payload of [`MessageOut::full_snapshot`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FullSnapshot {
+    pub tick: u32,
+    pub phase: u8,
+    pub phase_remaining_ms: u64,
+    pub chunk: u16,
+    pub chunk_count: u16,
+    pub players: Vec<PlayerSnapshot>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlayerSnapshot {
+    pub network_id: u16,
+    pub player_id: String,
+    pub position: Vec3,
+    pub rotation: Quat,
+    pub health: f32,
+    pub team: u8,
+    pub kills: u32,
+    pub deaths: u32,
+    pub assists: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct PositionMessageOut {
+    tick: u32,
     positions: Vec<PositionDetails>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct PositionDetails {
-    player_id: [u8; 16],
+    network_id: u16,
     position: Vec3,
 }
+#[derive(Serialize, Deserialize, Debug)]
+struct PositionVelocityMessageOut {
+    tick: u32,
+    positions: Vec<PositionVelocityDetails>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PositionVelocityDetails {
+    network_id: u16,
+    position: Vec3,
+    velocity: Vec3,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct RotationMessageOut {
+    tick: u32,
     rotations: Vec<RotationDetails>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct RotationDetails {
-    player_id: [u8; 16],
+    network_id: u16,
     rotation: Vec4,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct FireDetails {
-    player_id: [u8; 16],
+    network_id: u16,
     origin: Vec3,
     direction: Vec3,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct HitDetails {
-    player_id: [u8; 16],
-    target_id: [u8; 16],
+    network_id: u16,
+    target_network_id: u16,
     point: Vec3,
+    region: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TimeSyncDetails {
+    client_timestamp: u64,
+    server_receive_time: u64,
+    server_send_time: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct HealthDetails {
-    player_id: [u8; 16],
+    player_id: [u8; PLAYER_ID_MAX_BYTES],
     health: f32,
 }
 
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct ScoreDetails {
+    network_id: u16,
+    kills: u32,
+    deaths: u32,
+    assists: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ReconcileDetails {
+    tick: u32,
+    input_sequence: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct AmmoDetails {
+    weapon_id: u8,
+    mag: u32,
+    reserve: u32,
+    reloading: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ProjectileSpawnDetails {
+    projectile_id: u32,
+    owner_network_id: u16,
+    weapon_id: u8,
+    origin: Vec3,
+    velocity: Vec3,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ProjectileDespawnDetails {
+    projectile_id: u32,
+    point: Vec3,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct HitConfirmDetails {
+    target_network_id: u16,
+    damage: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TeleportDetails {
+    tick: u32,
+    network_id: u16,
+    position: Vec3,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct StanceDetails {
+    tick: u32,
+    network_id: u16,
+    stance: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct MatchStateDetails {
+    phase: u8,
+    phase_duration_ms: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct DisconnectMessage {
     disconnects: Vec<DisconnectDetails>,
@@ -242,7 +816,7 @@ struct DisconnectMessage {
 #[derive(Serialize, Deserialize, Debug)]
 
 struct DisconnectDetails {
-    player_id: [u8; 16],
+    player_id: [u8; PLAYER_ID_MAX_BYTES],
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -252,7 +826,258 @@ struct SpawnMessageOut {
 
 #[derive(Serialize, Deserialize, Debug)]
 struct SpawnDetails {
-    player_id: [u8; 16],
+    network_id: u16,
+    player_id: String,
     position: Vec3,
     rotation: Vec4,
 }
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::EulerRot;
+
+    use super::*;
+    use crate::server::server::tests::server_with_channels;
+
+    fn dequantize_position(quantized: [u16; 3]) -> Vec3 {
+        Vec3::from_array(quantized.map(|axis| {
+            axis as f32 / u16::MAX as f32 * 2.0 * WORLD_HALF_EXTENT - WORLD_HALF_EXTENT
+        }))
+    }
+
+    fn dequantize_rotation(quantized: [i16; 4]) -> Quat {
+        Quat::from_array(quantized.map(|component| component as f32 / i16::MAX as f32)).normalize()
+    }
+
+    fn read_tick(data: &[u8]) -> u32 {
+        u32::from_le_bytes(data[2..6].try_into().unwrap())
+    }
+
+    #[test]
+    fn position_tick_round_trip() {
+        let positions = vec![(Vec3::new(1.0, 2.0, 3.0), 7)];
+        let message = MessageOut::position_message(42, positions)
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.data[0], 1);
+
+        let decoded: PositionMessageOut = wire_options().deserialize(&message.data[2..]).unwrap();
+        assert_eq!(decoded.tick, 42);
+        assert_eq!(decoded.positions[0].position, Vec3::new(1.0, 2.0, 3.0));
+        assert_eq!(decoded.positions[0].network_id, 7);
+
+        let rotations = vec![(Quat::IDENTITY, 7)];
+        let message = MessageOut::rotation_message(42, rotations)
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.data[0], 2);
+
+        let decoded: RotationMessageOut = wire_options().deserialize(&message.data[2..]).unwrap();
+        assert_eq!(decoded.tick, 42);
+        assert_eq!(decoded.rotations[0].rotation, Vec4::new(0.0, 0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn tick_increments_per_send() {
+        let (mut server, _to_server_tx, _from_server_rx) = server_with_channels();
+
+        let mut ticks = vec![];
+        for _ in 0..3 {
+            server.update(Duration::from_millis(16));
+            let positions = vec![(Vec3::ZERO, 1)];
+            let message = MessageOut::position_message(server.tick(), positions)
+                .unwrap()
+                .unwrap();
+            ticks.push(read_tick(&message.data));
+        }
+
+        assert_eq!(ticks, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn spawn_message_keeps_long_player_id() {
+        let player_id = "a_player_id_longer_than_16_bytes".to_string();
+        let message =
+            MessageOut::spawn_message(3, player_id.clone(), Vec3::ONE, Quat::IDENTITY).unwrap();
+        assert_eq!(message.data[0], 0);
+
+        let decoded: SpawnMessageOut = wire_options().deserialize(&message.data[2..]).unwrap();
+        assert_eq!(decoded.spawns[0].network_id, 3);
+        assert_eq!(decoded.spawns[0].player_id, player_id);
+
+        // Hot messages only carry the 2 byte network id
+        let fire = MessageOut::fire_message(3, Vec3::ZERO, Vec3::X).unwrap();
+        assert_eq!(fire.data.len(), 2 + 2 + 12 + 12);
+        let hit = MessageOut::hit_message(3, 4, Vec3::ZERO, HitRegion::Body).unwrap();
+        assert_eq!(hit.data.len(), 2 + 2 + 2 + 12 + 1);
+    }
+
+    #[test]
+    fn long_player_ids_are_not_merged() {
+        let first = "player_with_a_long_id_1".to_string();
+        let second = "player_with_a_long_id_2".to_string();
+        assert_eq!(first.as_bytes()[..16], second.as_bytes()[..16]);
+
+        assert!(normalize_player_id(&first).is_none());
+        assert!(normalize_player_id(&second).is_none());
+        assert_eq!(&normalize_player_id("player1").unwrap()[..8], b"player1\0");
+
+        let message = MessageOut::health_message(vec![
+            (first, 100.0),
+            (second, 80.0),
+            ("player1".to_string(), 60.0),
+        ])
+        .unwrap();
+        let decoded: Vec<HealthDetails> =
+            wire_options().deserialize(&message[0].data[2..]).unwrap();
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].health, 60.0);
+    }
+
+    #[test]
+    fn health_of_many_players_is_split_over_messages() {
+        let healths = (1..=80)
+            .map(|player| (format!("player{player}"), player as f32))
+            .collect();
+        let messages = MessageOut::health_message(healths).unwrap();
+        assert!(messages.len() > 1);
+
+        let mut decoded_healths = vec![];
+        for message in &messages {
+            assert!(message.data.len() <= CHUNKED_MESSAGE_MAX_BYTES);
+            let decoded: Vec<HealthDetails> =
+                wire_options().deserialize(&message.data[2..]).unwrap();
+            decoded_healths.extend(decoded.iter().map(|details| details.health));
+        }
+        assert_eq!(
+            decoded_healths,
+            (1..=80).map(|h| h as f32).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn every_message_round_trips_with_pinned_config() {
+        let message =
+            MessageOut::spawn_message(5, "player1".to_string(), Vec3::X, Quat::IDENTITY).unwrap();
+        assert_eq!(message.data[..2], [0, MESSAGE_FORMAT_VERSION]);
+        let decoded: SpawnMessageOut = wire_options().deserialize(&message.data[2..]).unwrap();
+        assert_eq!(decoded.spawns[0].network_id, 5);
+        assert_eq!(decoded.spawns[0].player_id, "player1");
+        assert_eq!(decoded.spawns[0].position, Vec3::X);
+        assert_eq!(decoded.spawns[0].rotation, Vec4::new(0.0, 0.0, 0.0, 1.0));
+
+        let message = MessageOut::fire_message(5, Vec3::Y, Vec3::Z).unwrap();
+        assert_eq!(message.data[..2], [3, MESSAGE_FORMAT_VERSION]);
+        let decoded: FireDetails = wire_options().deserialize(&message.data[2..]).unwrap();
+        assert_eq!(decoded.network_id, 5);
+        assert_eq!(decoded.origin, Vec3::Y);
+        assert_eq!(decoded.direction, Vec3::Z);
+
+        let message = MessageOut::hit_message(5, 6, Vec3::ONE, HitRegion::Head).unwrap();
+        assert_eq!(message.data[..2], [4, MESSAGE_FORMAT_VERSION]);
+        let decoded: HitDetails = wire_options().deserialize(&message.data[2..]).unwrap();
+        assert_eq!(decoded.network_id, 5);
+        assert_eq!(decoded.target_network_id, 6);
+        assert_eq!(decoded.point, Vec3::ONE);
+        assert_eq!(decoded.region, HitRegion::Head as u8);
+
+        let message = MessageOut::health_message(vec![("player1".to_string(), 40.0)])
+            .unwrap()
+            .remove(0);
+        assert_eq!(message.data[..2], [6, MESSAGE_FORMAT_VERSION]);
+        let decoded: Vec<HealthDetails> = wire_options().deserialize(&message.data[2..]).unwrap();
+        assert_eq!(&decoded[0].player_id[..7], b"player1");
+        assert_eq!(decoded[0].health, 40.0);
+
+        let player_id = "player1".to_string();
+        let message = MessageOut::disconnect_message(vec![&player_id])
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.data[..2], [10, MESSAGE_FORMAT_VERSION]);
+        let decoded: DisconnectMessage = wire_options().deserialize(&message.data[2..]).unwrap();
+        assert_eq!(&decoded.disconnects[0].player_id[..7], b"player1");
+
+        // Fixed size integers, little endian: the count of the vec is a u64
+        let message = MessageOut::position_message(1, vec![(Vec3::ZERO, 2)])
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.data[6..14], 1u64.to_le_bytes());
+        assert_eq!(message.data.len(), 2 + 4 + 8 + 2 + 12);
+    }
+
+    #[test]
+    fn compact_transforms_stay_within_tolerance() {
+        let position_step = 2.0 * WORLD_HALF_EXTENT / u16::MAX as f32;
+        for position in [
+            Vec3::ZERO,
+            Vec3::new(1.234, -56.789, 300.001),
+            Vec3::new(-WORLD_HALF_EXTENT, WORLD_HALF_EXTENT, 0.5),
+        ] {
+            let error = dequantize_position(quantize_position(position)) - position;
+            assert!(error.abs().max_element() <= position_step / 2.0 + 1e-4);
+        }
+        // Out of bounds positions are clamped
+        assert_eq!(quantize_position(Vec3::splat(10_000.0)), [u16::MAX; 3]);
+
+        for rotation in [
+            Quat::IDENTITY,
+            Quat::from_rotation_y(1.3),
+            Quat::from_euler(EulerRot::YXZ, 2.1, -0.4, 0.2),
+        ] {
+            let dequantized = dequantize_rotation(quantize_rotation(rotation));
+            assert!(dequantized.angle_between(rotation) < 0.001);
+        }
+
+        let message = MessageOut::compact_position_message(42, vec![(Vec3::ONE, 7)]).unwrap();
+        assert_eq!(message.data[..2], [11, MESSAGE_FORMAT_VERSION]);
+        assert_eq!(read_tick(&message.data), 42);
+        assert_eq!(message.data[6..8], 1u16.to_le_bytes());
+        assert_eq!(message.data[8..10], 7u16.to_le_bytes());
+    }
+
+    #[test]
+    fn compact_transforms_are_about_half_the_size() {
+        let positions: Vec<(Vec3, u16)> = (0..64).map(|i| (Vec3::splat(i as f32), i)).collect();
+        let rotations: Vec<(Quat, u16)> = (0..64).map(|i| (Quat::IDENTITY, i)).collect();
+
+        let position = MessageOut::position_message(1, positions.clone())
+            .unwrap()
+            .unwrap();
+        let compact_position = MessageOut::compact_position_message(1, positions).unwrap();
+        assert_eq!(compact_position.data.len(), 2 + 4 + 2 + 64 * 8);
+        assert!(compact_position.data.len() * 10 < position.data.len() * 6);
+
+        let rotation = MessageOut::rotation_message(1, rotations.clone())
+            .unwrap()
+            .unwrap();
+        let compact_rotation = MessageOut::compact_rotation_message(1, rotations).unwrap();
+        assert_eq!(compact_rotation.data.len(), 2 + 4 + 2 + 64 * 10);
+        assert!(compact_rotation.data.len() * 10 < rotation.data.len() * 6);
+    }
+
+    #[test]
+    fn scoreboard_round_trips() {
+        let message = MessageOut::scoreboard_message(vec![(1, 3, 1, 0), (2, 1, 3, 2)]).unwrap();
+        assert_eq!(message.data[..2], [14, MESSAGE_FORMAT_VERSION]);
+        assert_eq!(message.data.len(), 2 + 8 + 2 * (2 + 3 * 4));
+
+        let decoded: Vec<ScoreDetails> = wire_options().deserialize(&message.data[2..]).unwrap();
+        assert_eq!(
+            decoded,
+            vec![
+                ScoreDetails {
+                    network_id: 1,
+                    kills: 3,
+                    deaths: 1,
+                    assists: 0,
+                },
+                ScoreDetails {
+                    network_id: 2,
+                    kills: 1,
+                    deaths: 3,
+                    assists: 2,
+                },
+            ]
+        );
+    }
+}
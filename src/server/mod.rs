@@ -1,4 +1,5 @@
 pub(crate) mod channel;
+pub(crate) mod clock;
 pub(crate) mod connection;
 pub(crate) mod connection_stats;
 pub(crate) mod error;
@@ -34,6 +34,13 @@ pub enum Packet {
         acked_mask: u32,
         end_posfix: u8,
     },
+    /// Moves the receiver of a reliable channel past the messages below `message_id`, which will
+    /// never be sent. It is acked like a packet of messages.
+    ResetPoint {
+        channel_id: u8,
+        sequence_id: u16,
+        message_id: u64,
+    },
 }
 
 impl Packet {
@@ -42,6 +49,7 @@ impl Packet {
             Packet::SmallReliable { sequence_id, .. } => *sequence_id,
             Packet::SmallUnreliable { .. } => 0, // Return 0 when there's no sequence_id
             Packet::Ack { sequence_id, .. } => *sequence_id,
+            Packet::ResetPoint { sequence_id, .. } => *sequence_id,
         }
     }
 
@@ -99,6 +107,19 @@ impl Packet {
                 writer.write_u32::<LittleEndian>(*acked_mask)?;
                 writer.write_u8(*end_posfix)?;
             }
+            Packet::ResetPoint {
+                channel_id,
+                sequence_id,
+                message_id,
+            } => {
+                writer.write_u8(*channel_id)?;
+                writer.write_u16::<LittleEndian>(2)?;
+                writer.write_u16::<LittleEndian>(0)?;
+                writer.write_u16::<LittleEndian>(*sequence_id)?;
+                writer.write_u16::<LittleEndian>(u16::MAX)?;
+                writer.write_u32::<LittleEndian>(0)?;
+                writer.write_u64::<LittleEndian>(*message_id)?;
+            }
         }
 
         Ok(before - writer.remaining())
@@ -166,6 +187,15 @@ impl Packet {
                             end_posfix,
                         })
                     }
+                    2 => {
+                        // SmallReliable ResetPoint
+                        let message_id = reader.read_u64::<LittleEndian>()?;
+                        Ok(Packet::ResetPoint {
+                            channel_id,
+                            sequence_id,
+                            message_id,
+                        })
+                    }
                     _ => Err(SerializationError::InvalidPacketType),
                 }
             }
@@ -179,11 +209,17 @@ pub enum SerializationError {
     BufferTooShort,
     #[allow(dead_code)]
     InvalidNumSlices,
-    #[allow(dead_code)]
+    /// An ack of a packet that was never sent
     InvalidAckRange,
     InvalidPacketType,
     InvalidChannelId,
     CursorReadError,
+    /// A direction of zero length or with a NaN or infinite component
+    InvalidDirection,
+    /// A stance outside of [`Stance`](crate::ecs::components::Stance)
+    InvalidStance,
+    /// A rotation quaternion of zero length or with a NaN or infinite component
+    InvalidRotation,
 }
 
 impl std::error::Error for SerializationError {}
@@ -199,6 +235,9 @@ impl fmt::Display for SerializationError {
             InvalidPacketType => write!(fmt, "invalid packet type"),
             InvalidChannelId => write!(fmt, "invalid channel id"),
             CursorReadError => write!(fmt, "cursor read error"),
+            InvalidDirection => write!(fmt, "invalid direction"),
+            InvalidStance => write!(fmt, "invalid stance"),
+            InvalidRotation => write!(fmt, "invalid rotation"),
         }
     }
 }
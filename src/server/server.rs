@@ -1,49 +1,129 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::time::Duration;
 
-use bevy::prelude::Resource;
+use bevy::prelude::{Resource, Vec3};
 use bytes::Bytes;
 use crossbeam::channel::{Receiver, Sender};
 
+use crate::constants::{
+    CLIENT_IDLE_TIMEOUT, MAX_CLIENT_MESSAGES_PER_TICK, SERVER_EVENT_HISTORY_CAPACITY,
+};
+use crate::logging::LogLimiter;
+
+use super::channel::DefaultChannel;
 use super::connection::{ConnectionConfig, NetworkInfo, UnityClient};
 use super::error::{ClientNotFound, DisconnectReason};
+use super::message_out::MessageOut;
 use super::packet::Payload;
 use super::transport::transport::{FromDenariaServerMessage, ToDenariaServerMessage};
 
-/// Connection and disconnection events in the server.
-#[derive(Debug, PartialEq, Eq)]
+/// Connection, disconnection and delivery events in the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ServerEvent {
     ClientConnected {
         client_id: ClientId,
     },
+    /// The client sent its first packet after the handshake, it is responsive.
+    /// The world snapshot is only sent from then on.
+    ClientReady {
+        client_id: ClientId,
+    },
     ClientDisconnected {
         client_id: ClientId,
         player_id: String,
         reason: DisconnectReason,
     },
+    /// A message sent with [`DenariaServer::send_tracked_message`] was acked by the client.
+    MessageAcked {
+        client_id: ClientId,
+        message_id: u64,
+    },
+    /// The client sent a control payload, e.g. ready or chat opt-in, tagged with its kind.
+    ControlMessage {
+        client_id: ClientId,
+        kind: u8,
+        payload: Vec<u8>,
+    },
+}
+
+/// A server event kept in the history, with when it happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerEventRecord {
+    pub tick: u32,
+    /// Time since the session started
+    pub time: Duration,
+    pub event: ServerEvent,
 }
 
 #[derive(Debug, Resource)]
 pub struct DenariaServer {
+    session_id: u32,
+    tick: u32,
+    current_time: Duration,
     connections: HashMap<ClientId, UnityClient>,
     player_connection_map: HashMap<String, ClientId>,
+    spectators: HashSet<ClientId>,
+    skip_self_updates: bool,
+    compact_transforms: bool,
+    send_velocity: bool,
+    paused: bool,
+    shutdown_requested: bool,
+    send_saturated: bool,
+    max_messages_per_tick: usize,
+    dropped_messages: HashMap<ClientId, u64>,
+    // Control messages of each client this tick, they count toward `max_messages_per_tick`
+    control_messages: HashMap<ClientId, usize>,
+    // When each client last sent an input message
+    last_input_time: HashMap<ClientId, Duration>,
+    idle_timeout: Option<Duration>,
+    // Teleports requested by player id, applied by the session on its next tick
+    teleports: Vec<(String, Vec3)>,
     connection_config: ConnectionConfig,
     events: VecDeque<ServerEvent>,
+    // The last events, still available once consumed from `events`
+    event_history: VecDeque<ServerEventRecord>,
+    event_history_capacity: usize,
+    // Per packet log sites
+    payload_log: LogLimiter,
+    invalid_payload_log: LogLimiter,
+    dropped_control_log: LogLimiter,
     from_transport_server_rx: Receiver<ToDenariaServerMessage>,
     to_transport_server_tx: Sender<FromDenariaServerMessage>,
 }
 
 impl DenariaServer {
     pub fn new(
+        session_id: u32,
         connection_config: ConnectionConfig,
         from_transport_server_rx: Receiver<ToDenariaServerMessage>,
         to_transport_server_tx: Sender<FromDenariaServerMessage>,
     ) -> Self {
         Self {
+            session_id,
+            tick: 0,
+            current_time: Duration::ZERO,
             connections: HashMap::new(),
             player_connection_map: HashMap::new(),
+            spectators: HashSet::new(),
+            skip_self_updates: false,
+            compact_transforms: false,
+            send_velocity: false,
+            paused: false,
+            shutdown_requested: false,
+            send_saturated: false,
+            max_messages_per_tick: MAX_CLIENT_MESSAGES_PER_TICK,
+            dropped_messages: HashMap::new(),
+            control_messages: HashMap::new(),
+            last_input_time: HashMap::new(),
+            idle_timeout: Some(CLIENT_IDLE_TIMEOUT),
+            teleports: Vec::new(),
             connection_config,
             events: VecDeque::new(),
+            event_history: VecDeque::new(),
+            event_history_capacity: SERVER_EVENT_HISTORY_CAPACITY,
+            payload_log: LogLimiter::default(),
+            invalid_payload_log: LogLimiter::default(),
+            dropped_control_log: LogLimiter::default(),
             from_transport_server_rx,
             to_transport_server_tx,
         }
@@ -62,16 +142,208 @@ impl DenariaServer {
         // Consider newly added connections as connected
         connection.set_connected(player_id.clone());
         self.connections.insert(client_id, connection);
+        self.last_input_time.insert(client_id, self.current_time);
         self.player_connection_map
             .insert(player_id.clone(), client_id);
-        self.events
-            .push_back(ServerEvent::ClientConnected { client_id })
+        self.push_event(ServerEvent::ClientConnected { client_id });
+    }
+
+    /// Returns the id of the session this server belongs to
+    pub fn session_id(&self) -> u32 {
+        self.session_id
+    }
+
+    /// Returns the current server tick, incremented on every [`DenariaServer::update`]
+    pub fn tick(&self) -> u32 {
+        self.tick
+    }
+
+    /// Returns the session clock, the sum of all [`DenariaServer::update`] durations.
+    /// It's monotonic and shared by every client of the session.
+    pub fn current_time(&self) -> Duration {
+        self.current_time
+    }
+
+    /// When enabled, position and rotation updates of a player are not sent back to that player.
+    pub fn set_skip_self_updates(&mut self, skip_self_updates: bool) {
+        self.skip_self_updates = skip_self_updates;
+    }
+
+    pub fn skip_self_updates(&self) -> bool {
+        self.skip_self_updates
+    }
+
+    /// When enabled, position and rotation updates are sent as the quantized compact messages.
+    pub fn set_compact_transforms(&mut self, compact_transforms: bool) {
+        self.compact_transforms = compact_transforms;
+    }
+
+    pub fn compact_transforms(&self) -> bool {
+        self.compact_transforms
+    }
+
+    /// When enabled, position updates carry the player velocity so clients can dead reckon.
+    /// The compact messages don't carry it.
+    pub fn set_send_velocity(&mut self, send_velocity: bool) {
+        self.send_velocity = send_velocity;
+    }
+
+    pub fn send_velocity(&self) -> bool {
+        self.send_velocity
+    }
+
+    /// While paused the gameplay is frozen, connections are still maintained.
+    /// Clients are told about every change with a pause message.
+    pub fn set_paused(&mut self, paused: bool) {
+        if self.paused == paused {
+            return;
+        }
+        tracing::info!(
+            session_id = self.session_id,
+            paused,
+            "Session pause changed"
+        );
+        self.paused = paused;
+        match MessageOut::pause_message(paused) {
+            Ok(pause_message) => {
+                self.broadcast_message(DefaultChannel::ReliableOrdered, pause_message.data)
+            }
+            Err(e) => tracing::error!("Failed to serialize pause message: {e}"),
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Stops the session, its app exits at the end of the current tick.
+    pub fn request_shutdown(&mut self) {
+        tracing::info!(session_id = self.session_id, "Session shutdown requested");
+        self.shutdown_requested = true;
+    }
+
+    pub fn is_shutdown_requested(&self) -> bool {
+        self.shutdown_requested
+    }
+
+    /// Moves the player to `position` on the next tick, clients snap to it instead of
+    /// interpolating. Unknown players are ignored then.
+    pub fn teleport(&mut self, player_id: String, position: Vec3) {
+        self.teleports.push((player_id, position));
+    }
+
+    /// Returns the teleports requested since the last call.
+    pub fn take_teleports(&mut self) -> Vec<(String, Vec3)> {
+        std::mem::take(&mut self.teleports)
+    }
+
+    /// Set by the transport while its socket can't keep up with the packets of the sessions.
+    pub fn set_send_saturated(&mut self, send_saturated: bool) {
+        if self.send_saturated != send_saturated {
+            tracing::debug!(
+                session_id = self.session_id,
+                send_saturated,
+                "Transport saturation changed"
+            );
+        }
+        self.send_saturated = send_saturated;
+    }
+
+    pub fn is_send_saturated(&self) -> bool {
+        self.send_saturated
+    }
+
+    /// Sets how many messages of a client are handled per tick, so a flooding client can't
+    /// force unbounded work. Default: [`MAX_CLIENT_MESSAGES_PER_TICK`]
+    pub fn set_max_messages_per_tick(&mut self, max_messages_per_tick: usize) {
+        self.max_messages_per_tick = max_messages_per_tick;
+    }
+
+    pub fn max_messages_per_tick(&self) -> usize {
+        self.max_messages_per_tick
+    }
+
+    /// Sets how long a client may go without sending input before it's disconnected,
+    /// `None` disables it. Default: [`CLIENT_IDLE_TIMEOUT`]
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    /// Returns how many control messages of the client were handled this tick, the gameplay
+    /// messages only get what they left of the per tick limit.
+    pub fn control_messages_this_tick(&self, client_id: ClientId) -> usize {
+        self.control_messages
+            .get(&client_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Counts messages of the client dropped for going over the per tick limit.
+    pub fn record_dropped_messages(&mut self, client_id: ClientId, count: u64) {
+        *self.dropped_messages.entry(client_id).or_default() += count;
+    }
+
+    /// Returns how many messages of the client were dropped for going over the per tick limit
+    pub fn dropped_messages(&self, client_id: ClientId) -> u64 {
+        self.dropped_messages
+            .get(&client_id)
+            .copied()
+            .unwrap_or_default()
     }
 
     pub fn get_event(&mut self) -> Option<ServerEvent> {
         self.events.pop_front()
     }
 
+    // Queues the event and records it in the history
+    fn push_event(&mut self, event: ServerEvent) {
+        if self.event_history_capacity > 0 {
+            if self.event_history.len() >= self.event_history_capacity {
+                self.event_history.pop_front();
+            }
+            self.event_history.push_back(ServerEventRecord {
+                tick: self.tick,
+                time: self.current_time,
+                event: event.clone(),
+            });
+        }
+        self.events.push_back(event);
+    }
+
+    /// Sets how many of the last events are kept in the history, the oldest are dropped first.
+    /// Default: [`SERVER_EVENT_HISTORY_CAPACITY`]
+    pub fn set_event_history_capacity(&mut self, capacity: usize) {
+        self.event_history_capacity = capacity;
+        while self.event_history.len() > capacity {
+            self.event_history.pop_front();
+        }
+    }
+
+    /// The last events of the server, oldest first, whether or not they were consumed.
+    pub fn event_history(&self) -> impl Iterator<Item = &ServerEventRecord> + '_ {
+        self.event_history.iter()
+    }
+
+    /// The events of the history about the client, oldest first.
+    pub fn client_event_history(
+        &self,
+        client_id: ClientId,
+    ) -> impl Iterator<Item = &ServerEventRecord> + '_ {
+        self.event_history.iter().filter(move |record| {
+            matches!(
+                record.event,
+                ServerEvent::ClientConnected { client_id: id }
+                | ServerEvent::ClientReady { client_id: id }
+                | ServerEvent::ClientDisconnected { client_id: id, .. }
+                | ServerEvent::MessageAcked { client_id: id, .. } if id == client_id
+            )
+        })
+    }
+
     /// Returns whether or not the server has connections
     pub fn has_connections(&self) -> bool {
         !self.connections.is_empty()
@@ -140,18 +412,49 @@ impl DenariaServer {
         }
     }
 
+    /// Marks a connected client as spectator. Spectators receive broadcasts but are never
+    /// spawned in the world. It does nothing if the client does not exits.
+    pub fn set_spectator(&mut self, client_id: ClientId) {
+        if self.connections.contains_key(&client_id) {
+            self.spectators.insert(client_id);
+        }
+    }
+
+    pub fn is_spectator(&self, client_id: ClientId) -> bool {
+        self.spectators.contains(&client_id)
+    }
+
+    /// Returns the ids of all spectating clients
+    pub fn spectators(&self) -> Vec<ClientId> {
+        self.spectators.iter().copied().collect()
+    }
+
     /// Removes a connection from the server, emits an disconnect server event.
     /// It does nothing if the client does not exits.
     /// <p style="background:rgba(77,220,255,0.16);padding:0.5em;">
     /// <strong>Note:</strong> This should only be called by the transport layer.
     /// </p>
     pub fn remove_connection(&mut self, client_id: ClientId) {
-        if let Some(connection) = self.connections.remove(&client_id) {
+        if let Some(mut connection) = self.connections.remove(&client_id) {
+            // Last send of the queued messages, the transport only delivers them to a client
+            // that left when it flushes on disconnect
+            let packets = connection.get_packets_to_send();
+            if !packets.is_empty() {
+                self.send_packets_to_server_transport(client_id, packets);
+            }
+            self.spectators.remove(&client_id);
+            self.dropped_messages.remove(&client_id);
+            self.control_messages.remove(&client_id);
+            self.last_input_time.remove(&client_id);
             let player_id = connection.player_id().clone();
+            // The player may already be mapped to a newer connection
+            if self.player_connection_map.get(&player_id) == Some(&client_id) {
+                self.player_connection_map.remove(&player_id);
+            }
             let reason = connection
                 .disconnect_reason()
                 .unwrap_or(DisconnectReason::Transport);
-            self.events.push_back(ServerEvent::ClientDisconnected {
+            self.push_event(ServerEvent::ClientDisconnected {
                 client_id,
                 player_id,
                 reason,
@@ -159,20 +462,60 @@ impl DenariaServer {
         }
     }
 
-    /// Disconnects a client, it does nothing if the client does not exist.
-    pub fn disconnect(&mut self, client_id: ClientId) {
+    /// Sets the size limit of the packets sent to the client, it does nothing if the client
+    /// does not exist.
+    pub fn set_max_packet_bytes(&mut self, client_id: ClientId, max_packet_bytes: usize) {
         if let Some(connection) = self.connections.get_mut(&client_id) {
-            connection.disconnect_with_reason(DisconnectReason::DisconnectedByServer)
+            connection.set_max_packet_bytes(max_packet_bytes);
         }
     }
 
+    /// Disconnects a client, it does nothing if the client does not exist.
+    pub fn disconnect(&mut self, client_id: ClientId) {
+        self.disconnect_with_reason(client_id, DisconnectReason::DisconnectedByServer);
+    }
+
     /// Disconnects all client.
     pub fn disconnect_all(&mut self) {
-        for connection in self.connections.values_mut() {
-            connection.disconnect_with_reason(DisconnectReason::DisconnectedByServer)
+        for client_id in self.connections.keys().copied().collect::<Vec<ClientId>>() {
+            self.disconnect(client_id);
         }
     }
 
+    /// Removes a client with the reason reported in its disconnect event and tells the
+    /// transport to drop it, it does nothing if the client does not exist.
+    pub fn disconnect_with_reason(&mut self, client_id: ClientId, reason: DisconnectReason) {
+        let Some(connection) = self.connections.get_mut(&client_id) else {
+            return;
+        };
+        connection.disconnect_with_reason(reason.clone());
+        self.remove_connection(client_id);
+        if let Err(e) =
+            self.to_transport_server_tx
+                .send(FromDenariaServerMessage::DisconnectClient {
+                    client_id: client_id.raw(),
+                    reason,
+                })
+        {
+            tracing::error!(
+                client_id = client_id.raw(),
+                session_id = self.session_id,
+                "Failed to send disconnect to server transport: {:?}",
+                e
+            );
+        }
+    }
+
+    /// Kicks a client, the reason is reported in its disconnect event.
+    pub fn kick(&mut self, client_id: ClientId, reason: String) {
+        self.disconnect_with_reason(client_id, DisconnectReason::Kicked { reason });
+    }
+
+    /// Bans a client until the unix timestamp in seconds, or permanently.
+    pub fn ban(&mut self, client_id: ClientId, until: Option<u64>) {
+        self.disconnect_with_reason(client_id, DisconnectReason::Banned { until });
+    }
+
     /// Send a message to all clients over a channel.
     pub fn broadcast_message<I: Into<u8>, B: Into<Bytes>>(&mut self, channel_id: I, message: B) {
         let channel_id = channel_id.into();
@@ -200,6 +543,24 @@ impl DenariaServer {
         }
     }
 
+    /// Send a different message to each connected client over a channel.
+    /// `message_for` is called once per connected client, returning None skips the client.
+    pub fn broadcast_per_client<I, F>(&mut self, channel_id: I, mut message_for: F)
+    where
+        I: Into<u8>,
+        F: FnMut(ClientId) -> Option<Bytes>,
+    {
+        let channel_id = channel_id.into();
+        for (client_id, connection) in self.connections.iter_mut() {
+            if !connection.is_connected() {
+                continue;
+            }
+            if let Some(message) = message_for(*client_id) {
+                connection.send_message(channel_id, message);
+            }
+        }
+    }
+
     /// Returns the available memory in bytes of a channel for the given client.
     /// Returns 0 if the client is not found.
     pub fn channel_available_memory<I: Into<u8>>(
@@ -213,6 +574,31 @@ impl DenariaServer {
         }
     }
 
+    /// Returns the `(used, max)` memory in bytes of a channel for the given client.
+    /// Returns `(0, 0)` if the client is not found.
+    pub fn channel_memory_usage<I: Into<u8>>(
+        &self,
+        client_id: ClientId,
+        channel_id: I,
+    ) -> (usize, usize) {
+        match self.connections.get(&client_id) {
+            Some(connection) => connection.channel_memory_usage(channel_id),
+            None => (0, 0),
+        }
+    }
+
+    /// Returns the `(used, max)` memory in bytes of a channel summed over all clients,
+    /// for capacity planning.
+    pub fn total_channel_memory_usage<I: Into<u8>>(&self, channel_id: I) -> (usize, usize) {
+        let channel_id = channel_id.into();
+        self.connections
+            .values()
+            .map(|connection| connection.channel_memory_usage(channel_id))
+            .fold((0, 0), |(used, max), (client_used, client_max)| {
+                (used + client_used, max + client_max)
+            })
+    }
+
     /// Checks if can send a message with the given size in bytes over a channel for the given client.
     /// Returns false if the client is not found.
     pub fn can_send_message<I: Into<u8>>(
@@ -236,7 +622,77 @@ impl DenariaServer {
     ) {
         match self.connections.get_mut(&client_id) {
             Some(connection) => connection.send_message(channel_id, message),
-            None => tracing::error!("Tried to send a message to invalid client {:?}", client_id),
+            None => tracing::error!(
+                client_id = client_id.raw(),
+                session_id = self.session_id,
+                "Tried to send a message to invalid client"
+            ),
+        }
+    }
+
+    /// Send a reliable message to a client and track its delivery, a
+    /// [`ServerEvent::MessageAcked`] with the returned id follows once the client acked it.
+    /// No event comes if the client leaves first. Returns `None` when the message could not
+    /// be queued.
+    pub fn send_tracked_message<B: Into<Bytes>>(
+        &mut self,
+        client_id: ClientId,
+        message: B,
+    ) -> Option<u64> {
+        match self.connections.get_mut(&client_id) {
+            Some(connection) => connection.send_tracked_message(message),
+            None => {
+                tracing::error!(
+                    client_id = client_id.raw(),
+                    session_id = self.session_id,
+                    "Tried to send a message to invalid client"
+                );
+                None
+            }
+        }
+    }
+
+    /// Moves a client that fell behind past the reliable messages it did not ack yet, see
+    /// [`UnityClient::reset_reliable_channel`]. Returns `None` if the client does not exist.
+    pub fn reset_reliable_channel(&mut self, client_id: ClientId) -> Option<u64> {
+        self.connections
+            .get_mut(&client_id)
+            .map(|connection| connection.reset_reliable_channel())
+    }
+
+    /// Send a reliable message to a client with a priority, see
+    /// [`UnityClient::send_reliable_with_priority`].
+    pub fn send_reliable_with_priority<B: Into<Bytes>>(
+        &mut self,
+        client_id: ClientId,
+        message: B,
+        priority: u8,
+    ) {
+        match self.connections.get_mut(&client_id) {
+            Some(connection) => connection.send_reliable_with_priority(message, priority),
+            None => tracing::error!(
+                client_id = client_id.raw(),
+                session_id = self.session_id,
+                "Tried to send a message to invalid client"
+            ),
+        }
+    }
+
+    /// Send an unreliable message to a client with a priority, see
+    /// [`UnityClient::send_unreliable_with_priority`].
+    pub fn send_unreliable_with_priority<B: Into<Bytes>>(
+        &mut self,
+        client_id: ClientId,
+        message: B,
+        priority: u8,
+    ) {
+        match self.connections.get_mut(&client_id) {
+            Some(connection) => connection.send_unreliable_with_priority(message, priority),
+            None => tracing::error!(
+                client_id = client_id.raw(),
+                session_id = self.session_id,
+                "Tried to send a message to invalid client"
+            ),
         }
     }
 
@@ -248,12 +704,32 @@ impl DenariaServer {
     ) -> Option<(Bytes, &String)> {
         if let Some(connection) = self.connections.get_mut(&client_id) {
             if let Some(message) = connection.receive_message(channel_id) {
+                self.last_input_time.insert(client_id, self.current_time);
                 return Some((message, connection.player_id()));
             }
         }
         None
     }
 
+    /// Drains the messages received from a client over a channel, in the order
+    /// [`DenariaServer::receive_message`] would return them.
+    pub fn receive_all_messages<I: Into<u8>>(
+        &mut self,
+        client_id: ClientId,
+        channel_id: I,
+    ) -> Vec<Bytes> {
+        let channel_id = channel_id.into();
+        let Some(connection) = self.connections.get_mut(&client_id) else {
+            return vec![];
+        };
+        let messages: Vec<Bytes> =
+            std::iter::from_fn(|| connection.receive_message(channel_id)).collect();
+        if !messages.is_empty() {
+            self.last_input_time.insert(client_id, self.current_time);
+        }
+        messages
+    }
+
     /// Return ids for all connected clients (iterator)
     pub fn clients_id_iter(&self) -> impl Iterator<Item = ClientId> + '_ {
         self.connections
@@ -299,9 +775,36 @@ impl DenariaServer {
     /// Advances the server by the duration.
     /// Should be called every tick
     pub fn update(&mut self, duration: Duration) {
+        self.tick = self.tick.wrapping_add(1);
+        self.current_time += duration;
         for connection in self.connections.values_mut() {
             connection.update(duration);
         }
+        self.disconnect_idle_clients();
+    }
+
+    /// Removes the clients that sent no input within the idle timeout and tells the transport
+    /// to drop them. Nobody is idle while the session is paused.
+    fn disconnect_idle_clients(&mut self) {
+        let Some(idle_timeout) = self.idle_timeout else {
+            return;
+        };
+        if self.paused {
+            for last_input_time in self.last_input_time.values_mut() {
+                *last_input_time = self.current_time;
+            }
+            return;
+        }
+
+        let idle_clients: Vec<ClientId> = self
+            .last_input_time
+            .iter()
+            .filter(|(_, last_input_time)| self.current_time - **last_input_time > idle_timeout)
+            .map(|(client_id, _)| *client_id)
+            .collect();
+        for client_id in idle_clients {
+            self.disconnect_with_reason(client_id, DisconnectReason::Idle);
+        }
     }
 
     /// Returns a list of packets to be sent to the client.
@@ -327,16 +830,24 @@ impl DenariaServer {
         payload: &[u8],
         client_id: ClientId,
     ) -> Result<(), ClientNotFound> {
-        match self.connections.get_mut(&client_id) {
+        let acked_messages = match self.connections.get_mut(&client_id) {
             Some(connection) => {
                 connection.process_packet(payload);
-                Ok(())
+                connection.take_acked_messages()
             }
-            None => Err(ClientNotFound),
+            None => return Err(ClientNotFound),
+        };
+        for message_id in acked_messages {
+            self.push_event(ServerEvent::MessageAcked {
+                client_id,
+                message_id,
+            });
         }
+        Ok(())
     }
 
     pub fn process_server_transport_messages(&mut self) {
+        self.control_messages.clear();
         while let Ok(message) = self.from_transport_server_rx.try_recv() {
             match message {
                 ToDenariaServerMessage::ClientConnected {
@@ -347,19 +858,77 @@ impl DenariaServer {
                 } => {
                     self.add_connection(ClientId::from_raw(client_id), player_id);
                 }
+                ToDenariaServerMessage::ClientConfirmed { client_id } => {
+                    let client_id = ClientId::from_raw(client_id);
+                    if self.connections.contains_key(&client_id) {
+                        self.push_event(ServerEvent::ClientReady { client_id });
+                    }
+                }
                 ToDenariaServerMessage::ClientDisconnected { client_id } => {
                     self.remove_connection(ClientId::from_raw(client_id));
                 }
+                ToDenariaServerMessage::SetPaused { paused } => self.set_paused(paused),
+                ToDenariaServerMessage::Teleport {
+                    player_id,
+                    position,
+                } => self.teleport(player_id, position),
+                ToDenariaServerMessage::SendSaturated { saturated } => {
+                    self.set_send_saturated(saturated)
+                }
+                ToDenariaServerMessage::Shutdown => self.request_shutdown(),
+                ToDenariaServerMessage::Control {
+                    client_id,
+                    kind,
+                    payload,
+                } => {
+                    let client_id = ClientId::from_raw(client_id);
+                    if !self.connections.contains_key(&client_id) {
+                        continue;
+                    }
+                    let received = self.control_messages.entry(client_id).or_default();
+                    if *received >= self.max_messages_per_tick {
+                        // Dropped like the gameplay messages over the limit
+                        self.record_dropped_messages(client_id, 1);
+                        if let Some(suppressed) = self.dropped_control_log.admit() {
+                            tracing::warn!(
+                                client_id = client_id.raw(),
+                                session_id = self.session_id,
+                                suppressed,
+                                "Client went over {} messages per tick, dropped a control message",
+                                self.max_messages_per_tick
+                            );
+                        }
+                    } else {
+                        *received += 1;
+                        self.push_event(ServerEvent::ControlMessage {
+                            client_id,
+                            kind,
+                            payload,
+                        });
+                    }
+                }
                 ToDenariaServerMessage::Payload { client_id, payload } => {
-                    tracing::debug!(
-                        "Received payload from client: {:?}: payload: {:?}",
-                        client_id,
-                        payload
-                    );
+                    if let Some(suppressed) = self.payload_log.admit() {
+                        tracing::debug!(
+                            client_id,
+                            session_id = self.session_id,
+                            suppressed,
+                            "Received payload from client: {:?}",
+                            payload
+                        );
+                    }
                     if let Err(e) =
                         self.process_packet_from(payload.as_slice(), ClientId::from_raw(client_id))
                     {
-                        tracing::error!("Failed to process packet from client: {:?}", e);
+                        if let Some(suppressed) = self.invalid_payload_log.admit() {
+                            tracing::error!(
+                                client_id,
+                                session_id = self.session_id,
+                                suppressed,
+                                "Failed to process packet from client: {:?}",
+                                e
+                            );
+                        }
                     }
                 }
             }
@@ -374,7 +943,12 @@ impl DenariaServer {
                 packets,
             })
         {
-            tracing::error!("Failed to send packet to server transport: {:?}", e);
+            tracing::error!(
+                client_id = client_id.raw(),
+                session_id = self.session_id,
+                "Failed to send packet to server transport: {:?}",
+                e
+            );
         }
     }
 }
@@ -417,3 +991,385 @@ impl<'de> serde::Deserialize<'de> for ClientId {
         u64::deserialize(deserializer).map(ClientId::from_raw)
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crossbeam::channel::unbounded;
+
+    use super::*;
+    use crate::server::{channel::DefaultChannel, packet::Packet};
+
+    /// A server without clients, along with the transport ends of its channels.
+    pub(crate) fn server_with_channels() -> (
+        DenariaServer,
+        Sender<ToDenariaServerMessage>,
+        Receiver<FromDenariaServerMessage>,
+    ) {
+        let (to_server_tx, from_transport_server_rx) = unbounded();
+        let (to_transport_server_tx, from_server_rx) = unbounded();
+        let server = DenariaServer::new(
+            0,
+            ConnectionConfig::default(),
+            from_transport_server_rx,
+            to_transport_server_tx,
+        );
+        (server, to_server_tx, from_server_rx)
+    }
+
+    fn server() -> DenariaServer {
+        server_with_channels().0
+    }
+
+    fn unreliable_messages(server: &mut DenariaServer, client_id: ClientId) -> Vec<Bytes> {
+        server
+            .get_packets_to_send(client_id)
+            .unwrap()
+            .iter()
+            .filter_map(|payload| match Packet::from_bytes(payload) {
+                Ok(Packet::SmallUnreliable { messages, .. }) => Some(messages),
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn broadcast_per_client_builds_each_payload() {
+        let mut server = server();
+        for raw in 1..=3 {
+            server.add_connection(ClientId::from_raw(raw), format!("player{raw}"));
+        }
+
+        let mut calls = vec![];
+        server.broadcast_per_client(DefaultChannel::Unreliable, |client_id| {
+            calls.push(client_id);
+            // Client 3 has nothing relevant
+            (client_id.raw() != 3).then(|| Bytes::from(vec![client_id.raw() as u8; 4]))
+        });
+
+        calls.sort();
+        assert_eq!(
+            calls,
+            (1..=3).map(ClientId::from_raw).collect::<Vec<ClientId>>()
+        );
+        for raw in 1..=2 {
+            let messages = unreliable_messages(&mut server, ClientId::from_raw(raw));
+            assert_eq!(messages, vec![Bytes::from(vec![raw as u8; 4])]);
+        }
+        assert!(unreliable_messages(&mut server, ClientId::from_raw(3)).is_empty());
+    }
+
+    #[test]
+    fn channel_memory_usage_follows_sends_and_acks() {
+        let mut server = server();
+        let client1 = ClientId::from_raw(1);
+        let client2 = ClientId::from_raw(2);
+        server.add_connection(client1, "player1".to_string());
+        server.add_connection(client2, "player2".to_string());
+        let (_, max) = server.channel_memory_usage(client1, DefaultChannel::ReliableOrdered);
+        assert_eq!(
+            server.channel_memory_usage(client1, DefaultChannel::ReliableOrdered),
+            (0, max)
+        );
+
+        server.send_message(client1, DefaultChannel::ReliableOrdered, vec![1u8; 100]);
+        server.send_message(client2, DefaultChannel::ReliableOrdered, vec![2u8; 40]);
+        server.send_message(client1, DefaultChannel::Unreliable, vec![3u8; 10]);
+        assert_eq!(
+            server.channel_memory_usage(client1, DefaultChannel::ReliableOrdered),
+            (100, max)
+        );
+        assert_eq!(
+            server
+                .channel_memory_usage(client1, DefaultChannel::Unreliable)
+                .0,
+            10
+        );
+        assert_eq!(
+            server.total_channel_memory_usage(DefaultChannel::ReliableOrdered),
+            (140, 2 * max)
+        );
+        assert_eq!(
+            server.network_info(client1).unwrap().reliable_memory,
+            (100, max)
+        );
+
+        // Reliable messages are held until the packet carrying them is acked
+        server.get_packets_to_send(client1).unwrap();
+        assert_eq!(
+            server.channel_memory_usage(client1, DefaultChannel::ReliableOrdered),
+            (100, max)
+        );
+        let ack = Packet::Ack {
+            channel_id: 1,
+            packet_type: 1,
+            packet_process_time: 0,
+            sequence_id: 0,
+            acked_seq_id: 0,
+            acked_mask: 1,
+            end_posfix: 0,
+        };
+        let mut buffer = [0u8; 64];
+        let len = ack.to_bytes(&mut buffer).unwrap();
+        server.process_packet_from(&buffer[..len], client1).unwrap();
+        assert_eq!(
+            server.channel_memory_usage(client1, DefaultChannel::ReliableOrdered),
+            (0, max)
+        );
+        assert_eq!(
+            server.total_channel_memory_usage(DefaultChannel::ReliableOrdered),
+            (40, 2 * max)
+        );
+    }
+
+    fn ack_packet(acked_seq_id: u16) -> Vec<u8> {
+        let ack = Packet::Ack {
+            channel_id: 1,
+            packet_type: 1,
+            packet_process_time: 0,
+            sequence_id: 0,
+            acked_seq_id,
+            acked_mask: 1,
+            end_posfix: 0,
+        };
+        let mut buffer = [0u8; 64];
+        let len = ack.to_bytes(&mut buffer).unwrap();
+        buffer[..len].to_vec()
+    }
+
+    fn acked_messages(server: &mut DenariaServer) -> Vec<(ClientId, u64)> {
+        std::iter::from_fn(|| server.get_event())
+            .filter_map(|event| match event {
+                ServerEvent::MessageAcked {
+                    client_id,
+                    message_id,
+                } => Some((client_id, message_id)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tracked_message_ack_is_reported_unless_client_leaves_first() {
+        let mut server = server();
+        let client1 = ClientId::from_raw(1);
+        let client2 = ClientId::from_raw(2);
+        server.add_connection(client1, "player1".to_string());
+        server.add_connection(client2, "player2".to_string());
+
+        // An untracked message shares the packet, only the tracked one is reported
+        server.send_message(client1, DefaultChannel::ReliableOrdered, vec![1u8; 10]);
+        let message_id = server.send_tracked_message(client1, vec![2u8; 10]).unwrap();
+        server.get_packets_to_send(client1).unwrap();
+        assert!(acked_messages(&mut server).is_empty());
+
+        server.process_packet_from(&ack_packet(0), client1).unwrap();
+        assert_eq!(acked_messages(&mut server), vec![(client1, message_id)]);
+        // A repeated ack is not reported again
+        server.process_packet_from(&ack_packet(0), client1).unwrap();
+        assert!(acked_messages(&mut server).is_empty());
+
+        server.send_tracked_message(client2, vec![3u8; 10]).unwrap();
+        server.get_packets_to_send(client2).unwrap();
+        server.remove_connection(client2);
+        assert!(server.process_packet_from(&ack_packet(0), client2).is_err());
+        assert!(acked_messages(&mut server).is_empty());
+    }
+
+    #[test]
+    fn event_history_keeps_the_last_events_after_they_are_consumed() {
+        let mut server = server();
+        server.set_event_history_capacity(3);
+
+        for client_id in 1..=3 {
+            server.add_connection(ClientId::from_raw(client_id), format!("player{client_id}"));
+            server.update(Duration::from_millis(16));
+        }
+        server.remove_connection(ClientId::from_raw(1));
+        while server.get_event().is_some() {}
+
+        let history: Vec<ServerEventRecord> = server.event_history().cloned().collect();
+        assert_eq!(history.len(), 3);
+        // The connection of client 1 was the oldest, it was dropped
+        assert_eq!(
+            history[0],
+            ServerEventRecord {
+                tick: 1,
+                time: Duration::from_millis(16),
+                event: ServerEvent::ClientConnected {
+                    client_id: ClientId::from_raw(2)
+                },
+            }
+        );
+        assert_eq!(
+            history[2],
+            ServerEventRecord {
+                tick: 3,
+                time: Duration::from_millis(48),
+                event: ServerEvent::ClientDisconnected {
+                    client_id: ClientId::from_raw(1),
+                    player_id: "player1".to_string(),
+                    reason: DisconnectReason::Transport,
+                },
+            }
+        );
+
+        let client_history: Vec<&ServerEventRecord> =
+            server.client_event_history(ClientId::from_raw(1)).collect();
+        assert_eq!(client_history.len(), 1);
+        assert_eq!(client_history[0], &history[2]);
+    }
+
+    #[test]
+    fn receive_all_messages_drains_the_channel_in_order() {
+        let (mut server, to_server_tx, _from_server_rx) = server_with_channels();
+        let client_id = ClientId::from_raw(1);
+        server.add_connection(client_id, "player1".to_string());
+
+        let mut buffer = [0u8; 64];
+        let len = Packet::SmallUnreliable {
+            channel_id: 0,
+            messages: vec![
+                Bytes::from_static(&[1]),
+                Bytes::from_static(&[2]),
+                Bytes::from_static(&[3]),
+            ],
+        }
+        .to_bytes(&mut buffer)
+        .unwrap();
+        to_server_tx
+            .send(ToDenariaServerMessage::Payload {
+                client_id: client_id.raw(),
+                payload: buffer[..len].to_vec(),
+            })
+            .unwrap();
+        server.process_server_transport_messages();
+
+        assert_eq!(
+            server.receive_all_messages(client_id, DefaultChannel::Unreliable),
+            vec![
+                Bytes::from_static(&[1]),
+                Bytes::from_static(&[2]),
+                Bytes::from_static(&[3]),
+            ]
+        );
+        assert!(server
+            .receive_all_messages(client_id, DefaultChannel::Unreliable)
+            .is_empty());
+        assert!(server
+            .receive_message(client_id, DefaultChannel::Unreliable)
+            .is_none());
+    }
+
+    #[test]
+    fn client_sending_no_input_is_disconnected_as_idle() {
+        let (mut server, to_server_tx, from_server_rx) = server_with_channels();
+        server.set_idle_timeout(Some(Duration::from_millis(500)));
+        let idle = ClientId::from_raw(1);
+        let active = ClientId::from_raw(2);
+        server.add_connection(idle, "idle".to_string());
+        server.add_connection(active, "active".to_string());
+        while server.get_event().is_some() {}
+
+        let packet = |packet: Packet| {
+            let mut buffer = [0u8; 64];
+            let len = packet.to_bytes(&mut buffer).unwrap();
+            buffer[..len].to_vec()
+        };
+        let keepalive = packet(Packet::Ack {
+            channel_id: 1,
+            packet_type: 1,
+            packet_process_time: 0,
+            sequence_id: 0,
+            acked_seq_id: 0,
+            acked_mask: 0,
+            end_posfix: 0,
+        });
+        let input = packet(Packet::SmallUnreliable {
+            channel_id: 0,
+            messages: vec![Bytes::from_static(&[1, 2, 3])],
+        });
+
+        for _ in 0..40 {
+            for (client_id, payload) in [(idle, &keepalive), (active, &input)] {
+                to_server_tx
+                    .send(ToDenariaServerMessage::Payload {
+                        client_id: client_id.raw(),
+                        payload: payload.clone(),
+                    })
+                    .unwrap();
+            }
+            server.update(Duration::from_millis(16));
+            server.process_server_transport_messages();
+            while server
+                .receive_message(active, DefaultChannel::Unreliable)
+                .is_some()
+            {}
+        }
+
+        assert!(!server.is_connected(idle));
+        assert!(server.is_connected(active));
+        assert_eq!(
+            server.get_event(),
+            Some(ServerEvent::ClientDisconnected {
+                client_id: idle,
+                player_id: "idle".to_string(),
+                reason: DisconnectReason::Idle,
+            })
+        );
+        assert_eq!(server.get_event(), None);
+        assert!(from_server_rx.try_iter().any(|message| matches!(
+            message,
+            FromDenariaServerMessage::DisconnectClient { client_id: 1, .. }
+        )));
+    }
+
+    #[test]
+    fn kick_and_ban_report_their_reason_in_disconnect_event() {
+        let (mut server, _to_server_tx, from_server_rx) = server_with_channels();
+        let kicked = ClientId::from_raw(1);
+        let banned = ClientId::from_raw(2);
+        server.add_connection(kicked, "kicked".to_string());
+        server.add_connection(banned, "banned".to_string());
+        while server.get_event().is_some() {}
+
+        let kick_reason = DisconnectReason::Kicked {
+            reason: "spawn camping".to_string(),
+        };
+        let ban_reason = DisconnectReason::Banned {
+            until: Some(1_700_000_000),
+        };
+        server.kick(kicked, "spawn camping".to_string());
+        server.ban(banned, Some(1_700_000_000));
+
+        assert!(!server.has_connections());
+        assert_eq!(
+            server.get_event(),
+            Some(ServerEvent::ClientDisconnected {
+                client_id: kicked,
+                player_id: "kicked".to_string(),
+                reason: kick_reason.clone(),
+            })
+        );
+        assert_eq!(
+            server.get_event(),
+            Some(ServerEvent::ClientDisconnected {
+                client_id: banned,
+                player_id: "banned".to_string(),
+                reason: ban_reason.clone(),
+            })
+        );
+
+        // The transport is told to drop both, with the reason for the disconnect packet
+        let disconnects: Vec<(u64, DisconnectReason)> = from_server_rx
+            .try_iter()
+            .filter_map(|message| match message {
+                FromDenariaServerMessage::DisconnectClient { client_id, reason } => {
+                    Some((client_id, reason))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(disconnects, vec![(1, kick_reason), (2, ban_reason)]);
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+};
+
+use crate::constants::DEFAULT_SERVER_PORT;
+
+/// Where the server socket is bound and which address is reported to clients.
+///
+/// Read from the `--bind <addr>`, `--port <port>` and `--public-addr <addr>` arguments, falling
+/// back to the SERVER_BIND_ADDR, SERVER_PORT and SERVER_PUBLIC_ADDR variables, so several
+/// instances can run on one host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BindConfig {
+    pub bind_addr: SocketAddr,
+    /// Reported instead of the bound address, e.g. when bound to `0.0.0.0` behind a NAT.
+    /// A port of 0 is replaced by the bound port
+    pub public_addr: Option<SocketAddr>,
+}
+
+impl Default for BindConfig {
+    fn default() -> Self {
+        Self {
+            bind_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DEFAULT_SERVER_PORT),
+            public_addr: None,
+        }
+    }
+}
+
+impl BindConfig {
+    /// Arguments take precedence over variables, `--port` and SERVER_PORT replace the port of
+    /// the bind address. Unrelated arguments are ignored.
+    pub fn from_args_and_env<I, F>(args: I, env: F) -> io::Result<Self>
+    where
+        I: IntoIterator<Item = String>,
+        F: Fn(&str) -> Option<String>,
+    {
+        Self::default().apply_args_and_env(args, env)
+    }
+
+    /// Like [`BindConfig::from_args_and_env`], starting from this config instead of the default.
+    pub fn apply_args_and_env<I, F>(self, args: I, env: F) -> io::Result<Self>
+    where
+        I: IntoIterator<Item = String>,
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut bind_arg = None;
+        let mut port_arg = None;
+        let mut public_arg = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let target = match arg.as_str() {
+                "--bind" => &mut bind_arg,
+                "--port" => &mut port_arg,
+                "--public-addr" => &mut public_arg,
+                _ => continue,
+            };
+            *target = Some(
+                args.next()
+                    .ok_or_else(|| invalid_input(&arg, "missing value"))?,
+            );
+        }
+
+        let mut config = self;
+        if let Some(bind_addr) = bind_arg.or_else(|| env("SERVER_BIND_ADDR")) {
+            config.bind_addr = parse("bind address", &bind_addr)?;
+        }
+        if let Some(port) = port_arg.or_else(|| env("SERVER_PORT")) {
+            config.bind_addr.set_port(parse("port", &port)?);
+        }
+        if let Some(public_addr) = public_arg.or_else(|| env("SERVER_PUBLIC_ADDR")) {
+            config.public_addr = Some(parse("public address", &public_addr)?);
+        }
+        Ok(config)
+    }
+
+    /// Binds the server socket and returns it with the addresses to put in
+    /// [`ServerConfig::public_addresses`](super::server::server::ServerConfig).
+    pub fn bind(&self) -> io::Result<(UdpSocket, Vec<SocketAddr>)> {
+        let socket = UdpSocket::bind(self.bind_addr)?;
+        // The bound address carries the actual port when binding to port 0
+        let local_addr = socket.local_addr()?;
+        let public_addr = match self.public_addr {
+            Some(mut public_addr) => {
+                if public_addr.port() == 0 {
+                    public_addr.set_port(local_addr.port());
+                }
+                public_addr
+            }
+            None => local_addr,
+        };
+        Ok((socket, vec![public_addr]))
+    }
+}
+
+fn parse<T: std::str::FromStr>(what: &str, value: &str) -> io::Result<T> {
+    value
+        .parse()
+        .map_err(|_| invalid_input(what, &format!("invalid value {value:?}")))
+}
+
+fn invalid_input(what: &str, error: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{what}: {error}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use super::*;
+    use crate::{
+        constants::{TRANSPORT_CONNECTION_TIMEOUT, TRANSPORT_SEND_RATE},
+        server::transport::{server::server::ServerConfig, transport::ServerTransport},
+    };
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn arguments_override_variables_and_default() {
+        let config = BindConfig::from_args_and_env(args(&[]), env(&[])).unwrap();
+        assert_eq!(config, BindConfig::default());
+        assert_eq!(config.bind_addr.port(), DEFAULT_SERVER_PORT);
+
+        let config = BindConfig::from_args_and_env(
+            args(&[]),
+            env(&[
+                ("SERVER_BIND_ADDR", "0.0.0.0:6000"),
+                ("SERVER_PORT", "6001"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.bind_addr, "0.0.0.0:6001".parse().unwrap());
+
+        let config = BindConfig::from_args_and_env(
+            args(&["load-test", "--port", "7000", "--public-addr", "10.0.0.1:0"]),
+            env(&[("SERVER_PORT", "6001")]),
+        )
+        .unwrap();
+        assert_eq!(config.bind_addr, "127.0.0.1:7000".parse().unwrap());
+        assert_eq!(config.public_addr, Some("10.0.0.1:0".parse().unwrap()));
+
+        assert!(BindConfig::from_args_and_env(args(&["--port"]), env(&[])).is_err());
+        assert!(BindConfig::from_args_and_env(args(&["--port", "nope"]), env(&[])).is_err());
+    }
+
+    #[test]
+    fn configured_port_is_bound_and_reported() {
+        // Reserve a free port, then release it for the server
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config =
+            BindConfig::from_args_and_env(args(&["--port", &port.to_string()]), env(&[])).unwrap();
+        let (socket, public_addresses) = config.bind().unwrap();
+        assert_eq!(socket.local_addr().unwrap().port(), port);
+
+        let transport = ServerTransport::new(
+            ServerConfig {
+                current_time: Duration::ZERO,
+                max_clients: 8,
+                public_addresses,
+                keep_alive_interval: TRANSPORT_SEND_RATE,
+                connection_timeout: TRANSPORT_CONNECTION_TIMEOUT,
+            },
+            socket,
+        )
+        .unwrap();
+        assert_eq!(
+            transport.addresses(),
+            vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)]
+        );
+
+        // A second instance on another port, announcing its public address
+        let config = BindConfig {
+            bind_addr: "127.0.0.1:0".parse().unwrap(),
+            public_addr: Some("10.0.0.1:0".parse().unwrap()),
+        };
+        let (socket, public_addresses) = config.bind().unwrap();
+        let bound_port = socket.local_addr().unwrap().port();
+        assert_ne!(bound_port, port);
+        assert_eq!(
+            public_addresses,
+            vec![SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                bound_port
+            )]
+        );
+    }
+}
@@ -0,0 +1,424 @@
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use crate::{
+    constants::{
+        PLAYER_ID_MAX_BYTES, TRANSPORT_CONNECTION_PREFIX, TRANSPORT_MAX_PACKET_BYTES,
+        TRANSPORT_SEND_RATE,
+    },
+    server::{
+        connection::{ConnectionConfig, UnityClient},
+        error::DisconnectReason,
+        packet::Packet as ChannelPacket,
+    },
+};
+
+use super::{
+    error::TransportError,
+    server::{error::TransportServerError, packet::Packet},
+};
+
+/// Handshake progress of a [`ClientTransport`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientState {
+    /// The connection request is resent until the server answers it
+    SendingConnectionRequest,
+    /// The auth request is resent until the server answers with a KeepAlive
+    SendingAuth,
+    Connected,
+    Disconnected,
+}
+
+/// UDP transport of a client, for bots and integration tests written in Rust.
+/// Performs the connect handshake (ConnectionRequest, auth Data, KeepAlive) and then carries
+/// the packets of its [`UnityClient`] in Data packets.
+pub struct ClientTransport {
+    socket: UdpSocket,
+    server_addr: SocketAddr,
+    client_id: u64,
+    player_id: String,
+    state: ClientState,
+    connection: UnityClient,
+    // Spawn message with the player id and session ticket, the server authenticates it
+    auth_payload: Vec<u8>,
+    current_time: Duration,
+    last_packet_send_time: Option<Duration>,
+    resend_interval: Duration,
+    buffer: [u8; TRANSPORT_MAX_PACKET_BYTES],
+    packets_sent: u64,
+    packets_received: u64,
+    // Packets the socket could not take without blocking
+    dropped_packets: u64,
+}
+
+impl ClientTransport {
+    pub fn new(
+        socket: UdpSocket,
+        server_addr: SocketAddr,
+        client_id: u64,
+        player_id: &str,
+        session_ticket: &str,
+        config: ConnectionConfig,
+    ) -> Result<Self, TransportError> {
+        socket.set_nonblocking(true)?;
+        let auth_payload = auth_payload(player_id, session_ticket)?;
+
+        Ok(Self {
+            socket,
+            server_addr,
+            client_id,
+            player_id: player_id.to_string(),
+            state: ClientState::SendingConnectionRequest,
+            connection: UnityClient::new(config),
+            auth_payload,
+            current_time: Duration::ZERO,
+            last_packet_send_time: None,
+            resend_interval: TRANSPORT_SEND_RATE,
+            buffer: [0u8; TRANSPORT_MAX_PACKET_BYTES],
+            packets_sent: 0,
+            packets_received: 0,
+            dropped_packets: 0,
+        })
+    }
+
+    pub fn state(&self) -> ClientState {
+        self.state
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state == ClientState::Connected
+    }
+
+    pub fn client_id(&self) -> u64 {
+        self.client_id
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// The connection used to send and receive messages once connected.
+    pub fn connection(&self) -> &UnityClient {
+        &self.connection
+    }
+
+    pub fn connection_mut(&mut self) -> &mut UnityClient {
+        &mut self.connection
+    }
+
+    pub fn packets_sent(&self) -> u64 {
+        self.packets_sent
+    }
+
+    pub fn packets_received(&self) -> u64 {
+        self.packets_received
+    }
+
+    /// Packets dropped because the socket send buffer was full.
+    pub fn dropped_packets(&self) -> u64 {
+        self.dropped_packets
+    }
+
+    /// Sets how long the handshake packets and KeepAlives wait before being sent again.
+    /// Default: [`TRANSPORT_SEND_RATE`]
+    pub fn set_resend_interval(&mut self, resend_interval: Duration) {
+        self.resend_interval = resend_interval;
+    }
+
+    /// Receives the pending packets from the server, then sends the handshake packets or
+    /// the packets of the connection. Should be called every tick
+    pub fn update(&mut self, duration: Duration) -> Result<(), TransportError> {
+        self.current_time += duration;
+        self.connection.update(duration);
+
+        loop {
+            match self.socket.recv_from(&mut self.buffer) {
+                Ok((len, addr)) => {
+                    if addr != self.server_addr || len == 0 {
+                        continue;
+                    }
+                    self.packets_received += 1;
+                    self.process_packet(len);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => break,
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        if let Some(reason) = self.connection.disconnect_reason() {
+            if self.state != ClientState::Disconnected {
+                tracing::debug!(client_id = self.client_id, "Disconnecting: {reason}");
+                self.disconnect()?;
+            }
+            return Ok(());
+        }
+
+        match self.state {
+            ClientState::SendingConnectionRequest if self.resend_due() => {
+                self.send(&Packet::ConnectionRequest {
+                    connection_prefix: TRANSPORT_CONNECTION_PREFIX,
+                    connection_side_id: 1,
+                    client_identifier: self.client_id,
+                })?;
+            }
+            ClientState::SendingAuth if self.resend_due() => {
+                let auth_payload = std::mem::take(&mut self.auth_payload);
+                let result = self.send(&Packet::Data {
+                    client_identifier: self.client_id,
+                    payload: &auth_payload,
+                });
+                self.auth_payload = auth_payload;
+                result?;
+            }
+            ClientState::Connected => {
+                for payload in self.connection.get_packets_to_send() {
+                    self.send(&Packet::Data {
+                        client_identifier: self.client_id,
+                        payload: &payload,
+                    })?;
+                }
+                if self.resend_due() {
+                    self.send(&Packet::KeepAlive {
+                        client_identifier: self.client_id,
+                    })?;
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Tells the server the client is leaving, it does nothing if already disconnected.
+    pub fn disconnect(&mut self) -> Result<(), TransportError> {
+        if self.state == ClientState::Disconnected {
+            return Ok(());
+        }
+        self.state = ClientState::Disconnected;
+        self.connection.disconnect();
+        self.send(&Packet::Disconnect {
+            client_identifier: self.client_id,
+            reason: DisconnectReason::DisconnectedByClient.code(),
+        })
+    }
+
+    fn process_packet(&mut self, len: usize) {
+        let packet = match Packet::decode(&mut self.buffer[..len]) {
+            Ok(packet) => packet,
+            Err(e) => {
+                tracing::warn!(client_id = self.client_id, "Dropped packet: {e}");
+                return;
+            }
+        };
+
+        match (self.state, packet) {
+            (
+                ClientState::SendingConnectionRequest,
+                Packet::ConnectionRequest {
+                    connection_side_id: 2,
+                    ..
+                },
+            ) => {
+                tracing::trace!(client_id = self.client_id, "Connection request accepted");
+                self.state = ClientState::SendingAuth;
+                self.last_packet_send_time = None;
+            }
+            (ClientState::SendingAuth, Packet::KeepAlive { .. }) => {
+                tracing::trace!(client_id = self.client_id, "Connected");
+                self.state = ClientState::Connected;
+                self.connection.set_connected(self.player_id.clone());
+            }
+            (ClientState::Connected, Packet::Data { payload, .. }) => {
+                self.connection.process_packet(payload);
+            }
+            (ClientState::Disconnected, _) => {}
+            (_, Packet::Disconnect { reason, .. }) => {
+                tracing::debug!(
+                    client_id = self.client_id,
+                    reason,
+                    "Disconnected by the server"
+                );
+                self.state = ClientState::Disconnected;
+                self.connection
+                    .disconnect_with_reason(DisconnectReason::DisconnectedByServer);
+            }
+            _ => {}
+        }
+    }
+
+    fn resend_due(&self) -> bool {
+        match self.last_packet_send_time {
+            Some(sent) => self.current_time >= sent + self.resend_interval,
+            None => true,
+        }
+    }
+
+    fn send(&mut self, packet: &Packet) -> Result<(), TransportError> {
+        let mut buffer = [0u8; TRANSPORT_MAX_PACKET_BYTES];
+        let len = packet.encode(&mut buffer)?;
+        match self.socket.send_to(&buffer[..len], self.server_addr) {
+            Ok(_) => self.packets_sent += 1,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => self.dropped_packets += 1,
+            Err(e) => return Err(e.into()),
+        }
+        self.last_packet_send_time = Some(self.current_time);
+        Ok(())
+    }
+}
+
+// The auth request is an unreliable packet with a single spawn message:
+// `u8 0 | [u8; PLAYER_ID_MAX_BYTES] player id, zero padded | session ticket`
+fn auth_payload(player_id: &str, session_ticket: &str) -> Result<Vec<u8>, TransportError> {
+    if player_id.len() > PLAYER_ID_MAX_BYTES {
+        return Err(TransportServerError::InvalidPlayerId.into());
+    }
+    let mut message = vec![0u8; 1 + PLAYER_ID_MAX_BYTES];
+    message[1..1 + player_id.len()].copy_from_slice(player_id.as_bytes());
+    message.extend_from_slice(session_ticket.as_bytes());
+
+    let packet = ChannelPacket::SmallUnreliable {
+        channel_id: 0,
+        messages: vec![message.into()],
+    };
+    let mut buffer = [0u8; TRANSPORT_MAX_PACKET_BYTES];
+    let len = packet
+        .to_bytes(&mut buffer)
+        .map_err(DisconnectReason::PacketSerialization)?;
+    Ok(buffer[..len].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+    use crate::{
+        constants::TRANSPORT_CONNECTION_TIMEOUT,
+        server::{
+            channel::DefaultChannel,
+            transport::server::server::{ServerConfig, ServerResult, TransportServer},
+        },
+    };
+
+    fn local_socket() -> UdpSocket {
+        UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap()
+    }
+
+    // Answers the packets received by the server socket like the server transport does,
+    // the connection of the client is driven by hand
+    fn pump_server(socket: &UdpSocket, server: &mut TransportServer, connection: &mut UnityClient) {
+        let mut buffer = [0u8; TRANSPORT_MAX_PACKET_BYTES];
+        while let Ok((len, addr)) = socket.recv_from(&mut buffer) {
+            match server.process_packet(addr, &mut buffer[..len]) {
+                ServerResult::PacketToSend { addr, payload } => {
+                    socket.send_to(payload, addr).unwrap();
+                }
+                ServerResult::ClientConnected {
+                    addr,
+                    payload,
+                    player_id,
+                    ..
+                } => {
+                    socket.send_to(payload, addr).unwrap();
+                    connection.set_connected(player_id);
+                }
+                ServerResult::ClientConfirmed {
+                    payload: Some(payload),
+                    ..
+                }
+                | ServerResult::Payload { payload, .. } => connection.process_packet(payload),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn client_connects_and_exchanges_messages_with_transport_server() {
+        let server_socket = local_socket();
+        server_socket.set_nonblocking(true).unwrap();
+        let server_addr = server_socket.local_addr().unwrap();
+        let mut server = TransportServer::new(ServerConfig {
+            current_time: Duration::ZERO,
+            max_clients: 8,
+            public_addresses: vec![server_addr],
+            keep_alive_interval: TRANSPORT_SEND_RATE,
+            connection_timeout: TRANSPORT_CONNECTION_TIMEOUT,
+        });
+        let mut server_connection = UnityClient::new_from_server(ConnectionConfig::default());
+
+        let client_socket = local_socket();
+        let client_addr = client_socket.local_addr().unwrap();
+        let mut client = ClientTransport::new(
+            client_socket,
+            server_addr,
+            7,
+            "bot1",
+            "ticket",
+            ConnectionConfig::default(),
+        )
+        .unwrap();
+        client.set_resend_interval(Duration::from_millis(20));
+
+        let tick = Duration::from_millis(16);
+        let mut step = |client: &mut ClientTransport,
+                        server: &mut TransportServer,
+                        server_connection: &mut UnityClient| {
+            client.update(tick).unwrap();
+            std::thread::sleep(Duration::from_millis(5));
+            server.update(tick);
+            server_connection.update(tick);
+            pump_server(&server_socket, server, server_connection);
+            // Stands in for the session ticket check of the auth service
+            server.authenticate_pending_client(client_addr, "bot1");
+            let mut out = [0u8; TRANSPORT_MAX_PACKET_BYTES];
+            for payload in server_connection.get_packets_to_send() {
+                if let Ok((addr, len)) = server.generate_payload_packet(7, &payload, &mut out) {
+                    server_socket.send_to(&out[..len], addr).unwrap();
+                }
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        };
+
+        for _ in 0..100 {
+            step(&mut client, &mut server, &mut server_connection);
+            if client.is_connected() && server_connection.is_connected() {
+                break;
+            }
+        }
+        assert!(client.is_connected());
+        assert!(server.is_client_connected(7));
+        assert_eq!(server_connection.player_id(), "bot1");
+
+        client
+            .connection_mut()
+            .send_message(DefaultChannel::ReliableOrdered, vec![0u8]);
+        server_connection.send_message(DefaultChannel::ReliableOrdered, b"welcome".to_vec());
+
+        let mut server_received = None;
+        let mut client_received = None;
+        for _ in 0..100 {
+            step(&mut client, &mut server, &mut server_connection);
+            server_received = server_received
+                .or_else(|| server_connection.receive_message(DefaultChannel::ReliableOrdered));
+            client_received = client_received.or_else(|| {
+                client
+                    .connection_mut()
+                    .receive_message(DefaultChannel::ReliableOrdered)
+            });
+            if server_received.is_some() && client_received.is_some() {
+                break;
+            }
+        }
+        assert_eq!(server_received.unwrap().as_ref(), &[0u8]);
+        assert_eq!(client_received.unwrap().as_ref(), b"welcome");
+
+        client.disconnect().unwrap();
+        step(&mut client, &mut server, &mut server_connection);
+        assert!(!server.is_client_connected(7));
+    }
+}
@@ -0,0 +1,473 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::{
+    constants::{DEFAULT_SERVER_PORT, PISTOL_WEAPON_ID},
+    server::{channel::DefaultChannel, connection::ConnectionConfig},
+};
+
+use super::{client::ClientTransport, error::TransportError};
+
+/// Message types of the bot traffic, see `MessageInType`
+const SPAWN_MESSAGE: u8 = 0;
+const MOVE_MESSAGE: u8 = 2;
+const ROTATION_MESSAGE: u8 = 3;
+const FIRE_MESSAGE: u8 = 5;
+
+/// A bot fires once every this many ticks, 5 shots per second at 60 ticks per second.
+const FIRE_INTERVAL_TICKS: u64 = 12;
+
+/// `/proc/<pid>/stat` reports cpu time in clock ticks, which are 100 per second on Linux.
+const CLOCK_TICKS_PER_SECOND: f64 = 100.0;
+
+pub struct LoadTestConfig {
+    pub server_addr: SocketAddr,
+    /// Number of simulated clients, named `player1` to `playerN`
+    pub clients: usize,
+    /// Ticks per second at which every bot sends its traffic
+    pub tick_rate: u32,
+    pub duration: Duration,
+    pub session_ticket: String,
+    /// When set, the cpu usage of this process is included in the report.
+    /// Only supported on Linux.
+    pub server_pid: Option<u32>,
+}
+
+impl Default for LoadTestConfig {
+    fn default() -> Self {
+        Self {
+            server_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DEFAULT_SERVER_PORT),
+            clients: 10,
+            tick_rate: 60,
+            duration: Duration::from_secs(30),
+            session_ticket: String::new(),
+            server_pid: None,
+        }
+    }
+}
+
+impl LoadTestConfig {
+    /// Reads LOAD_TEST_SERVER, LOAD_TEST_CLIENTS, LOAD_TEST_TICK_RATE, LOAD_TEST_DURATION_SECS,
+    /// LOAD_TEST_SESSION_TICKET and LOAD_TEST_SERVER_PID, missing values keep their default.
+    pub fn from_env() -> Self {
+        fn parse_env<T: std::str::FromStr>(name: &str) -> Option<T> {
+            std::env::var(name).ok().and_then(|v| v.parse().ok())
+        }
+
+        let default = Self::default();
+        Self {
+            server_addr: parse_env("LOAD_TEST_SERVER").unwrap_or(default.server_addr),
+            clients: parse_env("LOAD_TEST_CLIENTS").unwrap_or(default.clients),
+            tick_rate: parse_env("LOAD_TEST_TICK_RATE")
+                .filter(|rate| *rate > 0)
+                .unwrap_or(default.tick_rate),
+            duration: parse_env("LOAD_TEST_DURATION_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(default.duration),
+            session_ticket: std::env::var("LOAD_TEST_SESSION_TICKET")
+                .unwrap_or(default.session_ticket),
+            server_pid: parse_env("LOAD_TEST_SERVER_PID"),
+        }
+    }
+
+    pub fn tick_interval(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.tick_rate.max(1) as f64)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadTestReport {
+    pub clients: usize,
+    pub connected_clients: usize,
+    pub elapsed: Duration,
+    pub messages_sent: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    /// Messages not queued because the channel of the bot was full
+    pub dropped_messages: u64,
+    /// Packets not sent because the socket of the bot was saturated
+    pub dropped_packets: u64,
+    /// Average packet loss seen by the connected bots, between 0 and 1
+    pub packet_loss: f64,
+    /// Cpu time of the server over the run, in cores: 1.0 is one core fully used
+    pub server_cpu: Option<f64>,
+}
+
+impl LoadTestReport {
+    pub fn messages_per_second(&self) -> f64 {
+        per_second(self.messages_sent, self.elapsed)
+    }
+
+    pub fn packets_sent_per_second(&self) -> f64 {
+        per_second(self.packets_sent, self.elapsed)
+    }
+
+    pub fn packets_received_per_second(&self) -> f64 {
+        per_second(self.packets_received, self.elapsed)
+    }
+}
+
+impl fmt::Display for LoadTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} clients connected in {:.1}s: {:.0} messages/s, {:.0} packets/s sent, \
+             {:.0} packets/s received, {} dropped messages, {} dropped packets, \
+             {:.2}% packet loss",
+            self.connected_clients,
+            self.clients,
+            self.elapsed.as_secs_f64(),
+            self.messages_per_second(),
+            self.packets_sent_per_second(),
+            self.packets_received_per_second(),
+            self.dropped_messages,
+            self.dropped_packets,
+            self.packet_loss * 100.0,
+        )?;
+        match self.server_cpu {
+            Some(cpu) => write!(f, ", server cpu {:.1}%", cpu * 100.0),
+            None => Ok(()),
+        }
+    }
+}
+
+fn per_second(count: u64, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        return 0.0;
+    }
+    count as f64 / elapsed.as_secs_f64()
+}
+
+/// A simulated player: spawns once connected, then runs in circles while turning and firing.
+struct Bot {
+    transport: ClientTransport,
+    ticks: u64,
+    next_fire_tick: u64,
+    spawned: bool,
+    messages_sent: u64,
+    dropped_messages: u64,
+}
+
+impl Bot {
+    fn send_traffic(&mut self) {
+        if !self.transport.is_connected() {
+            return;
+        }
+        if !self.spawned {
+            self.spawned = true;
+            self.send(vec![SPAWN_MESSAGE]);
+        }
+
+        // Bots start at different angles so they don't all send the same bytes
+        let angle = (self.ticks as f32 * 0.05) + self.transport.client_id() as f32;
+        let (sin, cos) = angle.sin_cos();
+
+        self.send(float_message(MOVE_MESSAGE, &[cos, sin]));
+        // Yaw rotation as a quaternion, `x | y | z | w`
+        let (half_sin, half_cos) = (angle / 2.0).sin_cos();
+        self.send(float_message(
+            ROTATION_MESSAGE,
+            &[0.0, half_sin, 0.0, half_cos],
+        ));
+        if self.ticks >= self.next_fire_tick {
+            self.next_fire_tick += FIRE_INTERVAL_TICKS;
+            let mut message =
+                float_message(FIRE_MESSAGE, &[0.0, 1.6, 0.0, cos, 0.0, sin, 0.3, 1.4, 0.2]);
+            message.push(PISTOL_WEAPON_ID);
+            self.send(message);
+        }
+        self.ticks += 1;
+    }
+
+    fn send(&mut self, message: Vec<u8>) {
+        let connection = self.transport.connection_mut();
+        if !connection.can_send_message(DefaultChannel::Unreliable, message.len()) {
+            self.dropped_messages += 1;
+            return;
+        }
+        connection.send_message(DefaultChannel::Unreliable, message);
+        self.messages_sent += 1;
+    }
+}
+
+fn float_message(message_type: u8, values: &[f32]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(1 + values.len() * 4);
+    message.push(message_type);
+    for value in values {
+        message.write_f32::<LittleEndian>(*value).unwrap();
+    }
+    message
+}
+
+/// Drives N bots against a server, one [`LoadTest::step`] per tick.
+pub struct LoadTest {
+    config: LoadTestConfig,
+    bots: Vec<Bot>,
+    started: Instant,
+    server_cpu_at_start: Option<Duration>,
+}
+
+impl LoadTest {
+    pub fn new(config: LoadTestConfig) -> Result<Self, TransportError> {
+        let bind_addr = match config.server_addr {
+            SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED), 0),
+        };
+
+        let mut bots = Vec::with_capacity(config.clients);
+        for i in 1..=config.clients {
+            let socket = UdpSocket::bind(bind_addr)?;
+            let transport = ClientTransport::new(
+                socket,
+                config.server_addr,
+                i as u64,
+                &format!("player{i}"),
+                &config.session_ticket,
+                ConnectionConfig::default(),
+            )?;
+            bots.push(Bot {
+                transport,
+                ticks: 0,
+                next_fire_tick: 0,
+                spawned: false,
+                messages_sent: 0,
+                dropped_messages: 0,
+            });
+        }
+
+        let server_cpu_at_start = config.server_pid.and_then(process_cpu_time);
+        Ok(Self {
+            config,
+            bots,
+            started: Instant::now(),
+            server_cpu_at_start,
+        })
+    }
+
+    /// Advances every bot by one tick: queues its traffic and exchanges packets with the server.
+    pub fn step(&mut self) -> Result<(), TransportError> {
+        let tick = self.config.tick_interval();
+        for bot in self.bots.iter_mut() {
+            bot.send_traffic();
+            bot.transport.update(tick)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the bots for the configured duration at the configured tick rate, then disconnects them.
+    pub fn run(mut self) -> Result<LoadTestReport, TransportError> {
+        let tick = self.config.tick_interval();
+        let mut next_tick = Instant::now();
+        while self.started.elapsed() < self.config.duration {
+            self.step()?;
+            next_tick += tick;
+            let now = Instant::now();
+            if next_tick > now {
+                std::thread::sleep(next_tick - now);
+            } else {
+                // The bots can't keep up with the tick rate, don't try to catch up
+                next_tick = now;
+            }
+        }
+        let report = self.report();
+        self.disconnect()?;
+        Ok(report)
+    }
+
+    pub fn disconnect(&mut self) -> Result<(), TransportError> {
+        for bot in self.bots.iter_mut() {
+            bot.transport.disconnect()?;
+        }
+        Ok(())
+    }
+
+    pub fn report(&self) -> LoadTestReport {
+        let elapsed = self.started.elapsed();
+        let connected: Vec<&Bot> = self
+            .bots
+            .iter()
+            .filter(|bot| bot.transport.is_connected())
+            .collect();
+        let packet_loss = if connected.is_empty() {
+            0.0
+        } else {
+            connected
+                .iter()
+                .map(|bot| bot.transport.connection().packet_loss())
+                .sum::<f64>()
+                / connected.len() as f64
+        };
+        let server_cpu = match (
+            self.server_cpu_at_start,
+            self.config.server_pid.and_then(process_cpu_time),
+        ) {
+            (Some(start), Some(end)) if !elapsed.is_zero() => {
+                Some(end.saturating_sub(start).as_secs_f64() / elapsed.as_secs_f64())
+            }
+            _ => None,
+        };
+
+        LoadTestReport {
+            clients: self.bots.len(),
+            connected_clients: connected.len(),
+            elapsed,
+            messages_sent: self.bots.iter().map(|bot| bot.messages_sent).sum(),
+            packets_sent: self
+                .bots
+                .iter()
+                .map(|bot| bot.transport.packets_sent())
+                .sum(),
+            packets_received: self
+                .bots
+                .iter()
+                .map(|bot| bot.transport.packets_received())
+                .sum(),
+            dropped_messages: self.bots.iter().map(|bot| bot.dropped_messages).sum(),
+            dropped_packets: self
+                .bots
+                .iter()
+                .map(|bot| bot.transport.dropped_packets())
+                .sum(),
+            packet_loss,
+            server_cpu,
+        }
+    }
+}
+
+/// User and system cpu time of a process, read from `/proc/<pid>/stat`.
+fn process_cpu_time(pid: u32) -> Option<Duration> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // The process name may contain spaces, the fields after it start with the state (field 3)
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some(Duration::from_secs_f64(
+        (utime + stime) as f64 / CLOCK_TICKS_PER_SECOND,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        constants::{
+            TRANSPORT_CONNECTION_TIMEOUT, TRANSPORT_MAX_PACKET_BYTES, TRANSPORT_SEND_RATE,
+        },
+        server::{
+            connection::UnityClient,
+            transport::server::server::{ServerConfig, ServerResult, TransportServer},
+        },
+    };
+
+    #[test]
+    fn smoke_test_bots_connect_and_send_traffic() {
+        let server_socket =
+            UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+        server_socket.set_nonblocking(true).unwrap();
+        let server_addr = server_socket.local_addr().unwrap();
+        let mut server = TransportServer::new(ServerConfig {
+            current_time: Duration::ZERO,
+            max_clients: 8,
+            public_addresses: vec![server_addr],
+            keep_alive_interval: TRANSPORT_SEND_RATE,
+            connection_timeout: TRANSPORT_CONNECTION_TIMEOUT,
+        });
+        let mut connections: HashMap<u64, UnityClient> = HashMap::new();
+        let mut received: HashMap<u64, Vec<u8>> = HashMap::new();
+
+        let mut load_test = LoadTest::new(LoadTestConfig {
+            server_addr,
+            clients: 3,
+            tick_rate: 60,
+            duration: Duration::from_secs(1),
+            session_ticket: "ticket".to_string(),
+            server_pid: Some(std::process::id()),
+        })
+        .unwrap();
+        let bot_addrs: Vec<(SocketAddr, String)> = load_test
+            .bots
+            .iter()
+            .map(|bot| {
+                let id = bot.transport.client_id();
+                let port = bot.transport.local_addr().unwrap().port();
+                (
+                    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port),
+                    format!("player{id}"),
+                )
+            })
+            .collect();
+
+        let tick = load_test.config.tick_interval();
+        let mut buffer = [0u8; TRANSPORT_MAX_PACKET_BYTES];
+        for _ in 0..120 {
+            load_test.step().unwrap();
+            std::thread::sleep(Duration::from_millis(2));
+
+            server.update(tick);
+            while let Ok((len, addr)) = server_socket.recv_from(&mut buffer) {
+                match server.process_packet(addr, &mut buffer[..len]) {
+                    ServerResult::PacketToSend { addr, payload } => {
+                        server_socket.send_to(payload, addr).unwrap();
+                    }
+                    ServerResult::ClientConnected {
+                        client_id,
+                        addr,
+                        payload,
+                        player_id,
+                    } => {
+                        server_socket.send_to(payload, addr).unwrap();
+                        let mut connection =
+                            UnityClient::new_from_server(ConnectionConfig::default());
+                        connection.set_connected(player_id);
+                        connections.insert(client_id, connection);
+                    }
+                    ServerResult::ClientConfirmed {
+                        client_id,
+                        payload: Some(payload),
+                    }
+                    | ServerResult::Payload { client_id, payload } => {
+                        if let Some(connection) = connections.get_mut(&client_id) {
+                            connection.process_packet(payload);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            // Stands in for the session ticket check of the auth service
+            for (addr, player_id) in bot_addrs.iter() {
+                server.authenticate_pending_client(*addr, player_id);
+            }
+            for (client_id, connection) in connections.iter_mut() {
+                connection.update(tick);
+                while let Some(message) = connection.receive_message(DefaultChannel::Unreliable) {
+                    received.entry(*client_id).or_default().push(message[0]);
+                }
+            }
+        }
+
+        let report = load_test.report();
+        assert_eq!(report.clients, 3);
+        assert_eq!(report.connected_clients, 3);
+        assert!(report.messages_sent > 0);
+        assert!(report.packets_sent > 0);
+        assert!(report.packets_received > 0);
+        assert!(report.server_cpu.is_some());
+
+        for client_id in 1..=3 {
+            let types = &received[&client_id];
+            assert_eq!(types[0], SPAWN_MESSAGE);
+            for message_type in [MOVE_MESSAGE, ROTATION_MESSAGE, FIRE_MESSAGE] {
+                assert!(types.contains(&message_type));
+            }
+        }
+
+        load_test.disconnect().unwrap();
+    }
+}
@@ -1,3 +1,10 @@
+pub(crate) mod bind;
+// Only used by bots and integration tests
+#[allow(dead_code)]
+pub(crate) mod client;
 pub(crate) mod error;
+pub(crate) mod load_test;
+pub(crate) mod recording;
+pub(crate) mod sender;
 pub(crate) mod server;
 pub(crate) mod transport;
@@ -0,0 +1,404 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use super::server::{
+    serialize::{read_bytes, read_u16, read_u32, read_u64, read_u8},
+    server::{ServerResult, TransportServer},
+};
+
+/// An inbound datagram as it was received by the transport.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedPacket {
+    /// Transport clock at the time the packet was received
+    pub timestamp: Duration,
+    pub addr: SocketAddr,
+    pub packet: Vec<u8>,
+}
+
+/// Appends every inbound datagram to a length-delimited log file.
+///
+/// Each record is `u32 record_len | u64 timestamp_micros | u8 ip_version | ip bytes | u16 port | packet`,
+/// little endian. When the file would grow past `max_file_bytes` it is renamed to `<path>.<n>`
+/// and a fresh file is started at `path`. The suffixes continue after the ones of earlier runs,
+/// which are never overwritten.
+#[derive(Debug)]
+pub struct PacketRecorder {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    file_bytes: u64,
+    max_file_bytes: u64,
+    rotations: u32,
+}
+
+impl PacketRecorder {
+    pub fn new<P: AsRef<Path>>(path: P, max_file_bytes: u64) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let file_bytes = file.metadata()?.len();
+        let rotations = last_rotation(&path)?;
+
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
+            file_bytes,
+            max_file_bytes,
+            rotations,
+        })
+    }
+
+    pub fn record(
+        &mut self,
+        timestamp: Duration,
+        addr: SocketAddr,
+        packet: &[u8],
+    ) -> io::Result<()> {
+        let mut record: Vec<u8> = Vec::with_capacity(packet.len() + 35);
+        record.extend_from_slice(&(timestamp.as_micros() as u64).to_le_bytes());
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                record.push(4);
+                record.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                record.push(6);
+                record.extend_from_slice(&ip.octets());
+            }
+        }
+        record.extend_from_slice(&addr.port().to_le_bytes());
+        record.extend_from_slice(packet);
+
+        let record_bytes = 4 + record.len() as u64;
+        if self.file_bytes > 0 && self.file_bytes + record_bytes > self.max_file_bytes {
+            self.rotate()?;
+        }
+
+        self.writer
+            .write_all(&(record.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&record)?;
+        self.file_bytes += record_bytes;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.rotations += 1;
+        let mut rotated_path = self.path.clone().into_os_string();
+        rotated_path.push(format!(".{}", self.rotations));
+        fs::rename(&self.path, rotated_path)?;
+
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&self.path)?;
+        self.writer = BufWriter::new(file);
+        self.file_bytes = 0;
+
+        Ok(())
+    }
+}
+
+// Highest `<n>` of the `<path>.<n>` files already next to `path`, 0 if there are none
+fn last_rotation(path: &Path) -> io::Result<u32> {
+    let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+        return Ok(0);
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = format!("{file_name}.");
+    let mut last = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let rotation = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix(&prefix))
+            .and_then(|suffix| suffix.parse::<u32>().ok());
+        if let Some(rotation) = rotation {
+            last = last.max(rotation);
+        }
+    }
+    Ok(last)
+}
+
+impl Drop for PacketRecorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            tracing::error!("Failed to flush packet recording: {e}");
+        }
+    }
+}
+
+/// Reads all packets of a single recording file.
+pub fn read_recording<P: AsRef<Path>>(path: P) -> io::Result<Vec<RecordedPacket>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut packets = vec![];
+
+    loop {
+        let record_len = match read_u32(&mut reader) {
+            Ok(len) => len as usize,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        let mut record = vec![0u8; record_len];
+        reader.read_exact(&mut record)?;
+
+        let cursor = &mut io::Cursor::new(record.as_slice());
+        let timestamp = Duration::from_micros(read_u64(cursor)?);
+        let ip = match read_u8(cursor)? {
+            4 => IpAddr::V4(Ipv4Addr::from(read_bytes::<4>(cursor)?)),
+            6 => IpAddr::V6(Ipv6Addr::from(read_bytes::<16>(cursor)?)),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid ip version in recording",
+                ))
+            }
+        };
+        let port = read_u16(cursor)?;
+        let packet = record[cursor.position() as usize..].to_vec();
+
+        packets.push(RecordedPacket {
+            timestamp,
+            addr: SocketAddr::new(ip, port),
+            packet,
+        });
+    }
+
+    Ok(packets)
+}
+
+/// Feeds a recording into `server`, advancing its clock to each packet timestamp before
+/// processing it. Every [`ServerResult`] is handed to `on_result`.
+/// Authentication runs against an outside service and isn't replayed, the clients it let in
+/// are added to `server` beforehand.
+pub fn replay_recording<F>(
+    mut server: TransportServer,
+    packets: &[RecordedPacket],
+    mut on_result: F,
+) where
+    F: FnMut(ServerResult),
+{
+    for recorded in packets {
+        if recorded.timestamp > server.current_time() {
+            server.update(recorded.timestamp - server.current_time());
+        }
+        let mut packet = recorded.packet.clone();
+        on_result(server.process_packet(recorded.addr, &mut packet));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{
+            TRANSPORT_CONNECTION_TIMEOUT, TRANSPORT_MAX_PACKET_BYTES, TRANSPORT_SEND_RATE,
+        },
+        server::transport::server::{packet::Packet, server::ServerConfig},
+    };
+
+    fn connection_request(client_id: u64) -> Vec<u8> {
+        let mut packet = vec![85, b'M', b'T', b'A', 1];
+        packet.extend_from_slice(&client_id.to_le_bytes());
+        packet
+    }
+
+    fn data(client_id: u64, payload: &[u8]) -> Vec<u8> {
+        let mut buffer = [0u8; TRANSPORT_MAX_PACKET_BYTES];
+        let len = Packet::Data {
+            client_identifier: client_id,
+            payload,
+        }
+        .encode(&mut buffer)
+        .unwrap();
+        buffer[..len].to_vec()
+    }
+
+    fn server_config() -> ServerConfig {
+        ServerConfig {
+            current_time: Duration::ZERO,
+            max_clients: 8,
+            public_addresses: vec!["127.0.0.1:5000".parse().unwrap()],
+            keep_alive_interval: TRANSPORT_SEND_RATE,
+            connection_timeout: TRANSPORT_CONNECTION_TIMEOUT,
+        }
+    }
+
+    #[test]
+    fn replay_reconstructs_server_results() {
+        let path = std::env::temp_dir().join(format!("replay_{}.rec", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let traffic: Vec<(Duration, SocketAddr, Vec<u8>)> = vec![
+            (
+                Duration::from_millis(10),
+                "127.0.0.1:6001".parse().unwrap(),
+                connection_request(1),
+            ),
+            (
+                Duration::from_millis(20),
+                "[::1]:6002".parse().unwrap(),
+                connection_request(2),
+            ),
+            // Repeated request from the same pending client
+            (
+                Duration::from_millis(30),
+                "127.0.0.1:6001".parse().unwrap(),
+                connection_request(1),
+            ),
+            (
+                Duration::from_millis(40),
+                "127.0.0.1:6003".parse().unwrap(),
+                vec![3, 0, 0, 0, 0, 0, 0, 0, 9],
+            ),
+        ];
+
+        let mut live_results = vec![];
+        let mut recorder = PacketRecorder::new(&path, 1024 * 1024).unwrap();
+        let mut server = TransportServer::new(server_config());
+        for (timestamp, addr, packet) in traffic.iter() {
+            server.update(*timestamp - server.current_time());
+            recorder.record(*timestamp, *addr, packet).unwrap();
+            let mut packet = packet.clone();
+            live_results.push(format!("{:?}", server.process_packet(*addr, &mut packet)));
+        }
+        drop(recorder);
+
+        let recording = read_recording(&path).unwrap();
+        assert_eq!(recording.len(), traffic.len());
+        assert_eq!(recording[1].addr, traffic[1].1);
+
+        let mut replayed_results = vec![];
+        replay_recording(
+            TransportServer::new(server_config()),
+            &recording,
+            |result| replayed_results.push(format!("{:?}", result)),
+        );
+        assert_eq!(replayed_results, live_results);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replay_reconstructs_data_of_authenticated_clients() {
+        let path = std::env::temp_dir().join(format!("replay_data_{}.rec", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let addr: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let authenticated_server = || {
+            let mut server = TransportServer::new(server_config());
+            server.insert_authenticated_client(1, addr, "player1");
+            server
+        };
+
+        let traffic = [data(1, &[0]), data(1, &[7]), data(1, &[8])];
+        let mut live_results = vec![];
+        let mut recorder = PacketRecorder::new(&path, 1024 * 1024).unwrap();
+        let mut server = authenticated_server();
+        for (i, packet) in traffic.iter().enumerate() {
+            let timestamp = Duration::from_millis(10 * (i as u64 + 1));
+            server.update(timestamp - server.current_time());
+            recorder.record(timestamp, addr, packet).unwrap();
+            let mut packet = packet.clone();
+            live_results.push(format!("{:?}", server.process_packet(addr, &mut packet)));
+        }
+        drop(recorder);
+
+        let mut replayed_results = vec![];
+        let mut payloads = vec![];
+        replay_recording(
+            authenticated_server(),
+            &read_recording(&path).unwrap(),
+            |result| {
+                replayed_results.push(format!("{:?}", result));
+                match result {
+                    ServerResult::ClientConfirmed {
+                        payload: Some(payload),
+                        ..
+                    }
+                    | ServerResult::Payload { payload, .. } => payloads.push(payload.to_vec()),
+                    _ => {}
+                }
+            },
+        );
+        assert_eq!(replayed_results, live_results);
+        assert_eq!(payloads, vec![vec![7], vec![8]]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recorder_rotates_by_size() {
+        let path = std::env::temp_dir().join(format!("rotate_{}.rec", std::process::id()));
+        let mut rotated_path = path.clone().into_os_string();
+        rotated_path.push(".1");
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&rotated_path);
+
+        let addr: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        // Each record is 4 + 8 + 1 + 4 + 2 + 13 = 32 bytes
+        let mut recorder = PacketRecorder::new(&path, 64).unwrap();
+        for i in 0..3 {
+            recorder
+                .record(Duration::from_millis(i), addr, &connection_request(i))
+                .unwrap();
+        }
+        drop(recorder);
+
+        assert_eq!(read_recording(&rotated_path).unwrap().len(), 2);
+        let current = read_recording(&path).unwrap();
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0].packet, connection_request(2));
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&rotated_path).unwrap();
+    }
+
+    #[test]
+    fn rotation_keeps_the_files_of_earlier_runs() {
+        let dir = std::env::temp_dir().join(format!("rotate_runs_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("packets.rec");
+        let addr: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+
+        // Two runs each rotating once, records are 32 bytes and a file holds two
+        for run in 0..2 {
+            let mut recorder = PacketRecorder::new(&path, 64).unwrap();
+            for i in 0..3 {
+                recorder
+                    .record(Duration::from_millis(i), addr, &connection_request(run))
+                    .unwrap();
+            }
+            drop(recorder);
+        }
+
+        let rotated = |n: u32| read_recording(dir.join(format!("packets.rec.{n}"))).unwrap();
+        let packets = |recording: Vec<RecordedPacket>| -> Vec<Vec<u8>> {
+            recording
+                .into_iter()
+                .map(|recorded| recorded.packet)
+                .collect()
+        };
+        assert_eq!(
+            packets(rotated(1)),
+            vec![connection_request(0), connection_request(0)]
+        );
+        // The second run rotated to the next suffix instead of replacing the first file
+        assert_eq!(
+            packets(rotated(2)),
+            vec![connection_request(0), connection_request(1)]
+        );
+        assert!(!dir.join("packets.rec.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
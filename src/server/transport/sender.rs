@@ -0,0 +1,191 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::{SocketAddr, UdpSocket},
+};
+
+use crate::logging::LogLimiter;
+
+/// The sending half of a datagram socket, implemented by [`UdpSocket`].
+pub trait DatagramSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+}
+
+impl DatagramSocket for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+}
+
+#[derive(Debug)]
+struct QueuedPacket {
+    addr: SocketAddr,
+    payload: Vec<u8>,
+    retries: u32,
+}
+
+/// Sends packets on a nonblocking socket.
+///
+/// A send that would block is queued and retried on every [`PacketSender::flush`], the packet
+/// is dropped once it was retried `max_retries` times or when the queue is full.
+#[derive(Debug)]
+pub struct PacketSender<S: DatagramSocket = UdpSocket> {
+    socket: S,
+    queue: VecDeque<QueuedPacket>,
+    max_queued_packets: usize,
+    max_retries: u32,
+    dropped_sends: u64,
+    dropped_send_log: LogLimiter,
+}
+
+impl<S: DatagramSocket> PacketSender<S> {
+    pub fn new(socket: S, max_queued_packets: usize, max_retries: u32) -> Self {
+        Self {
+            socket,
+            queue: VecDeque::new(),
+            max_queued_packets,
+            max_retries,
+            dropped_sends: 0,
+            dropped_send_log: LogLimiter::default(),
+        }
+    }
+
+    /// Returns the number of bytes sent, or None when the packet was queued or dropped.
+    pub fn send_to(&mut self, payload: &[u8], addr: SocketAddr) -> Option<usize> {
+        match self.socket.send_to(payload, addr) {
+            Ok(len) => Some(len),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if self.queue.len() >= self.max_queued_packets {
+                    if let Some(suppressed) = self.dropped_send_log.admit() {
+                        tracing::warn!(
+                            suppressed,
+                            "Dropped packet to {addr}, the send queue is full"
+                        );
+                    }
+                    self.dropped_sends += 1;
+                } else {
+                    self.queue.push_back(QueuedPacket {
+                        addr,
+                        payload: payload.to_vec(),
+                        retries: 0,
+                    });
+                }
+                None
+            }
+            Err(e) => {
+                tracing::error!("Failed to send packet to {addr}: {e}");
+                self.dropped_sends += 1;
+                None
+            }
+        }
+    }
+
+    /// Retries the queued packets.
+    /// Returns the number of packets and bytes sent.
+    pub fn flush(&mut self) -> (u64, u64) {
+        let mut packets_sent = 0;
+        let mut bytes_sent = 0;
+        for _ in 0..self.queue.len() {
+            let Some(mut queued) = self.queue.pop_front() else {
+                break;
+            };
+            match self.socket.send_to(&queued.payload, queued.addr) {
+                Ok(len) => {
+                    packets_sent += 1;
+                    bytes_sent += len as u64;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    queued.retries += 1;
+                    if queued.retries >= self.max_retries {
+                        tracing::warn!(
+                            "Dropped packet to {}, the socket was still blocked after {} retries",
+                            queued.addr,
+                            queued.retries
+                        );
+                        self.dropped_sends += 1;
+                    } else {
+                        self.queue.push_back(queued);
+                    }
+                }
+                Err(e) => {
+                    tracing::error!("Failed to send packet to {}: {e}", queued.addr);
+                    self.dropped_sends += 1;
+                }
+            }
+        }
+        (packets_sent, bytes_sent)
+    }
+
+    pub fn queued_packets(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Packets that were never sent, because of a send error or a socket that stayed blocked.
+    pub fn dropped_sends(&self) -> u64 {
+        self.dropped_sends
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use super::*;
+
+    /// Returns `WouldBlock` for the first `blocked_sends` sends.
+    #[derive(Default)]
+    struct BlockingSocket {
+        blocked_sends: Cell<usize>,
+        sent: RefCell<Vec<(SocketAddr, Vec<u8>)>>,
+    }
+
+    impl DatagramSocket for BlockingSocket {
+        fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+            if self.blocked_sends.get() > 0 {
+                self.blocked_sends.set(self.blocked_sends.get() - 1);
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.sent.borrow_mut().push((addr, buf.to_vec()));
+            Ok(buf.len())
+        }
+    }
+
+    fn blocking_sender(blocked_sends: usize) -> PacketSender<BlockingSocket> {
+        let socket = BlockingSocket::default();
+        socket.blocked_sends.set(blocked_sends);
+        PacketSender::new(socket, 8, 3)
+    }
+
+    #[test]
+    fn would_block_is_retried_until_sent() {
+        let addr: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let mut sender = blocking_sender(2);
+
+        assert_eq!(sender.send_to(&[1, 2, 3], addr), None);
+        assert_eq!(sender.queued_packets(), 1);
+
+        // Still blocked on the first retry
+        assert_eq!(sender.flush(), (0, 0));
+        assert_eq!(sender.queued_packets(), 1);
+
+        assert_eq!(sender.flush(), (1, 3));
+        assert_eq!(sender.queued_packets(), 0);
+        assert_eq!(sender.dropped_sends(), 0);
+        assert_eq!(*sender.socket.sent.borrow(), vec![(addr, vec![1, 2, 3])]);
+    }
+
+    #[test]
+    fn packet_is_dropped_after_retry_limit() {
+        let addr: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let mut sender = blocking_sender(usize::MAX);
+
+        sender.send_to(&[1], addr);
+        for _ in 0..3 {
+            sender.flush();
+        }
+
+        assert_eq!(sender.queued_packets(), 0);
+        assert_eq!(sender.dropped_sends(), 1);
+        assert!(sender.socket.sent.borrow().is_empty());
+    }
+}
@@ -1,6 +1,9 @@
 use std::{error, fmt, io};
 
-use crate::{constants::TRANSPORT_MAX_PAYLOAD_BYTES, server::error::DisconnectReason};
+use crate::{
+    constants::{PLAYER_ID_MAX_BYTES, TRANSPORT_MAX_PAYLOAD_BYTES},
+    server::error::DisconnectReason,
+};
 
 // allow dead code because we have some unused message types
 #[allow(dead_code)]
@@ -10,6 +13,8 @@ pub enum TransportServerError {
     InvalidPacketType,
     /// Invalid player id in connect packet
     InvalidPlayerId,
+    /// Player id longer than [`PLAYER_ID_MAX_BYTES`]
+    PlayerIdTooLong(String),
     /// Invalid session ticket in connect packet
     InvalidSessionTicket,
     /// Packet size is too small to be a netcode packet.
@@ -41,6 +46,10 @@ impl fmt::Display for TransportServerError {
         match *self {
             InvalidPacketType => write!(fmt, "invalid packet type"),
             InvalidPlayerId => write!(fmt, "invalid player_id bytes to deserialize"),
+            PlayerIdTooLong(ref player_id) => write!(
+                fmt,
+                "player id {player_id:?} is longer than {PLAYER_ID_MAX_BYTES} bytes"
+            ),
             InvalidSessionTicket => write!(fmt, "invalid session ticket bytes to deserialize"),
             PacketTooSmall => write!(fmt, "packet is too small"),
             PayloadAboveLimit => write!(
@@ -50,7 +59,7 @@ impl fmt::Display for TransportServerError {
             ),
             Expired => write!(fmt, "connection expired"),
             DuplicatedSequence => write!(fmt, "sequence already received"),
-            Disconnected(reason) => write!(fmt, "disconnected: {}", reason),
+            Disconnected(ref reason) => write!(fmt, "disconnected: {}", reason),
             NoMoreServers => write!(fmt, "client has no more servers to connect"),
             NotInHostList => write!(fmt, "token does not contain the server address"),
             ClientNotFound => write!(fmt, "client was not found"),
@@ -1,18 +1,21 @@
 use std::io::{self, Cursor, Write};
 
+use crate::{constants::PLAYER_ID_MAX_BYTES, ecs::components::MovementConfig};
+
 use super::{error::TransportServerError, serialize::*};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u8)]
 pub enum PacketType {
     ConnectionRequest = 85,
     Data = 1,
     Disconnect = 2,
     KeepAlive = 3,
+    Control = 4,
     CreateSession = 100,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq)]
 #[allow(clippy::large_enum_variant)] // TODO: Consider boxing types
 pub enum Packet<'a> {
     ConnectionRequest {
@@ -27,13 +30,29 @@ pub enum Packet<'a> {
         client_identifier: u64,
         payload: &'a [u8],
     },
+    /// Out-of-band payload of a connected client, e.g. ready or chat opt-in, which the session
+    /// routes on its `kind` tag instead of decoding it as channel packets like [`Packet::Data`].
+    Control {
+        client_identifier: u64,
+        kind: u8,
+        payload: &'a [u8],
+    },
+    /// A missing reason byte reads as 0, see [`DisconnectReason::code`](crate::server::error::DisconnectReason::code)
     Disconnect {
         client_identifier: u64,
+        reason: u8,
     },
+    /// Optionally followed by `f32 gravity | f32 jump_speed | f32 velocity_mul`,
+    /// the defaults of [`MovementConfig`] are used when they are missing.
+    /// An optional `u64 seed` for the session RNG comes next, then an optional `u16 tick_rate`
+    /// in ticks per second, which can only be given after a seed.
     CreateSession {
         client_identifier: u64,
         session_id: u32,
         player_ids: Vec<String>,
+        movement_config: MovementConfig,
+        seed: Option<u64>,
+        tick_rate: Option<u16>,
     },
 }
 
@@ -45,6 +64,7 @@ impl PacketType {
             1 => Data,
             3 => KeepAlive,
             2 => Disconnect,
+            4 => Control,
             85 => ConnectionRequest,
             100 => CreateSession,
             _ => return Err(TransportServerError::InvalidPacketType),
@@ -59,6 +79,7 @@ impl PacketType {
             Data => 1,
             KeepAlive => 3,
             Disconnect => 2,
+            Control => 4,
             ConnectionRequest => 85,
             CreateSession => 100,
         };
@@ -72,6 +93,7 @@ impl<'a> Packet<'a> {
             Packet::ConnectionRequest { .. } => PacketType::ConnectionRequest,
             Packet::KeepAlive { .. } => PacketType::KeepAlive,
             Packet::Data { .. } => PacketType::Data,
+            Packet::Control { .. } => PacketType::Control,
             Packet::Disconnect { .. } => PacketType::Disconnect,
             Packet::CreateSession { .. } => PacketType::CreateSession,
         }
@@ -102,13 +124,29 @@ impl<'a> Packet<'a> {
                 let _ = writer.write_all(&client_identifier.to_le_bytes());
                 writer.write_all(payload)?;
             }
-            Packet::Disconnect { client_identifier } => {
+            Packet::Control {
+                client_identifier,
+                kind,
+                payload,
+            } => {
+                writer.write_all(&client_identifier.to_le_bytes())?;
+                writer.write_all(&[*kind])?;
+                writer.write_all(payload)?;
+            }
+            Packet::Disconnect {
+                client_identifier,
+                reason,
+            } => {
                 let _ = writer.write_all(&client_identifier.to_le_bytes());
+                writer.write_all(&[*reason])?;
             }
             Packet::CreateSession {
                 client_identifier,
                 session_id,
                 player_ids,
+                movement_config,
+                seed,
+                tick_rate,
             } => {
                 let _ = writer.write_all(&client_identifier.to_le_bytes());
                 let _ = writer.write_all(&session_id.to_le_bytes());
@@ -117,6 +155,15 @@ impl<'a> Packet<'a> {
                 for player_id in player_ids {
                     let _ = writer.write_all(&player_id.as_bytes());
                 }
+                writer.write_all(&movement_config.gravity.to_le_bytes())?;
+                writer.write_all(&movement_config.jump_speed.to_le_bytes())?;
+                writer.write_all(&movement_config.velocity_mul.to_le_bytes())?;
+                if let Some(seed) = seed {
+                    writer.write_all(&seed.to_le_bytes())?;
+                    if let Some(tick_rate) = tick_rate {
+                        writer.write_all(&tick_rate.to_le_bytes())?;
+                    }
+                }
             }
         }
 
@@ -136,6 +183,17 @@ impl<'a> Packet<'a> {
                     payload,
                 })
             }
+            PacketType::Control => {
+                let client_identifier = read_u64(cursor)?;
+                let kind = read_u8(cursor)?;
+
+                let payload = &src[cursor.position() as usize..];
+                Ok(Packet::Control {
+                    client_identifier,
+                    kind,
+                    payload,
+                })
+            }
             PacketType::ConnectionRequest => {
                 let connection_prefix = read_bytes(cursor)?;
                 let connection_side_id = read_u8(cursor)?;
@@ -153,16 +211,20 @@ impl<'a> Packet<'a> {
             }
             PacketType::Disconnect => {
                 let client_identifier = read_u64(cursor)?;
-                Ok(Packet::Disconnect { client_identifier })
+                let reason = read_u8(cursor).unwrap_or_default();
+                Ok(Packet::Disconnect {
+                    client_identifier,
+                    reason,
+                })
             }
             PacketType::CreateSession => {
                 let client_identifier = read_u64(cursor)?;
                 let session_id = read_u32(cursor)?;
                 let players_length = read_u16(cursor)?;
                 tracing::info!("players_length: {}", players_length);
-                let player_ids: Vec<[u8; 16]> = (0..players_length)
+                let player_ids: Vec<[u8; PLAYER_ID_MAX_BYTES]> = (0..players_length)
                     .map(|_| read_bytes(cursor))
-                    .collect::<Result<Vec<[u8; 16]>, _>>()
+                    .collect::<Result<Vec<[u8; PLAYER_ID_MAX_BYTES]>, _>>()
                     .expect("Failed to read player IDs");
 
                 // convert player_ids from [u8; 16] to utf8 Strings, trimming only trailing null bytes
@@ -174,10 +236,39 @@ impl<'a> Packet<'a> {
                     })
                     .collect();
 
+                let remaining = src.len() as u64 - cursor.position();
+                let movement_config = if remaining >= 12 {
+                    MovementConfig {
+                        gravity: read_f32(cursor)?,
+                        jump_speed: read_f32(cursor)?,
+                        velocity_mul: read_f32(cursor)?,
+                    }
+                } else {
+                    MovementConfig::default()
+                };
+
+                let remaining = src.len() as u64 - cursor.position();
+                let seed = if remaining >= 8 {
+                    Some(read_u64(cursor)?)
+                } else {
+                    None
+                };
+
+                // A rate of 0 keeps the default one
+                let remaining = src.len() as u64 - cursor.position();
+                let tick_rate = if seed.is_some() && remaining >= 2 {
+                    Some(read_u16(cursor)?).filter(|&tick_rate| tick_rate > 0)
+                } else {
+                    None
+                };
+
                 Ok(Packet::CreateSession {
                     client_identifier,
                     session_id,
                     player_ids,
+                    movement_config,
+                    seed,
+                    tick_rate,
                 })
             }
         }
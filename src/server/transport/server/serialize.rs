@@ -28,6 +28,13 @@ pub fn read_u8(src: &mut impl io::Read) -> Result<u8, io::Error> {
     Ok(u8::from_le_bytes(buffer))
 }
 
+#[inline]
+pub fn read_f32(src: &mut impl io::Read) -> Result<f32, io::Error> {
+    let mut buffer = [0u8; 4];
+    src.read_exact(&mut buffer)?;
+    Ok(f32::from_le_bytes(buffer))
+}
+
 #[inline]
 pub fn read_bytes<const N: usize>(src: &mut impl io::Read) -> Result<[u8; N], io::Error> {
     let mut data = [0u8; N];
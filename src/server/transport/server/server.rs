@@ -1,22 +1,30 @@
 use std::{
     collections::HashMap,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     sync::{Arc, Mutex},
     time::Duration,
 };
 
+use serde::Deserialize;
+
 use crate::{
     constants::{
-        TRANSPORT_MAX_CLIENTS, TRANSPORT_MAX_PACKET_BYTES, TRANSPORT_MAX_PENDING_CLIENTS,
-        TRANSPORT_SEND_RATE,
+        PLAYER_ID_MAX_BYTES, TRANSPORT_CONNECTION_PREFIX, TRANSPORT_CONNECTION_TIMEOUT,
+        TRANSPORT_DISCONNECT_FLUSH_WINDOW, TRANSPORT_MAX_CLIENTS, TRANSPORT_MAX_PACKET_BYTES,
+        TRANSPORT_MAX_PENDING_CLIENTS, TRANSPORT_SEND_RATE,
+    },
+    ecs::components::MovementConfig,
+    logging::LogLimiter,
+    server::{
+        error::DisconnectReason,
+        transport::server::packet::{Packet, PacketType},
     },
-    server::transport::server::packet::Packet,
 };
 
 use super::error::TransportServerError;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum ConnectionState {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConnectionState {
     Disconnected,
     PendingResponse,
     Authenticating,
@@ -29,29 +37,102 @@ struct Connection {
     client_id: u64,
     state: ConnectionState,
     is_authenticated: Arc<Mutex<(bool, String)>>,
+    // The auth Data payload, the client resends it until it receives a KeepAlive.
+    // Cleared once the connection is confirmed, later identical payloads are game data.
+    auth_payload: Vec<u8>,
     // TODO MAYBE user_data: [u8; NETCODE_USER_DATA_BYTES],
     addr: SocketAddr,
     last_packet_received_time: Duration,
     last_packet_send_time: Duration,
+    keep_alive_interval: Duration,
     timeout_seconds: i32,
     expire_timestamp: u64,
 }
 
+/// What happens when a client authenticates with the player id of an already connected client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicatePlayerPolicy {
+    /// The new client is sent a disconnect packet, the connected one stays
+    RejectNew,
+    /// The connected client is disconnected, the new one connects on its next data packet
+    #[default]
+    KickOld,
+}
+
+/// Packet types accepted from a client in each [`ConnectionState`], the others are dropped and
+/// counted, see [`TransportServer::unexpected_packets`]. Addresses without a connection are in
+/// the `Disconnected` state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketPolicy {
+    pub disconnected: Vec<PacketType>,
+    pub pending_response: Vec<PacketType>,
+    pub authenticating: Vec<PacketType>,
+    pub connected: Vec<PacketType>,
+}
+
+impl PacketPolicy {
+    pub fn allowed(&self, state: ConnectionState) -> &[PacketType] {
+        match state {
+            ConnectionState::Disconnected => &self.disconnected,
+            ConnectionState::PendingResponse => &self.pending_response,
+            ConnectionState::Authenticating => &self.authenticating,
+            ConnectionState::Connected => &self.connected,
+        }
+    }
+
+    pub fn allows(&self, state: ConnectionState, packet_type: PacketType) -> bool {
+        self.allowed(state).contains(&packet_type)
+    }
+}
+
+impl Default for PacketPolicy {
+    /// The packets of the handshake, then data and keep alives once connected.
+    /// A client may leave in any state.
+    fn default() -> Self {
+        use PacketType::*;
+
+        Self {
+            disconnected: vec![ConnectionRequest, CreateSession, Disconnect],
+            pending_response: vec![ConnectionRequest, Data, Disconnect],
+            authenticating: vec![ConnectionRequest, Data, Disconnect],
+            connected: vec![Data, Control, KeepAlive, Disconnect],
+        }
+    }
+}
+
 /// A server that can generate packets from connect clients, that are encrypted, or process
 /// incoming encrypted packets from clients. The server is agnostic from the transport layer, only
 /// consuming and generating bytes that can be transported in any way desired.
 #[derive(Debug)]
 pub struct TransportServer {
-    clients: Box<[Option<Connection>]>,
+    // Grows and shrinks with `max_clients`, see `TransportServer::set_max_clients`
+    clients: Vec<Option<Connection>>,
     pending_clients: HashMap<SocketAddr, Connection>,
     max_clients: usize,
     public_addresses: Vec<SocketAddr>,
+    duplicate_player_policy: DuplicatePlayerPolicy,
+    packet_policy: PacketPolicy,
+    // Packets dropped by the packet policy, a steady count points at a misbehaving client
+    unexpected_packets: u64,
+    unexpected_packet_log: LogLimiter,
+    invalid_packet_log: LogLimiter,
+    keep_alive_interval: Duration,
+    connection_timeout: Duration,
+    // PlayFab credentials, read from the environment on each authentication when not set
+    auth_config: Option<AuthConfig>,
+    flush_on_disconnect: bool,
+    // Address and expire time of the clients that left, while their last packets can be sent
+    flushing_clients: HashMap<u64, (SocketAddr, Duration)>,
+    // Addresses refused at connection request, with the expire time of temporary bans
+    banned_ips: HashMap<IpAddr, Option<Duration>>,
     current_time: Duration,
+    // Shared by the packets of every ServerResult, each result must be consumed before the next
+    // call. Payload packets are written to a buffer of the caller instead, so several can be kept
     out: [u8; TRANSPORT_MAX_PACKET_BYTES],
 }
 
 /// Result from processing an packet in the server
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq)]
 pub enum ServerResult<'a, 's> {
     /// Nothing needs to be done.
     None,
@@ -61,8 +142,11 @@ pub enum ServerResult<'a, 's> {
         payload: &'s mut [u8],
     },
     /// A payload received from the client.
-    Payload {
+    Payload { client_id: u64, payload: &'a [u8] },
+    /// A control payload received from the client, tagged with its kind.
+    Control {
         client_id: u64,
+        kind: u8,
         payload: &'a [u8],
     },
     /// A new client has connected
@@ -72,6 +156,12 @@ pub enum ServerResult<'a, 's> {
         payload: &'s mut [u8],
         player_id: String,
     },
+    /// The client sent its first packet after connecting, the connection is fully established.
+    /// Carries the payload when the confirming packet was a data packet.
+    ClientConfirmed {
+        client_id: u64,
+        payload: Option<&'a [u8]>,
+    },
     /// The client connection has been terminated.
     ClientDisconnected {
         client_id: u64,
@@ -81,15 +171,113 @@ pub enum ServerResult<'a, 's> {
     CreateSession {
         id: u32,
         player_ids: Vec<String>,
+        movement_config: MovementConfig,
+        seed: Option<u64>,
+        tick_rate: Option<u16>,
     },
 }
 
+impl<'a, 's> ServerResult<'a, 's> {
+    // Maps the payload written to `TransportServer::out`, the other payloads are left as is
+    fn map_out<'t>(self, f: impl FnOnce(&'s mut [u8]) -> &'t mut [u8]) -> ServerResult<'a, 't> {
+        match self {
+            ServerResult::None => ServerResult::None,
+            ServerResult::PacketToSend { addr, payload } => ServerResult::PacketToSend {
+                addr,
+                payload: f(payload),
+            },
+            ServerResult::Payload { client_id, payload } => {
+                ServerResult::Payload { client_id, payload }
+            }
+            ServerResult::Control {
+                client_id,
+                kind,
+                payload,
+            } => ServerResult::Control {
+                client_id,
+                kind,
+                payload,
+            },
+            ServerResult::ClientConnected {
+                client_id,
+                addr,
+                payload,
+                player_id,
+            } => ServerResult::ClientConnected {
+                client_id,
+                addr,
+                payload: f(payload),
+                player_id,
+            },
+            ServerResult::ClientConfirmed { client_id, payload } => {
+                ServerResult::ClientConfirmed { client_id, payload }
+            }
+            ServerResult::ClientDisconnected {
+                client_id,
+                addr,
+                payload,
+            } => ServerResult::ClientDisconnected {
+                client_id,
+                addr,
+                payload: payload.map(f),
+            },
+            ServerResult::CreateSession {
+                id,
+                player_ids,
+                movement_config,
+                seed,
+                tick_rate,
+            } => ServerResult::CreateSession {
+                id,
+                player_ids,
+                movement_config,
+                seed,
+                tick_rate,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct ServerConfig {
     pub current_time: Duration,
     /// Maximum numbers of clients that can be connected at a time
     pub max_clients: usize,
     /// Publicly available addresses to which clients will attempt to connect.
     pub public_addresses: Vec<SocketAddr>,
+    /// How long a connected client may go without a packet from the server before
+    /// a KeepAlive is sent. Default: [`TRANSPORT_SEND_RATE`]
+    pub keep_alive_interval: Duration,
+    /// How long a client may go without sending a packet before it's disconnected, pending
+    /// clients must finish the handshake within it. Default: [`TRANSPORT_CONNECTION_TIMEOUT`]
+    pub connection_timeout: Duration,
+}
+
+/// Credentials of the PlayFab server API that authenticates the session tickets of clients.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    pub playfab_api_url: String,
+    pub playfab_api_key: String,
+}
+
+impl AuthConfig {
+    /// Reads PLAYFAB_API_URL and PLAYFAB_API_KEY, panics when one is missing.
+    pub fn from_env() -> Self {
+        Self {
+            playfab_api_url: std::env::var("PLAYFAB_API_URL").unwrap(),
+            playfab_api_key: std::env::var("PLAYFAB_API_KEY").unwrap(),
+        }
+    }
+}
+
+// The key stays out of the logs
+impl std::fmt::Debug for AuthConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthConfig")
+            .field("playfab_api_url", &self.playfab_api_url)
+            .finish_non_exhaustive()
+    }
 }
 
 impl TransportServer {
@@ -100,7 +288,7 @@ impl TransportServer {
             panic!("The max clients allowed is {}", TRANSPORT_MAX_CLIENTS);
         }
 
-        let clients = vec![None; config.max_clients].into_boxed_slice();
+        let clients = vec![None; config.max_clients];
 
         Self {
             clients,
@@ -108,6 +296,17 @@ impl TransportServer {
             max_clients: config.max_clients,
 
             public_addresses: config.public_addresses,
+            duplicate_player_policy: DuplicatePlayerPolicy::default(),
+            packet_policy: PacketPolicy::default(),
+            unexpected_packets: 0,
+            unexpected_packet_log: LogLimiter::default(),
+            invalid_packet_log: LogLimiter::default(),
+            keep_alive_interval: config.keep_alive_interval,
+            connection_timeout: config.connection_timeout,
+            auth_config: None,
+            flush_on_disconnect: false,
+            flushing_clients: HashMap::new(),
+            banned_ips: HashMap::new(),
             current_time: config.current_time,
             out: [0u8; TRANSPORT_MAX_PACKET_BYTES],
         }
@@ -121,6 +320,68 @@ impl TransportServer {
         self.current_time
     }
 
+    /// Sets how a second connection with the player id of a connected client is handled.
+    /// Default: [`DuplicatePlayerPolicy::KickOld`]
+    pub fn set_duplicate_player_policy(&mut self, policy: DuplicatePlayerPolicy) {
+        self.duplicate_player_policy = policy;
+    }
+
+    /// Sets the packet types accepted in each connection state. Default: [`PacketPolicy::default`]
+    pub fn set_packet_policy(&mut self, packet_policy: PacketPolicy) {
+        self.packet_policy = packet_policy;
+    }
+
+    /// Returns how many packets were dropped because the packet policy doesn't allow them in
+    /// the state of their sender.
+    pub fn unexpected_packets(&self) -> u64 {
+        self.unexpected_packets
+    }
+
+    fn record_unexpected_packet(
+        &mut self,
+        addr: SocketAddr,
+        state: ConnectionState,
+        packet_type: PacketType,
+    ) {
+        self.unexpected_packets += 1;
+        if let Some(suppressed) = self.unexpected_packet_log.admit() {
+            tracing::warn!(%addr, ?state, ?packet_type, suppressed, "Unexpected packet");
+        }
+    }
+
+    /// Sets the PlayFab credentials used to authenticate clients, instead of reading
+    /// PLAYFAB_API_URL and PLAYFAB_API_KEY on each authentication.
+    pub fn set_auth_config(&mut self, auth_config: AuthConfig) {
+        self.auth_config = Some(auth_config);
+    }
+
+    /// When enabled, payload packets can still be generated for a client that asked to
+    /// disconnect during [`TRANSPORT_DISCONNECT_FLUSH_WINDOW`], so the messages its session
+    /// still had queued are delivered. When disabled they are lost. Default: disabled
+    pub fn set_flush_on_disconnect(&mut self, flush_on_disconnect: bool) {
+        self.flush_on_disconnect = flush_on_disconnect;
+    }
+
+    /// Refuses the connection requests from the address, for the duration or permanently.
+    /// Banning an address again replaces its previous ban, connected clients are not affected.
+    pub fn ban_ip(&mut self, ip: IpAddr, duration: Option<Duration>) {
+        let expire_time = duration.map(|duration| self.current_time + duration);
+        self.banned_ips.insert(ip, expire_time);
+    }
+
+    /// Lifts the ban of the address, returns false if it wasn't banned.
+    pub fn unban_ip(&mut self, ip: IpAddr) -> bool {
+        self.banned_ips.remove(&ip).is_some()
+    }
+
+    pub fn is_ip_banned(&self, ip: IpAddr) -> bool {
+        match self.banned_ips.get(&ip) {
+            Some(Some(expire_time)) => *expire_time > self.current_time,
+            Some(None) => true,
+            None => false,
+        }
+    }
+
     // /// Returns the user data from the connected client.
     // pub fn user_data(&self, client_id: u64) -> Option<[u8; NETCODE_USER_DATA_BYTES]> {
     //     if let Some(client) = find_client_by_id(&self.clients, client_id) {
@@ -141,6 +402,12 @@ impl TransportServer {
         None
     }
 
+    /// Returns whether the connected client has sent a packet since it connected.
+    /// Returns None if the client is not connected.
+    pub fn confirmed(&self, client_id: u64) -> Option<bool> {
+        find_client_by_id(&self.clients, client_id).map(|client| client.confirmed)
+    }
+
     /// Returns the client address if connected.
     pub fn client_addr(&self, client_id: u64) -> Option<SocketAddr> {
         if let Some(client) = find_client_by_id(&self.clients, client_id) {
@@ -156,6 +423,34 @@ impl TransportServer {
         connection_prefix: [u8; 3],
         client_identifier: u64,
     ) -> Result<ServerResult<'a, '_>, TransportServerError> {
+        if self.is_ip_banned(addr.ip()) {
+            tracing::debug!(
+                client_id = client_identifier,
+                "Connection request denied: address {} is banned.",
+                addr
+            );
+            return Ok(ServerResult::None);
+        }
+
+        if connection_prefix != TRANSPORT_CONNECTION_PREFIX {
+            tracing::debug!(
+                client_id = client_identifier,
+                "Connection request denied: incompatible protocol version {:?} from {}.",
+                connection_prefix,
+                addr
+            );
+            self.pending_clients.remove(&addr);
+            let packet = Packet::Disconnect {
+                client_identifier,
+                reason: DisconnectReason::IncompatibleVersion.code(),
+            };
+            let len = packet.encode(&mut self.out)?;
+            return Ok(ServerResult::PacketToSend {
+                addr,
+                payload: &mut self.out[..len],
+            });
+        }
+
         let addr_already_connected = find_client_mut_by_addr(&mut self.clients, addr).is_some();
         let id_already_connected =
             find_client_mut_by_id(&mut self.clients, client_identifier).is_some();
@@ -193,7 +488,10 @@ impl TransportServer {
 
         let len = packet.encode(&mut self.out)?;
 
-        tracing::trace!("Connection request from Client {}", client_identifier);
+        tracing::trace!(
+            client_id = client_identifier,
+            "Connection request from Client"
+        );
 
         let pending = self
             .pending_clients
@@ -201,14 +499,15 @@ impl TransportServer {
             .or_insert_with(|| Connection {
                 confirmed: false,
                 is_authenticated: Arc::new(Mutex::new((false, String::new()))),
+                auth_payload: Vec::new(),
                 client_id: client_identifier,
                 last_packet_received_time: self.current_time,
                 last_packet_send_time: self.current_time,
+                keep_alive_interval: self.keep_alive_interval,
                 addr,
                 state: ConnectionState::PendingResponse,
-                timeout_seconds: 10,
-                // write code to calculate based on timeout_seconds and current_time
-                expire_timestamp: self.current_time.as_secs() + 10 as u64,
+                timeout_seconds: self.connection_timeout.as_secs() as i32,
+                expire_timestamp: (self.current_time + self.connection_timeout).as_secs(),
             });
         pending.last_packet_received_time = self.current_time;
         pending.last_packet_send_time = self.current_time;
@@ -219,22 +518,33 @@ impl TransportServer {
         })
     }
 
-    /// Returns an encoded packet payload to be sent to the client
-    pub fn generate_payload_packet<'s>(
-        &'s mut self,
+    /// Encodes a packet with the payload to be sent to the client into `out`.
+    /// Returns the address of the client and the length of the packet.
+    pub fn generate_payload_packet(
+        &mut self,
         client_identifier: u64,
         payload: &[u8],
-    ) -> Result<(SocketAddr, &'s mut [u8]), TransportServerError> {
+        out: &mut [u8],
+    ) -> Result<(SocketAddr, usize), TransportServerError> {
         if let Some(client) = find_client_mut_by_id(&mut self.clients, client_identifier) {
             let packet = Packet::Data {
                 client_identifier,
                 payload,
             };
-            let len = packet.encode(&mut self.out)?;
+            let len = packet.encode(out)?;
 
             client.last_packet_send_time = self.current_time;
 
-            return Ok((client.addr, &mut self.out[..len]));
+            return Ok((client.addr, len));
+        }
+
+        if let Some((addr, _)) = self.flushing_clients.get(&client_identifier) {
+            let packet = Packet::Data {
+                client_identifier,
+                payload,
+            };
+            let len = packet.encode(out)?;
+            return Ok((*addr, len));
         }
 
         Err(TransportServerError::ClientNotFound)
@@ -247,13 +557,23 @@ impl TransportServer {
         addr: SocketAddr,
         buffer: &'a mut [u8],
     ) -> ServerResult<'a, 's> {
-        match self.process_packet_internal(addr, buffer) {
+        // A result returned as is would keep the server borrowed in the error branch, so the
+        // payload written to `out` is only borrowed again once the error is logged
+        let mut out_len = 0;
+        let result = match self.process_packet_internal(addr, buffer) {
             Err(e) => {
-                tracing::error!("Failed to process packet: {}", e);
-                ServerResult::None
+                if let Some(suppressed) = self.invalid_packet_log.admit() {
+                    tracing::error!(%addr, suppressed, "Failed to process packet: {}", e);
+                }
+                return ServerResult::None;
             }
-            Ok(r) => r,
-        }
+            Ok(r) => r.map_out(|payload| {
+                out_len = payload.len();
+                &mut []
+            }),
+        };
+        let out = &mut self.out[..out_len];
+        result.map_out(move |_| out)
     }
 
     fn process_packet_internal<'a, 's>(
@@ -264,17 +584,26 @@ impl TransportServer {
         // Handle connected client
         if let Some((slot, client)) = find_client_mut_by_addr(&mut self.clients, addr) {
             let packet = Packet::decode(buffer)?;
+            let state = client.state;
+            if !self.packet_policy.allows(state, packet.packet_type()) {
+                self.record_unexpected_packet(addr, state, packet.packet_type());
+                return Ok(ServerResult::None);
+            }
 
             client.last_packet_received_time = self.current_time;
             match client.state {
                 ConnectionState::Connected => match packet {
-                    Packet::Disconnect {
-                        client_identifier: _,
-                    } => {
+                    Packet::Disconnect { .. } => {
                         client.state = ConnectionState::Disconnected;
                         let client_id = client.client_id;
                         self.clients[slot] = None;
-                        tracing::trace!("Client {} requested to disconnect", client_id);
+                        if self.flush_on_disconnect {
+                            self.flushing_clients.insert(
+                                client_id,
+                                (addr, self.current_time + TRANSPORT_DISCONNECT_FLUSH_WINDOW),
+                            );
+                        }
+                        tracing::trace!(client_id, "Client requested to disconnect");
                         return Ok(ServerResult::ClientDisconnected {
                             client_id,
                             addr,
@@ -282,22 +611,54 @@ impl TransportServer {
                         });
                     }
                     Packet::Data {
-                        client_identifier: _,
+                        client_identifier,
                         payload,
                     } => {
+                        // The KeepAlive that completed the connection was lost
+                        if !client.auth_payload.is_empty() && payload == client.auth_payload {
+                            tracing::trace!(
+                                client_id = client.client_id,
+                                "Repeated auth request from connected client"
+                            );
+                            client.last_packet_send_time = self.current_time;
+                            let packet = Packet::KeepAlive { client_identifier };
+                            let len = packet.encode(&mut self.out)?;
+                            return Ok(ServerResult::PacketToSend {
+                                addr,
+                                payload: &mut self.out[..len],
+                            });
+                        }
                         if !client.confirmed {
-                            tracing::trace!("Confirmed connection for Client {}", client.client_id);
+                            tracing::trace!(client_id = client.client_id, "Confirmed connection");
                             client.confirmed = true;
+                            client.auth_payload = Vec::new();
+                            return Ok(ServerResult::ClientConfirmed {
+                                client_id: client.client_id,
+                                payload: Some(payload),
+                            });
                         }
                         return Ok(ServerResult::Payload {
                             client_id: client.client_id,
                             payload,
                         });
                     }
+                    // Only data and keep alives confirm the connection
+                    Packet::Control { kind, payload, .. } => {
+                        return Ok(ServerResult::Control {
+                            client_id: client.client_id,
+                            kind,
+                            payload,
+                        });
+                    }
                     Packet::KeepAlive { .. } => {
                         if !client.confirmed {
-                            tracing::trace!("Confirmed connection for Client {}", client.client_id);
+                            tracing::trace!(client_id = client.client_id, "Confirmed connection");
                             client.confirmed = true;
+                            client.auth_payload = Vec::new();
+                            return Ok(ServerResult::ClientConfirmed {
+                                client_id: client.client_id,
+                                payload: None,
+                            });
                         }
                         return Ok(ServerResult::None);
                     }
@@ -310,6 +671,11 @@ impl TransportServer {
         // Handle pending client
         if let Some(pending) = self.pending_clients.get_mut(&addr) {
             let packet = Packet::decode(buffer)?;
+            let state = pending.state;
+            if !self.packet_policy.allows(state, packet.packet_type()) {
+                self.record_unexpected_packet(addr, state, packet.packet_type());
+                return Ok(ServerResult::None);
+            }
 
             pending.last_packet_received_time = self.current_time;
             tracing::trace!(
@@ -354,9 +720,81 @@ impl TransportServer {
                                     return Ok(ServerResult::None);
                                 }
 
-                                match self.clients.iter().position(|c| c.is_none()) {
+                                if let Some(slot) = find_client_slot_by_player_id(
+                                    &self.clients,
+                                    &is_authenticated.1,
+                                ) {
+                                    match self.duplicate_player_policy {
+                                        DuplicatePlayerPolicy::RejectNew => {
+                                            tracing::debug!(
+                                                client_id = client_identifier,
+                                                player_id = is_authenticated.1.as_str(),
+                                                "Rejected connection, player already connected"
+                                            );
+                                            let packet = Packet::Disconnect {
+                                                client_identifier,
+                                                reason: DisconnectReason::DisconnectedByServer
+                                                    .code(),
+                                            };
+                                            let len = packet.encode(&mut self.out)?;
+                                            return Ok(ServerResult::PacketToSend {
+                                                addr,
+                                                payload: &mut self.out[..len],
+                                            });
+                                        }
+                                        DuplicatePlayerPolicy::KickOld => {
+                                            // Keep the new client pending until the old one is gone
+                                            drop(is_authenticated);
+                                            self.pending_clients.insert(addr, pending);
+
+                                            let old = self.clients[slot].take().unwrap();
+                                            tracing::debug!(
+                                                client_id = old.client_id,
+                                                "Disconnected client, player connected again"
+                                            );
+                                            let packet = Packet::Disconnect {
+                                                client_identifier: old.client_id,
+                                                reason: DisconnectReason::DisconnectedByServer
+                                                    .code(),
+                                            };
+                                            let len = packet.encode(&mut self.out)?;
+                                            return Ok(ServerResult::ClientDisconnected {
+                                                client_id: old.client_id,
+                                                addr: old.addr,
+                                                payload: Some(&mut self.out[..len]),
+                                            });
+                                        }
+                                    }
+                                }
+
+                                if let Err(e) = validate_player_id(&is_authenticated.1) {
+                                    tracing::warn!(
+                                        client_id = client_identifier,
+                                        "Rejected connection: {e}"
+                                    );
+                                    let packet = Packet::Disconnect {
+                                        client_identifier,
+                                        reason: DisconnectReason::InvalidPlayerId.code(),
+                                    };
+                                    let len = packet.encode(&mut self.out)?;
+                                    return Ok(ServerResult::PacketToSend {
+                                        addr,
+                                        payload: &mut self.out[..len],
+                                    });
+                                }
+
+                                // Slots can outnumber max_clients after it was lowered
+                                let free_slot = if self.connected_clients() < self.max_clients {
+                                    self.clients.iter().position(|c| c.is_none())
+                                } else {
+                                    None
+                                };
+                                match free_slot {
                                     None => {
-                                        let packet = Packet::Disconnect { client_identifier };
+                                        let packet = Packet::Disconnect {
+                                            client_identifier,
+                                            reason: DisconnectReason::DisconnectedByServer.code(),
+                                        };
                                         let len = packet.encode(&mut self.out)?;
                                         pending.state = ConnectionState::Disconnected;
 
@@ -387,35 +825,49 @@ impl TransportServer {
                                         });
                                     }
                                 }
-                            } else {
-                                self.pending_clients.insert(addr, pending.clone());
                             }
-                            return Ok(ServerResult::None);
+
+                            // Auth is still running, only the KeepAlive is sent again
+                            drop(is_authenticated);
+                            pending.last_packet_send_time = self.current_time;
+                            self.pending_clients.insert(addr, pending);
+                            let packet = Packet::KeepAlive { client_identifier };
+                            let len = packet.encode(&mut self.out)?;
+                            return Ok(ServerResult::PacketToSend {
+                                addr,
+                                payload: &mut self.out[..len],
+                            });
                         }
                         ConnectionState::PendingResponse => {
                             pending.state = ConnectionState::Authenticating;
 
                             let bytes = payload.to_vec();
+                            if bytes.len() < 6 + PLAYER_ID_MAX_BYTES {
+                                return Err(TransportServerError::InvalidPacketType);
+                            }
+
                             let channel_id = bytes[0];
                             let messages_len = bytes[1];
                             let message_type = bytes[5];
 
-                            if bytes.len() < 17
-                                || channel_id != 0
-                                || messages_len != 1
-                                || message_type != 0
-                            {
+                            if channel_id != 0 || messages_len != 1 || message_type != 0 {
                                 return Err(TransportServerError::InvalidPacketType);
                             }
 
-                            let (player_id_bytes, session_ticket_bytes) = bytes[6..].split_at(16);
+                            // The player id field is exactly PLAYER_ID_MAX_BYTES, zero padded
+                            let (player_id_bytes, session_ticket_bytes) =
+                                bytes[6..].split_at(PLAYER_ID_MAX_BYTES);
 
                             let player_id = String::from_utf8(player_id_bytes.to_vec())
                                 .map_err(|_| TransportServerError::InvalidPlayerId)?
                                 .trim_end_matches(char::from(0))
                                 .to_string();
 
-                            tracing::trace!("Authenticating: {:?}", player_id);
+                            tracing::trace!(
+                                client_id = client_identifier,
+                                player_id = player_id.as_str(),
+                                "Authenticating"
+                            );
 
                             let session_ticket = String::from_utf8(session_ticket_bytes.to_vec())
                                 .map_err(|_| TransportServerError::InvalidSessionTicket)?
@@ -423,11 +875,14 @@ impl TransportServer {
                                 .to_string();
 
                             let is_authenticated = pending.is_authenticated.clone();
+                            pending.auth_payload = bytes;
+                            let auth_config = self.auth_config.clone();
 
                             std::thread::spawn(move || {
                                 let rt = tokio::runtime::Runtime::new().unwrap();
                                 rt.block_on(async move {
                                     authenticate_player(
+                                        auth_config.unwrap_or_else(AuthConfig::from_env),
                                         player_id,
                                         session_ticket,
                                         is_authenticated,
@@ -454,6 +909,17 @@ impl TransportServer {
         } else {
             // Handle new client
             let packet = Packet::decode(buffer)?;
+            if !self
+                .packet_policy
+                .allows(ConnectionState::Disconnected, packet.packet_type())
+            {
+                self.record_unexpected_packet(
+                    addr,
+                    ConnectionState::Disconnected,
+                    packet.packet_type(),
+                );
+                return Ok(ServerResult::None);
+            }
             match packet {
                 Packet::ConnectionRequest {
                     connection_prefix,
@@ -474,10 +940,16 @@ impl TransportServer {
                     client_identifier: _,
                     session_id,
                     player_ids,
+                    movement_config,
+                    seed,
+                    tick_rate,
                 } => {
                     return Ok(ServerResult::CreateSession {
                         id: session_id,
                         player_ids,
+                        movement_config,
+                        seed,
+                        tick_rate,
                     });
                 }
                 _ => Ok(ServerResult::None),
@@ -510,12 +982,30 @@ impl TransportServer {
         self.max_clients
     }
 
-    /// Update the maximum numbers of clients that can be connected
+    /// Update the maximum numbers of clients that can be connected, up to
+    /// [`TRANSPORT_MAX_CLIENTS`]. Raising it adds client slots right away.
     ///
     /// Changing the `max_clients` to a lower value than the current number of connect clients
-    /// does not disconnect clients. So [`NetcodeServer::connected_clients()`] can return a higher value than [`NetcodeServer::max_clients()`].
+    /// does not disconnect clients, only new ones are denied until enough of them left. So
+    /// [`TransportServer::connected_clients()`] can return a higher value than
+    /// [`TransportServer::max_clients()`].
     pub fn set_max_clients(&mut self, max_clients: usize) {
+        let max_clients = if max_clients > TRANSPORT_MAX_CLIENTS {
+            tracing::warn!(
+                "Requested {max_clients} max clients, the max clients allowed is {TRANSPORT_MAX_CLIENTS}"
+            );
+            TRANSPORT_MAX_CLIENTS
+        } else {
+            max_clients
+        };
         self.max_clients = max_clients;
+        if self.clients.len() < max_clients {
+            self.clients.resize(max_clients, None);
+        }
+        // Free slots past the new size go, the ones of connected clients stay
+        while self.clients.len() > max_clients && self.clients.last().is_some_and(Option::is_none) {
+            self.clients.pop();
+        }
     }
 
     /// Returns current number of clients connected.
@@ -523,6 +1013,28 @@ impl TransportServer {
         self.clients.iter().filter(|slot| slot.is_some()).count()
     }
 
+    /// Returns the id, address and player id of every connected client.
+    pub fn connected_roster(&self) -> Vec<(u64, SocketAddr, String)> {
+        self.clients
+            .iter()
+            .flatten()
+            .map(|client| {
+                let player_id = client.is_authenticated.lock().unwrap().1.clone();
+                (client.client_id, client.addr, player_id)
+            })
+            .collect()
+    }
+
+    /// Returns how many pending clients are in each handshake state.
+    /// Many clients piling up in `Authenticating` usually means the auth service is down.
+    pub fn pending_clients_by_state(&self) -> HashMap<ConnectionState, usize> {
+        let mut counts = HashMap::new();
+        for pending in self.pending_clients.values() {
+            *counts.entry(pending.state).or_insert(0) += 1;
+        }
+        counts
+    }
+
     /// Advance the server current time, and remove any pending connections that have expired.
     pub fn update(&mut self, duration: Duration) {
         self.current_time += duration;
@@ -530,8 +1042,8 @@ impl TransportServer {
         for client in self.pending_clients.values_mut() {
             if self.current_time.as_secs() > client.expire_timestamp {
                 tracing::debug!(
-                    "Pending Client {} disconnected, connection token expired.",
-                    client.client_id
+                    client_id = client.client_id,
+                    "Pending Client disconnected, connection token expired."
                 );
                 client.state = ConnectionState::Disconnected;
             }
@@ -539,6 +1051,14 @@ impl TransportServer {
 
         self.pending_clients
             .retain(|_, c| c.state != ConnectionState::Disconnected);
+
+        let current_time = self.current_time;
+        self.flushing_clients
+            .retain(|_, (_, expire_time)| *expire_time > current_time);
+        self.banned_ips.retain(|_, expire_time| match expire_time {
+            Some(expire_time) => *expire_time > current_time,
+            None => true,
+        });
     }
 
     pub fn update_client(&mut self, client_id: u64) -> ServerResult<'_, '_> {
@@ -554,8 +1074,8 @@ impl TransportServer {
                     < self.current_time);
             if connection_timed_out {
                 tracing::debug!(
-                    "Client {} disconnected, connection timed out",
-                    client.client_id
+                    client_id = client.client_id,
+                    "Client disconnected, connection timed out"
                 );
                 client.state = ConnectionState::Disconnected;
             }
@@ -563,6 +1083,7 @@ impl TransportServer {
             if client.state == ConnectionState::Disconnected {
                 let packet = Packet::Disconnect {
                     client_identifier: client_id,
+                    reason: DisconnectReason::Transport.code(),
                 };
 
                 let addr = client.addr;
@@ -587,7 +1108,7 @@ impl TransportServer {
                 };
             }
 
-            if client.last_packet_send_time + TRANSPORT_SEND_RATE <= self.current_time {
+            if client.last_packet_send_time + client.keep_alive_interval <= self.current_time {
                 let packet = Packet::KeepAlive {
                     client_identifier: client_id as u64,
                 };
@@ -615,15 +1136,21 @@ impl TransportServer {
         find_client_slot_by_id(&self.clients, client_id).is_some()
     }
 
-    /// Disconnect an client and returns its address and a disconnect packet to be sent to them.
+    /// Disconnect an client and returns its address and a disconnect packet to be sent to them,
+    /// carrying the code of the reason.
     // TODO: we can return Result<PacketToSend, NetcodeError>
     //       but the library user would need to be aware that he has to run
     //       the same code as Result::ClientDisconnected
-    pub fn disconnect(&mut self, client_id: u64) -> ServerResult<'_, '_> {
+    pub fn disconnect(
+        &mut self,
+        client_id: u64,
+        reason: &DisconnectReason,
+    ) -> ServerResult<'_, '_> {
         if let Some(slot) = find_client_slot_by_id(&self.clients, client_id) {
             let client = self.clients[slot].take().unwrap();
             let packet = Packet::Disconnect {
                 client_identifier: client_id,
+                reason: reason.code(),
             };
 
             let len = match packet.encode(&mut self.out) {
@@ -646,6 +1173,96 @@ impl TransportServer {
 
         ServerResult::None
     }
+
+    /// Places an already authenticated client straight into a free slot.
+    #[cfg(test)]
+    pub(crate) fn insert_connected_client(&mut self, client_id: u64, addr: SocketAddr) {
+        let slot = self
+            .clients
+            .iter()
+            .position(|c| c.is_none())
+            .expect("no free client slot");
+        self.clients[slot] = Some(Connection {
+            confirmed: true,
+            client_id,
+            state: ConnectionState::Connected,
+            is_authenticated: Arc::new(Mutex::new((true, String::new()))),
+            auth_payload: Vec::new(),
+            addr,
+            last_packet_received_time: self.current_time,
+            last_packet_send_time: self.current_time,
+            keep_alive_interval: self.keep_alive_interval,
+            timeout_seconds: self.connection_timeout.as_secs() as i32,
+            expire_timestamp: (self.current_time + self.connection_timeout).as_secs(),
+        });
+    }
+
+    /// Marks the pending client at `addr` as authenticated, as if the auth service accepted
+    /// its session ticket. Returns false when there is no such pending client.
+    #[cfg(test)]
+    pub(crate) fn authenticate_pending_client(
+        &mut self,
+        addr: SocketAddr,
+        player_id: &str,
+    ) -> bool {
+        let Some(pending) = self.pending_clients.get_mut(&addr) else {
+            return false;
+        };
+        pending.state = ConnectionState::Authenticating;
+        *pending.is_authenticated.lock().unwrap() = (true, player_id.to_string());
+        true
+    }
+
+    /// Places a pending client whose authentication already finished, its next data packet
+    /// completes the connection.
+    #[cfg(test)]
+    pub(crate) fn insert_authenticated_client(
+        &mut self,
+        client_id: u64,
+        addr: SocketAddr,
+        player_id: &str,
+    ) {
+        let is_authenticated = self.insert_authenticating_client(client_id, addr, Vec::new());
+        *is_authenticated.lock().unwrap() = (true, player_id.to_string());
+    }
+
+    /// Places a pending client that sent `auth_payload`, authentication finishes once
+    /// the returned flag is set.
+    #[cfg(test)]
+    pub(crate) fn insert_authenticating_client(
+        &mut self,
+        client_id: u64,
+        addr: SocketAddr,
+        auth_payload: Vec<u8>,
+    ) -> Arc<Mutex<(bool, String)>> {
+        let is_authenticated = Arc::new(Mutex::new((false, String::new())));
+        self.pending_clients.insert(
+            addr,
+            Connection {
+                confirmed: false,
+                client_id,
+                state: ConnectionState::Authenticating,
+                is_authenticated: is_authenticated.clone(),
+                auth_payload,
+                addr,
+                last_packet_received_time: self.current_time,
+                last_packet_send_time: self.current_time,
+                keep_alive_interval: self.keep_alive_interval,
+                timeout_seconds: self.connection_timeout.as_secs() as i32,
+                expire_timestamp: (self.current_time + self.connection_timeout).as_secs(),
+            },
+        );
+        is_authenticated
+    }
+}
+
+/// Refuses player ids that don't fit the [`PLAYER_ID_MAX_BYTES`] wire field of the messages
+/// naming players, checked where the ids enter the server.
+pub fn validate_player_id(player_id: &str) -> Result<(), TransportServerError> {
+    if player_id.len() > PLAYER_ID_MAX_BYTES {
+        return Err(TransportServerError::PlayerIdTooLong(player_id.to_string()));
+    }
+    Ok(())
 }
 
 fn find_client_mut_by_id(
@@ -669,6 +1286,13 @@ fn find_client_slot_by_id(clients: &[Option<Connection>], client_id: u64) -> Opt
     })
 }
 
+fn find_client_slot_by_player_id(clients: &[Option<Connection>], player_id: &str) -> Option<usize> {
+    clients.iter().enumerate().find_map(|(i, c)| match c {
+        Some(c) if c.is_authenticated.lock().unwrap().1 == player_id => Some(i),
+        _ => None,
+    })
+}
+
 fn find_client_mut_by_addr(
     clients: &mut [Option<Connection>],
     addr: SocketAddr,
@@ -680,19 +1304,18 @@ fn find_client_mut_by_addr(
 }
 
 async fn authenticate_player(
+    auth_config: AuthConfig,
     player_id: String,
     session_ticket: String,
     is_authenticated: Arc<Mutex<(bool, String)>>,
 ) {
     let client = reqwest::Client::new();
-    let playfab_api_key = std::env::var("PLAYFAB_API_KEY").unwrap();
-    let playfab_api_url = std::env::var("PLAYFAB_API_URL").unwrap();
     let response = client
         .post(format!(
             "{}/Server/AuthenticateSessionTicket", // /Server/AuthenticateSessionTicket
-            playfab_api_url
+            auth_config.playfab_api_url
         ))
-        .header("X-SecretKey", playfab_api_key)
+        .header("X-SecretKey", auth_config.playfab_api_key)
         .json(&serde_json::json!({
             "SessionTicket": session_ticket,
         }))
@@ -720,3 +1343,492 @@ async fn authenticate_player(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server() -> TransportServer {
+        TransportServer::new(ServerConfig {
+            current_time: Duration::ZERO,
+            max_clients: 8,
+            public_addresses: vec!["127.0.0.1:5000".parse().unwrap()],
+            keep_alive_interval: TRANSPORT_SEND_RATE,
+            connection_timeout: TRANSPORT_CONNECTION_TIMEOUT,
+        })
+    }
+
+    fn encode(packet: Packet) -> Vec<u8> {
+        let mut buffer = [0u8; TRANSPORT_MAX_PACKET_BYTES];
+        let len = packet.encode(&mut buffer).unwrap();
+        buffer[..len].to_vec()
+    }
+
+    fn data(client_id: u64, payload: &[u8]) -> Vec<u8> {
+        encode(Packet::Data {
+            client_identifier: client_id,
+            payload,
+        })
+    }
+
+    fn keep_alive(client_id: u64) -> Vec<u8> {
+        encode(Packet::KeepAlive {
+            client_identifier: client_id,
+        })
+    }
+
+    #[test]
+    fn payload_packets_generated_in_a_row_are_kept_apart() {
+        let mut server = server();
+        let addr_1: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let addr_2: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        server.insert_connected_client(1, addr_1);
+        server.insert_connected_client(2, addr_2);
+
+        let mut first = [0u8; TRANSPORT_MAX_PACKET_BYTES];
+        let mut second = [0u8; TRANSPORT_MAX_PACKET_BYTES];
+        let (first_addr, first_len) = server
+            .generate_payload_packet(1, &[1, 1, 1], &mut first)
+            .unwrap();
+        let (second_addr, second_len) = server
+            .generate_payload_packet(2, &[2, 2], &mut second)
+            .unwrap();
+
+        // Both packets are still intact, ready to be sent in one batch
+        assert_eq!(first_addr, addr_1);
+        assert_eq!(second_addr, addr_2);
+        assert_eq!(&first[..first_len], data(1, &[1, 1, 1]).as_slice());
+        assert_eq!(&second[..second_len], data(2, &[2, 2]).as_slice());
+
+        assert!(matches!(
+            server.generate_payload_packet(3, &[3], &mut first),
+            Err(TransportServerError::ClientNotFound)
+        ));
+    }
+
+    #[test]
+    fn first_packet_confirms_connection_once() {
+        let mut server = server();
+        let addr: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        server.insert_authenticated_client(1, addr, "player1");
+
+        let mut packet = data(1, &[0]);
+        let result = server.process_packet(addr, &mut packet);
+        assert!(matches!(
+            result,
+            ServerResult::ClientConnected { client_id: 1, ref player_id, .. } if player_id == "player1"
+        ));
+        assert_eq!(server.confirmed(1), Some(false));
+
+        let mut confirmations = 0;
+        let mut payloads = vec![];
+        let packets = vec![keep_alive(1), data(1, &[7]), keep_alive(1), data(1, &[8])];
+        for mut packet in packets {
+            match server.process_packet(addr, &mut packet) {
+                ServerResult::ClientConfirmed { client_id, payload } => {
+                    assert_eq!(client_id, 1);
+                    assert!(payload.is_none());
+                    confirmations += 1;
+                }
+                ServerResult::Payload { client_id, payload } => {
+                    assert_eq!(client_id, 1);
+                    payloads.push(payload.to_vec());
+                }
+                ServerResult::None => {}
+                result => panic!("unexpected result {result:?}"),
+            }
+        }
+
+        assert_eq!(confirmations, 1);
+        assert_eq!(payloads, vec![vec![7], vec![8]]);
+        assert_eq!(server.confirmed(1), Some(true));
+        assert_eq!(server.confirmed(2), None);
+    }
+
+    #[test]
+    fn long_authenticated_player_id_is_refused() {
+        let mut server = server();
+        let addr: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        server.insert_authenticated_client(1, addr, "a_player_id_longer_than_16_bytes");
+
+        match server.process_packet(addr, &mut data(1, &[0])) {
+            ServerResult::PacketToSend { addr: to, payload } => {
+                assert_eq!(to, addr);
+                assert_eq!(
+                    Packet::decode(payload).unwrap(),
+                    Packet::Disconnect {
+                        client_identifier: 1,
+                        reason: DisconnectReason::InvalidPlayerId.code(),
+                    }
+                );
+            }
+            result => panic!("unexpected result {result:?}"),
+        }
+        assert_eq!(server.connected_clients(), 0);
+        assert!(server.pending_clients_by_state().is_empty());
+    }
+
+    #[test]
+    fn data_confirming_connection_keeps_payload() {
+        let mut server = server();
+        let addr: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        server.insert_authenticated_client(1, addr, "player1");
+        server.process_packet(addr, &mut data(1, &[0]));
+
+        let mut packet = data(1, &[9]);
+        let result = server.process_packet(addr, &mut packet);
+        assert_eq!(
+            result,
+            ServerResult::ClientConfirmed {
+                client_id: 1,
+                payload: Some(&[9][..]),
+            }
+        );
+    }
+
+    #[test]
+    fn pending_clients_counted_by_state() {
+        let mut server = server();
+        assert!(server.pending_clients_by_state().is_empty());
+
+        for port in 6001..6004 {
+            let addr: SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
+            let mut packet = encode(Packet::ConnectionRequest {
+                connection_prefix: [b'M', b'T', b'A'],
+                connection_side_id: 1,
+                client_identifier: port as u64,
+            });
+            server.process_packet(addr, &mut packet);
+        }
+        server.insert_authenticated_client(10, "127.0.0.1:6010".parse().unwrap(), "player10");
+        server.insert_authenticated_client(11, "127.0.0.1:6011".parse().unwrap(), "player11");
+
+        // A finished handshake leaves the pending clients
+        let addr: SocketAddr = "127.0.0.1:6011".parse().unwrap();
+        server.process_packet(addr, &mut data(11, &[0]));
+
+        let counts = server.pending_clients_by_state();
+        assert_eq!(counts.get(&ConnectionState::PendingResponse), Some(&3));
+        assert_eq!(counts.get(&ConnectionState::Authenticating), Some(&1));
+        assert_eq!(counts.get(&ConnectionState::Connected), None);
+        assert_eq!(server.connected_clients(), 1);
+    }
+
+    #[test]
+    fn handshake_packet_from_connected_client_is_unexpected() {
+        let mut server = server();
+        let addr: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        server.insert_connected_client(1, addr);
+        let mut connection_request = encode(Packet::ConnectionRequest {
+            connection_prefix: [b'M', b'T', b'A'],
+            connection_side_id: 1,
+            client_identifier: 1,
+        });
+
+        assert_eq!(
+            server.process_packet(addr, &mut connection_request),
+            ServerResult::None
+        );
+        assert_eq!(server.unexpected_packets(), 1);
+        assert_eq!(server.connected_clients(), 1);
+
+        // Packets expected once connected are not counted
+        assert_eq!(
+            server.process_packet(addr, &mut keep_alive(1)),
+            ServerResult::None
+        );
+        assert_eq!(server.unexpected_packets(), 1);
+    }
+
+    #[test]
+    fn connection_request_with_other_protocol_version_is_refused() {
+        let mut server = server();
+        let addr: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let connection_request = |connection_prefix: [u8; 3]| {
+            encode(Packet::ConnectionRequest {
+                connection_prefix,
+                connection_side_id: 1,
+                client_identifier: 1,
+            })
+        };
+
+        match server.process_packet(addr, &mut connection_request(*b"MTB")) {
+            ServerResult::PacketToSend { addr: to, payload } => {
+                assert_eq!(to, addr);
+                assert_eq!(
+                    Packet::decode(payload).unwrap(),
+                    Packet::Disconnect {
+                        client_identifier: 1,
+                        reason: DisconnectReason::IncompatibleVersion.code(),
+                    }
+                );
+            }
+            result => panic!("unexpected result {result:?}"),
+        }
+        assert!(server.pending_clients_by_state().is_empty());
+
+        match server.process_packet(addr, &mut connection_request(TRANSPORT_CONNECTION_PREFIX)) {
+            ServerResult::PacketToSend { payload, .. } => {
+                assert!(matches!(
+                    Packet::decode(payload).unwrap(),
+                    Packet::ConnectionRequest {
+                        connection_side_id: 2,
+                        ..
+                    }
+                ));
+            }
+            result => panic!("unexpected result {result:?}"),
+        }
+        assert_eq!(
+            server
+                .pending_clients_by_state()
+                .get(&ConnectionState::PendingResponse),
+            Some(&1)
+        );
+    }
+
+    #[test]
+    fn banned_ip_connection_request_is_refused() {
+        let mut server = server();
+        let banned: SocketAddr = "10.0.0.1:6001".parse().unwrap();
+        let allowed: SocketAddr = "10.0.0.2:6001".parse().unwrap();
+        server.ban_ip(banned.ip(), Some(Duration::from_secs(60)));
+        let connection_request = |client_id: u64| {
+            encode(Packet::ConnectionRequest {
+                connection_prefix: [b'M', b'T', b'A'],
+                connection_side_id: 1,
+                client_identifier: client_id,
+            })
+        };
+
+        assert_eq!(
+            server.process_packet(banned, &mut connection_request(1)),
+            ServerResult::None
+        );
+        assert!(matches!(
+            server.process_packet(allowed, &mut connection_request(2)),
+            ServerResult::PacketToSend { addr, .. } if addr == allowed
+        ));
+        assert_eq!(
+            server
+                .pending_clients_by_state()
+                .get(&ConnectionState::PendingResponse),
+            Some(&1)
+        );
+
+        // Temporary bans expire
+        server.update(Duration::from_secs(61));
+        assert!(!server.is_ip_banned(banned.ip()));
+        assert!(matches!(
+            server.process_packet(banned, &mut connection_request(1)),
+            ServerResult::PacketToSend { .. }
+        ));
+    }
+
+    #[test]
+    fn roster_matches_connected_clients() {
+        let mut server = server();
+        let first: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        server.insert_authenticated_client(1, first, "player1");
+        server.insert_authenticated_client(2, second, "player2");
+        server.insert_authenticated_client(3, "127.0.0.1:6003".parse().unwrap(), "player3");
+        server.process_packet(first, &mut data(1, &[0]));
+        server.process_packet(second, &mut data(2, &[0]));
+
+        let mut roster = server.connected_roster();
+        roster.sort();
+        assert_eq!(
+            roster,
+            vec![
+                (1, first, "player1".to_string()),
+                (2, second, "player2".to_string()),
+            ]
+        );
+
+        server.disconnect(1, &DisconnectReason::DisconnectedByServer);
+        assert_eq!(
+            server.connected_roster(),
+            vec![(2, second, "player2".to_string())]
+        );
+    }
+
+    fn connect_same_player_twice(server: &mut TransportServer) -> (SocketAddr, SocketAddr) {
+        let first: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        server.insert_authenticated_client(1, first, "player1");
+        server.insert_authenticated_client(2, second, "player1");
+        server.process_packet(first, &mut data(1, &[0]));
+        (first, second)
+    }
+
+    #[test]
+    fn raised_max_clients_accepts_clients_beyond_initial_size() {
+        let mut server = TransportServer::new(ServerConfig {
+            current_time: Duration::ZERO,
+            max_clients: 2,
+            public_addresses: vec!["127.0.0.1:5000".parse().unwrap()],
+            keep_alive_interval: TRANSPORT_SEND_RATE,
+            connection_timeout: TRANSPORT_CONNECTION_TIMEOUT,
+        });
+        let connect = |server: &mut TransportServer, client_id: u64| {
+            let addr: SocketAddr = format!("127.0.0.1:{}", 6000 + client_id).parse().unwrap();
+            server.insert_authenticated_client(client_id, addr, &format!("player{client_id}"));
+            matches!(
+                server.process_packet(addr, &mut data(client_id, &[0])),
+                ServerResult::ClientConnected { .. }
+            )
+        };
+
+        assert!(connect(&mut server, 1));
+        assert!(connect(&mut server, 2));
+        assert!(!connect(&mut server, 3));
+
+        server.set_max_clients(3);
+        assert!(connect(&mut server, 4));
+        assert_eq!(server.connected_clients(), 3);
+
+        // Lowering the cap keeps the connected clients but denies new ones
+        server.set_max_clients(1);
+        assert_eq!(server.connected_clients(), 3);
+        assert!(!connect(&mut server, 5));
+        assert_eq!(server.max_clients(), 1);
+    }
+
+    #[test]
+    fn duplicate_player_rejects_new_connection() {
+        let mut server = server();
+        server.set_duplicate_player_policy(DuplicatePlayerPolicy::RejectNew);
+        let (first, second) = connect_same_player_twice(&mut server);
+
+        let mut packet = data(2, &[0]);
+        let result = server.process_packet(second, &mut packet);
+        assert!(matches!(result, ServerResult::PacketToSend { addr, .. } if addr == second));
+
+        assert_eq!(
+            server.connected_roster(),
+            vec![(1, first, "player1".to_string())]
+        );
+        assert!(server.pending_clients_by_state().is_empty());
+    }
+
+    #[test]
+    fn duplicate_player_kicks_old_connection() {
+        let mut server = server();
+        server.set_duplicate_player_policy(DuplicatePlayerPolicy::KickOld);
+        let (first, second) = connect_same_player_twice(&mut server);
+
+        let mut packet = data(2, &[0]);
+        let result = server.process_packet(second, &mut packet);
+        assert!(matches!(
+            result,
+            ServerResult::ClientDisconnected { client_id: 1, addr, payload: Some(_) } if addr == first
+        ));
+        assert!(server.connected_roster().is_empty());
+
+        let mut packet = data(2, &[0]);
+        let result = server.process_packet(second, &mut packet);
+        assert!(matches!(
+            result,
+            ServerResult::ClientConnected { client_id: 2, .. }
+        ));
+        assert_eq!(
+            server.connected_roster(),
+            vec![(2, second, "player1".to_string())]
+        );
+    }
+
+    #[test]
+    fn repeated_auth_data_is_idempotent() {
+        let mut server = server();
+        let addr: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let auth_payload = vec![0, 1, 0, 0, 0, 0, b'p', b'1'];
+        let is_authenticated = server.insert_authenticating_client(1, addr, auth_payload.clone());
+
+        // Every resend while auth runs gets the KeepAlive again
+        for _ in 0..3 {
+            let mut packet = data(1, &auth_payload);
+            let result = server.process_packet(addr, &mut packet);
+            assert_eq!(
+                result,
+                ServerResult::PacketToSend {
+                    addr,
+                    payload: &mut keep_alive(1),
+                }
+            );
+        }
+        let counts = server.pending_clients_by_state();
+        assert_eq!(counts.get(&ConnectionState::Authenticating), Some(&1));
+
+        *is_authenticated.lock().unwrap() = (true, "player1".to_string());
+        let mut packet = data(1, &auth_payload);
+        let result = server.process_packet(addr, &mut packet);
+        assert!(matches!(
+            result,
+            ServerResult::ClientConnected { client_id: 1, .. }
+        ));
+
+        // The KeepAlive of the connection got lost
+        for _ in 0..3 {
+            let mut packet = data(1, &auth_payload);
+            let result = server.process_packet(addr, &mut packet);
+            assert_eq!(
+                result,
+                ServerResult::PacketToSend {
+                    addr,
+                    payload: &mut keep_alive(1),
+                }
+            );
+        }
+        assert_eq!(server.connected_clients(), 1);
+        assert_eq!(server.confirmed(1), Some(false));
+
+        let mut packet = data(1, &[7]);
+        let result = server.process_packet(addr, &mut packet);
+        assert_eq!(
+            result,
+            ServerResult::ClientConfirmed {
+                client_id: 1,
+                payload: Some(&[7][..]),
+            }
+        );
+
+        // Once confirmed, the same bytes are a regular payload
+        let mut packet = data(1, &auth_payload);
+        let result = server.process_packet(addr, &mut packet);
+        assert_eq!(
+            result,
+            ServerResult::Payload {
+                client_id: 1,
+                payload: &auth_payload[..],
+            }
+        );
+    }
+
+    #[test]
+    fn keep_alives_follow_configured_interval() {
+        let mut server = TransportServer::new(ServerConfig {
+            current_time: Duration::ZERO,
+            max_clients: 8,
+            public_addresses: vec!["127.0.0.1:5000".parse().unwrap()],
+            keep_alive_interval: Duration::from_millis(100),
+            connection_timeout: TRANSPORT_CONNECTION_TIMEOUT,
+        });
+        let addr: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        server.insert_connected_client(1, addr);
+
+        let mut keep_alives = vec![];
+        for _ in 0..100 {
+            server.update(Duration::from_millis(10));
+            if let ServerResult::PacketToSend { payload, .. } = server.update_client(1) {
+                assert_eq!(payload.to_vec(), keep_alive(1));
+                keep_alives.push(server.current_time());
+            }
+        }
+
+        assert_eq!(keep_alives.len(), 10);
+        for (i, sent_at) in keep_alives.iter().enumerate() {
+            assert_eq!(*sent_at, Duration::from_millis(100 * (i as u64 + 1)));
+        }
+    }
+}
@@ -1,20 +1,44 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     io,
-    net::{SocketAddr, UdpSocket},
-    time::{Duration, Instant},
+    net::{IpAddr, SocketAddr, UdpSocket},
+    path::Path,
+    sync::Arc,
+    thread::JoinHandle,
+    time::Duration,
 };
 
-use bevy::prelude::Resource;
+use bevy::prelude::{Resource, Vec3};
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 
 use crate::{
-    constants::TRANSPORT_MAX_PACKET_BYTES, server::server::ClientId, sessions::new_session,
+    admin::AdminCommand,
+    constants::{
+        RECORDING_MAX_FILE_BYTES, TICK_DELTA, TRANSPORT_MAX_PACKET_BYTES, TRANSPORT_SEND_BUDGET,
+        TRANSPORT_SEND_MAX_RETRIES, TRANSPORT_SEND_QUEUE_MAX_PACKETS,
+    },
+    ecs::components::{MovementConfig, SessionDiagnostics, TickRate},
+    health::HealthState,
+    server::{
+        clock::{Clock, SystemClock},
+        connection::ConnectionConfig,
+        error::DisconnectReason,
+        server::ClientId,
+    },
+    sessions::new_session,
 };
 
 use super::{
     error::TransportError,
-    server::server::{ServerConfig, ServerResult, TransportServer},
+    recording::PacketRecorder,
+    sender::PacketSender,
+    server::{
+        error::TransportServerError,
+        server::{
+            validate_player_id, AuthConfig, ConnectionState, DuplicatePlayerPolicy, PacketPolicy,
+            ServerConfig, ServerResult, TransportServer,
+        },
+    },
 };
 
 pub enum ToDenariaServerMessage {
@@ -25,6 +49,10 @@ pub enum ToDenariaServerMessage {
         payload: Vec<u8>,
         player_id: String,
     },
+    /// The client sent its first packet after connecting
+    ClientConfirmed {
+        client_id: u64,
+    },
     ClientDisconnected {
         client_id: u64,
     },
@@ -32,6 +60,28 @@ pub enum ToDenariaServerMessage {
         client_id: u64,
         payload: Vec<u8>,
     },
+    /// A control payload of the client, kept apart from the channel packets of [`ToDenariaServerMessage::Payload`]
+    Control {
+        client_id: u64,
+        kind: u8,
+        payload: Vec<u8>,
+    },
+    /// Admin command freezing or resuming the gameplay of the session
+    SetPaused {
+        paused: bool,
+    },
+    /// Admin command moving a player of the session
+    Teleport {
+        player_id: String,
+        position: Vec3,
+    },
+    /// The socket stopped or resumed keeping up with the packets of the sessions,
+    /// non-essential broadcasts are throttled while saturated
+    SendSaturated {
+        saturated: bool,
+    },
+    /// Admin command stopping the session, its app exits after the current tick
+    Shutdown,
 }
 
 pub enum FromDenariaServerMessage {
@@ -39,11 +89,17 @@ pub enum FromDenariaServerMessage {
         client_id: u64,
         packets: Vec<Vec<u8>>,
     },
+    /// The session dropped the client, e.g. for being idle
+    DisconnectClient {
+        client_id: u64,
+        reason: DisconnectReason,
+    },
 }
 
 #[derive(Debug, Resource)]
 pub struct ServerTransport {
     socket: UdpSocket,
+    sender: PacketSender,
     transport_server: TransportServer,
     buffer: [u8; TRANSPORT_MAX_PACKET_BYTES],
     from_denaria_server_rx: Receiver<FromDenariaServerMessage>,
@@ -51,12 +107,39 @@ pub struct ServerTransport {
     player_id_session_map: HashMap<String, u32>,
     session_to_denaria_server_tx: HashMap<u32, Sender<ToDenariaServerMessage>>,
     client_id_to_server_tx_map: HashMap<u64, Sender<ToDenariaServerMessage>>,
+    client_id_session_map: HashMap<u64, u32>,
+    dead_sessions: Vec<u32>,
+    // Connected clients without a session to route their payloads to
+    orphaned_clients: Vec<u64>,
+    // Threads running the sessions, checked every update so a session that panicked is closed
+    session_threads: HashMap<u32, JoinHandle<()>>,
+    session_diagnostics: HashMap<u32, SessionDiagnostics>,
+    // Given to the DenariaServer of every new session
+    connection_config: ConnectionConfig,
+    // Of the sessions created without a tick rate
+    session_tick_rate: TickRate,
+    health: HealthState,
+    tick_budget: Duration,
+    recorder: Option<PacketRecorder>,
+    // Packets received from the sessions that are not sent yet, per client
+    send_queues: HashMap<u64, VecDeque<Vec<u8>>>,
+    // Clients with queued packets, in the order they are served
+    send_order: VecDeque<u64>,
+    send_budget: Duration,
+    send_saturated: bool,
+    // Times the update and send phases against their budgets
+    clock: Arc<dyn Clock>,
 }
 
 impl ServerTransport {
     pub fn new(server_config: ServerConfig, socket: UdpSocket) -> Result<Self, std::io::Error> {
         socket.set_nonblocking(true)?;
 
+        let sender = PacketSender::new(
+            socket.try_clone()?,
+            TRANSPORT_SEND_QUEUE_MAX_PACKETS,
+            TRANSPORT_SEND_MAX_RETRIES,
+        );
         let transport_server = TransportServer::new(server_config);
 
         let (from_denaria_server_tx, from_denaria_server_rx) =
@@ -64,6 +147,7 @@ impl ServerTransport {
 
         Ok(Self {
             socket,
+            sender,
             transport_server,
             buffer: [0; TRANSPORT_MAX_PACKET_BYTES],
             from_denaria_server_rx,
@@ -71,24 +155,168 @@ impl ServerTransport {
             player_id_session_map: HashMap::new(),
             session_to_denaria_server_tx: HashMap::new(),
             client_id_to_server_tx_map: HashMap::new(),
+            client_id_session_map: HashMap::new(),
+            dead_sessions: Vec::new(),
+            orphaned_clients: Vec::new(),
+            session_threads: HashMap::new(),
+            session_diagnostics: HashMap::new(),
+            connection_config: ConnectionConfig::default(),
+            session_tick_rate: TickRate::default(),
+            health: HealthState::new(),
+            tick_budget: TICK_DELTA,
+            recorder: None,
+            send_queues: HashMap::new(),
+            send_order: VecDeque::new(),
+            send_budget: TRANSPORT_SEND_BUDGET,
+            send_saturated: false,
+            clock: Arc::new(SystemClock),
         })
     }
 
-    pub fn create_session(&mut self, id: u32, player_ids: Vec<String>) {
+    /// Starts the session `id` for `player_ids` on its own thread. A player id longer than
+    /// [`PLAYER_ID_MAX_BYTES`](crate::constants::PLAYER_ID_MAX_BYTES) refuses the whole session, its player could never be named
+    /// in the messages.
+    pub fn create_session(
+        &mut self,
+        id: u32,
+        player_ids: Vec<String>,
+        movement_config: MovementConfig,
+        seed: u64,
+        tick_rate: TickRate,
+    ) -> Result<(), TransportServerError> {
+        for player_id in &player_ids {
+            validate_player_id(player_id)?;
+        }
+
         // create bevy app in a new thread giving the channel receiver to the DenariaServer
         let (tx, rx) = unbounded::<ToDenariaServerMessage>();
 
         let from_denaria_server_tx = self.from_denaria_server_tx.clone();
+        let connection_config = self.connection_config.clone();
 
         for player_id in player_ids {
             self.player_id_session_map.insert(player_id, id);
         }
 
+        if self.send_saturated {
+            let _ = tx.send(ToDenariaServerMessage::SendSaturated { saturated: true });
+        }
         self.session_to_denaria_server_tx.insert(id, tx);
+        let diagnostics = SessionDiagnostics::default();
+        self.session_diagnostics.insert(id, diagnostics.clone());
 
-        std::thread::spawn(move || {
-            new_session(from_denaria_server_tx, rx);
+        let session_thread = std::thread::spawn(move || {
+            new_session(
+                id,
+                movement_config,
+                seed,
+                tick_rate,
+                connection_config,
+                diagnostics,
+                from_denaria_server_tx,
+                rx,
+            );
         });
+        self.session_threads.insert(id, session_thread);
+        Ok(())
+    }
+
+    /// Sets how long a single [`ServerTransport::update`] or [`ServerTransport::send_packets`]
+    /// call may take before a warning is emitted. Default: [`TICK_DELTA`]
+    pub fn set_tick_budget(&mut self, tick_budget: Duration) {
+        self.tick_budget = tick_budget;
+    }
+
+    /// Sets how long [`ServerTransport::send_packets`] may keep sending the packets queued by the
+    /// sessions, the rest is sent on the next call. Default: [`TRANSPORT_SEND_BUDGET`]
+    pub fn set_send_budget(&mut self, send_budget: Duration) {
+        self.send_budget = send_budget;
+    }
+
+    /// Replaces the wall clock the update and send phases are timed with, to control the
+    /// timings in tests. Default: [`SystemClock`]
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Records every inbound datagram to `path` so it can be replayed later with
+    /// [`replay_recording`](super::recording::replay_recording).
+    pub fn record_to<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.recorder = Some(PacketRecorder::new(path, RECORDING_MAX_FILE_BYTES)?);
+        Ok(())
+    }
+
+    /// Sets the config of the client connections of the sessions created afterwards.
+    /// Default: [`ConnectionConfig::default`]
+    pub fn set_connection_config(&mut self, connection_config: ConnectionConfig) {
+        self.connection_config = connection_config;
+    }
+
+    /// Sets the tick rate of the sessions created afterwards whose creation doesn't set one.
+    /// Default: [`SESSION_TICK_RATE`](crate::constants::SESSION_TICK_RATE)
+    pub fn set_session_tick_rate(&mut self, tick_rate: TickRate) {
+        self.session_tick_rate = tick_rate;
+    }
+
+    /// Sets the PlayFab credentials, see [`TransportServer::set_auth_config`].
+    pub fn set_auth_config(&mut self, auth_config: AuthConfig) {
+        self.transport_server.set_auth_config(auth_config);
+    }
+
+    /// Sets how a second connection with the player id of a connected client is handled.
+    pub fn set_duplicate_player_policy(&mut self, policy: DuplicatePlayerPolicy) {
+        self.transport_server.set_duplicate_player_policy(policy);
+    }
+
+    /// Sets the packet types accepted in each connection state, see
+    /// [`TransportServer::set_packet_policy`].
+    pub fn set_packet_policy(&mut self, packet_policy: PacketPolicy) {
+        self.transport_server.set_packet_policy(packet_policy);
+    }
+
+    /// Returns how many packets the packet policy dropped, see
+    /// [`TransportServer::unexpected_packets`].
+    pub fn unexpected_packets(&self) -> u64 {
+        self.transport_server.unexpected_packets()
+    }
+
+    /// Sets whether the messages a session still had queued for a client that disconnected
+    /// are sent to it, see [`TransportServer::set_flush_on_disconnect`].
+    pub fn set_flush_on_disconnect(&mut self, flush_on_disconnect: bool) {
+        self.transport_server
+            .set_flush_on_disconnect(flush_on_disconnect);
+    }
+
+    /// Admin command refusing the connection requests from the address, for the duration or
+    /// permanently, see [`TransportServer::ban_ip`].
+    pub fn ban_ip(&mut self, ip: IpAddr, duration: Option<Duration>) {
+        tracing::info!(%ip, ?duration, "Banned address");
+        self.transport_server.ban_ip(ip, duration);
+    }
+
+    /// Admin command lifting the ban of the address, returns false if it wasn't banned.
+    pub fn unban_ip(&mut self, ip: IpAddr) -> bool {
+        self.transport_server.unban_ip(ip)
+    }
+
+    /// Applies a command of the admin endpoint, returns whether it succeeded.
+    pub fn apply_admin_command(&mut self, command: AdminCommand) -> bool {
+        match command {
+            AdminCommand::Ban { ip, duration } => {
+                self.ban_ip(ip, duration);
+                true
+            }
+            AdminCommand::Unban { ip } => self.unban_ip(ip),
+            AdminCommand::Teleport {
+                player_id,
+                position,
+            } => self.teleport_player(&player_id, position),
+        }
+    }
+
+    /// Returns the liveness state updated on every [`ServerTransport::update`].
+    pub fn health(&self) -> HealthState {
+        self.health.clone()
     }
 
     /// Returns the server public address
@@ -101,6 +329,11 @@ impl ServerTransport {
         self.transport_server.max_clients()
     }
 
+    /// See [`TransportServer::set_max_clients`].
+    pub fn set_max_clients(&mut self, max_clients: usize) {
+        self.transport_server.set_max_clients(max_clients);
+    }
+
     /// Returns current number of clients connected.
     pub fn connected_clients(&self) -> usize {
         self.transport_server.connected_clients()
@@ -111,29 +344,154 @@ impl ServerTransport {
         self.transport_server.client_addr(client_id.raw())
     }
 
+    /// Returns the id, address and player id of every connected client.
+    pub fn connected_roster(&self) -> Vec<(u64, SocketAddr, String)> {
+        self.transport_server.connected_roster()
+    }
+
+    /// Returns the session the connected client was routed to.
+    pub fn session_of_client(&self, client_id: u64) -> Option<u32> {
+        self.client_id_session_map.get(&client_id).copied()
+    }
+
+    /// Returns the ids of the connected clients routed to the session.
+    pub fn clients_in_session(&self, id: u32) -> Vec<u64> {
+        self.client_id_session_map
+            .iter()
+            .filter(|(_, session_id)| **session_id == id)
+            .map(|(client_id, _)| *client_id)
+            .collect()
+    }
+
+    /// Returns the tick time and entity count the session publishes every tick.
+    pub fn session_diagnostics(&self, id: u32) -> Option<SessionDiagnostics> {
+        self.session_diagnostics.get(&id).cloned()
+    }
+
+    /// Admin command pausing or resuming the gameplay of a session, its clients stay connected.
+    /// Returns false when the session doesn't exist.
+    pub fn set_session_paused(&self, id: u32, paused: bool) -> bool {
+        match self.session_to_denaria_server_tx.get(&id) {
+            Some(sender) => sender
+                .send(ToDenariaServerMessage::SetPaused { paused })
+                .is_ok(),
+            None => false,
+        }
+    }
+
+    /// Admin command stopping a session, its thread ends after the current tick and the session
+    /// is closed on a following update. Returns false when the session doesn't exist.
+    pub fn shutdown_session(&self, id: u32) -> bool {
+        match self.session_to_denaria_server_tx.get(&id) {
+            Some(sender) => sender.send(ToDenariaServerMessage::Shutdown).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Admin command moving a player to `position`, see [`DenariaServer::teleport`].
+    /// Returns false when the player is in no session.
+    pub fn teleport_player(&self, player_id: &str, position: Vec3) -> bool {
+        let Some(sender) = self
+            .player_id_session_map
+            .get(player_id)
+            .and_then(|id| self.session_to_denaria_server_tx.get(id))
+        else {
+            return false;
+        };
+        sender
+            .send(ToDenariaServerMessage::Teleport {
+                player_id: player_id.to_string(),
+                position,
+            })
+            .is_ok()
+    }
+
+    /// Returns how many clients are in each state of the connection handshake.
+    pub fn pending_clients_by_state(&self) -> HashMap<ConnectionState, usize> {
+        self.transport_server.pending_clients_by_state()
+    }
+
     /// Disconnects all connected clients.
     /// This sends the disconnect packet instantly, use this when closing/exiting games,
     pub fn disconnect_all(&mut self) {
         for client_id in self.transport_server.clients_id() {
-            let server_result = self.transport_server.disconnect(client_id);
+            let server_result = self
+                .transport_server
+                .disconnect(client_id, &DisconnectReason::DisconnectedByServer);
             // get tx map by client id and send disconnect message
             if let Some(sender) = self.client_id_to_server_tx_map.get_mut(&client_id) {
                 if let Err(e) =
                     sender.send(ToDenariaServerMessage::ClientDisconnected { client_id })
                 {
-                    tracing::error!("Failed to send disconnect message to client {client_id}: {e}");
+                    tracing::error!(
+                        client_id,
+                        "Failed to send disconnect message to client: {e}"
+                    );
                 }
             }
             handle_server_result(
                 server_result,
-                &self.socket,
+                &mut self.sender,
                 &self.player_id_session_map,
                 &self.session_to_denaria_server_tx,
                 &mut self.client_id_to_server_tx_map,
+                &mut self.client_id_session_map,
+                &mut self.dead_sessions,
+                &mut self.orphaned_clients,
             );
         }
     }
 
+    /// Disconnects the clients routed to the session, e.g. when its match ended, and tells the
+    /// session they left. Clients of other sessions stay connected. The session keeps running
+    /// and its players can join it again, see [`ServerTransport::shutdown_session`] to stop it.
+    /// Returns the number of clients disconnected.
+    pub fn disconnect_session(&mut self, session_id: u32) -> usize {
+        let client_ids: Vec<u64> = self
+            .client_id_session_map
+            .iter()
+            .filter(|(_, client_session_id)| **client_session_id == session_id)
+            .map(|(client_id, _)| *client_id)
+            .collect();
+        tracing::info!(
+            session_id,
+            clients = client_ids.len(),
+            "Disconnecting the clients of the session"
+        );
+        for &client_id in client_ids.iter() {
+            let server_result = self
+                .transport_server
+                .disconnect(client_id, &DisconnectReason::DisconnectedByServer);
+            if !matches!(server_result, ServerResult::ClientDisconnected { .. }) {
+                // Not connected on the transport anymore, only the routing is left
+                self.client_id_session_map.remove(&client_id);
+                self.client_id_to_server_tx_map.remove(&client_id);
+                continue;
+            }
+            handle_server_result(
+                server_result,
+                &mut self.sender,
+                &self.player_id_session_map,
+                &self.session_to_denaria_server_tx,
+                &mut self.client_id_to_server_tx_map,
+                &mut self.client_id_session_map,
+                &mut self.dead_sessions,
+                &mut self.orphaned_clients,
+            );
+        }
+        client_ids.len()
+    }
+
+    /// Whether the last [`ServerTransport::send_packets`] left packets unsent or dropped some.
+    pub fn is_send_saturated(&self) -> bool {
+        self.send_saturated
+    }
+
+    /// Number of packets dropped because of send errors or a socket that stayed blocked.
+    pub fn dropped_sends(&self) -> u64 {
+        self.sender.dropped_sends()
+    }
+
     /// Returns the duration since the connected client last received a packet.
     /// Usefull to detect users that are timing out.
     pub fn time_since_last_received_packet(&self, client_id: ClientId) -> Option<Duration> {
@@ -143,23 +501,61 @@ impl ServerTransport {
 
     /// Advances the transport by the duration, and receive packets from the network.
     pub fn update(&mut self, duration: Duration) -> Result<(), TransportError> {
+        let span = tracing::debug_span!(
+            "transport_update",
+            sessions = self.session_to_denaria_server_tx.len(),
+            packets_processed = tracing::field::Empty,
+            clients_updated = tracing::field::Empty,
+            elapsed_us = tracing::field::Empty,
+        );
+        let _enter = span.enter();
+        let start_time = self.clock.now();
+
         self.transport_server.update(duration);
 
+        let mut packets_processed: u64 = 0;
         loop {
             match self.socket.recv_from(&mut self.buffer) {
                 Ok((len, addr)) => {
+                    packets_processed += 1;
+                    if let Some(recorder) = self.recorder.as_mut() {
+                        if let Err(e) = recorder.record(
+                            self.transport_server.current_time(),
+                            addr,
+                            &self.buffer[..len],
+                        ) {
+                            tracing::error!("Failed to record packet, recording stopped: {e}");
+                            self.recorder = None;
+                        }
+                    }
                     let server_result = self
                         .transport_server
                         .process_packet(addr, &mut self.buffer[..len]);
 
                     if let Some(new_session_details) = handle_server_result(
                         server_result,
-                        &self.socket,
+                        &mut self.sender,
                         &self.player_id_session_map,
                         &self.session_to_denaria_server_tx,
                         &mut self.client_id_to_server_tx_map,
+                        &mut self.client_id_session_map,
+                        &mut self.dead_sessions,
+                        &mut self.orphaned_clients,
                     ) {
-                        self.create_session(new_session_details.id, new_session_details.player_ids);
+                        let session_id = new_session_details.id;
+                        let created = self.create_session(
+                            new_session_details.id,
+                            new_session_details.player_ids,
+                            new_session_details.movement_config,
+                            new_session_details.seed,
+                            new_session_details
+                                .tick_rate
+                                .map(|tick_rate| TickRate(tick_rate as u32))
+                                .unwrap_or(self.session_tick_rate),
+                        );
+                        if let Err(e) = created {
+                            tracing::error!(session_id, "Refused to create session: {e}");
+                        }
                     }
                 }
                 Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
@@ -169,69 +565,288 @@ impl ServerTransport {
             };
         }
 
-        for client_id in self.transport_server.clients_id() {
+        let clients_id = self.transport_server.clients_id();
+        for &client_id in clients_id.iter() {
             let server_result = self.transport_server.update_client(client_id);
             handle_server_result(
                 server_result,
-                &self.socket,
+                &mut self.sender,
                 &self.player_id_session_map,
                 &self.session_to_denaria_server_tx,
                 &mut self.client_id_to_server_tx_map,
+                &mut self.client_id_session_map,
+                &mut self.dead_sessions,
+                &mut self.orphaned_clients,
             );
         }
-        // for disconnection_id in server.disconnections_id() {
-        //     let server_result = self.transport_server.disconnect(disconnection_id.raw());
-        //     handle_server_result(server_result, &self.socket);
-        // }
+
+        self.collect_ended_sessions();
+        self.close_dead_sessions();
+        self.disconnect_orphaned_clients();
+
+        self.health
+            .record_tick(self.session_to_denaria_server_tx.len());
+
+        let elapsed = self.clock.now().saturating_duration_since(start_time);
+        span.record("packets_processed", packets_processed);
+        span.record("clients_updated", clients_id.len());
+        span.record("elapsed_us", elapsed.as_micros() as u64);
+        self.warn_if_over_budget("update", elapsed);
 
         Ok(())
     }
 
+    fn warn_if_over_budget(&self, phase: &str, elapsed: Duration) {
+        if elapsed > self.tick_budget {
+            tracing::warn!(
+                phase,
+                elapsed_us = elapsed.as_micros() as u64,
+                budget_us = self.tick_budget.as_micros() as u64,
+                "Transport tick exceeded its budget"
+            );
+        }
+    }
+
+    /// Marks the sessions whose thread ended as dead, without waiting for a send to them to fail.
+    fn collect_ended_sessions(&mut self) {
+        let ended_sessions: Vec<u32> = self
+            .session_threads
+            .iter()
+            .filter(|(_, session_thread)| session_thread.is_finished())
+            .map(|(session_id, _)| *session_id)
+            .collect();
+        for session_id in ended_sessions {
+            let Some(session_thread) = self.session_threads.remove(&session_id) else {
+                continue;
+            };
+            match session_thread.join() {
+                Ok(()) => tracing::warn!(session_id, "Session thread exited"),
+                Err(panic) => {
+                    let message = panic
+                        .downcast_ref::<&str>()
+                        .map(|message| message.to_string())
+                        .or_else(|| panic.downcast_ref::<String>().cloned())
+                        .unwrap_or_default();
+                    tracing::error!(session_id, "Session thread panicked: {message}");
+                }
+            }
+            self.dead_sessions.push(session_id);
+        }
+    }
+
+    /// Disconnects the clients whose payloads had no session to go to, instead of dropping
+    /// every payload they send from then on.
+    fn disconnect_orphaned_clients(&mut self) {
+        for client_id in std::mem::take(&mut self.orphaned_clients) {
+            self.client_id_to_server_tx_map.remove(&client_id);
+            self.client_id_session_map.remove(&client_id);
+            if let ServerResult::ClientDisconnected {
+                addr,
+                payload: Some(payload),
+                ..
+            } = self
+                .transport_server
+                .disconnect(client_id, &DisconnectReason::SessionClosed)
+            {
+                tracing::warn!(
+                    client_id,
+                    "Client disconnected: {}",
+                    DisconnectReason::SessionClosed
+                );
+                self.sender.send_to(payload, addr);
+            }
+        }
+    }
+
+    /// Removes sessions whose DenariaServer stopped receiving (e.g. its thread panicked)
+    /// and disconnects every client that was routed to them.
+    fn close_dead_sessions(&mut self) {
+        for session_id in std::mem::take(&mut self.dead_sessions) {
+            self.session_threads.remove(&session_id);
+            self.session_diagnostics.remove(&session_id);
+            let Some(session_tx) = self.session_to_denaria_server_tx.remove(&session_id) else {
+                continue;
+            };
+            tracing::error!(session_id, "Session is no longer running, closing it");
+
+            self.player_id_session_map
+                .retain(|_, player_session_id| *player_session_id != session_id);
+
+            let client_ids: Vec<u64> = self
+                .client_id_to_server_tx_map
+                .iter()
+                .filter(|(_, sender)| sender.same_channel(&session_tx))
+                .map(|(client_id, _)| *client_id)
+                .collect();
+
+            for client_id in client_ids {
+                self.client_id_to_server_tx_map.remove(&client_id);
+                self.client_id_session_map.remove(&client_id);
+                tracing::warn!(
+                    client_id,
+                    session_id,
+                    "Client disconnected: {}",
+                    DisconnectReason::SessionClosed
+                );
+                if let ServerResult::ClientDisconnected {
+                    addr,
+                    payload: Some(payload),
+                    ..
+                } = self
+                    .transport_server
+                    .disconnect(client_id, &DisconnectReason::SessionClosed)
+                {
+                    self.sender.send_to(payload, addr);
+                }
+            }
+        }
+    }
+
     /// Send packets to connected clients.
     pub fn send_packets(&mut self) {
-        self.handle_messages();
+        let span = tracing::debug_span!(
+            "transport_send_packets",
+            sessions = self.session_to_denaria_server_tx.len(),
+            packets_sent = tracing::field::Empty,
+            bytes_sent = tracing::field::Empty,
+            elapsed_us = tracing::field::Empty,
+        );
+        let _enter = span.enter();
+        let start_time = self.clock.now();
+        let dropped_sends = self.sender.dropped_sends();
+
+        let (retried_packets, retried_bytes) = self.sender.flush();
+        let (packets_sent, bytes_sent) = self.handle_messages();
+        let packets_sent = packets_sent + retried_packets;
+        let bytes_sent = bytes_sent + retried_bytes;
+
+        // Packets still waiting for the socket or the budget, or dropped, mean the sessions
+        // produce more than can be sent
+        let saturated = self.sender.queued_packets() > 0
+            || !self.send_order.is_empty()
+            || self.sender.dropped_sends() > dropped_sends;
+        self.set_send_saturated(saturated);
+
+        let elapsed = self.clock.now().saturating_duration_since(start_time);
+        span.record("packets_sent", packets_sent);
+        span.record("bytes_sent", bytes_sent);
+        span.record("elapsed_us", elapsed.as_micros() as u64);
+        self.warn_if_over_budget("send_packets", elapsed);
     }
 
-    fn handle_messages(&mut self) {
-        let start_time = Instant::now();
-        loop {
-            if start_time.elapsed() >= Duration::from_millis(10) {
-                break; // Time limit reached
-            }
-            match self.from_denaria_server_rx.try_recv() {
-                Ok(message) => self.send_message(message),
-                Err(TryRecvError::Empty) => break, // No more messages to process
-                Err(TryRecvError::Disconnected) => {
-                    tracing::error!("Channel to DenariaServer disconnected");
+    // Tells every session when the saturation of the socket changes
+    fn set_send_saturated(&mut self, saturated: bool) {
+        if self.send_saturated == saturated {
+            return;
+        }
+        self.send_saturated = saturated;
+        if saturated {
+            tracing::warn!(
+                queued_packets = self.sender.queued_packets(),
+                clients_waiting = self.send_order.len(),
+                "Socket saturated, sessions throttle their broadcasts"
+            );
+        } else {
+            tracing::info!("Socket caught up, sessions resume their broadcasts");
+        }
+        for sender in self.session_to_denaria_server_tx.values() {
+            // A session that stopped receiving is closed on the next update
+            let _ = sender.send(ToDenariaServerMessage::SendSaturated { saturated });
+        }
+    }
+
+    /// Sends the packets queued by the sessions, one packet per client in turns so no client
+    /// uses up the send budget. The budget is checked between turns: every client with queued
+    /// packets gets at least one sent per call.
+    /// Returns the number of packets and bytes sent.
+    fn handle_messages(&mut self) -> (u64, u64) {
+        self.queue_session_messages();
+
+        let start_time = self.clock.now();
+        let mut packets_sent = 0;
+        let mut bytes_sent = 0;
+        let mut out = [0u8; TRANSPORT_MAX_PACKET_BYTES];
+        while !self.send_order.is_empty() {
+            for _ in 0..self.send_order.len() {
+                let Some(client_id) = self.send_order.pop_front() else {
                     break;
+                };
+                let Some(queue) = self.send_queues.get_mut(&client_id) else {
+                    continue;
+                };
+                let Some(packet) = queue.pop_front() else {
+                    self.send_queues.remove(&client_id);
+                    continue;
+                };
+
+                match self
+                    .transport_server
+                    .generate_payload_packet(client_id, &packet, &mut out)
+                {
+                    Ok((addr, len)) => {
+                        if let Some(len) = self.sender.send_to(&out[..len], addr) {
+                            packets_sent += 1;
+                            bytes_sent += len as u64;
+                        }
+                    }
+                    // The client is gone, its remaining packets would fail the same way
+                    Err(TransportServerError::ClientNotFound) => {
+                        tracing::debug!(client_id, "Dropped packets of a disconnected client");
+                        queue.clear();
+                    }
+                    Err(e) => {
+                        tracing::error!(
+                            "Failed to encrypt payload packet for client {client_id}: {e}"
+                        );
+                        queue.clear();
+                    }
+                }
+
+                if queue.is_empty() {
+                    self.send_queues.remove(&client_id);
+                } else {
+                    self.send_order.push_back(client_id);
                 }
             }
+            if self.clock.now().saturating_duration_since(start_time) >= self.send_budget {
+                break; // Time limit reached
+            }
         }
+        (packets_sent, bytes_sent)
     }
 
-    fn send_message(&mut self, message: FromDenariaServerMessage) {
-        match message {
-            FromDenariaServerMessage::SendPacket { client_id, packets } => {
-                for packet in packets {
-                    match self
-                        .transport_server
-                        .generate_payload_packet(client_id, &packet)
-                    {
-                        Ok((addr, payload)) => {
-                            if let Err(e) = self.socket.send_to(payload, addr) {
-                                tracing::error!(
-                                    "Failed to send packet to client {client_id} ({addr}): {e}"
-                                );
-                            }
-                            break;
-                        }
-                        Err(e) => {
-                            tracing::error!(
-                                "Failed to encrypt payload packet for client {client_id}: {e}"
-                            );
-                            break;
-                        }
+    /// Moves the packets sent by the sessions since the last call into the per client queues.
+    fn queue_session_messages(&mut self) {
+        loop {
+            match self.from_denaria_server_rx.try_recv() {
+                Ok(FromDenariaServerMessage::SendPacket { client_id, packets }) => {
+                    if packets.is_empty() {
+                        continue;
+                    }
+                    let queue = self.send_queues.entry(client_id).or_default();
+                    if queue.is_empty() {
+                        self.send_order.push_back(client_id);
                     }
+                    queue.extend(packets);
+                }
+                Ok(FromDenariaServerMessage::DisconnectClient { client_id, reason }) => {
+                    tracing::info!(client_id, "Client disconnected by its session: {reason}");
+                    let server_result = self.transport_server.disconnect(client_id, &reason);
+                    handle_server_result(
+                        server_result,
+                        &mut self.sender,
+                        &self.player_id_session_map,
+                        &self.session_to_denaria_server_tx,
+                        &mut self.client_id_to_server_tx_map,
+                        &mut self.client_id_session_map,
+                        &mut self.dead_sessions,
+                        &mut self.orphaned_clients,
+                    );
+                }
+                Err(TryRecvError::Empty) => break, // No more messages to process
+                Err(TryRecvError::Disconnected) => {
+                    tracing::error!("Channel to DenariaServer disconnected");
+                    break;
                 }
             }
         }
@@ -241,18 +856,33 @@ impl ServerTransport {
 struct NewSessionDetails {
     id: u32,
     player_ids: Vec<String>,
+    movement_config: MovementConfig,
+    seed: u64,
+    tick_rate: Option<u16>,
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handle_server_result(
     server_result: ServerResult,
-    socket: &UdpSocket,
+    sender: &mut PacketSender,
     player_id_session_map: &HashMap<String, u32>,
     session_to_denaria_server_tx: &HashMap<u32, Sender<ToDenariaServerMessage>>,
     client_id_to_server_tx_map: &mut HashMap<u64, Sender<ToDenariaServerMessage>>,
+    client_id_session_map: &mut HashMap<u64, u32>,
+    dead_sessions: &mut Vec<u32>,
+    orphaned_clients: &mut Vec<u64>,
 ) -> Option<NewSessionDetails> {
-    let send_packet = |packet: &[u8], addr: SocketAddr| {
-        if let Err(err) = socket.send_to(packet, addr) {
-            tracing::error!("Failed to send packet to {addr}: {err}");
+    let mut send_packet = |packet: &[u8], addr: SocketAddr| {
+        sender.send_to(packet, addr);
+    };
+
+    // A failed send means every receiver of the session was dropped
+    let mut mark_session_dead = |sender: &Sender<ToDenariaServerMessage>| {
+        if let Some((session_id, _)) = session_to_denaria_server_tx
+            .iter()
+            .find(|(_, session_tx)| session_tx.same_channel(sender))
+        {
+            dead_sessions.push(*session_id);
         }
     };
 
@@ -268,11 +898,64 @@ fn handle_server_result(
                         client_id,
                         payload: payload.to_vec(),
                     }) {
-                        tracing::error!("Failed to send payload to client {client_id}: {e}");
+                        tracing::error!(client_id, "Failed to send payload to client: {e}");
+                        mark_session_dead(sender);
                     }
                 }
                 None => {
-                    tracing::error!("Server (in a session) not found for client {client_id}");
+                    tracing::error!(
+                        client_id,
+                        "Server (in a session) not found for client, disconnecting it"
+                    );
+                    orphaned_clients.push(client_id);
+                }
+            }
+        }
+        ServerResult::Control {
+            client_id,
+            kind,
+            payload,
+        } => match client_id_to_server_tx_map.get(&client_id) {
+            Some(sender) => {
+                if let Err(e) = sender.send(ToDenariaServerMessage::Control {
+                    client_id,
+                    kind,
+                    payload: payload.to_vec(),
+                }) {
+                    tracing::error!(client_id, "Failed to send control payload to client: {e}");
+                    mark_session_dead(sender);
+                }
+            }
+            None => {
+                tracing::error!(
+                    client_id,
+                    "Server (in a session) not found for client, disconnecting it"
+                );
+                orphaned_clients.push(client_id);
+            }
+        },
+        ServerResult::ClientConfirmed { client_id, payload } => {
+            match client_id_to_server_tx_map.get(&client_id) {
+                Some(sender) => {
+                    let mut result =
+                        sender.send(ToDenariaServerMessage::ClientConfirmed { client_id });
+                    if let (Ok(()), Some(payload)) = (&result, payload) {
+                        result = sender.send(ToDenariaServerMessage::Payload {
+                            client_id,
+                            payload: payload.to_vec(),
+                        });
+                    }
+                    if let Err(e) = result {
+                        tracing::error!(client_id, "Failed to send confirmation to client: {e}");
+                        mark_session_dead(sender);
+                    }
+                }
+                None => {
+                    tracing::error!(
+                        client_id,
+                        "Server (in a session) not found for client, disconnecting it"
+                    );
+                    orphaned_clients.push(client_id);
                 }
             }
         }
@@ -291,36 +974,742 @@ fn handle_server_result(
                         player_id,
                     }) {
                         tracing::error!(
-                            "Failed to send client connected message to client {client_id}: {e}"
+                            client_id,
+                            session_id = *session_id,
+                            "Failed to send client connected message to client: {e}"
                         );
+                        mark_session_dead(sender);
                     }
                     client_id_to_server_tx_map.insert(client_id, sender.clone());
+                    client_id_session_map.insert(client_id, *session_id);
+                    send_packet(payload, addr);
+                    return None;
                 }
-                send_packet(payload, addr);
             }
+            // Without a session its payloads would be dropped, it's better off reconnecting
+            tracing::error!(
+                client_id,
+                player_id,
+                "No session found for connected client, disconnecting it"
+            );
+            orphaned_clients.push(client_id);
         }
         ServerResult::ClientDisconnected {
             client_id,
             addr,
             payload,
         } => {
-            if let Some(sender) = client_id_to_server_tx_map.get(&client_id) {
+            client_id_session_map.remove(&client_id);
+            if let Some(sender) = client_id_to_server_tx_map.remove(&client_id) {
                 if let Err(e) =
                     sender.send(ToDenariaServerMessage::ClientDisconnected { client_id })
                 {
                     tracing::error!(
-                        "Failed to send client disconnected message to client {client_id}: {e}"
+                        client_id,
+                        "Failed to send client disconnected message to client: {e}"
                     );
+                    mark_session_dead(&sender);
                 }
             }
             if let Some(payload) = payload {
                 send_packet(payload, addr);
             }
         }
-        ServerResult::CreateSession { id, player_ids } => {
-            tracing::info!("CreateSession: {id} {player_ids:?}");
-            return Some(NewSessionDetails { id, player_ids });
+        ServerResult::CreateSession {
+            id,
+            player_ids,
+            movement_config,
+            seed,
+            tick_rate,
+        } => {
+            tracing::info!(session_id = id, "CreateSession: {player_ids:?}");
+            return Some(NewSessionDetails {
+                id,
+                player_ids,
+                movement_config,
+                // Sessions created without a seed get a fresh one, it's logged so they can be replayed
+                seed: seed.unwrap_or_else(rand::random),
+                tick_rate,
+            });
         }
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        sync::Mutex,
+    };
+
+    use tracing::{
+        field::{Field, Visit},
+        Event, Level, Subscriber,
+    };
+    use tracing_subscriber::{layer::Context, prelude::*, Layer};
+
+    use super::*;
+    use crate::{
+        constants::{TRANSPORT_CONNECTION_TIMEOUT, TRANSPORT_SEND_RATE},
+        server::{
+            channel::DefaultChannel, clock::ManualClock, connection::ConnectionConfig,
+            server::DenariaServer,
+        },
+    };
+
+    /// Collects the message of every warn event.
+    #[derive(Clone, Default)]
+    struct WarnCapture(Arc<Mutex<Vec<String>>>);
+
+    impl<S: Subscriber> Layer<S> for WarnCapture {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            struct MessageVisitor(String);
+            impl Visit for MessageVisitor {
+                fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                    if field.name() == "message" {
+                        self.0 = format!("{value:?}");
+                    }
+                }
+            }
+
+            if *event.metadata().level() == Level::WARN {
+                let mut visitor = MessageVisitor(String::new());
+                event.record(&mut visitor);
+                self.0.lock().unwrap().push(visitor.0);
+            }
+        }
+    }
+
+    fn new_transport() -> ServerTransport {
+        let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let server_config = ServerConfig {
+            current_time: Duration::ZERO,
+            max_clients: 8,
+            public_addresses: vec![addr],
+            keep_alive_interval: TRANSPORT_SEND_RATE,
+            connection_timeout: TRANSPORT_CONNECTION_TIMEOUT,
+        };
+        ServerTransport::new(server_config, socket).unwrap()
+    }
+
+    // Routes `client_id` to a session 0 whose messages arrive on the returned receiver
+    fn connect_client(
+        transport: &mut ServerTransport,
+        client_id: u64,
+    ) -> (UdpSocket, Receiver<ToDenariaServerMessage>) {
+        let (tx, rx) = unbounded::<ToDenariaServerMessage>();
+        (connect_client_to_session(transport, client_id, 0, tx), rx)
+    }
+
+    fn connect_client_to_session(
+        transport: &mut ServerTransport,
+        client_id: u64,
+        session_id: u32,
+        tx: Sender<ToDenariaServerMessage>,
+    ) -> UdpSocket {
+        let client_socket =
+            UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+        client_socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        transport
+            .session_to_denaria_server_tx
+            .insert(session_id, tx.clone());
+        transport.client_id_to_server_tx_map.insert(client_id, tx);
+        transport
+            .client_id_session_map
+            .insert(client_id, session_id);
+        transport
+            .transport_server
+            .insert_connected_client(client_id, client_socket.local_addr().unwrap());
+        client_socket
+    }
+
+    #[test]
+    fn update_forwards_client_payloads_to_its_session() {
+        let mut transport = new_transport();
+        let server_addr = transport.socket.local_addr().unwrap();
+        let client_id = 7;
+        let (client_socket, rx) = connect_client(&mut transport, client_id);
+
+        let mut data_packet = vec![1u8];
+        data_packet.extend_from_slice(&client_id.to_le_bytes());
+        data_packet.extend_from_slice(&[4, 5, 6]);
+        client_socket.send_to(&data_packet, server_addr).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        transport.update(Duration::from_millis(16)).unwrap();
+
+        match rx.try_recv() {
+            Ok(ToDenariaServerMessage::Payload {
+                client_id: received_id,
+                payload,
+            }) => {
+                assert_eq!(received_id, client_id);
+                assert_eq!(payload, vec![4, 5, 6]);
+            }
+            _ => panic!("expected the payload to be forwarded"),
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn update_routes_control_payloads_apart_from_gameplay_packets() {
+        let mut transport = new_transport();
+        let server_addr = transport.socket.local_addr().unwrap();
+        let client_id = 7;
+        let (client_socket, rx) = connect_client(&mut transport, client_id);
+
+        let mut data_packet = vec![1u8];
+        data_packet.extend_from_slice(&client_id.to_le_bytes());
+        data_packet.extend_from_slice(&[4, 5, 6]);
+        client_socket.send_to(&data_packet, server_addr).unwrap();
+        // Control packet of kind 9 with the same bytes
+        let mut control_packet = vec![4u8];
+        control_packet.extend_from_slice(&client_id.to_le_bytes());
+        control_packet.extend_from_slice(&[9, 4, 5, 6]);
+        client_socket.send_to(&control_packet, server_addr).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        transport.update(Duration::from_millis(16)).unwrap();
+
+        match rx.try_recv() {
+            Ok(ToDenariaServerMessage::Payload { payload, .. }) => {
+                assert_eq!(payload, vec![4, 5, 6]);
+            }
+            _ => panic!("expected the gameplay payload to be forwarded"),
+        }
+        match rx.try_recv() {
+            Ok(ToDenariaServerMessage::Control {
+                client_id: received_id,
+                kind,
+                payload,
+            }) => {
+                assert_eq!(received_id, client_id);
+                assert_eq!(kind, 9);
+                assert_eq!(payload, vec![4, 5, 6]);
+            }
+            _ => panic!("expected the control payload to be forwarded"),
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn payload_without_a_session_disconnects_the_client() {
+        let mut transport = new_transport();
+        let server_addr = transport.socket.local_addr().unwrap();
+        let client_id = 7;
+        let (client_socket, _rx) = connect_client(&mut transport, client_id);
+        // The routing of the client was lost
+        transport.client_id_to_server_tx_map.remove(&client_id);
+        transport.client_id_session_map.remove(&client_id);
+
+        let mut data_packet = vec![1u8];
+        data_packet.extend_from_slice(&client_id.to_le_bytes());
+        data_packet.extend_from_slice(&[4, 5, 6]);
+        client_socket.send_to(&data_packet, server_addr).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        transport.update(Duration::from_millis(16)).unwrap();
+
+        let mut buffer = [0u8; 1500];
+        let (len, _) = client_socket.recv_from(&mut buffer).unwrap();
+        assert!(len > 0);
+        // Disconnect packet with the session closed reason
+        assert_eq!(buffer[0], 2);
+        assert_eq!(buffer[9], DisconnectReason::SessionClosed.code());
+        assert!(transport.transport_server.clients_id().is_empty());
+    }
+
+    #[test]
+    fn send_packets_sends_every_packet_of_a_message() {
+        let mut transport = new_transport();
+        let client_id = 7;
+        let (client_socket, _rx) = connect_client(&mut transport, client_id);
+
+        transport
+            .from_denaria_server_tx
+            .send(FromDenariaServerMessage::SendPacket {
+                client_id,
+                packets: vec![vec![1], vec![2, 2], vec![3, 3, 3]],
+            })
+            .unwrap();
+        transport.send_packets();
+
+        let mut buffer = [0u8; TRANSPORT_MAX_PACKET_BYTES];
+        for expected in [vec![1], vec![2, 2], vec![3, 3, 3]] {
+            let (len, _) = client_socket.recv_from(&mut buffer).unwrap();
+            assert_eq!(buffer[0], 1); // Data
+            assert_eq!(&buffer[1..9], &client_id.to_le_bytes());
+            assert_eq!(&buffer[9..len], expected.as_slice());
+        }
+    }
+
+    #[test]
+    fn send_budget_serves_every_client_in_turns() {
+        let mut transport = new_transport();
+        let client_sockets: Vec<UdpSocket> = (1..=4)
+            .map(|client_id| connect_client(&mut transport, client_id).0)
+            .collect();
+
+        for client_id in 1..=4 {
+            for i in 0..50u8 {
+                transport
+                    .from_denaria_server_tx
+                    .send(FromDenariaServerMessage::SendPacket {
+                        client_id,
+                        packets: vec![vec![i]],
+                    })
+                    .unwrap();
+            }
+        }
+
+        // No budget at all still sends one packet to each client, the first queued one
+        transport.set_send_budget(Duration::ZERO);
+        transport.send_packets();
+        let mut buffer = [0u8; TRANSPORT_MAX_PACKET_BYTES];
+        for client_socket in client_sockets.iter() {
+            let (len, _) = client_socket.recv_from(&mut buffer).unwrap();
+            assert_eq!(&buffer[9..len], &[0]);
+        }
+
+        // The remaining packets are kept and sent in order once there is time
+        transport.set_send_budget(Duration::from_secs(1));
+        transport.send_packets();
+        for client_socket in client_sockets.iter() {
+            for i in 1..50u8 {
+                let (len, _) = client_socket.recv_from(&mut buffer).unwrap();
+                assert_eq!(&buffer[9..len], &[i]);
+            }
+        }
+        assert!(transport.send_queues.is_empty());
+        assert!(transport.send_order.is_empty());
+    }
+
+    #[test]
+    fn send_budget_is_timed_with_injected_clock() {
+        let mut transport = new_transport();
+        let client_id = 7;
+        let (client_socket, _rx) = connect_client(&mut transport, client_id);
+        for i in 0..50u8 {
+            transport
+                .from_denaria_server_tx
+                .send(FromDenariaServerMessage::SendPacket {
+                    client_id,
+                    packets: vec![vec![i]],
+                })
+                .unwrap();
+        }
+
+        // A stopped clock never uses up the budget, however slow the sends are
+        transport.set_clock(Arc::new(ManualClock::default()));
+        transport.set_send_budget(Duration::from_nanos(1));
+        transport.send_packets();
+        assert!(transport.send_queues.is_empty());
+
+        let mut buffer = [0u8; TRANSPORT_MAX_PACKET_BYTES];
+        for i in 0..50u8 {
+            let (len, _) = client_socket.recv_from(&mut buffer).unwrap();
+            assert_eq!(&buffer[9..len], &[i]);
+        }
+    }
+
+    #[test]
+    fn sessions_are_told_when_the_transport_saturates() {
+        let mut transport = new_transport();
+        let client_id = 7;
+        let (client_socket, rx) = connect_client(&mut transport, client_id);
+
+        let queue_packets = |transport: &ServerTransport, count: u8| {
+            for i in 0..count {
+                transport
+                    .from_denaria_server_tx
+                    .send(FromDenariaServerMessage::SendPacket {
+                        client_id,
+                        packets: vec![vec![i]],
+                    })
+                    .unwrap();
+            }
+        };
+
+        // No budget leaves all but one packet unsent, like a socket that can't keep up
+        transport.set_send_budget(Duration::ZERO);
+        queue_packets(&transport, 20);
+        transport.send_packets();
+        assert!(transport.is_send_saturated());
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ToDenariaServerMessage::SendSaturated { saturated: true })
+        ));
+
+        // Still saturated, nothing new to tell
+        transport.send_packets();
+        assert!(rx.try_recv().is_err());
+
+        transport.set_send_budget(Duration::from_secs(1));
+        transport.send_packets();
+        assert!(!transport.is_send_saturated());
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ToDenariaServerMessage::SendSaturated { saturated: false })
+        ));
+
+        let mut buffer = [0u8; TRANSPORT_MAX_PACKET_BYTES];
+        for i in 0..20u8 {
+            let (len, _) = client_socket.recv_from(&mut buffer).unwrap();
+            assert_eq!(&buffer[9..len], &[i]);
+        }
+    }
+
+    #[test]
+    fn client_disconnect_is_forwarded_to_its_session() {
+        let mut transport = new_transport();
+        let server_addr = transport.socket.local_addr().unwrap();
+        let client_id = 7;
+        let (client_socket, rx) = connect_client(&mut transport, client_id);
+
+        let mut disconnect_packet = vec![2u8];
+        disconnect_packet.extend_from_slice(&client_id.to_le_bytes());
+        client_socket
+            .send_to(&disconnect_packet, server_addr)
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        transport.update(Duration::from_millis(16)).unwrap();
+
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ToDenariaServerMessage::ClientDisconnected { client_id: 7 })
+        ));
+        assert_eq!(transport.connected_clients(), 0);
+        assert_eq!(transport.session_of_client(client_id), None);
+        assert!(!transport
+            .client_id_to_server_tx_map
+            .contains_key(&client_id));
+        // The session itself keeps running
+        assert!(transport.session_to_denaria_server_tx.contains_key(&0));
+    }
+
+    #[test]
+    fn clean_disconnect_flushes_queued_reliable_message() {
+        for flush_on_disconnect in [true, false] {
+            let mut transport = new_transport();
+            transport.set_flush_on_disconnect(flush_on_disconnect);
+            let server_addr = transport.socket.local_addr().unwrap();
+            let client_id = 7;
+            let (client_socket, rx) = connect_client(&mut transport, client_id);
+            client_socket
+                .set_read_timeout(Some(Duration::from_millis(100)))
+                .unwrap();
+
+            let mut session = DenariaServer::new(
+                0,
+                ConnectionConfig::default(),
+                rx,
+                transport.from_denaria_server_tx.clone(),
+            );
+            session.add_connection(ClientId::from_raw(client_id), "player1".to_string());
+            session.send_message(
+                ClientId::from_raw(client_id),
+                DefaultChannel::ReliableOrdered,
+                b"goodbye".to_vec(),
+            );
+
+            let mut disconnect_packet = vec![2u8];
+            disconnect_packet.extend_from_slice(&client_id.to_le_bytes());
+            client_socket
+                .send_to(&disconnect_packet, server_addr)
+                .unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            transport.update(Duration::from_millis(16)).unwrap();
+
+            session.process_server_transport_messages();
+            assert!(session.clients_id().is_empty());
+            transport.send_packets();
+
+            let mut buffer = [0u8; TRANSPORT_MAX_PACKET_BYTES];
+            match client_socket.recv_from(&mut buffer) {
+                Ok((len, _)) => {
+                    assert!(flush_on_disconnect);
+                    assert_eq!(buffer[0], 1); // Data
+                    assert!(buffer[9..len]
+                        .windows(b"goodbye".len())
+                        .any(|window| window == b"goodbye"));
+                }
+                Err(_) => assert!(!flush_on_disconnect),
+            }
+        }
+    }
+
+    #[test]
+    fn dead_session_disconnects_its_clients() {
+        let mut transport = new_transport();
+        let server_addr = transport.socket.local_addr().unwrap();
+
+        let client_socket =
+            UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+        client_socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let client_id = 7;
+
+        let (tx, rx) = unbounded::<ToDenariaServerMessage>();
+        transport
+            .player_id_session_map
+            .insert("player1".to_string(), 0);
+        transport.session_to_denaria_server_tx.insert(0, tx.clone());
+        transport.client_id_to_server_tx_map.insert(client_id, tx);
+        transport.client_id_session_map.insert(client_id, 0);
+        transport
+            .transport_server
+            .insert_connected_client(client_id, client_socket.local_addr().unwrap());
+
+        // Session thread is gone
+        drop(rx);
+
+        let mut data_packet = vec![1u8];
+        data_packet.extend_from_slice(&client_id.to_le_bytes());
+        data_packet.extend_from_slice(&[0, 0, 0]);
+        client_socket.send_to(&data_packet, server_addr).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        transport.update(Duration::from_millis(16)).unwrap();
+
+        assert_eq!(transport.connected_clients(), 0);
+        assert!(transport.session_to_denaria_server_tx.is_empty());
+        assert!(transport.player_id_session_map.is_empty());
+        assert!(transport.client_id_to_server_tx_map.is_empty());
+        assert_eq!(transport.session_of_client(client_id), None);
+
+        let mut buffer = [0u8; TRANSPORT_MAX_PACKET_BYTES];
+        let (len, _) = client_socket.recv_from(&mut buffer).unwrap();
+        assert_eq!(buffer[0], 2); // Disconnect
+        assert_eq!(&buffer[1..9], &client_id.to_le_bytes());
+        assert_eq!(&buffer[9..len], &[DisconnectReason::SessionClosed.code()]);
+    }
+
+    #[test]
+    fn panicked_session_thread_disconnects_its_clients() {
+        let mut transport = new_transport();
+        let client_socket =
+            UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+        client_socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let client_id = 7;
+
+        let (tx, rx) = unbounded::<ToDenariaServerMessage>();
+        transport
+            .player_id_session_map
+            .insert("player1".to_string(), 0);
+        transport.session_to_denaria_server_tx.insert(0, tx.clone());
+        transport.client_id_to_server_tx_map.insert(client_id, tx);
+        transport.client_id_session_map.insert(client_id, 0);
+        transport
+            .transport_server
+            .insert_connected_client(client_id, client_socket.local_addr().unwrap());
+
+        let session_thread = std::thread::spawn(move || {
+            let _rx = rx;
+            panic!("system panicked");
+        });
+        while !session_thread.is_finished() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        transport.session_threads.insert(0, session_thread);
+
+        // Nothing is sent to the session, its end is noticed on the next update
+        transport.update(Duration::from_millis(16)).unwrap();
+
+        assert_eq!(transport.connected_clients(), 0);
+        assert!(transport.session_threads.is_empty());
+        assert!(transport.session_to_denaria_server_tx.is_empty());
+        assert!(transport.player_id_session_map.is_empty());
+        assert!(transport.client_id_to_server_tx_map.is_empty());
+        assert_eq!(transport.session_of_client(client_id), None);
+
+        let mut buffer = [0u8; TRANSPORT_MAX_PACKET_BYTES];
+        let (len, _) = client_socket.recv_from(&mut buffer).unwrap();
+        assert_eq!(buffer[0], 2); // Disconnect
+        assert_eq!(&buffer[9..len], &[DisconnectReason::SessionClosed.code()]);
+    }
+
+    #[test]
+    fn session_queries_follow_connect_and_disconnect() {
+        let mut transport = new_transport();
+        let server_addr = transport.socket.local_addr().unwrap();
+
+        let (tx, _rx) = unbounded::<ToDenariaServerMessage>();
+        transport.session_to_denaria_server_tx.insert(3, tx);
+        for player_id in ["player1", "player2"] {
+            transport
+                .player_id_session_map
+                .insert(player_id.to_string(), 3);
+        }
+
+        let client_sockets: Vec<UdpSocket> = (0..2)
+            .map(|_| UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap())
+            .collect();
+        for (i, client_socket) in client_sockets.iter().enumerate() {
+            let client_id = i as u64 + 1;
+            transport.transport_server.insert_authenticated_client(
+                client_id,
+                client_socket.local_addr().unwrap(),
+                &format!("player{client_id}"),
+            );
+            let mut data_packet = vec![1u8];
+            data_packet.extend_from_slice(&client_id.to_le_bytes());
+            data_packet.push(0);
+            client_socket.send_to(&data_packet, server_addr).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(50));
+
+        assert_eq!(transport.session_of_client(1), None);
+        transport.update(Duration::from_millis(16)).unwrap();
+
+        assert_eq!(transport.session_of_client(1), Some(3));
+        assert_eq!(transport.session_of_client(2), Some(3));
+        let mut clients = transport.clients_in_session(3);
+        clients.sort();
+        assert_eq!(clients, vec![1, 2]);
+        assert!(transport.clients_in_session(4).is_empty());
+
+        transport.disconnect_all();
+
+        assert_eq!(transport.session_of_client(1), None);
+        assert!(transport.clients_in_session(3).is_empty());
+    }
+
+    #[test]
+    fn disconnect_session_only_disconnects_its_clients() {
+        let mut transport = new_transport();
+        let (tx0, rx0) = unbounded::<ToDenariaServerMessage>();
+        let (tx1, rx1) = unbounded::<ToDenariaServerMessage>();
+        let socket1 = connect_client_to_session(&mut transport, 1, 0, tx0.clone());
+        let socket2 = connect_client_to_session(&mut transport, 2, 0, tx0);
+        let _socket3 = connect_client_to_session(&mut transport, 3, 1, tx1);
+
+        assert_eq!(transport.disconnect_session(0), 2);
+
+        let mut buffer = [0u8; 1500];
+        for client_socket in [&socket1, &socket2] {
+            let (len, _) = client_socket.recv_from(&mut buffer).unwrap();
+            assert!(len > 0);
+            // Disconnect packet
+            assert_eq!(buffer[0], 2);
+        }
+        let mut disconnected: Vec<u64> = rx0
+            .try_iter()
+            .map(|message| match message {
+                ToDenariaServerMessage::ClientDisconnected { client_id } => client_id,
+                _ => panic!("expected only ClientDisconnected messages"),
+            })
+            .collect();
+        disconnected.sort();
+        assert_eq!(disconnected, vec![1, 2]);
+
+        assert!(rx1.try_recv().is_err());
+        assert_eq!(transport.transport_server.clients_id(), vec![3]);
+        assert_eq!(transport.client_id_session_map.get(&3), Some(&1));
+        assert!(transport.client_id_to_server_tx_map.contains_key(&3));
+        assert!(!transport.client_id_session_map.contains_key(&1));
+        assert!(!transport.client_id_to_server_tx_map.contains_key(&2));
+        // The session itself keeps running
+        assert!(transport.session_to_denaria_server_tx.contains_key(&0));
+    }
+
+    #[test]
+    fn slow_tick_warns_over_budget() {
+        let mut transport = new_transport();
+        let capture = WarnCapture::default();
+        let subscriber = tracing_subscriber::registry().with(capture.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            transport.update(Duration::from_millis(16)).unwrap();
+            assert!(capture.0.lock().unwrap().is_empty());
+
+            // Any real tick takes longer than no time at all
+            transport.set_tick_budget(Duration::ZERO);
+            transport.update(Duration::from_millis(16)).unwrap();
+        });
+
+        let warnings = capture.0.lock().unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0], "Transport tick exceeded its budget");
+    }
+
+    #[test]
+    fn session_with_a_long_player_id_is_refused() {
+        let mut transport = new_transport();
+        let result = transport.create_session(
+            3,
+            vec![
+                "player1".to_string(),
+                "a_player_id_longer_than_16_bytes".to_string(),
+            ],
+            MovementConfig::default(),
+            0,
+            TickRate::default(),
+        );
+
+        assert!(matches!(
+            result,
+            Err(TransportServerError::PlayerIdTooLong(ref player_id))
+                if player_id == "a_player_id_longer_than_16_bytes"
+        ));
+        assert!(transport.player_id_session_map.is_empty());
+        assert!(transport.session_threads.is_empty());
+    }
+
+    #[test]
+    fn admin_commands_ban_and_unban_addresses() {
+        let mut transport = new_transport();
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        assert!(transport.apply_admin_command(AdminCommand::Ban { ip, duration: None }));
+        assert!(transport.transport_server.is_ip_banned(ip));
+        assert!(transport.apply_admin_command(AdminCommand::Unban { ip }));
+        assert!(!transport.transport_server.is_ip_banned(ip));
+        // Not banned anymore
+        assert!(!transport.apply_admin_command(AdminCommand::Unban { ip }));
+    }
+
+    #[test]
+    fn admin_teleport_is_forwarded_to_the_session_of_the_player() {
+        let mut transport = new_transport();
+        let (tx, rx) = unbounded::<ToDenariaServerMessage>();
+        transport.session_to_denaria_server_tx.insert(3, tx);
+        transport
+            .player_id_session_map
+            .insert("player1".to_string(), 3);
+        let teleport = |player_id: &str| AdminCommand::Teleport {
+            player_id: player_id.to_string(),
+            position: Vec3::new(1.0, 2.0, 3.0),
+        };
+
+        assert!(!transport.apply_admin_command(teleport("nobody")));
+        assert!(transport.apply_admin_command(teleport("player1")));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ToDenariaServerMessage::Teleport { player_id, position })
+                if player_id == "player1" && position == Vec3::new(1.0, 2.0, 3.0)
+        ));
+    }
+
+    #[test]
+    fn set_session_paused_is_forwarded_to_the_session() {
+        let mut transport = new_transport();
+        let (tx, rx) = unbounded::<ToDenariaServerMessage>();
+        transport.session_to_denaria_server_tx.insert(3, tx);
+
+        assert!(!transport.set_session_paused(4, true));
+        assert!(transport.set_session_paused(3, true));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ToDenariaServerMessage::SetPaused { paused: true })
+        ));
+    }
+}
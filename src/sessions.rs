@@ -1,7 +1,6 @@
-use std::time::Duration;
+use std::{io, time::Duration};
 
 use bevy::{
-    app::ScheduleRunnerPlugin,
     diagnostic::{
         EntityCountDiagnosticsPlugin, FrameTimeDiagnosticsPlugin,
         SystemInformationDiagnosticsPlugin,
@@ -9,25 +8,46 @@ use bevy::{
     prelude::*,
 };
 use bevy_rapier3d::{
-    plugin::{NoUserData, RapierPhysicsPlugin},
+    plugin::{NoUserData, RapierPhysicsPlugin, TimestepMode},
     render::RapierDebugRenderPlugin,
 };
-use crossbeam::channel::{Receiver, Sender};
+use crossbeam::channel::{unbounded, Receiver, Sender};
 use iyes_perf_ui::PerfUiPlugin;
 
 use crate::{
+    ecs::components::{
+        DamagePolicy, DisconnectHook, DisconnectedPlayer, HealthBroadcastInterval,
+        InputAckInterval, InterestConfig, Interval, IntervalTimer, MatchConfig, MatchState, Motd,
+        MovementConfig, ScoreboardInterval, SessionDiagnostics, SessionRng, SpawnPoints,
+        TeamConfig, TickRate, TransformSendThreshold, WeaponRegistry, WorldBounds,
+    },
     ecs::systems::{
+        ammo::{handle_reload_events, update_reloads},
+        death::{enforce_world_bounds, handle_death_events},
         debug::{
             look_debug_camera, move_debug_camera, set_debug_3d_render_camera, set_debug_metrics,
             set_debug_metrics_cam,
         },
+        diagnostics::export_diagnostics,
         handle_events::{
             handle_character_movement, handle_disconnect_events, handle_fire_events,
             handle_hit_events, handle_look_events, handle_spawn_events,
         },
-        handle_server::{handle_outgoing_messages, handle_server_events, handle_server_messages},
-        on_change::{on_health_change, on_spawn_change, on_transform_change},
-        setup::{setup, setup_level},
+        handle_server::{
+            exit_on_shutdown, handle_control_messages, handle_outgoing_messages,
+            handle_server_events, handle_server_messages,
+        },
+        match_state::update_match_state,
+        on_change::{
+            broadcast_health, on_health_change, on_spawn_change, on_transform_change,
+            send_input_acks, send_motd, send_world_snapshot, transform_broadcast_due,
+        },
+        pause::{pause_physics, session_running},
+        projectile::advance_projectiles,
+        scoreboard::broadcast_scoreboard,
+        setup::{setup, setup_level, stream_level_objects, LevelCache, LevelLoadConfig},
+        stance::handle_stance_events,
+        teleport::handle_teleports,
     },
     server::{
         connection::ConnectionConfig,
@@ -36,21 +56,180 @@ use crate::{
     },
 };
 
+#[allow(clippy::too_many_arguments)]
 pub fn new_session(
+    session_id: u32,
+    movement_config: MovementConfig,
+    seed: u64,
+    tick_rate: TickRate,
+    connection_config: ConnectionConfig,
+    diagnostics: SessionDiagnostics,
     to_transport_server_tx: Sender<FromDenariaServerMessage>,
     from_transport_server_rx: Receiver<ToDenariaServerMessage>,
 ) {
-    tracing::info!("Creating new session");
+    tracing::info!(
+        session_id,
+        seed,
+        tick_rate = tick_rate.0,
+        "Creating new session with {movement_config:?}"
+    );
 
-    let server = DenariaServer::new(
-        ConnectionConfig::default(),
+    let mut server = DenariaServer::new(
+        session_id,
+        connection_config,
         from_transport_server_rx,
         to_transport_server_tx,
     );
 
+    let skip_self_updates =
+        std::env::var("SKIP_SELF_UPDATES").is_ok_and(|v| v.to_lowercase() == "true");
+    server.set_skip_self_updates(skip_self_updates);
+    let compact_transforms =
+        std::env::var("COMPACT_TRANSFORMS").is_ok_and(|v| v.to_lowercase() == "true");
+    server.set_compact_transforms(compact_transforms);
+    let send_velocity = std::env::var("SEND_VELOCITY").is_ok_and(|v| v.to_lowercase() == "true");
+    server.set_send_velocity(send_velocity);
+    if let Some(max_messages_per_tick) = std::env::var("MAX_CLIENT_MESSAGES_PER_TICK")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        server.set_max_messages_per_tick(max_messages_per_tick);
+    }
+    if let Some(capacity) = std::env::var("SERVER_EVENT_HISTORY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        server.set_event_history_capacity(capacity);
+    }
+    // An idle timeout of 0 disables it
+    if let Some(idle_timeout) = std::env::var("CLIENT_IDLE_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        server.set_idle_timeout(Some(Duration::from_secs(idle_timeout)).filter(|t| !t.is_zero()));
+    }
+
     let mut app = App::new();
 
     app.insert_resource(server);
+    app.insert_resource(movement_config);
+    app.insert_resource(WorldBounds::default());
+    app.insert_resource(SessionRng::new(seed));
+    app.insert_resource(SpawnPoints::default());
+
+    let mut transform_send_threshold = TransformSendThreshold::default();
+    if let Some(position) = std::env::var("TRANSFORM_POSITION_EPSILON")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        transform_send_threshold.position = position;
+    }
+    if let Some(rotation) = std::env::var("TRANSFORM_ROTATION_EPSILON")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        transform_send_threshold.rotation = rotation;
+    }
+    app.insert_resource(transform_send_threshold);
+
+    let mut interest_config = InterestConfig::default();
+    if let Some(radius) = std::env::var("INTEREST_RADIUS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        interest_config.radius = radius;
+    }
+    app.insert_resource(interest_config);
+
+    let motd = std::env::var("MOTD").map(Motd::new).unwrap_or_default();
+    app.insert_resource(motd);
+
+    let mut level_load_config = LevelLoadConfig::default();
+    if let Some(max_objects) = std::env::var("LEVEL_MAX_OBJECTS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        level_load_config.max_objects = max_objects;
+    }
+    if let Some(batch_size) = std::env::var("LEVEL_SPAWN_BATCH_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        level_load_config.batch_size = batch_size;
+    }
+    app.insert_resource(level_load_config);
+    app.insert_resource(LevelCache::global());
+
+    app.insert_resource(interval_timer_from_env::<ScoreboardInterval>(
+        "SCOREBOARD_INTERVAL_MS",
+    ));
+    app.insert_resource(interval_timer_from_env::<HealthBroadcastInterval>(
+        "HEALTH_BROADCAST_INTERVAL_MS",
+    ));
+    app.insert_resource(interval_timer_from_env::<InputAckInterval>(
+        "INPUT_ACK_RESEND_INTERVAL_MS",
+    ));
+
+    // A score or time limit of 0 disables that end condition
+    let mut match_config = MatchConfig::default();
+    if let Some(warmup) = std::env::var("MATCH_WARMUP_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        match_config.warmup = Duration::from_secs(warmup);
+    }
+    if let Some(score_limit) = std::env::var("MATCH_SCORE_LIMIT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        match_config.score_limit = Some(score_limit).filter(|limit| *limit > 0);
+    }
+    if let Some(time_limit) = std::env::var("MATCH_TIME_LIMIT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        match_config.time_limit =
+            Some(Duration::from_secs(time_limit)).filter(|limit| !limit.is_zero());
+    }
+    tracing::info!(session_id, "Match config {match_config:?}");
+    app.insert_resource(match_config);
+    app.insert_resource(MatchState::default());
+
+    let mut team_config = TeamConfig::default();
+    if let Some(team_count) = std::env::var("TEAM_COUNT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        team_config.team_count = team_count;
+    }
+    let friendly_fire =
+        std::env::var("FRIENDLY_FIRE").map_or(true, |v| v.to_lowercase() != "false");
+    team_config.friendly_fire = friendly_fire;
+    app.insert_resource(team_config);
+
+    let mut damage_policy = DamagePolicy::default();
+    if let Ok(self_damage) = std::env::var("SELF_DAMAGE") {
+        damage_policy.self_damage = self_damage.to_lowercase() == "true";
+    }
+    if let Some(friendly_fire_multiplier) = std::env::var("FRIENDLY_FIRE_DAMAGE_MULTIPLIER")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        damage_policy.friendly_fire_multiplier = friendly_fire_multiplier;
+    }
+    tracing::info!(session_id, "Damage policy {damage_policy:?}");
+    app.insert_resource(damage_policy);
+    app.insert_resource(WeaponRegistry::default());
+
+    // Final player state is posted to the stats backend when a player leaves
+    if let Ok(player_stats_url) = std::env::var("PLAYER_STATS_URL") {
+        match player_stats_hook(player_stats_url, session_id) {
+            Ok(hook) => {
+                app.insert_resource(hook);
+            }
+            Err(e) => tracing::error!(session_id, "Failed to start the player stats worker: {e}"),
+        }
+    }
 
     let enable_debug_metrics =
         std::env::var("ENABLE_DEBUG_METRICS").is_ok_and(|v| v.to_lowercase() == "true");
@@ -58,13 +237,9 @@ pub fn new_session(
         std::env::var("ENABLE_DEBUG_CAM").is_ok_and(|v| v.to_lowercase() == "true");
 
     if !enable_debug_metrics && !enable_debug_cam {
-        app.add_plugins(MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(
-            Duration::from_secs_f64(1.0 / 120.0),
-        )));
+        app.add_plugins(MinimalPlugins.set(tick_rate.runner()));
     } else {
         app.add_plugins(DefaultPlugins)
-            .add_plugins(FrameTimeDiagnosticsPlugin)
-            .add_plugins(EntityCountDiagnosticsPlugin)
             .add_plugins(SystemInformationDiagnosticsPlugin)
             .add_plugins(PerfUiPlugin)
             .add_systems(PostStartup, set_debug_metrics);
@@ -78,26 +253,70 @@ pub fn new_session(
         }
     }
 
+    // Exported to the transport whether the debug metrics are shown or not
+    app.add_plugins(FrameTimeDiagnosticsPlugin)
+        .add_plugins(EntityCountDiagnosticsPlugin)
+        .insert_resource(diagnostics)
+        .add_systems(PostUpdate, export_diagnostics);
+
     app.add_plugins(RapierPhysicsPlugin::<NoUserData>::default())
+        // One physics step per tick
+        .insert_resource(TimestepMode::Fixed {
+            dt: tick_rate.delta().as_secs_f32(),
+            substeps: 1,
+        })
+        .insert_resource(tick_rate)
         .add_systems(Startup, (setup, setup_level).chain())
+        // Level colliders are spawned a batch per frame
+        .add_systems(PreUpdate, stream_level_objects)
         .add_systems(
             PreUpdate,
-            (handle_server_events, handle_server_messages).chain(),
+            (
+                handle_server_events,
+                exit_on_shutdown,
+                handle_server_messages,
+                handle_control_messages,
+                pause_physics,
+            )
+                .chain(),
         )
         .add_systems(PostUpdate, handle_outgoing_messages)
         .add_systems(
             Update,
             (
                 (
-                    handle_character_movement,
-                    handle_look_events,
-                    handle_fire_events,
-                    handle_hit_events,
+                    // Frozen while the session is paused, joins and leaves are still handled
+                    (
+                        update_match_state,
+                        handle_character_movement,
+                        handle_look_events,
+                        // The stance resizes the hitbox of the player before the fires
+                        (
+                            handle_stance_events,
+                            handle_fire_events,
+                            advance_projectiles,
+                        )
+                            .chain(),
+                        (update_reloads, handle_reload_events).chain(),
+                        (handle_hit_events, enforce_world_bounds, handle_death_events).chain(),
+                    )
+                        .run_if(session_running),
                     handle_spawn_events,
                     handle_disconnect_events,
+                    // Also while paused, the pending move of the player is dropped
+                    handle_teleports.after(handle_character_movement),
                 )
                     .in_set(MySet::HandleGameEvents),
-                (on_spawn_change, on_transform_change, on_health_change)
+                (
+                    (send_world_snapshot, send_motd).chain(),
+                    on_spawn_change,
+                    // Throttled while the transport can't keep up
+                    on_transform_change.run_if(transform_broadcast_due),
+                    on_health_change,
+                    send_input_acks,
+                    broadcast_health,
+                    broadcast_scoreboard,
+                )
                     .after(MySet::HandleGameEvents),
             ),
         );
@@ -105,6 +324,72 @@ pub fn new_session(
     app.run();
 }
 
+/// Timer of `T` with the interval of the `name` variable in milliseconds, or its default.
+fn interval_timer_from_env<T: Interval>(name: &str) -> IntervalTimer<T> {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(|ms| IntervalTimer::new(Duration::from_millis(ms)))
+        .unwrap_or_default()
+}
+
+/// Hook posting the final state of the leaving players to `url` from one worker thread of the
+/// session, in the order they left. Once the session is gone the worker still finishes the
+/// queued posts.
+fn player_stats_hook(url: String, session_id: u32) -> io::Result<DisconnectHook> {
+    let (players_tx, players_rx) = unbounded::<DisconnectedPlayer>();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    std::thread::Builder::new()
+        .name(format!("player-stats-{session_id}"))
+        .spawn(move || {
+            for player in players_rx {
+                runtime.block_on(persist_player_stats(url.clone(), session_id, player));
+            }
+        })?;
+
+    Ok(DisconnectHook::new(move |player| {
+        if let Err(e) = players_tx.send(player) {
+            tracing::error!(
+                session_id,
+                player_id = e.0.player_id.as_str(),
+                "Player stats worker stopped, stats not persisted"
+            );
+        }
+    }))
+}
+
+async fn persist_player_stats(url: String, session_id: u32, player: DisconnectedPlayer) {
+    let client = reqwest::Client::new();
+    let response = client
+        .post(url)
+        .json(&serde_json::json!({
+            "SessionId": session_id,
+            "PlayerId": player.player_id,
+            "DisconnectReason": player.reason.to_string(),
+            "Health": player.health,
+        }))
+        .send()
+        .await;
+    match response {
+        Ok(response) if !response.status().is_success() => {
+            tracing::error!(
+                player_id = player.player_id,
+                "Failed to persist player stats: {}",
+                response.status()
+            );
+        }
+        Ok(_) => {}
+        Err(e) => {
+            tracing::error!(
+                player_id = player.player_id,
+                "Failed to persist player stats: {e}"
+            );
+        }
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash, SystemSet)]
 pub enum MySet {
     HandleGameEvents,
@@ -0,0 +1,291 @@
+use std::{io, net::SocketAddr, path::Path, time::Duration};
+
+use serde::Deserialize;
+
+use crate::{
+    constants::{
+        SESSION_TICK_RATE, TRANSPORT_CONNECTION_TIMEOUT, TRANSPORT_MAX_CLIENTS, TRANSPORT_SEND_RATE,
+    },
+    server::{
+        channel::{ChannelConfig, DefaultChannel, SendType},
+        connection::ConnectionConfig,
+        transport::{
+            bind::BindConfig,
+            server::server::{AuthConfig, ServerConfig},
+        },
+    },
+};
+
+/// Settings of the whole server, read from the TOML file given with `--config <path>` or
+/// SERVER_CONFIG. Missing keys keep their default, without a file every setting does.
+///
+/// ```toml
+/// bind_addr = "0.0.0.0:5000"
+/// max_clients = 32
+///
+/// [channels]
+/// reliable_max_memory_bytes = 1048576
+///
+/// [auth]
+/// playfab_api_url = "https://titleid.playfabapi.com"
+/// playfab_api_key = "secret"
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub bind_addr: SocketAddr,
+    /// See [`BindConfig::public_addr`]
+    pub public_addr: Option<SocketAddr>,
+    pub max_clients: usize,
+    /// Transport updates per second
+    pub tick_rate: u32,
+    /// Simulation ticks per second of the sessions created without a tick rate
+    pub session_tick_rate: u32,
+    pub keep_alive_interval_ms: u64,
+    pub connection_timeout_secs: u64,
+    pub channels: ChannelSettings,
+    /// PLAYFAB_API_URL and PLAYFAB_API_KEY are read instead when missing
+    pub auth: Option<AuthConfig>,
+}
+
+/// Limits of the channels of every client connection, the same on both sides.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelSettings {
+    pub available_bytes_per_tick: u64,
+    pub unreliable_max_memory_bytes: usize,
+    pub reliable_max_memory_bytes: usize,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            bind_addr: BindConfig::default().bind_addr,
+            public_addr: None,
+            max_clients: 64,
+            tick_rate: 60,
+            session_tick_rate: SESSION_TICK_RATE,
+            keep_alive_interval_ms: TRANSPORT_SEND_RATE.as_millis() as u64,
+            connection_timeout_secs: TRANSPORT_CONNECTION_TIMEOUT.as_secs(),
+            channels: ChannelSettings::default(),
+            auth: None,
+        }
+    }
+}
+
+impl Default for ChannelSettings {
+    fn default() -> Self {
+        // The reliable channel comes first, then the unreliable one
+        let channels = DefaultChannel::config();
+        Self {
+            available_bytes_per_tick: ConnectionConfig::default().available_bytes_per_tick,
+            unreliable_max_memory_bytes: channels[1].max_memory_usage_bytes,
+            reliable_max_memory_bytes: channels[0].max_memory_usage_bytes,
+        }
+    }
+}
+
+impl ServerSettings {
+    /// Reads the file of `--config <path>`, or else of SERVER_CONFIG. The defaults are used
+    /// when neither is set.
+    pub fn load<I, F>(args: I, env: F) -> io::Result<Self>
+    where
+        I: IntoIterator<Item = String>,
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut path = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--config" {
+                path = Some(
+                    args.next()
+                        .ok_or_else(|| invalid_data("--config: missing value"))?,
+                );
+            }
+        }
+        match path.or_else(|| env("SERVER_CONFIG")) {
+            Some(path) => Self::from_file(path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let toml = std::fs::read_to_string(path)?;
+        Self::from_toml(&toml)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))
+    }
+
+    /// Parses and validates the settings.
+    pub fn from_toml(toml: &str) -> io::Result<Self> {
+        let settings: Self = toml::from_str(toml).map_err(|e| invalid_data(&e.to_string()))?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn validate(&self) -> io::Result<()> {
+        if !(1..=TRANSPORT_MAX_CLIENTS).contains(&self.max_clients) {
+            return Err(invalid_data(&format!(
+                "max_clients must be between 1 and {TRANSPORT_MAX_CLIENTS}"
+            )));
+        }
+        if !(1..=1000).contains(&self.tick_rate) {
+            return Err(invalid_data("tick_rate must be between 1 and 1000"));
+        }
+        if !(1..=1000).contains(&self.session_tick_rate) {
+            return Err(invalid_data("session_tick_rate must be between 1 and 1000"));
+        }
+        if self.keep_alive_interval_ms == 0
+            || self.keep_alive_interval() >= self.connection_timeout()
+        {
+            return Err(invalid_data(
+                "keep_alive_interval_ms must be above 0 and below the connection timeout",
+            ));
+        }
+        if self.channels.available_bytes_per_tick == 0 {
+            return Err(invalid_data(
+                "channels.available_bytes_per_tick must be above 0",
+            ));
+        }
+        self.connection_config().map(|_| ())
+    }
+
+    pub fn tick_delta(&self) -> Duration {
+        Duration::from_millis(1000 / self.tick_rate as u64)
+    }
+
+    pub fn keep_alive_interval(&self) -> Duration {
+        Duration::from_millis(self.keep_alive_interval_ms)
+    }
+
+    pub fn connection_timeout(&self) -> Duration {
+        Duration::from_secs(self.connection_timeout_secs)
+    }
+
+    /// `--bind`, `--port` and `--public-addr` and their variables still take precedence.
+    pub fn bind_config(&self) -> BindConfig {
+        BindConfig {
+            bind_addr: self.bind_addr,
+            public_addr: self.public_addr,
+        }
+    }
+
+    pub fn server_config(
+        &self,
+        current_time: Duration,
+        public_addresses: Vec<SocketAddr>,
+    ) -> ServerConfig {
+        ServerConfig {
+            current_time,
+            max_clients: self.max_clients,
+            public_addresses,
+            keep_alive_interval: self.keep_alive_interval(),
+            connection_timeout: self.connection_timeout(),
+        }
+    }
+
+    pub fn connection_config(&self) -> io::Result<ConnectionConfig> {
+        let channels: Vec<ChannelConfig> = DefaultChannel::config()
+            .into_iter()
+            .map(|mut channel| {
+                channel.max_memory_usage_bytes = match channel.send_type {
+                    SendType::Unreliable => self.channels.unreliable_max_memory_bytes,
+                    _ => self.channels.reliable_max_memory_bytes,
+                };
+                channel
+            })
+            .collect();
+        ConnectionConfig::builder()
+            .available_bytes_per_tick(self.channels.available_bytes_per_tick)
+            .server_channels_config(channels.clone())
+            .client_channels_config(channels)
+            .build()
+            .map_err(|e| invalid_data(&e.to_string()))
+    }
+}
+
+fn invalid_data(error: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("server settings: {error}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_config_derives_server_config() {
+        let settings = ServerSettings::from_toml(
+            r#"
+            bind_addr = "0.0.0.0:6000"
+            public_addr = "203.0.113.7:6000"
+            max_clients = 32
+            tick_rate = 30
+            keep_alive_interval_ms = 500
+            connection_timeout_secs = 20
+
+            [channels]
+            reliable_max_memory_bytes = 1048576
+
+            [auth]
+            playfab_api_url = "https://title.playfabapi.com"
+            playfab_api_key = "secret"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            settings.server_config(Duration::ZERO, vec!["203.0.113.7:6000".parse().unwrap()]),
+            ServerConfig {
+                current_time: Duration::ZERO,
+                max_clients: 32,
+                public_addresses: vec!["203.0.113.7:6000".parse().unwrap()],
+                keep_alive_interval: Duration::from_millis(500),
+                connection_timeout: Duration::from_secs(20),
+            }
+        );
+        assert_eq!(settings.tick_delta(), Duration::from_millis(33));
+        assert_eq!(
+            settings.bind_config(),
+            BindConfig {
+                bind_addr: "0.0.0.0:6000".parse().unwrap(),
+                public_addr: Some("203.0.113.7:6000".parse().unwrap()),
+            }
+        );
+        assert_eq!(
+            settings
+                .auth
+                .as_ref()
+                .map(|auth| auth.playfab_api_url.as_str()),
+            Some("https://title.playfabapi.com")
+        );
+        // The key is never logged
+        assert!(!format!("{settings:?}").contains("secret"));
+
+        let connection_config = settings.connection_config().unwrap();
+        let memory: Vec<usize> = connection_config
+            .server_channels_config
+            .iter()
+            .map(|channel| channel.max_memory_usage_bytes)
+            .collect();
+        // Reliable then unreliable
+        assert_eq!(memory, vec![1024 * 1024, 5 * 1024 * 1024]);
+        assert_eq!(connection_config.available_bytes_per_tick, 60_000);
+    }
+
+    #[test]
+    fn invalid_config_is_rejected() {
+        assert_eq!(
+            ServerSettings::from_toml("").unwrap(),
+            ServerSettings::default()
+        );
+        assert!(ServerSettings::from_toml("max_clients = 0").is_err());
+        assert!(ServerSettings::from_toml("tick_rate = 0").is_err());
+        assert!(ServerSettings::from_toml("session_tick_rate = 5000").is_err());
+        assert!(ServerSettings::from_toml("keep_alive_interval_ms = 10000").is_err());
+        assert!(ServerSettings::from_toml("max_client = 8").is_err());
+        assert!(ServerSettings::from_toml("bind_addr = \"nope\"").is_err());
+    }
+}